@@ -26,3 +26,31 @@ pub const DEFAULT_LOSSLESS: u8 = 1;
 pub const DEFAULT_LOSSWINDOW_MS: u32 = 1000;
 pub const DEFAULT_BLOCKDUMP: u8 = 0;
 pub const MAX_COMMAND_LENGTH: libc::c_int = 1024;
+pub const MTU_PROBE_TIMEOUT_MS: u64 = 200;
+pub const MAX_VERIFICATION_RETRIES: u32 = 3;
+pub const MAX_REFINED_CHUNKS: usize = 1024;
+pub const STATE_FILE_SUFFIX: &str = ".namida-state";
+pub const STATE_SAVE_INTERVAL_MS: u64 = 5000;
+pub const DELTA_BASIS_SUFFIX: &str = ".namida-old";
+pub const MAX_PARALLEL_TRANSFERS: u16 = 64;
+
+/// How often to try connecting again if the server is busy, before giving up.
+pub const MAX_BUSY_RETRIES: u32 = 3;
+
+/// The local filename that stands for standard output.
+pub const STDOUT_PATH: &str = "-";
+
+/// How much data that arrives ahead of the data due next is kept in memory when writing to
+/// standard output. Blocks beyond this window are discarded, and retransmitted later.
+pub const REORDER_WINDOW: u64 = 64 * 1024 * 1024;
+
+/// How long to wait for data of a followed file before checking whether the server has reported
+/// that the file has grown, and requesting lost blocks again.
+pub const FOLLOW_RECEIVE_TIMEOUT_MS: u64 = 500;
+
+/// How long to wait at least for the replies to the pings the server has not replied to yet, once a
+/// transfer has ended. The round trip time estimate extends the wait on slow connections.
+pub const PING_REPLY_TIMEOUT_SECS: u64 = 5;
+
+/// How long to wait for the server to let a further connection join a session.
+pub const JOIN_TIMEOUT_SECS: u64 = 30;
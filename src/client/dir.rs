@@ -1,6 +1,10 @@
 use std::path::PathBuf;
 
-use crate::message;
+use crate::{
+    filter::FileFilter,
+    message::{self, ListRequest},
+    types::{EntryType, ListOrder},
+};
 use anyhow::bail;
 
 #[derive(Clone, clap::Args)]
@@ -25,9 +29,38 @@ pub struct Parameter {
 
     /// If specified, the output will be given in machine readable format, i.e. only the file paths
     /// will be printed to standard output, without any extraneous decorating information.
+    /// Directories and symbolic links are omitted in this format.
     #[arg(short = 'm')]
     pub machine_readable: bool,
 
+    #[command(flatten)]
+    pub filter: FileFilter,
+
+    /// Only list the entries within this directory, given the way it appears in the server's
+    /// listing.
+    pub directory: Option<PathBuf>,
+
+    /// Only list the direct children of the directory, or the paths shared by the server if no
+    /// directory is given, so that huge trees can be browsed one level at a time.
+    #[arg(long = "shallow")]
+    pub shallow: bool,
+
+    /// The order in which the entries are listed.
+    #[arg(long = "sort", value_name = "ORDER", default_value_t = ListOrder::Index, value_enum)]
+    pub order: ListOrder,
+
+    /// List the entries in reverse order.
+    #[arg(long = "reverse")]
+    pub reverse: bool,
+
+    /// The number of entries to skip, to list the following page of entries.
+    #[arg(long = "offset", default_value_t = 0)]
+    pub offset: u64,
+
+    /// The maximum number of entries to list.
+    #[arg(long = "limit")]
+    pub limit: Option<u64>,
+
     #[arg(skip = *crate::common::DEFAULT_SECRET)]
     pub secret: [u8; 32],
 }
@@ -51,35 +84,61 @@ pub fn run(mut parameter: Parameter) -> anyhow::Result<()> {
     // send request and parse the resulting response
     session
         .server
-        .write(message::ClientToServer::FileListRequest)?;
-    let message::ServerToClient::FileCount(num_files) = session.server.read()? else {
+        .write(message::ClientToServer::FileListRequest(ListRequest {
+            filter: parameter.filter.clone(),
+            directory: parameter.directory.clone(),
+            shallow: parameter.shallow,
+            order: parameter.order,
+            reverse: parameter.reverse,
+            offset: parameter.offset,
+            limit: parameter.limit,
+        }))?;
+    let message::ServerToClient::FileCount { total, count } = session.server.read()? else {
         bail!("Expected file count");
     };
 
+    let first = parameter.offset;
+    let end = first.saturating_add(count);
     if !parameter.machine_readable {
-        if num_files == 0 {
+        if total == 0 {
             eprintln!(
                 "Server advertises 0 files. Either no files are available, or indexing is disabled."
             );
-        } else {
+        } else if count == total {
             eprintln!("Remote file list:");
+        } else if count == 0 {
+            eprintln!("Remote file list has only {total} entries.");
+        } else {
+            eprintln!(
+                "Remote file list, entries {first} to {} of {total}:",
+                end.saturating_sub(1)
+            );
         }
     }
 
-    for i in 0..num_files {
+    for i in first..end {
         let message::ServerToClient::FileListEntry(file_metadata) = session.server.read()? else {
             bail!("Expected file list entry");
         };
 
-        if parameter.machine_readable {
-            println!("{}", file_metadata.path.display());
-        } else {
-            eprintln!(
+        match (&file_metadata.entry_type, parameter.machine_readable) {
+            (EntryType::File, true) => println!("{}", file_metadata.path.display()),
+            (_, true) => {}
+            (EntryType::File, false) => eprintln!(
                 " {:2}) {:<64} {:10}",
                 i,
                 file_metadata.path.display(),
                 file_metadata.size.0
-            );
+            ),
+            (EntryType::Directory, false) => {
+                eprintln!(" {:2}) {}/", i, file_metadata.path.display());
+            }
+            (EntryType::Symlink(target), false) => eprintln!(
+                " {:2}) {} -> {}",
+                i,
+                file_metadata.path.display(),
+                target.display()
+            ),
         }
     }
 
@@ -1,22 +1,30 @@
 use std::{
+    collections::HashMap,
     io::Write,
     path::{Path, PathBuf},
-    sync::Arc,
-    time::Instant,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 use anyhow::bail;
 
 use crate::{
-    client::Statistics,
+    client::{state::FileIdentity, Statistics},
+    compression::Decompressor,
     datagram::{self, BlockType},
+    filter::FileFilter,
     message,
+    stream::Stream,
     types::{
-        BlockIndex, ErrorRate, FileMetadata, FileSize, Fraction, ReceivedMap, TargetRate, UdpErrors,
+        BlockIndex, ChecksumAlgorithm, Compression, EntryType, ErrorRate, FileMetadata, FileSize,
+        Fraction, ReceivedMap, TargetRate, UdpErrors,
     },
 };
 
-use super::{ring, OutputMode, Transfer};
+use super::{ring, OutputMode, Session, Transfer};
 
 #[derive(Clone, clap::Args)]
 #[allow(clippy::struct_excessive_bools)]
@@ -74,6 +82,15 @@ pub struct Parameter {
     #[arg(long = "faster", value_parser = clap::builder::ValueParser::new(parse_fraction), default_value_t = super::config::DEFAULT_FASTER)]
     pub faster: Fraction,
 
+    /// The block size (in bytes) to request from the server. Each UDP datagram carries one block,
+    /// so on links with a large MTU, a larger block size reduces the overhead. The server may
+    /// choose a smaller block size if it does not allow the requested one.
+    ///
+    /// If not specified, the largest block size that fits through the path MTU is determined
+    /// automatically by probing. If probing fails, 1024 byte blocks are used.
+    #[arg(long = "blocksize", value_parser = clap::value_parser!(u16).range(i64::from(crate::common::MIN_BLOCK_SIZE)..=i64::from(crate::common::MAX_BLOCK_SIZE)))]
+    pub block_size: Option<u16>,
+
     #[arg(long = "history", default_value_t = super::config::DEFAULT_HISTORY)]
     pub history: u16,
 
@@ -99,14 +116,17 @@ pub struct Parameter {
     ///
     /// This will only work if exactly one file is being requested, otherwise the command will fail!
     /// If you need to download multiple files to specific local paths, it is recommended to invoke
-    /// namida multiple times.
-    #[arg(long = "local")]
+    /// namida multiple times. If `-` is given, the data is written to standard output in order,
+    /// e.g. to pipe it into another command; all messages are written to standard error then.
+    #[arg(long = "local", short = 'o')]
     pub local_filename: Option<PathBuf>,
 
     /// Try to recreate the requested directory tree on the client.
     ///
     /// If requesting one or more files located within nested subdirectories, the client will
-    /// attempt to create the same directory tree locally.
+    /// attempt to create the same directory tree locally. Together with `--all`, the served tree
+    /// is mirrored faithfully: empty directories and symbolic links are recreated, and the
+    /// permissions and modification times of all entries are applied.
     #[arg(long = "tree", action = clap::ArgAction::SetTrue)]
     pub tree: bool,
 
@@ -118,6 +138,107 @@ pub struct Parameter {
     #[arg(long = "no-resume", action = clap::ArgAction::SetFalse)]
     pub resume: bool,
 
+    /// How often the state of a transfer is saved next to the local file (in milliseconds), so
+    /// that an interrupted transfer can be resumed without comparing checksums.
+    ///
+    /// Before saving, the received data is synced to disk, which holds up writing further blocks
+    /// until the disk has caught up; while it does, the ring buffer fills up, and the server is
+    /// asked to slow down. On slow disks, a longer interval makes these stalls rarer, at the cost of
+    /// comparing more data by checksums after an interruption.
+    #[arg(long = "state-interval", default_value_t = super::config::STATE_SAVE_INTERVAL_MS)]
+    pub state_interval_ms: u64,
+
+    /// The checksum algorithm used to find the parts of a file that are already present locally
+    /// when resuming a transfer.
+    ///
+    /// BLAKE3 is cryptographically strong, so data is only skipped if it really matches. xxh3 is
+    /// faster, but only detects accidental damage; a local file that has been tampered with could
+    /// cause mismatching data to be skipped.
+    #[arg(long = "checksum", value_enum, default_value_t = ChecksumAlgorithm::Blake3)]
+    pub checksum_algorithm: ChecksumAlgorithm,
+
+    /// Do not verify the integrity of downloaded files.
+    ///
+    /// By default, after a lossless transfer has completed, namida compares BLAKE3 digests of the
+    /// whole file and of its chunks with the server, and fetches any chunks that do not match
+    /// again. If this behaviour is not desired, this option can be specified to skip the check.
+    #[arg(long = "no-verify", action = clap::ArgAction::SetFalse)]
+    pub verify: bool,
+
+    /// Update existing local files using a delta transfer.
+    ///
+    /// If a local file already exists, it is treated as the previous version of the requested
+    /// file. The server searches the current version for the blocks of the previous version at any
+    /// offset, using rolling checksums, so data that has merely moved (e.g. because something was
+    /// inserted before it) is copied locally instead of being transmitted. While the transfer is in
+    /// progress, the previous version is kept next to the local file with the suffix
+    /// `.namida-old`.
+    #[arg(long = "delta")]
+    pub delta: bool,
+
+    /// Compress blocks on the wire using the given algorithm.
+    ///
+    /// This speeds up the transfer of compressible data (e.g. text, logs or uncompressed images)
+    /// if the link is slower than compression. Blocks that do not become smaller are sent
+    /// uncompressed.
+    #[arg(long = "compress", value_enum, default_value_t = Compression::None)]
+    pub compression: Compression,
+
+    /// Protect the transfer against losses using forward error correction.
+    ///
+    /// A parity block is sent after every group of this many data blocks (16 if no value is
+    /// given), from which a single lost block of the group can be reconstructed without waiting a
+    /// round trip for its retransmission. This is useful on links with a long round trip time. The
+    /// group size is adapted to the losses during the transfer.
+    #[arg(long = "fec", value_name = "BLOCKS", num_args = 0..=1, default_missing_value = "16", value_parser = clap::value_parser!(u8).range(i64::from(crate::fec::MIN_GROUP_SIZE)..=i64::from(crate::fec::MAX_GROUP_SIZE)))]
+    pub fec_group_size: Option<u8>,
+
+    /// Follow the requested files while they grow, e.g. to fetch log files that are still being
+    /// written.
+    ///
+    /// Data appended to a file on the server is transferred as it arrives, and the transfer of the
+    /// file only finishes once it has been closed by its writer, or has not grown for this many
+    /// seconds (10 if no value is given). Followed files are never resumed, and cannot be
+    /// transferred in batches or as deltas.
+    #[arg(long = "follow", value_name = "IDLE_SECONDS", num_args = 0..=1, default_missing_value = "10")]
+    pub follow_idle: Option<u32>,
+
+    /// Only transfer the part of the requested files starting at this byte offset.
+    ///
+    /// The range is stored as a file of its own, unless `--in-place` is given.
+    #[arg(long = "offset", value_name = "BYTES")]
+    pub offset: Option<u64>,
+
+    /// Only transfer at most this many bytes of the requested files, starting at `--offset`, or at
+    /// their start if no offset is given.
+    #[arg(long = "length", value_name = "BYTES")]
+    pub length: Option<u64>,
+
+    /// Write the requested byte range into the matching region of the local file, instead of
+    /// storing it as a file of its own. The rest of an existing local file is left alone, and a
+    /// local file that is too short is extended.
+    #[arg(long = "in-place")]
+    pub in_place: bool,
+
+    /// The number of files to transfer at the same time.
+    ///
+    /// Each parallel transfer uses a further connection to the server, which joins the session of
+    /// the first one. The transfers share the target rate, so their total rate still respects
+    /// `--rate`. This mostly speeds up the transfer of many small files, where setting up each
+    /// transfer takes a large share of the time. If a static UDP port is specified, the transfers
+    /// use consecutive ports starting with it.
+    #[arg(long = "parallel", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..=i64::from(super::config::MAX_PARALLEL_TRANSFERS)))]
+    pub parallel: u16,
+
+    /// Transfer the requested files in batches, each of which is sent as one stream.
+    ///
+    /// The files of a batch are transferred like a single file consisting of their contents
+    /// concatenated, and split up into the local files again by their offsets. This avoids setting
+    /// up a transfer for each file, which takes most of the time when transferring many small
+    /// files over a link with a long round trip time.
+    #[arg(long = "batch")]
+    pub batch: bool,
+
     #[arg(skip = *crate::common::DEFAULT_SECRET)]
     pub secret: [u8; 32],
 
@@ -128,6 +249,25 @@ pub struct Parameter {
     /// Download all files indexed on the server.
     #[arg(long = "all")]
     pub all: bool,
+
+    /// Synchronise the local tree with the one served by the server.
+    ///
+    /// This implies `--all` and `--tree`. Files that already exist locally with the same size and
+    /// modification time as on the server are skipped without comparing their contents, so that
+    /// only new or changed files are transferred.
+    #[arg(long = "sync", conflicts_with_all = ["files", "local_filename"])]
+    pub sync: bool,
+
+    /// When synchronising, delete local files and directories that no longer exist on the server.
+    ///
+    /// Only entries within the directories served by the server are considered.
+    #[arg(long = "delete", requires = "sync")]
+    pub delete: bool,
+
+    /// Selects the files to download from the files indexed on the server, instead of naming
+    /// them. Giving any of these options implies `--all`.
+    #[command(flatten)]
+    pub filter: FileFilter,
 }
 
 /// Parse a string in the form `123M` into an integer like `123000000`.
@@ -183,6 +323,31 @@ pub fn parse_fraction(fraction: &str) -> anyhow::Result<Fraction> {
 #[allow(clippy::missing_errors_doc)]
 #[allow(clippy::missing_panics_doc)]
 pub fn run(mut parameter: Parameter) -> anyhow::Result<()> {
+    // When writing the data to standard output, all messages go to standard error instead
+    if parameter.local_filename.as_deref() == Some(Path::new(super::config::STDOUT_PATH)) {
+        if parameter.delta || parameter.batch || parameter.in_place || !parameter.lossless {
+            bail!("Delta, batched, in-place and lossy transfers cannot write to standard output.");
+        }
+        super::io::redirect_stdout()?;
+        parameter.resume = false;
+    }
+
+    if parameter.offset.is_some() || parameter.length.is_some() {
+        if parameter.delta || parameter.batch || parameter.follow_idle.is_some() {
+            bail!("Byte ranges cannot be transferred in batches, as deltas, or while following a file.");
+        }
+    } else if parameter.in_place {
+        bail!("Writing in place requires a byte range to be requested using `--offset` or `--length`.");
+    }
+
+    // A followed file is still being written, so a local copy of it cannot be resumed
+    if parameter.follow_idle.is_some() {
+        if parameter.delta || parameter.batch {
+            bail!("Followed files cannot be transferred in batches or as deltas.");
+        }
+        parameter.resume = false;
+    }
+
     crate::common::load_secret(&parameter.secret_file, &mut parameter.secret);
     super::print_intro(parameter.encrypted);
 
@@ -197,13 +362,38 @@ pub fn run(mut parameter: Parameter) -> anyhow::Result<()> {
     // These variables are only used when requesting multiple files.
     let mut file_names: Vec<PathBuf> = vec![];
 
+    // The entries of the server's file list by their paths, if all files are requested, so that
+    // their metadata can be applied locally
+    let mut listing: HashMap<PathBuf, FileMetadata> = HashMap::new();
+
+    if parameter.sync {
+        parameter.all = true;
+        parameter.tree = true;
+    }
+
+    // Filters select files from the server's index, which are then downloaded as with `--all`
+    if !parameter.filter.is_empty() {
+        if !parameter.files.is_empty() {
+            bail!("Files cannot be named when selecting them using filters.");
+        }
+        if parameter.delete {
+            bail!("Deleting local files is not possible when selecting files using filters, as the files that are not selected would be deleted.");
+        }
+        parameter.all = true;
+    }
+
     if parameter.all {
         println!("Requesting all indexed files");
 
         session
             .server
-            .write(message::ClientToServer::FileListRequest)?;
-        let message::ServerToClient::FileCount(count) = session.server.read()? else {
+            .write(message::ClientToServer::FileListRequest(
+                message::ListRequest {
+                    filter: parameter.filter.clone(),
+                    ..Default::default()
+                },
+            ))?;
+        let message::ServerToClient::FileCount { count, .. } = session.server.read()? else {
             bail!("Expected file count");
         };
         if count == 0 {
@@ -211,21 +401,36 @@ pub fn run(mut parameter: Parameter) -> anyhow::Result<()> {
         }
 
         println!();
-        println!("Server is sharing {count} files");
-        println!("Multi-GET of {count} files:");
+        println!("Server is sharing {count} entries");
+        println!("Multi-GET of {count} entries:");
 
         for _i in 0..count {
             let message::ServerToClient::FileListEntry(file_metadata) = session.server.read()?
             else {
                 bail!("Expected file");
             };
-            let FileMetadata { path, size } = file_metadata;
 
-            println!(" {} ({} bytes)", path.display(), size.0);
-            file_names.push(path);
+            let path = &file_metadata.path;
+            match &file_metadata.entry_type {
+                EntryType::File => {
+                    println!(" {} ({} bytes)", path.display(), file_metadata.size.0);
+                    file_names.push(path.clone());
+                }
+                EntryType::Directory => println!(" {}/", path.display()),
+                EntryType::Symlink(target) => {
+                    println!(" {} -> {}", path.display(), target.display());
+                }
+            }
+            listing.insert(path.clone(), file_metadata);
         }
 
         session.server.flush()?;
+
+        // When mirroring the served tree, its paths are used locally as they are, so they must not
+        // lead anywhere outside of it. This is checked before anything is deleted or created.
+        if parameter.tree {
+            super::sync::check_listing(&listing)?;
+        }
     } else {
         if parameter.files.is_empty() {
             bail!("No files are specified. Either specify a list of files to be downloaded, or use the `--all` option to download all indexed files.");
@@ -234,528 +439,1098 @@ pub fn run(mut parameter: Parameter) -> anyhow::Result<()> {
         file_names.extend_from_slice(&parameter.files);
     }
 
-    if file_names.is_empty() {
+    if parameter.delete {
+        let deleted = super::sync::delete_extraneous(Path::new("."), &listing)?;
+        println!("Deleted {deleted} local entries that no longer exist on the server");
+    }
+
+    // When synchronising, skip the files that are up to date already. Their permissions may still
+    // have changed, which does not change the modification time, so they are applied anyway.
+    if parameter.sync {
+        let count = file_names.len();
+        file_names.retain(|path| {
+            let Some(metadata) = listing.get(path) else {
+                return true;
+            };
+            if !super::sync::is_unchanged(metadata) {
+                return true;
+            }
+            if let Err(err) = super::metadata::apply(path, metadata) {
+                println!(
+                    "WARNING: Could not apply metadata to '{}': {err}",
+                    path.display()
+                );
+            }
+            false
+        });
+        println!(
+            "Skipping {} unchanged files, {} files are new or have changed",
+            count.saturating_sub(file_names.len()),
+            file_names.len()
+        );
+    }
+
+    if file_names.is_empty() && (!parameter.tree || listing.is_empty()) {
         bail!("No files are to be downloaded.");
     }
 
+    // When mirroring the served tree, create its directories first, so that empty ones are
+    // included
+    if parameter.tree {
+        for metadata in listing.values() {
+            if metadata.entry_type == EntryType::Directory && metadata.path.file_name().is_some() {
+                super::metadata::create_directory(&metadata.path)?;
+            }
+        }
+    }
+
     if file_names.len() > 1 && parameter.local_filename.is_some() {
         bail!("A local filename can only be specified if only one file is to be downloaded.");
     }
 
-    let mut stats_iteration = 0;
-    let mut successful = true;
+    if parameter.batch && parameter.delta {
+        bail!("Delta transfers cannot be combined with batched transfers.");
+    }
 
-    let mut this_type = BlockType::Original;
-    let mut last_type;
+    // Each job is either a single file, or a batch of files that is transferred as one stream
+    let jobs = if parameter.batch {
+        batches(file_names)
+    } else {
+        file_names.into_iter().map(|name| vec![name]).collect()
+    };
 
-    'outer: for remote_filename in file_names {
-        // Get a suitable local filename for the remote one
-        let local_filename =
-            create_local_filename(&remote_filename, &parameter.local_filename, parameter.tree)?;
+    let parallel = usize::from(parameter.parallel).min(jobs.len());
+    let successful = if parallel > 1 {
+        get_parallel(&mut session, &parameter, jobs, parallel, &listing)?
+    } else {
+        let mut stats_iteration = 0;
+        let mut successful = true;
+        for remote_filenames in jobs {
+            if !get_files(
+                &mut session,
+                &mut parameter,
+                &remote_filenames,
+                &listing,
+                &mut stats_iteration,
+            )? {
+                successful = false;
+                break;
+            }
+        }
+        successful
+    };
 
-        // negotiate the file request with the server
-        let (remote_udp_port, resume) = super::protocol::open_transfer(
-            &mut session,
-            &parameter,
-            remote_filename,
-            local_filename,
-        )?;
+    if successful {
+        if parameter.tree {
+            finish_tree(&listing);
+        }
+        eprintln!("All transfers were successful!");
+        session.server.write(message::ClientToServer::Close)?;
+    } else {
+        eprintln!("Transfer not successful.");
+        eprintln!();
 
-        // create the UDP data socket
-        super::protocol::open_port(&mut session, &parameter, remote_udp_port, resume)?;
+        session.transfer.udp_socket.take();
+        session.transfer.retransmit.previous_table.clear();
+
+        bail!("Transfer unsuccessful");
+    }
 
-        // allocate the retransmission table and received bitfield
-        session.transfer.retransmit.previous_table = vec![];
-        session.transfer.received = ReceivedMap::new(session.transfer.block_count);
+    Ok(())
+}
 
-        // If we desire to resume an existing transfer, we need to find out which blocks we already
-        // have, and tell the server about that
-        if resume {
-            super::protocol::resume(&mut session)?;
+/// Completes the local mirror of the served tree after all files have been transferred, by
+/// creating the symbolic links, and applying the metadata of the directories. Directories are
+/// handled after their contents, since creating entries in a directory changes its modification
+/// time. Symbolic links are only created at the end, so that no data is written through them, and
+/// only if they point to an entry within the mirror.
+fn finish_tree(listing: &HashMap<PathBuf, FileMetadata>) {
+    let mut entries: Vec<&FileMetadata> = listing
+        .values()
+        .filter(|metadata| {
+            metadata.entry_type != EntryType::File && metadata.path.file_name().is_some()
+        })
+        .collect();
+    entries.sort_by_key(|metadata| std::cmp::Reverse(metadata.path.components().count()));
+
+    for metadata in entries {
+        let result = match &metadata.entry_type {
+            EntryType::Symlink(target) => super::sync::check_link_target(&metadata.path, target)
+                .and_then(|()| super::metadata::create_symlink(&metadata.path, target))
+                .and_then(|()| super::metadata::apply(&metadata.path, metadata)),
+            _ => super::metadata::apply(&metadata.path, metadata),
+        };
+        if let Err(err) = result {
+            println!(
+                "WARNING: Could not recreate '{}': {err}",
+                metadata.path.display()
+            );
         }
+    }
+}
 
-        // allocate the ring buffer
-        // We want to avoid unwrapping the buffer every time we use it. But, on the other hand, we
-        // cannot borrow it for the entire length of the function, because it will prevent us from
-        // borrowing the session as a whole. So we make a local clone of the `Arc` which will be
-        // dropped at the end of the function.
-        let ring_buffer_ref = session
-            .transfer
-            .ring_buffer
-            .insert(Arc::new(ring::Buffer::create()));
-        let ring_buffer = Arc::clone(ring_buffer_ref);
+/// Groups the given files into batches that are each transferred as one stream, keeping the
+/// batch requests within the size limit of the control connection.
+fn batches(file_names: Vec<PathBuf>) -> Vec<Vec<PathBuf>> {
+    let mut batches: Vec<Vec<PathBuf>> = vec![];
+    let mut path_bytes = 0_usize;
+    for file_name in file_names {
+        let length = file_name.as_os_str().len();
+        match batches.last_mut() {
+            Some(batch)
+                if batch.len() < crate::common::MAX_BATCH_FILES
+                    && path_bytes.saturating_add(length) <= crate::common::MAX_BATCH_PATH_BYTES =>
+            {
+                path_bytes = path_bytes.saturating_add(length);
+                batch.push(file_name);
+            }
+            _ => {
+                path_bytes = length;
+                batches.push(vec![file_name]);
+            }
+        }
+    }
 
-        // allocate the faster local buffer
-        let local_datagram_buffer_size = (crate::common::BLOCK_SIZE as usize)
-            .checked_add(6)
-            .expect("datagram buffer size overflow");
-        let mut local_datagram_buffer =
-            ring::allocate_zeroed_boxed_slice(local_datagram_buffer_size);
-
-        // allocate the buffer for the ciphertext, if necessary
-        let mut encrypted_buffer = if parameter.encrypted {
-            let size = (crate::common::BLOCK_SIZE as usize)
-                .checked_add(30) // 8 for nonce + 16 for noise auth data + 6 for block header
-                .expect("datagram buffer size overflow");
-            vec![0_u8; size]
+    batches
+}
+
+/// Downloads the given remote files using the given session, re-fetching data that fails
+/// verification. A single file is transferred on its own, while several files are transferred as
+/// one batch. Returns `Ok(false)` if the transfer could not be completed.
+///
+/// # Errors
+/// Returns an error on I/O failure, if the server sent unexpected data, or if the data still fails
+/// verification after several attempts.
+///
+/// # Panics
+/// Panics on arithmetic overflow.
+fn get_files(
+    session: &mut Session,
+    parameter: &mut Parameter,
+    remote_filenames: &[PathBuf],
+    listing: &HashMap<PathBuf, FileMetadata>,
+    stats_iteration: &mut u64,
+) -> anyhow::Result<bool> {
+    let resume_requested = parameter.resume;
+
+    // Get suitable local filenames for the remote ones
+    let local_filenames = remote_filenames
+        .iter()
+        .map(|remote_filename| {
+            create_local_filename(
+                remote_filename,
+                parameter.local_filename.as_ref(),
+                parameter.tree,
+            )
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let description = match remote_filenames {
+        [remote_filename] => format!("'{}'", remote_filename.display()),
+        _ => format!("a batch of {} files", remote_filenames.len()),
+    };
+
+    let mut retries = 0;
+    let mut invalid_ranges = vec![];
+    loop {
+        // When re-fetching data that failed verification, we always need to resume, so that
+        // only the mismatching chunks are transmitted again.
+        parameter.resume = resume_requested || retries > 0;
+
+        // negotiate the file request with the server
+        let (remote_udp_port, resume) = if let ([remote_filename], [local_filename]) =
+            (remote_filenames, local_filenames.as_slice())
+        {
+            super::protocol::open_transfer(
+                session,
+                parameter,
+                remote_filename.clone(),
+                local_filename.clone(),
+            )?
         } else {
-            vec![]
+            super::protocol::open_batch(session, parameter, remote_filenames, &local_filenames)?
         };
+        session.transfer.invalid_ranges = std::mem::take(&mut invalid_ranges);
+        if parameter.parallel > 1 {
+            println!(
+                "Transfer {}: receiving {description}",
+                session.transfer.transfer_id
+            );
+        }
 
-        // This other clone of the ring buffer will be moved into the disk thread.
-        let cloned_ring_buffer = Arc::clone(&ring_buffer);
+        // create the UDP data socket
+        super::protocol::open_port(session, parameter, remote_udp_port, resume)?;
 
-        // start up the disk I/O thread
-        let block_count = session.transfer.block_count;
-        let file_size = session.transfer.file_size;
-        let file = session
-            .transfer
-            .file
-            .take()
-            .expect("file should have been opened");
-        let disk_thread_handle = std::thread::spawn(move || {
-            disk_thread(cloned_ring_buffer, block_count, file_size, file)
-        });
+        // if we did not request a specific block size, the server will now probe the path MTU
+        if parameter.block_size.is_none() {
+            super::protocol::answer_mtu_probes(session, parameter)?;
+        }
 
-        // we start by expecting block #1
-        session.transfer.next_block = BlockIndex(1);
-        session.transfer.gapless_to_block = BlockIndex(0);
+        // receive the file data
+        if !receive_file(session, parameter, resume, stats_iteration)? {
+            return Ok(false);
+        }
 
-        // Start timing
-        session.transfer.stats = Statistics::default();
-        session.transfer.stats.udp_errors = UdpErrors::new();
-        session.transfer.stats.start_time = Some(Instant::now());
-        session.transfer.stats.this_time = Some(Instant::now());
-        if parameter.transcript_yn {
-            crate::common::transcript_warn_error(super::transcript::data_start(&mut session));
+        // Check the integrity of the received data. In lossy mode, the file is expected to
+        // differ from the original, so there is no point in verifying it. Streamed data cannot be
+        // read again by the server, and data written to an output cannot be read back by us.
+        if !parameter.verify
+            || !parameter.lossless
+            || session.transfer.streamed
+            || session.transfer.emitted_position.is_some()
+            || super::protocol::verify(session)?
+        {
+            break;
         }
 
-        let mut dumpcount = 0_u32;
+        if retries >= super::config::MAX_VERIFICATION_RETRIES {
+            bail!(
+                "{description} still failed integrity verification after {retries} attempts to re-fetch it"
+            );
+        }
+        retries = retries.checked_add(1).expect("retries overflow");
+        invalid_ranges = std::mem::take(&mut session.transfer.invalid_ranges);
+        println!("Re-fetching the chunks that failed verification.");
+    }
 
-        // until we break out of the transfer
-        loop {
-            // try to receive a datagram
-            let receive_buffer = if parameter.encrypted {
-                encrypted_buffer.as_mut_slice()
-            } else {
-                &mut local_datagram_buffer
-            };
+    for (remote_filename, local_filename) in remote_filenames.iter().zip(&local_filenames) {
+        // apply the permissions and modification time of the remote file, if we know them
+        if local_filename == Path::new(super::config::STDOUT_PATH) {
+            continue;
+        }
+        if let Some(metadata) = listing.get(remote_filename) {
+            if let Err(err) = super::metadata::apply(local_filename, metadata) {
+                println!(
+                    "WARNING: Could not apply the metadata of '{}': {err}",
+                    local_filename.display()
+                );
+            }
+        }
 
-            let udp_result = session
-                .transfer
-                .udp_socket
-                .as_ref()
-                .expect("UDP socket should be present")
-                .recv_from(receive_buffer);
-
-            match udp_result {
-                Ok((len, _)) => {
-                    if len != receive_buffer.len() {
-                        println!(
-                            "Ignoring datagram with incorrect length: {len} != {}",
-                            receive_buffer.len()
-                        );
-                    }
+        // the file is complete, so its saved transfer state is not needed anymore
+        if let Err(err) = super::state::remove(local_filename) {
+            println!("WARNING: Could not remove the saved transfer state: {err}");
+        }
+
+        // likewise, the previous version of the file is not needed anymore after a delta transfer
+        if parameter.delta {
+            let basis_path = super::protocol::basis_path_for(local_filename);
+            match std::fs::remove_file(&basis_path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    println!("WARNING: Could not remove the previous version of the file: {err}");
                 }
-                Err(err) => {
-                    println!("WARNING: UDP data transmission error: {err}");
-                    println!("Apparently frozen transfer, trying to do retransmit request");
-                    if let Err(err) = super::protocol::repeat_retransmit(&mut session) {
-                        println!("WARNING: Repeat of retransmission requests failed: {err:?}");
-                        successful = false;
-                        break 'outer;
+                _ => {}
+            }
+        }
+    }
+
+    parameter.resume = resume_requested;
+    Ok(true)
+}
+
+/// Downloads the given jobs (single files or batches) using the given number of parallel transfers. The first transfer uses
+/// the given session, and the others use further connections, which join its session on the
+/// server so that all transfers share the target rate. Returns `Ok(false)` if a transfer could not
+/// be completed.
+///
+/// # Errors
+/// Returns an error on I/O failure, or if one of the transfers failed.
+///
+/// # Panics
+/// Panics on arithmetic overflow.
+fn get_parallel(
+    session: &mut Session,
+    parameter: &Parameter,
+    jobs: Vec<Vec<PathBuf>>,
+    parallel: usize,
+    listing: &HashMap<PathBuf, FileMetadata>,
+) -> anyhow::Result<bool> {
+    let (token, max_connections) = super::protocol::request_session_token(session)?;
+    if parallel > usize::from(max_connections) {
+        println!(
+            "WARNING: The server allows at most {max_connections} connections per session, so only {max_connections} files are transferred in parallel."
+        );
+    }
+    let parallel = parallel.min(usize::from(max_connections));
+    let mut further_sessions = vec![];
+    for _ in 1..parallel {
+        let mut further_session = super::protocol::connect(
+            &parameter.server,
+            parameter.encrypted,
+            &parameter.secret,
+            true,
+        )?;
+        super::protocol::join_session(&mut further_session, token)?;
+        further_sessions.push(further_session);
+    }
+
+    // every transfer takes the next file from the queue once it has completed the previous one
+    let queue = Mutex::new(jobs.into_iter());
+    let failed = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = std::iter::once(session)
+            .chain(further_sessions.iter_mut())
+            .enumerate()
+            .map(|(index, session)| {
+                let mut parameter = parameter.clone();
+                parameter.client_port = parameter.client_port.map(|port| {
+                    u16::try_from(index)
+                        .ok()
+                        .and_then(|index| port.checked_add(index))
+                        .expect("UDP port overflow")
+                });
+                let queue = &queue;
+                let failed = &failed;
+
+                scope.spawn(move || -> anyhow::Result<()> {
+                    let mut stats_iteration = 0;
+                    while !failed.load(Ordering::Relaxed) {
+                        let Some(remote_filenames) =
+                            queue.lock().expect("file queue lock poisoned").next()
+                        else {
+                            break;
+                        };
+                        if !get_files(
+                            session,
+                            &mut parameter,
+                            &remote_filenames,
+                            listing,
+                            &mut stats_iteration,
+                        )? {
+                            failed.store(true, Ordering::Relaxed);
+                        }
                     }
+                    Ok(())
+                })
+            })
+            .collect();
+
+        // wait for all transfers to finish, keeping the first error
+        let mut result = Ok(());
+        for worker in workers {
+            let worker_result = worker
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+            if let Err(err) = worker_result {
+                failed.store(true, Ordering::Relaxed);
+                if result.is_ok() {
+                    result = Err(err);
                 }
             }
+        }
+        result
+    })?;
 
-            let local_datagram_view: datagram::View = if parameter.encrypted {
-                const U64_SIZE: usize = size_of::<u64>();
-                let (nonce, _) = bincode::decode_from_slice(
-                    &encrypted_buffer[..U64_SIZE],
-                    crate::common::BINCODE_CONFIG,
-                )?;
-                let payload = &encrypted_buffer[U64_SIZE..];
-                session
-                    .server
-                    .decrypt_borrow_decode(nonce, payload, &mut local_datagram_buffer)?
-            } else {
-                let (datagram_view, _) = bincode::borrow_decode_from_slice(
-                    &local_datagram_buffer,
-                    crate::common::BINCODE_CONFIG,
-                )?;
-                datagram_view
-            };
+    for mut further_session in further_sessions {
+        further_session
+            .server
+            .write(message::ClientToServer::Close)?;
+    }
 
-            let this_block = local_datagram_view.header.block_index; // 1-based
-            last_type = this_type;
-            this_type = local_datagram_view.header.block_type;
-
-            // keep statistics on received blocks
-            session.transfer.stats.total_blocks =
-                session.transfer.stats.total_blocks.safe_add(BlockIndex(1));
-            if matches!(this_type, BlockType::Retransmission) {
-                session.transfer.stats.this_flow_retransmitteds = session
-                    .transfer
-                    .stats
-                    .this_flow_retransmitteds
-                    .safe_add(BlockIndex(1));
-                session.transfer.stats.total_recvd_retransmits = session
-                    .transfer
-                    .stats
-                    .total_recvd_retransmits
-                    .safe_add(BlockIndex(1));
-            } else {
-                session.transfer.stats.this_flow_originals = session
-                    .transfer
-                    .stats
-                    .this_flow_originals
-                    .safe_add(BlockIndex(1));
+    Ok(!failed.load(Ordering::Relaxed))
+}
+
+/// Receives the data of the file for which a transfer has been set up in the given session (i.e.
+/// the transfer parameters are known, the local file has been opened, and the UDP socket has been
+/// created). If `resume` is true, we first tell the sending side which blocks we already have.
+///
+/// This is used both for downloading files from a server, and, on the server side, for receiving
+/// files uploaded by a client.
+///
+/// Returns `Ok(false)` if the transfer could not be completed.
+///
+/// # Errors
+/// Returns an error on I/O failure, or if the sending side sent unexpected data.
+///
+/// # Panics
+/// Panics if no local file or UDP socket is present.
+pub fn receive_file(
+    session: &mut Session,
+    parameter: &mut Parameter,
+    resume: bool,
+    stats_iteration: &mut u64,
+) -> anyhow::Result<bool> {
+    let mut this_type = BlockType::Original;
+    let mut last_type;
+
+    // allocate the retransmission table and received bitfield
+    session.transfer.retransmit.previous_table = vec![];
+    session.transfer.received = ReceivedMap::new(if session.transfer.streamed {
+        BlockIndex(0)
+    } else {
+        session.transfer.block_count
+    });
+
+    // Identify the remote file, so that the state of this transfer can be saved, and the saved
+    // state of a previous one can be recognised. Data written to an output cannot be resumed, and
+    // neither can a followed file, whose size changes. The state is only kept for whole files.
+    let resumable = session.transfer.emitted_position.is_none()
+        && !session.transfer.followed
+        && parameter.offset.is_none()
+        && parameter.length.is_none();
+    let identity = session
+        .transfer
+        .remote_mtime
+        .zip(session.transfer.remote_sample_digest)
+        .filter(|_| resumable)
+        .map(|(mtime, sample_digest)| FileIdentity {
+            remote_path: session.transfer.remote_filename.clone().unwrap_or_default(),
+            file_size: session.transfer.file_size,
+            mtime,
+            sample_digest,
+        });
+    let local_filename = session
+        .transfer
+        .local_filename
+        .clone()
+        .expect("local_filename should be present");
+
+    // If we desire to resume an existing transfer, we need to find out which blocks we already
+    // have, and tell the server about that. If we have saved the state of the previous transfer,
+    // we already know that; otherwise, we need to compare checksums with the server.
+    if resume {
+        let saved_state = match &identity {
+            Some(identity) => super::state::load(
+                &local_filename,
+                identity,
+                session.transfer.block_size,
+                session.transfer.block_count,
+            )
+            .unwrap_or_else(|err| {
+                println!("WARNING: Could not load the saved transfer state: {err}");
+                None
+            }),
+            None => None,
+        };
+
+        let basis_path = super::protocol::basis_path_for(&local_filename);
+        match saved_state {
+            Some(present) => super::protocol::resume_from_state(session, present)?,
+            None if parameter.delta && basis_path.exists() => {
+                super::protocol::resume_delta(session, &basis_path)?;
             }
+            None => super::protocol::resume(session, parameter)?,
+        }
+    }
+    let state = identity.map(|identity| {
+        super::state::State::new(
+            &local_filename,
+            identity,
+            session.transfer.block_size,
+            session.transfer.block_count,
+            session.transfer.received.clone(),
+            Duration::from_millis(parameter.state_interval_ms),
+        )
+    });
+
+    // allocate the ring buffer
+    // We want to avoid unwrapping the buffer every time we use it. But, on the other hand, we
+    // cannot borrow it for the entire length of the function, because it will prevent us from
+    // borrowing the session as a whole. So we make a local clone of the `Arc` which will be
+    // dropped at the end of the function.
+    let ring_buffer_ref = session
+        .transfer
+        .ring_buffer
+        .insert(Arc::new(ring::Buffer::create(session.transfer.block_size)));
+    let ring_buffer = Arc::clone(ring_buffer_ref);
+
+    // allocate the faster local buffer
+    let local_datagram_buffer_size = usize::from(session.transfer.block_size)
+        .checked_add(6)
+        .expect("datagram buffer size overflow");
+    let mut local_datagram_buffer = ring::allocate_zeroed_boxed_slice(local_datagram_buffer_size);
+
+    // allocate the buffer for the ciphertext, if necessary
+    let mut encrypted_buffer = if parameter.encrypted {
+        let size = usize::from(session.transfer.block_size)
+            .checked_add(30) // 8 for nonce + 16 for noise auth data + 6 for block header
+            .expect("datagram buffer size overflow");
+        vec![0_u8; size]
+    } else {
+        vec![]
+    };
 
-            // main transfer control logic
-            if !ring_buffer.is_full() // don't let disk-I/O freeze stop feedback of stats to server
-                && (!session.got_block(this_block)
-                    || matches!(this_type, BlockType::Final)
-                    || session.transfer.restart_pending)
+    // This other clone of the ring buffer will be moved into the disk thread.
+    let cloned_ring_buffer = Arc::clone(&ring_buffer);
+
+    // start up the disk I/O thread. The last block of a followed file is not known yet, but it is
+    // sent shortened, just like the final block of streamed data.
+    let block_size = session.transfer.block_size;
+    let block_count = if session.transfer.followed {
+        crate::common::STREAM_BLOCK_COUNT
+    } else {
+        session.transfer.block_count
+    };
+    let file_size = session.transfer.file_size;
+    let file = session
+        .transfer
+        .file
+        .take()
+        .expect("file should have been opened");
+    let disk_thread_handle = std::thread::spawn(move || {
+        disk_thread(
+            cloned_ring_buffer,
+            block_size,
+            block_count,
+            file_size,
+            file,
+            state,
+        )
+    });
+
+    // we start by expecting block #1
+    session.transfer.next_block = BlockIndex(1);
+    session.transfer.gapless_to_block = BlockIndex(0);
+
+    // Start timing
+    session.transfer.stats = Statistics::default();
+    session.transfer.stats.udp_errors = UdpErrors::new();
+    session.transfer.stats.start_time = Some(Instant::now());
+    session.transfer.stats.this_time = Some(Instant::now());
+    if parameter.transcript_yn {
+        crate::common::transcript_warn_error(super::transcript::data_start(session));
+    }
+
+    let mut dumpcount = 0_u32;
+
+    // keep the state needed to reconstruct lost blocks, if forward error correction is used
+    let mut fec_recovery = parameter
+        .fec_group_size
+        .map(|_| crate::fec::Recovery::new(session.transfer.block_size));
+
+    // the length of the most recently received datagram; compressed blocks make it shorter
+    let mut received_len = if parameter.encrypted {
+        encrypted_buffer.len()
+    } else {
+        local_datagram_buffer.len()
+    };
+
+    // While following a file, keep checking whether it has grown when no data arrives
+    if session.transfer.followed {
+        session
+            .transfer
+            .udp_socket
+            .as_ref()
+            .expect("UDP socket should be present")
+            .set_read_timeout(Some(Duration::from_millis(
+                super::config::FOLLOW_RECEIVE_TIMEOUT_MS,
+            )))?;
+    }
+
+    // until we break out of the transfer. An empty file has no blocks to be received at all,
+    // unless it is followed and may still grow.
+    while !session.transfer.block_count.is_zero() || session.transfer.following {
+        // try to receive a datagram
+        let receive_buffer = if parameter.encrypted {
+            encrypted_buffer.as_mut_slice()
+        } else {
+            &mut local_datagram_buffer
+        };
+
+        let udp_result = session
+            .transfer
+            .udp_socket
+            .as_ref()
+            .expect("UDP socket should be present")
+            .recv_from(receive_buffer);
+
+        match udp_result {
+            Ok((len, _)) => received_len = len,
+            Err(err)
+                if session.transfer.followed
+                    && matches!(
+                        err.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
             {
-                // insert new blocks into disk write ringbuffer
-                if !session.got_block(this_block) {
-                    // reserve ring space, copy the data in, confirm the reservation
-                    ring_buffer.reserve(local_datagram_view);
-                    ring_buffer.confirm();
-
-                    // mark the block as received
-                    session.transfer.received.set(this_block);
-
-                    if session.transfer.blocks_left.is_zero() {
-                        println!("Oops! Negative-going blocks_left count at block: type={:?} this={} final={} left={}",
-                                this_type,
-                                this_block.0,
-                                session.transfer.block_count.0,
-                                session.transfer.blocks_left.0,
-                            );
-                    } else {
-                        session.transfer.blocks_left =
-                            session.transfer.blocks_left.safe_sub(BlockIndex(1));
-                    }
+                // The server has sent all data of the followed file so far. Check whether it has
+                // grown, and request the blocks we are still missing, as lost blocks at its end
+                // are not followed by any others that would reveal their loss.
+                super::protocol::handle_server_messages(session, parameter)?;
+                let mut block = session.transfer.gapless_to_block.safe_add(BlockIndex(1));
+                while block <= session.transfer.block_count {
+                    super::protocol::request_retransmit(session, block);
+                    block = block.safe_add(BlockIndex(1));
                 }
+                super::protocol::repeat_retransmit(session)?;
+                continue;
+            }
+            Err(err) => {
+                println!("WARNING: UDP data transmission error: {err}");
+                println!("Apparently frozen transfer, trying to do retransmit request");
+                if let Err(err) = super::protocol::repeat_retransmit(session) {
+                    println!("WARNING: Repeat of retransmission requests failed: {err:?}");
+                    return Ok(false);
+                }
+            }
+        }
 
-                // If a transfer restart is pending, avoid re-triggering on blocks still down the
-                // wire before the server reacts
-                if !session.transfer.restart_pending
-                    || matches!(this_type, BlockType::Final)
-                    || this_block <= session.transfer.restart_lastidx
-                    || this_block > session.transfer.restart_wireclearidx
-                {
-                    // queue any retransmits we need
-                    if this_block > session.transfer.next_block {
-                        if parameter.lossless {
-                            // lossless transfer mode, request all missing data to be resent
-                            let mut block = session.transfer.next_block;
-                            while block < this_block {
-                                super::protocol::request_retransmit(&mut session, block);
-                                block = block.safe_add(BlockIndex(1));
-                            }
-                        } else {
-                            // lossy transfer mode
-                            if parameter.losswindow_ms == 0 {
-                                // lossy transfer, no retransmits
-                                session.transfer.gapless_to_block = this_block;
-                            } else {
-                                // semi-lossy transfer, purge data past specified approximate time
-                                // window
-                                let mut path_capability: f64 = 0.8_f64
-                                    * (session.transfer.stats.this_transmit_rate
-                                        + session.transfer.stats.this_retransmit_rate);
-                                path_capability *= 0.001_f64 * f64::from(parameter.losswindow_ms);
-
-                                let first = 1_000_000.0 * path_capability
-                                    / (8.0 * f64::from(crate::common::BLOCK_SIZE));
-                                let second = f64::from(
-                                    (this_block.safe_sub(session.transfer.gapless_to_block)).0,
-                                );
-                                let block_diff = f64::min(first, second);
-
-                                // TODO: potentially rewrite this part using more precise non-FP
-                                // arithmetic. It will not match what tsunami does but might be
-                                // more desirable
-                                #[allow(clippy::cast_possible_truncation)]
-                                #[allow(clippy::cast_sign_loss)]
-                                let earliest_block =
-                                    BlockIndex((f64::from(this_block.0) - block_diff) as u32);
-                                let mut block = earliest_block;
-                                while block < this_block {
-                                    super::protocol::request_retransmit(&mut session, block);
-                                    block = block.safe_add(BlockIndex(1));
-                                }
-
-                                // hop over the missing section
-                                session.transfer.next_block = earliest_block;
-                                session.transfer.gapless_to_block = earliest_block;
-                            }
+        let local_datagram_view: datagram::View = if parameter.encrypted {
+            const U64_SIZE: usize = size_of::<u64>();
+            let (nonce, _) = bincode::decode_from_slice(
+                &encrypted_buffer[..U64_SIZE],
+                crate::common::BINCODE_CONFIG,
+            )?;
+            let Some(payload) = encrypted_buffer.get(U64_SIZE..received_len) else {
+                println!("Ignoring datagram with incorrect length: {received_len}");
+                continue;
+            };
+            let message = session
+                .server
+                .decrypt(nonce, payload, &mut local_datagram_buffer)?;
+            datagram::View::decode(message)?
+        } else {
+            datagram::View::decode(&local_datagram_buffer[..received_len])?
+        };
+
+        // Only compressed blocks, and the final block of streamed data or of a followed file, may
+        // be shorter than the block size
+        let expected_len = usize::from(session.transfer.block_size);
+        let block_len = local_datagram_view.block.len();
+        if block_len > expected_len
+            || (block_len < expected_len
+                && matches!(local_datagram_view.header.compression, Compression::None)
+                && !session.transfer.streamed
+                && !session.transfer.followed)
+        {
+            println!("Ignoring datagram with incorrect length: {block_len} != {expected_len}");
+            continue;
+        }
+
+        // Parity blocks are only used to reconstruct a lost data block of their group, for which
+        // the recently received data blocks are kept in memory. A block that can be reconstructed
+        // this way never ends up being requested for retransmission.
+        if matches!(local_datagram_view.header.block_type, BlockType::Parity) {
+            if let Some(recovery) = fec_recovery.as_mut() {
+                if !ring_buffer.is_full() {
+                    let recovered = recovery.recover(&local_datagram_view, |block_index| {
+                        session.got_block(block_index)
+                    })?;
+                    if let Some((block_index, block)) = recovered {
+                        ring_buffer.reserve(datagram::View {
+                            header: datagram::Header {
+                                block_index,
+                                block_type: BlockType::Original,
+                                compression: Compression::None,
+                                group_size: 0,
+                            },
+                            block,
+                        });
+                        ring_buffer.confirm();
+                        session.transfer.received.set(block_index);
+                        if !session.transfer.blocks_left.is_zero() {
+                            session.transfer.blocks_left =
+                                session.transfer.blocks_left.safe_sub(BlockIndex(1));
                         }
+                        session.transfer.stats.this_recovered = session
+                            .transfer
+                            .stats
+                            .this_recovered
+                            .safe_add(BlockIndex(1));
+                        session.transfer.stats.total_recovered = session
+                            .transfer
+                            .stats
+                            .total_recovered
+                            .safe_add(BlockIndex(1));
                     }
+                }
+            }
+            continue;
+        }
+        if let Some(recovery) = fec_recovery.as_mut() {
+            recovery.remember(&local_datagram_view);
+        }
 
-                    // advance the index of the gapless section going from start block to highest
-                    // block
-                    while session
-                        .got_block(session.transfer.gapless_to_block.safe_add(BlockIndex(1)))
-                        && session.transfer.gapless_to_block < session.transfer.block_count
-                    {
-                        session.transfer.gapless_to_block =
-                            session.transfer.gapless_to_block.safe_add(BlockIndex(1));
-                    }
+        let this_block = local_datagram_view.header.block_index; // 1-based
+        last_type = this_type;
+        this_type = local_datagram_view.header.block_type;
 
-                    // if this is an orignal, we expect to receive the successor to this block next
-                    // transmit restart note: these resent blocks are labeled original as well
-                    if matches!(this_type, BlockType::Original) {
-                        session.transfer.next_block = this_block.safe_add(BlockIndex(1));
-                    }
+        // blocks beyond the end of a followed file have been appended to it; the server has told
+        // us about that, but its report may not have arrived before the blocks
+        if session.transfer.following && this_block > session.transfer.block_count {
+            super::protocol::handle_server_messages(session, parameter)?;
+        }
 
-                    // transmit restart: already got out of the missing blocks range?
-                    if session.transfer.restart_pending
-                        && session.transfer.next_block >= session.transfer.restart_lastidx
-                    {
-                        session.transfer.restart_pending = false;
-                    }
+        // the final block of streamed data tells us its size
+        if session.transfer.streamed
+            && matches!(this_type, BlockType::Final)
+            && this_block < session.transfer.block_count
+        {
+            end_stream(session, this_block, block_len);
+        }
 
-                    // are we at the end of the transmission?
-                    //
-                    // meew0 NOTE:
-                    // After it has transmitted all blocks once, the server will flood us
-                    // with `Final` blocks. If we respond to every one of them with a
-                    // `repeat_retransmit`, we will overload the network and become unable to
-                    // receive any further blocks at all. So, we only want to do this if it is
-                    // unlikely that we will receive any further retransmitted blocks. However, it
-                    // is impossible to know this for sure, since some or all retransmitted packets
-                    // may be lost.
-                    //
-                    // My solution here is to not react to `Final` blocks if the last block was
-                    // also a final block, unless a certain timeout has passed to account for the
-                    // possibility of *all* retransmitted blocks being lost. This will of course
-                    // incur a delay in rare cases, but it should be preferable to the alternative.
-                    if matches!(this_type, BlockType::Final)
-                        && (!matches!(last_type, BlockType::Final)
-                            || crate::common::get_µs_since(
-                                session
-                                    .transfer
-                                    .stats
-                                    .this_time
-                                    .expect("this_time should be set"),
-                            ) > 100_000)
-                    {
-                        // got all blocks by now
-                        if session.transfer.blocks_left == BlockIndex(0) {
-                            break;
-                        }
-                        if !parameter.lossless
-                            && session.transfer.retransmit.previous_table.is_empty()
-                            && !session.transfer.restart_pending
-                        {
-                            break;
-                        }
+        // keep statistics on received blocks
+        session.transfer.stats.total_blocks =
+            session.transfer.stats.total_blocks.safe_add(BlockIndex(1));
+        if matches!(this_type, BlockType::Retransmission) {
+            session.transfer.stats.this_flow_retransmitteds = session
+                .transfer
+                .stats
+                .this_flow_retransmitteds
+                .safe_add(BlockIndex(1));
+            session.transfer.stats.total_recvd_retransmits = session
+                .transfer
+                .stats
+                .total_recvd_retransmits
+                .safe_add(BlockIndex(1));
+        } else {
+            session.transfer.stats.this_flow_originals = session
+                .transfer
+                .stats
+                .this_flow_originals
+                .safe_add(BlockIndex(1));
+        }
 
-                        // add possible still missing blocks to retransmit list
-                        let mut block = session.transfer.gapless_to_block.safe_add(BlockIndex(1));
-                        while block < session.transfer.block_count {
-                            super::protocol::request_retransmit(&mut session, block);
+        // main transfer control logic
+        if !ring_buffer.is_full() // don't let disk-I/O freeze stop feedback of stats to server
+            && within_reorder_window(session, this_block)
+            && (!session.got_block(this_block)
+                || matches!(this_type, BlockType::Final)
+                || session.transfer.restart_pending)
+        {
+            // insert new blocks into disk write ringbuffer
+            if !session.got_block(this_block) {
+                // reserve ring space, copy the data in, confirm the reservation
+                ring_buffer.reserve(local_datagram_view);
+                ring_buffer.confirm();
+
+                // mark the block as received
+                session.transfer.received.set(this_block);
+
+                if session.transfer.blocks_left.is_zero() {
+                    println!("Oops! Negative-going blocks_left count at block: type={:?} this={} final={} left={}",
+                            this_type,
+                            this_block.0,
+                            session.transfer.block_count.0,
+                            session.transfer.blocks_left.0,
+                        );
+                } else {
+                    session.transfer.blocks_left =
+                        session.transfer.blocks_left.safe_sub(BlockIndex(1));
+                }
+            }
+
+            // If a transfer restart is pending, avoid re-triggering on blocks still down the
+            // wire before the server reacts
+            if !session.transfer.restart_pending
+                || matches!(this_type, BlockType::Final)
+                || this_block <= session.transfer.restart_lastidx
+                || this_block > session.transfer.restart_wireclearidx
+            {
+                // queue any retransmits we need
+                if this_block > session.transfer.next_block {
+                    if parameter.lossless {
+                        // lossless transfer mode, request all missing data to be resent
+                        let mut block = session.transfer.next_block;
+                        while block < this_block {
+                            super::protocol::request_retransmit(session, block);
                             block = block.safe_add(BlockIndex(1));
                         }
+                    } else {
+                        // lossy transfer mode
+                        if parameter.losswindow_ms == 0 {
+                            // lossy transfer, no retransmits
+                            session.transfer.gapless_to_block = this_block;
+                        } else {
+                            // semi-lossy transfer, purge data past specified approximate time
+                            // window
+                            let mut path_capability: f64 = 0.8_f64
+                                * (session.transfer.stats.this_transmit_rate
+                                    + session.transfer.stats.this_retransmit_rate);
+                            path_capability *= 0.001_f64 * f64::from(parameter.losswindow_ms);
+
+                            let first = 1_000_000.0 * path_capability
+                                / (8.0 * f64::from(session.transfer.block_size));
+                            let second = f64::from(
+                                (this_block.safe_sub(session.transfer.gapless_to_block)).0,
+                            );
+                            let block_diff = f64::min(first, second);
+
+                            // TODO: potentially rewrite this part using more precise non-FP
+                            // arithmetic. It will not match what tsunami does but might be
+                            // more desirable
+                            #[allow(clippy::cast_possible_truncation)]
+                            #[allow(clippy::cast_sign_loss)]
+                            let earliest_block =
+                                BlockIndex((f64::from(this_block.0) - block_diff) as u32);
+                            let mut block = earliest_block;
+                            while block < this_block {
+                                super::protocol::request_retransmit(session, block);
+                                block = block.safe_add(BlockIndex(1));
+                            }
 
-                        // send the retransmit request list again
-                        super::protocol::repeat_retransmit(&mut session)?;
+                            // hop over the missing section
+                            session.transfer.next_block = earliest_block;
+                            session.transfer.gapless_to_block = earliest_block;
+                        }
                     }
                 }
-            }
 
-            // repeat our server feedback and requests if it's time
-            if session.transfer.stats.total_blocks.0 % 50 != 0 {
-                continue;
-            }
+                // advance the index of the gapless section going from start block to highest
+                // block
+                while session.got_block(session.transfer.gapless_to_block.safe_add(BlockIndex(1)))
+                    && session.transfer.gapless_to_block < session.transfer.block_count
+                {
+                    session.transfer.gapless_to_block =
+                        session.transfer.gapless_to_block.safe_add(BlockIndex(1));
+                }
 
-            // if it's been at least 350ms
-            if crate::common::get_µs_since(
-                session
-                    .transfer
-                    .stats
-                    .this_time
-                    .expect("this_time should be set"),
-            ) <= 350_000
-            {
-                continue;
-            }
+                // if this is an orignal, we expect to receive the successor to this block next
+                // transmit restart note: these resent blocks are labeled original as well
+                if matches!(this_type, BlockType::Original) {
+                    session.transfer.next_block = this_block.safe_add(BlockIndex(1));
+                }
 
-            // repeat our retransmission requests
-            super::protocol::repeat_retransmit(&mut session)?;
+                // transmit restart: already got out of the missing blocks range?
+                if session.transfer.restart_pending
+                    && session.transfer.next_block >= session.transfer.restart_lastidx
+                {
+                    session.transfer.restart_pending = false;
+                }
 
-            // send and show our current statistics
-            super::protocol::update_stats(&mut session, &parameter, &mut stats_iteration)?;
+                // are we at the end of the transmission?
+                //
+                // meew0 NOTE:
+                // After it has transmitted all blocks once, the server will flood us
+                // with `Final` blocks. If we respond to every one of them with a
+                // `repeat_retransmit`, we will overload the network and become unable to
+                // receive any further blocks at all. So, we only want to do this if it is
+                // unlikely that we will receive any further retransmitted blocks. However, it
+                // is impossible to know this for sure, since some or all retransmitted packets
+                // may be lost.
+                //
+                // My solution here is to not react to `Final` blocks if the last block was
+                // also a final block, unless a certain timeout has passed to account for the
+                // possibility of *all* retransmitted blocks being lost. This will of course
+                // incur a delay in rare cases, but it should be preferable to the alternative.
+                if matches!(this_type, BlockType::Final)
+                    && (!matches!(last_type, BlockType::Final)
+                        || crate::common::get_µs_since(
+                            session
+                                .transfer
+                                .stats
+                                .this_time
+                                .expect("this_time should be set"),
+                        ) > 100_000)
+                {
+                    // got all blocks by now
+                    if session.transfer.blocks_left == BlockIndex(0) {
+                        break;
+                    }
+                    if !parameter.lossless
+                        && session.transfer.retransmit.previous_table.is_empty()
+                        && !session.transfer.restart_pending
+                    {
+                        break;
+                    }
+
+                    // add possible still missing blocks to retransmit list
+                    let mut block = session.transfer.gapless_to_block.safe_add(BlockIndex(1));
+                    while block < session.transfer.block_count {
+                        super::protocol::request_retransmit(session, block);
+                        block = block.safe_add(BlockIndex(1));
+                    }
 
-            // progress blockmap (DEBUG)
-            if parameter.blockdump {
-                let postfix = format!(".bmap{dumpcount}");
-                if let Err(err) = dump_blockmap(&postfix, &session.transfer) {
-                    eprintln!("Failed to write blockmap dump: {err:?}");
+                    // send the retransmit request list again
+                    super::protocol::repeat_retransmit(session)?;
                 }
-                dumpcount = dumpcount.wrapping_add(1);
             }
         }
 
-        println!("Transfer complete. Flushing to disk and signaling server to stop...");
-        session.transfer.udp_socket.take();
-
-        // tell the server to quit transmitting
-        if let Err(err) = super::protocol::request_stop(&mut session) {
-            println!("WARNING: Could not request end of transfer: {err:?}");
-            successful = false;
-            break;
+        // apply the replies to our pings as soon as they arrive, so that they are timed precisely
+        if session.transfer.pings_outstanding > 0 {
+            super::protocol::handle_server_messages(session, parameter)?;
         }
 
-        // add a stop block to the ring buffer
-        ring_buffer.reserve_zero();
-        ring_buffer.confirm();
-
-        // wait for the disk thread to die
-        if let Err(err) = disk_thread_handle.join() {
-            println!("Error in disk thread: {err:?}");
+        // repeat our server feedback and requests if it's time
+        if !session.transfer.stats.total_blocks.0.is_multiple_of(50) {
+            continue;
         }
 
-        // get finishing time
-        session.transfer.stats.stop_time = Some(Instant::now());
-        let delta = crate::common::get_µs_since(
+        // if it's been at least 350ms
+        if crate::common::get_µs_since(
             session
                 .transfer
                 .stats
-                .start_time
-                .expect("start_time should have been set"),
-        );
-
-        // count the truly lost blocks from the `received` bitmap table
-        session.transfer.stats.total_lost = BlockIndex(0);
-        let mut block = BlockIndex(1);
-        while block <= session.transfer.block_count {
-            if !session.got_block(block) {
-                session.transfer.stats.total_lost =
-                    session.transfer.stats.total_lost.safe_add(BlockIndex(1));
-            }
-            block = block.safe_add(BlockIndex(1));
+                .this_time
+                .expect("this_time should be set"),
+        ) <= 350_000
+        {
+            continue;
         }
 
-        // calculate and display the final results
-        let bit_thru = 8.0_f64
-            * f64::from(session.transfer.stats.total_blocks.0)
-            * f64::from(crate::common::BLOCK_SIZE);
-        let bit_good = (8.0_f64 * f64::from(session.transfer.stats.total_recvd_retransmits.0))
-            .mul_add(-f64::from(crate::common::BLOCK_SIZE), bit_thru);
-        #[allow(clippy::cast_precision_loss)]
-        let bit_file = 8.0_f64 * session.transfer.file_size.0 as f64;
-
-        let megabit_thru = bit_thru / 1_000_000.0;
-        let megabit_good = bit_good / 1_000_000.0;
-        let megabit_file = bit_file / 1_000_000.0;
+        // measure the round trip time, before anything else delays it, and let the server measure
+        // it as well by echoing the reply
+        super::protocol::ping(session)?;
 
-        #[allow(clippy::cast_precision_loss)]
-        let time_secs = delta as f64 / 1e6_f64;
-
-        println!("PC performance figure : {} packets dropped (if high this indicates receiving PC overload)",
-                session.transfer.stats.udp_errors,
-            );
-        println!("Transfer duration     : {time_secs:0>.2} seconds");
-        println!("Total packet data     : {megabit_thru:0>.2} Mbit");
-        println!("Goodput data          : {megabit_good:0>.2} Mbit");
-        println!("File data             : {megabit_file:0>.2} Mbit");
-        println!(
-            "Throughput            : {:0>.2} Mbps",
-            megabit_thru / time_secs
-        );
-        println!(
-            "Goodput w/ restarts   : {:0>.2} Mbps",
-            megabit_good / time_secs
-        );
-        println!(
-            "Final file rate       : {:0>.2} Mbps",
-            megabit_file / time_secs
-        );
-        print!("Transfer mode         : ");
-        if parameter.lossless {
-            if session.transfer.stats.total_lost == BlockIndex(0) {
-                println!("lossless");
-            } else {
-                println!(
-                    "lossless mode - but lost count={} > 0, please file a bug report!!",
-                    session.transfer.stats.total_lost.0,
-                );
-            }
-        } else {
-            if parameter.losswindow_ms == 0 {
-                println!("lossy");
-            } else {
-                println!("semi-lossy, time window {} ms", parameter.losswindow_ms);
-            }
-            println!(
-                    "Data blocks lost      : {} ({:.2}% of data) per user-specified time window constraint",
-                    session.transfer.stats.total_lost.0,
-                    100.0_f64 * f64::from(session.transfer.stats.total_lost.0)
-                        / f64::from(session.transfer.block_count.0),
-                );
+        // repeat our retransmission requests, and let the server know how much of streamed data
+        // it can stop keeping
+        super::protocol::repeat_retransmit(session)?;
+        if session.transfer.streamed {
+            super::protocol::acknowledge(session)?;
         }
-        println!();
-
-        // update the transcript
-        if parameter.transcript_yn {
-            crate::common::transcript_warn_error(super::transcript::data_stop(&mut session));
-            crate::common::transcript_warn_error(super::transcript::close(&mut session, delta));
+        if session.transfer.following {
+            super::protocol::handle_server_messages(session, parameter)?;
         }
 
-        // dump the received packet bitfield to a file, with added filename prefix `.blockmap`
+        // send and show our current statistics
+        super::protocol::update_stats(session, parameter, stats_iteration)?;
+
+        // progress blockmap (DEBUG)
         if parameter.blockdump {
-            if let Err(err) = dump_blockmap(".blockmap", &session.transfer) {
-                eprintln!("Failed to write blockmap: {err}");
+            let postfix = format!(".bmap{dumpcount}");
+            if let Err(err) = dump_blockmap(&postfix, &session.transfer) {
+                eprintln!("Failed to write blockmap dump: {err:?}");
             }
+            dumpcount = dumpcount.wrapping_add(1);
         }
+    }
+
+    println!("Transfer complete. Flushing to disk and signaling server to stop...");
+    session.transfer.udp_socket.take();
+
+    // tell the server to quit transmitting
+    if let Err(err) = super::protocol::request_stop(session) {
+        println!("WARNING: Could not request end of transfer: {err:?}");
+        return Ok(false);
+    }
+    if let Err(err) = super::protocol::finish_pings(session, parameter) {
+        println!("WARNING: Could not receive the replies to our pings: {err:?}");
+        return Ok(false);
+    }
+
+    // add a stop block to the ring buffer
+    ring_buffer.reserve_zero();
+    ring_buffer.confirm();
 
-        session.transfer.retransmit.previous_table = vec![];
+    // wait for the disk thread to die
+    if let Err(err) = disk_thread_handle.join() {
+        println!("Error in disk thread: {err:?}");
+    }
 
-        // update the target rate
-        if parameter.rate_adjust {
-            #[allow(clippy::cast_sign_loss)]
-            #[allow(clippy::cast_possible_truncation)]
-            let new_target_rate =
-                TargetRate((1.15_f64 * 1e6_f64 * (megabit_file / time_secs)) as u64);
-            parameter.target_rate = new_target_rate;
+    // get finishing time
+    session.transfer.stats.stop_time = Some(Instant::now());
+    let delta = crate::common::get_µs_since(
+        session
+            .transfer
+            .stats
+            .start_time
+            .expect("start_time should have been set"),
+    );
 
-            #[allow(clippy::cast_precision_loss)]
-            let new_target_rate_megabits = parameter.target_rate.0 as f64 / 1e6_f64;
+    // count the truly lost blocks from the `received` bitmap table
+    session.transfer.stats.total_lost = BlockIndex(0);
+    let mut block = BlockIndex(1);
+    while block <= session.transfer.block_count {
+        if !session.got_block(block) {
+            session.transfer.stats.total_lost =
+                session.transfer.stats.total_lost.safe_add(BlockIndex(1));
+        }
+        block = block.safe_add(BlockIndex(1));
+    }
+
+    // calculate and display the final results
+    let bit_thru = 8.0_f64
+        * f64::from(session.transfer.stats.total_blocks.0)
+        * f64::from(session.transfer.block_size);
+    let bit_good = (8.0_f64 * f64::from(session.transfer.stats.total_recvd_retransmits.0))
+        .mul_add(-f64::from(session.transfer.block_size), bit_thru);
+    #[allow(clippy::cast_precision_loss)]
+    let bit_file = 8.0_f64 * session.transfer.file_size.0 as f64;
+
+    let megabit_thru = bit_thru / 1_000_000.0;
+    let megabit_good = bit_good / 1_000_000.0;
+    let megabit_file = bit_file / 1_000_000.0;
+
+    #[allow(clippy::cast_precision_loss)]
+    let time_secs = delta as f64 / 1e6_f64;
+
+    println!(
+        "PC performance figure : {} packets dropped (if high this indicates receiving PC overload)",
+        session.transfer.stats.udp_errors,
+    );
+    println!("Transfer duration     : {time_secs:0>.2} seconds");
+    println!("Total packet data     : {megabit_thru:0>.2} Mbit");
+    println!("Goodput data          : {megabit_good:0>.2} Mbit");
+    println!("File data             : {megabit_file:0>.2} Mbit");
+    println!(
+        "Throughput            : {:0>.2} Mbps",
+        megabit_thru / time_secs
+    );
+    println!(
+        "Goodput w/ restarts   : {:0>.2} Mbps",
+        megabit_good / time_secs
+    );
+    println!(
+        "Final file rate       : {:0>.2} Mbps",
+        megabit_file / time_secs
+    );
+    if parameter.fec_group_size.is_some() {
+        println!(
+            "Recovered by FEC      : {} blocks",
+            session.transfer.stats.total_recovered.0
+        );
+    }
+    print!("Transfer mode         : ");
+    if parameter.lossless {
+        if session.transfer.stats.total_lost == BlockIndex(0) {
+            println!("lossless");
+        } else {
             println!(
-                "Adjusting target rate to {new_target_rate_megabits:.3} Mbps for next transfer.",
+                "lossless mode - but lost count={} > 0, please file a bug report!!",
+                session.transfer.stats.total_lost.0,
             );
         }
+    } else {
+        if parameter.losswindow_ms == 0 {
+            println!("lossy");
+        } else {
+            println!("semi-lossy, time window {} ms", parameter.losswindow_ms);
+        }
+        println!(
+            "Data blocks lost      : {} ({:.2}% of data) per user-specified time window constraint",
+            session.transfer.stats.total_lost.0,
+            100.0_f64 * f64::from(session.transfer.stats.total_lost.0)
+                / f64::from(session.transfer.block_count.0),
+        );
+    }
+    println!();
 
-        // continue with the next file, if it exists
+    // update the transcript
+    if parameter.transcript_yn {
+        crate::common::transcript_warn_error(super::transcript::data_stop(session));
+        crate::common::transcript_warn_error(super::transcript::close(session, delta));
     }
 
-    if successful {
-        eprintln!("All transfers were successful!");
-        session.server.write(message::ClientToServer::Close)?;
-    } else {
-        eprintln!("Transfer not successful.");
-        eprintln!();
+    // dump the received packet bitfield to a file, with added filename prefix `.blockmap`
+    if parameter.blockdump {
+        if let Err(err) = dump_blockmap(".blockmap", &session.transfer) {
+            eprintln!("Failed to write blockmap: {err}");
+        }
+    }
 
-        session.transfer.udp_socket.take();
-        session.transfer.retransmit.previous_table.clear();
+    session.transfer.retransmit.previous_table = vec![];
 
-        bail!("Transfer unsuccessful");
+    // update the target rate
+    if parameter.rate_adjust {
+        #[allow(clippy::cast_sign_loss)]
+        #[allow(clippy::cast_possible_truncation)]
+        let new_target_rate = TargetRate((1.15_f64 * 1e6_f64 * (megabit_file / time_secs)) as u64);
+        parameter.target_rate = new_target_rate;
+
+        #[allow(clippy::cast_precision_loss)]
+        let new_target_rate_megabits = parameter.target_rate.0 as f64 / 1e6_f64;
+        println!("Adjusting target rate to {new_target_rate_megabits:.3} Mbps for next transfer.");
     }
 
-    Ok(())
+    Ok(true)
 }
 
 fn create_local_filename(
     remote_filename: &Path,
-    local_filename: &Option<PathBuf>,
+    local_filename: Option<&PathBuf>,
     tree: bool,
 ) -> anyhow::Result<PathBuf> {
-    if let Some(local_filename) = local_filename.as_ref() {
+    if let Some(local_filename) = local_filename {
         // Local filename was specified
         Ok(PathBuf::from(local_filename))
     } else if let Some(file_name_part) = remote_filename.file_name() {
@@ -782,32 +1557,104 @@ fn create_local_filename(
 
 /// This is the thread that takes care of saved received blocks to disk. It runs until the network
 /// thread sends it a datagram with a block number of 0.
+///
+/// If a transfer state is given, the blocks that have been written are recorded in it, and it is
+/// saved periodically, as well as when the thread stops.
 #[allow(clippy::needless_pass_by_value)]
 fn disk_thread(
     ring_buffer: Arc<ring::Buffer>,
+    block_size: u16,
     block_count: BlockIndex,
     file_size: FileSize,
-    mut file: std::fs::File,
+    mut file: Stream,
+    mut state: Option<super::state::State>,
 ) -> anyhow::Result<()> {
+    let mut decompressor = Decompressor::new(block_size);
+
     // while the world is turning
     loop {
         // get another block
-        ring_buffer.peek(|datagram_view| {
-            // quit if we got the mythical 0 block
-            if datagram_view.header.block_index == BlockIndex(0) {
-                bail!("!!!!");
+        let block_index = ring_buffer.peek(|datagram_view| {
+            let block_index = datagram_view.header.block_index;
+
+            // save it to disk, unless it is the mythical 0 block
+            if block_index != BlockIndex(0) {
+                super::io::accept_block(
+                    datagram_view,
+                    block_size,
+                    block_count,
+                    file_size,
+                    &mut file,
+                    &mut decompressor,
+                )?;
             }
-
-            // save it to disk
-            super::io::accept_block(datagram_view, block_count, file_size, &mut file)?;
-            Ok(())
+            anyhow::Ok(block_index)
         })?;
 
+        // quit if we got the mythical 0 block, saving the final state of the transfer
+        if block_index == BlockIndex(0) {
+            if let Some(state) = &mut state {
+                if let Err(err) = state.save(&file) {
+                    println!("WARNING: Could not save the transfer state: {err}");
+                }
+            }
+            bail!("!!!!");
+        }
+
+        // keep track of the blocks we have written
+        if let Some(state_ref) = &mut state {
+            state_ref.set(block_index);
+            if let Err(err) = state_ref.save_if_due(&file) {
+                println!("WARNING: Could not save the transfer state, disabling it: {err}");
+                state = None;
+            }
+        }
+
         // pop the block
         ring_buffer.pop();
     }
 }
 
+/// Sets the size of streamed data, which has become known by receiving its final block with the
+/// given index and length, and counts the blocks that are still missing from it.
+fn end_stream(session: &mut Session, final_block: BlockIndex, final_block_len: usize) {
+    session.transfer.block_count = final_block;
+    session.transfer.file_size = FileSize(
+        u64::from(session.transfer.block_size)
+            .checked_mul(u64::from(final_block.safe_sub(BlockIndex(1)).0))
+            .and_then(|size| size.checked_add(final_block_len as u64))
+            .expect("stream size overflow"),
+    );
+
+    let mut blocks_left = BlockIndex(0);
+    let mut block = BlockIndex(1);
+    while block <= final_block {
+        if !session.got_block(block) {
+            blocks_left = blocks_left.safe_add(BlockIndex(1));
+        }
+        block = block.safe_add(BlockIndex(1));
+    }
+    session.transfer.blocks_left = blocks_left;
+}
+
+/// Returns whether the given block is close enough to the data that is due to be written next to
+/// be accepted, if the data is written to an output in order. Blocks further ahead would have to be
+/// kept in memory for too long; they are discarded, and requested again later.
+fn within_reorder_window(session: &Session, block: BlockIndex) -> bool {
+    let Some(emitted_position) = &session.transfer.emitted_position else {
+        return true;
+    };
+
+    let block_size = u64::from(session.transfer.block_size);
+    let block_start = block_size
+        .checked_mul(u64::from(block.safe_sub(BlockIndex(1)).0))
+        .expect("block position overflow");
+    block_start
+        < emitted_position
+            .load(Ordering::Acquire)
+            .saturating_add(super::config::REORDER_WINDOW)
+}
+
 /// Writes the current bitmap of received block accounting into a file named like the transferred
 /// file but with an extra postfix.
 ///
@@ -837,7 +1684,9 @@ pub fn dump_blockmap(postfix: &str, xfer: &Transfer) -> anyhow::Result<()> {
         .truncate(true)
         .open(fname)?;
     fbits.write_all(&xfer.block_count.0.to_le_bytes())?;
-    let block_data = &xfer.received.inner[0..((xfer.block_count.0 / 8).wrapping_add(1) as usize)];
+    let block_data_len =
+        ((xfer.block_count.0 / 8).wrapping_add(1) as usize).min(xfer.received.inner.len());
+    let block_data = &xfer.received.inner[0..block_data_len];
     fbits.write_all(block_data)?;
 
     Ok(())
@@ -1,27 +1,78 @@
-use std::io::{Seek, SeekFrom, Write};
+use std::{
+    fs::File,
+    io::{Seek, SeekFrom, Write},
+    os::fd::AsFd,
+    sync::OnceLock,
+};
+
+use anyhow::{anyhow, bail};
 
 use crate::{
+    compression::Decompressor,
     datagram,
+    stream::Stream,
     types::{BlockIndex, FileSize},
 };
 
-/// Accepts the given block of data, which involves writing the block to disk.
+/// The original standard output, once it has been redirected using `redirect_stdout`.
+static STDOUT: OnceLock<File> = OnceLock::new();
+
+/// Redirects standard output to standard error, so that the data of a transfer can be written to
+/// the original standard output without being mixed up with status messages. The original
+/// standard output can be obtained using `stdout` afterwards.
+///
+/// # Errors
+/// Returns an error on I/O failure, or if standard output has been redirected already.
+pub fn redirect_stdout() -> anyhow::Result<()> {
+    std::io::stdout().flush()?;
+    let original = std::io::stdout().as_fd().try_clone_to_owned()?;
+
+    // SAFETY: both file descriptors are open for the whole lifetime of the process
+    if unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+        bail!(
+            "Could not redirect standard output: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+
+    STDOUT
+        .set(File::from(original))
+        .map_err(|_| anyhow!("Standard output has already been redirected"))
+}
+
+/// Returns a handle to the original standard output, which has been redirected using
+/// `redirect_stdout`.
+///
+/// # Errors
+/// Returns an error on I/O failure, or if standard output has not been redirected.
+pub fn stdout() -> anyhow::Result<File> {
+    let Some(stdout) = STDOUT.get() else {
+        bail!("Standard output has not been redirected");
+    };
+    Ok(stdout.try_clone()?)
+}
+
+/// Accepts the given block of data, which involves decompressing the block if it has been
+/// compressed, and writing it to disk. The final block of streamed data is shorter than the block
+/// size, and is written as it is.
 ///
 /// # Errors
-/// Returns an error on I/O failure.
+/// Returns an error on I/O failure, or if the block cannot be decompressed.
 ///
 /// # Panics
 /// Panics on arithmetic overflow.
 pub fn accept_block(
     datagram: datagram::View,
+    block_size: u16,
     block_count: BlockIndex,
     file_size: FileSize,
-    file: &mut std::fs::File,
+    file: &mut Stream,
+    decompressor: &mut Decompressor,
 ) -> anyhow::Result<()> {
-    let block_size = datagram.block.len();
+    let block = decompressor.decompress(datagram.header.compression, datagram.block)?;
 
     // seek to the proper location
-    let offset = (block_size as u64)
+    let offset = u64::from(block_size)
         .checked_mul(u64::from(
             (datagram.header.block_index.safe_sub(BlockIndex(1))).0,
         ))
@@ -33,17 +84,17 @@ pub fn accept_block(
         // The last block may be smaller than `block_size`.
         let write_size: usize = (file_size
             .0
-            .checked_rem(block_size as u64)
+            .checked_rem(u64::from(block_size))
             .expect("block_size is 0"))
         .try_into()
         .expect("write_size overflow");
         if write_size == 0 {
-            datagram.block
+            block
         } else {
-            &datagram.block[0..write_size]
+            &block[0..write_size]
         }
     } else {
-        datagram.block
+        block
     };
 
     // write the block to disk
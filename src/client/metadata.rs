@@ -0,0 +1,104 @@
+use std::{
+    ffi::CString,
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::bail;
+
+use crate::types::{EntryType, FileMetadata};
+
+/// Creates the given directory, and its parent directories if necessary.
+///
+/// # Errors
+/// Returns an error on I/O failure.
+pub fn create_directory(path: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir_all(path)?;
+    Ok(())
+}
+
+/// Creates a symbolic link at the given path, pointing to the given target. An existing file or
+/// symbolic link at the path is replaced; an existing directory is left alone.
+///
+/// # Errors
+/// Returns an error on I/O failure, or if a directory exists at the path.
+pub fn create_symlink(path: &Path, target: &Path) -> anyhow::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(existing) if existing.is_dir() => {
+            bail!("A directory exists in place of the symbolic link");
+        }
+        Ok(existing) => {
+            if existing.is_symlink() && std::fs::read_link(path)? == target {
+                return Ok(());
+            }
+            std::fs::remove_file(path)?;
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::os::unix::fs::symlink(target, path)?;
+    Ok(())
+}
+
+/// Applies the permissions and the modification time in the given metadata to the entry at the
+/// given local path. Symbolic links have no permissions of their own, so only their modification
+/// time is changed, without following them.
+///
+/// # Errors
+/// Returns an error on I/O failure.
+pub fn apply(path: &Path, metadata: &FileMetadata) -> anyhow::Result<()> {
+    if let EntryType::Symlink(_) = metadata.entry_type {
+        if let Some(mtime) = metadata.mtime {
+            set_symlink_mtime(path, mtime)?;
+        }
+        return Ok(());
+    }
+
+    if let Some(mtime) = metadata.mtime {
+        let Some(modified) = std::time::UNIX_EPOCH.checked_add(mtime) else {
+            bail!("Modification time out of range");
+        };
+        std::fs::File::open(path)?.set_modified(modified)?;
+    }
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(metadata.mode))?;
+    Ok(())
+}
+
+/// Sets the modification time of the given symbolic link itself, leaving its access time alone.
+fn set_symlink_mtime(path: &Path, mtime: Duration) -> anyhow::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let times = [
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+        libc::timespec {
+            tv_sec: mtime.as_secs().try_into()?,
+            tv_nsec: mtime.subsec_nanos().into(),
+        },
+    ];
+
+    // SAFETY: `path` is a NUL-terminated string, and `times` holds the two timestamps that
+    // `utimensat` reads, both of which outlive the call
+    let status = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if status < 0 {
+        bail!(
+            "Could not set the modification time of the symbolic link: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+
+    Ok(())
+}
@@ -2,20 +2,27 @@ pub mod config;
 pub mod dir;
 pub mod get;
 pub mod io;
+pub mod metadata;
 pub mod network;
 pub mod protocol;
+pub mod put;
 pub mod ring;
+pub mod state;
+pub mod sync;
 pub mod transcript;
 
 use std::{
     net::UdpSocket,
+    ops::Range,
     path::PathBuf,
-    sync::Arc,
+    sync::{atomic::AtomicU64, Arc},
     time::{Duration, Instant},
 };
 
 use crate::{
     common::SocketWrapper,
+    rtt::RttEstimator,
+    stream::Stream,
     types::{BlockIndex, FileSize, ReceivedMap, UdpErrors},
 };
 
@@ -30,6 +37,8 @@ pub struct Statistics {
     pub total_retransmits: BlockIndex,
     pub total_recvd_retransmits: BlockIndex,
     pub total_lost: BlockIndex,
+    pub this_recovered: BlockIndex,
+    pub total_recovered: BlockIndex,
     pub this_flow_originals: BlockIndex,
     pub this_flow_retransmitteds: BlockIndex,
     pub this_transmit_rate: f64,
@@ -48,6 +57,10 @@ pub struct Retransmit {
 impl Retransmit {
     pub const MAX_RETRANSMISSION_BUFFER: u32 = 2048;
 
+    /// The largest number of ranges of missing blocks that are requested at once. If more blocks
+    /// are missing, the transfer is restarted from the first of them instead.
+    pub const MAX_RANGES: usize = crate::common::MAX_RETRANSMIT_RANGES;
+
     pub fn swap_tables(&mut self) {
         std::mem::swap(&mut self.previous_table, &mut self.next_table);
     }
@@ -69,14 +82,20 @@ pub enum OutputMode {
 }
 
 #[derive(Default)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct Transfer {
+    pub transfer_id: u32,
     pub epoch: Duration,
+    pub remote_mtime: Option<Duration>,
+    pub remote_sample_digest: Option<[u8; 32]>,
     pub remote_filename: Option<PathBuf>,
     pub local_filename: Option<PathBuf>,
-    pub file: Option<std::fs::File>,
+    pub batch_filenames: Vec<PathBuf>,
+    pub file: Option<Stream>,
     pub transcript: Option<std::fs::File>,
     pub udp_socket: Option<UdpSocket>,
     pub file_size: FileSize,
+    pub block_size: u16,
     pub block_count: BlockIndex,
     pub next_block: BlockIndex,
     pub gapless_to_block: BlockIndex,
@@ -89,6 +108,36 @@ pub struct Transfer {
     pub restart_lastidx: BlockIndex,
     pub restart_wireclearidx: BlockIndex,
     pub on_wire_estimate: BlockIndex,
+    pub invalid_ranges: Vec<Range<u64>>,
+
+    /// Whether the data is streamed by the server, so that its size only becomes known with its
+    /// final block.
+    pub streamed: bool,
+
+    /// The offset within the local file at which the data is written, if a range of the remote
+    /// file is written into the matching region of the local file.
+    pub local_offset: Option<u64>,
+
+    /// Whether the file is followed by the server while it grows, so that its size only becomes
+    /// final during the transfer.
+    pub followed: bool,
+
+    /// Whether the followed file may still grow, so that only its complete blocks are counted.
+    pub following: bool,
+
+    /// The position up to which the data has been written to the output, if it is written to an
+    /// output in order rather than to a file.
+    pub emitted_position: Option<Arc<AtomicU64>>,
+
+    /// The size of the data that was present locally before the local file has been extended to
+    /// the size of the transfer, if it is known. Only chunks within it are compared when resuming.
+    pub local_size: Option<u64>,
+
+    /// The round trip time to the server, measured by pinging it during the transfer.
+    pub rtt: RttEstimator,
+
+    /// The number of pings that the server has not replied to yet.
+    pub pings_outstanding: u32,
 }
 
 pub struct Session {
@@ -111,9 +160,8 @@ impl Session {
 pub fn print_intro(encrypted: bool) {
     // show version / build information
     eprintln!(
-        "namida client for protocol revision {} (block size = {}, magic = 0x{:x})\nVersion: {} (revision {})\nCompiled: {}\n",
+        "namida client for protocol revision {} (magic = 0x{:x})\nVersion: {} (revision {})\nCompiled: {}\n",
         crate::version::NAMIDA_PROTOCOL_REVISION,
-        crate::common::BLOCK_SIZE,
         crate::version::magic(encrypted),
         crate::version::NAMIDA_VERSION,
         &crate::version::GIT_HASH[0..7],
@@ -55,7 +55,7 @@ pub fn create_udp_socket(parameter: &get::Parameter, ipv6: bool) -> anyhow::Resu
     // set the receive buffer size
     if let Err(err) = set_udp_receive_buffer(&mut socket, parameter.udp_buffer) {
         println!("WARNING: {err}");
-    };
+    }
 
     println!("Receiving data over UDP at: {}", socket.local_addr()?);
 
@@ -1,7 +1,7 @@
 use std::{
     borrow::Cow,
-    io::Write,
-    path::PathBuf,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
     time::{Duration, Instant},
 };
 
@@ -11,8 +11,12 @@ use anyhow::bail;
 use super::{get, OutputMode, Retransmit, Session, Transfer};
 use crate::{
     common::SocketWrapper,
-    message::{self, ClientToServer, FileRequest, ServerToClient, TransmissionControl, UdpMethod},
-    types::{BlockIndex, ErrorRate},
+    message::{
+        self, BatchRequest, ClientToServer, FileRequest, ServerToClient, TransmissionControl,
+        UdpMethod,
+    },
+    stream::{Batch, Output, Region, Stream},
+    types::{BlockIndex, BlockRange, ChecksumRequest, ErrorRate, FileSize, SkipChunks},
 };
 
 /// Opens a new control session to the specified server. On success, we return the created session
@@ -21,24 +25,48 @@ use crate::{
 /// Note that the default host and port stored in the parameter object are updated if they were
 /// specified in the command itself.
 ///
+/// If the server is busy, we wait for as long as it tells us to, and try again a few times.
+///
 /// # Errors
-/// Returns an error on I/O failure.
+/// Returns an error on I/O failure, or if the server stays busy.
+///
+/// # Panics
+/// Panics on arithmetic overflow.
 pub fn connect(
     server: &str,
     encrypted: bool,
     secret: &[u8],
     quiet: bool,
 ) -> anyhow::Result<Session> {
-    // obtain our client socket, and create a new session object with it
-    let mut session = Session {
-        transfer: Transfer::default(),
-        server: SocketWrapper::new(super::network::create_tcp_socket(server)?),
-    };
+    let mut retries = 0;
+    let mut session = loop {
+        // obtain our client socket, and create a new session object with it
+        let mut session = Session {
+            transfer: Transfer::default(),
+            server: SocketWrapper::new(super::network::create_tcp_socket(server)?),
+        };
+
+        // negotiate the connection parameters
+        if let Err(err) = negotiate(&mut session, encrypted) {
+            bail!("Protocol negotiation failed: {:?}", err);
+        }
 
-    // negotiate the connection parameters
-    if let Err(err) = negotiate(&mut session, encrypted) {
-        bail!("Protocol negotiation failed: {:?}", err);
-    }
+        // the server may be serving too many clients to serve us as well
+        match session.server.read()? {
+            ServerToClient::Admitted => break session,
+            ServerToClient::Busy { retry_after } if retries < super::config::MAX_BUSY_RETRIES => {
+                if !quiet {
+                    eprintln!("Server is busy, retrying in {retry_after} seconds...");
+                }
+                retries = retries.checked_add(1).expect("retry count overflow");
+                std::thread::sleep(Duration::from_secs(u64::from(retry_after)));
+            }
+            ServerToClient::Busy { retry_after } => {
+                bail!("Server is busy, try again in {retry_after} seconds")
+            }
+            _ => bail!("Expected admission"),
+        }
+    };
 
     // authenticate to the server, and potentially initiate an encrypted connection
     let auth_result = if encrypted {
@@ -54,6 +82,51 @@ pub fn connect(
     Ok(session)
 }
 
+/// Requests a token from the server, with which further connections can join the given session to
+/// transfer files in parallel. Returns the token, and the number of connections the session may
+/// have in total.
+///
+/// # Errors
+/// Returns an error on I/O failure, or if the server sent unexpected data.
+pub fn request_session_token(session: &mut Session) -> anyhow::Result<(u64, u16)> {
+    session.server.write(ClientToServer::SessionTokenRequest)?;
+    let ServerToClient::SessionToken {
+        token,
+        max_connections,
+    } = session.server.read()?
+    else {
+        bail!("Expected session token");
+    };
+
+    Ok((token, max_connections))
+}
+
+/// Makes the given connection join the session identified by the given token, so that its
+/// transfers share the rate of the session's other transfers.
+///
+/// # Errors
+/// Returns an error on I/O failure, if the server sent unexpected data, or if the session could
+/// not be joined.
+pub fn join_session(session: &mut Session, token: u64) -> anyhow::Result<()> {
+    session.server.write(ClientToServer::JoinSession(token))?;
+
+    // the server answers right away, as the session to join is already being served
+    session
+        .server
+        .socket
+        .set_read_timeout(Some(Duration::from_secs(super::config::JOIN_TIMEOUT_SECS)))?;
+    let reply = session.server.read();
+    session.server.socket.set_read_timeout(None)?;
+    let ServerToClient::SessionJoined(joined) = reply? else {
+        bail!("Expected session join result");
+    };
+    if !joined {
+        bail!("The server did not find the session to join");
+    }
+
+    Ok(())
+}
+
 /// Given an active session, returns `Ok(())` if we were able to successfully authenticate to the
 /// server, and an error otherwise. Used only for unencrypted connections. See the documentation of
 /// `server::protocol::authenticate_unencrypted` for a description of the unencrypted authentication
@@ -180,36 +253,173 @@ pub fn open_transfer(
     // submit the transfer request
     session
         .server
-        .write(ClientToServer::FileRequest(FileRequest {
-            path: remote_filename.clone(),
-            target_rate: parameter.target_rate,
-            error_rate: parameter.error_rate,
-            slowdown: parameter.slower,
-            speedup: parameter.faster,
-        }))?;
+        .write(ClientToServer::FileRequest(file_request(
+            parameter,
+            remote_filename.clone(),
+        )))?;
 
     // see if the request was successful
+    let remote_udp_port = accept_transfer(session, parameter, remote_filename, local_filename)?;
+
+    // open the local file for writing
+    let resume = open_local_file(session, parameter)?;
+
+    // indicate success, and let the outside know of the server's UDP port
+    Ok((remote_udp_port, resume))
+}
+
+/// Requests the given remote files from the server as one batch, which is transferred as a single
+/// stream consisting of the contents of the files concatenated in order. The data is written into
+/// the given local files, which correspond to the remote ones. Returns the server's UDP port, and
+/// whether the transfer should be resumed because some of the local files are present already.
+///
+/// # Errors
+/// Returns an error on I/O failure, or if the server refuses the request or sends unexpected data.
+///
+/// # Panics
+/// Panics if no files are given.
+pub fn open_batch(
+    session: &mut Session,
+    parameter: &get::Parameter,
+    remote_filenames: &[PathBuf],
+    local_filenames: &[PathBuf],
+) -> anyhow::Result<(u16, bool)> {
+    session
+        .server
+        .write(ClientToServer::BatchRequest(BatchRequest {
+            request: file_request(parameter, PathBuf::new()),
+            paths: remote_filenames.to_vec(),
+        }))?;
+
+    let sizes = match session.server.read()? {
+        ServerToClient::BatchSizes(sizes) => sizes,
+        ServerToClient::FileRequestError(err) => {
+            bail!("Server: Files do not exist or cannot be transmitted: {err:?}");
+        }
+        other => bail!("Expected `BatchSizes` or `FileRequestError` but got: {other:?}"),
+    };
+    if sizes.len() != local_filenames.len() {
+        bail!(
+            "Server sent {} file sizes for a batch of {} files",
+            sizes.len(),
+            local_filenames.len()
+        );
+    }
+
+    let remote_udp_port = accept_transfer(
+        session,
+        parameter,
+        remote_filenames[0].clone(),
+        local_filenames[0].clone(),
+    )?;
+    let total_size = sizes
+        .iter()
+        .try_fold(0_u64, |total, size| total.checked_add(size.0));
+    if total_size != Some(session.transfer.file_size.0) {
+        bail!("The sizes of the files of the batch do not add up to the size of the transfer");
+    }
+
+    // Resume if some of the files are already present locally; matching data is found by
+    // comparing checksums over the whole batch.
+    let present_count = local_filenames.iter().filter(|path| path.exists()).count();
+    let resume = parameter.resume && present_count > 0;
+    if resume {
+        println!(
+            "{present_count} of {} files of the batch are already present locally — resuming previous transfer.",
+            local_filenames.len()
+        );
+    }
+
+    let files: Vec<(&Path, FileSize)> = local_filenames
+        .iter()
+        .map(PathBuf::as_path)
+        .zip(sizes)
+        .collect();
+    session.transfer.file = Some(Stream::Batch(Batch::create(&files)?));
+    session.transfer.batch_filenames = local_filenames.to_vec();
+    initialise_block_counters(session, parameter);
+
+    // if we're doing a transcript
+    if parameter.transcript_yn {
+        crate::common::transcript_warn_error(super::transcript::open(session, parameter));
+    }
+
+    Ok((remote_udp_port, resume))
+}
+
+/// Builds a request for the given remote file, using the transfer parameters given by the user.
+fn file_request(parameter: &get::Parameter, path: PathBuf) -> FileRequest {
+    FileRequest {
+        path,
+        target_rate: parameter.target_rate,
+        error_rate: parameter.error_rate,
+        slowdown: parameter.slower,
+        speedup: parameter.faster,
+        block_size: parameter.block_size,
+        checksum_algorithm: parameter.checksum_algorithm,
+        compression: parameter.compression,
+        fec_group_size: parameter.fec_group_size,
+        follow_idle: parameter.follow_idle,
+        offset: parameter.offset,
+        length: parameter.length,
+    }
+}
+
+/// Reads the server's response to a transfer request. If the request was successful, the transfer
+/// is initialised using the parameters sent by the server, and the server's UDP port is returned.
+///
+/// # Errors
+/// Returns an error on I/O failure, or if the server refuses the request or sends unexpected data.
+fn accept_transfer(
+    session: &mut Session,
+    parameter: &get::Parameter,
+    remote_filename: PathBuf,
+    local_filename: PathBuf,
+) -> anyhow::Result<u16> {
     let result = session.server.read()?;
-    let remote_udp_port = match result {
+    match result {
         ServerToClient::FileRequestSuccess {
             file_size,
+            block_size,
             block_count,
             epoch,
             udp_port,
+            mtime,
+            sample_digest,
+            compression,
+            transfer_id,
+            streamed,
+            offset,
         } => {
+            if streamed && parameter.delta {
+                bail!("Delta transfers are not possible for data streamed by the server");
+            }
+
             // It was. Initialise the transfer
             session.transfer = Transfer::default();
+            session.transfer.transfer_id = transfer_id;
             session.transfer.remote_filename = Some(remote_filename);
             session.transfer.local_filename = Some(local_filename);
 
             // Get the server's parameters
             session.transfer.file_size = file_size;
+            session.transfer.block_size = block_size;
             session.transfer.block_count = block_count;
             session.transfer.epoch = epoch;
+            session.transfer.remote_mtime = mtime;
+            session.transfer.remote_sample_digest = sample_digest;
+            session.transfer.streamed = streamed;
+            session.transfer.local_offset = parameter.in_place.then_some(offset);
+            session.transfer.followed = parameter.follow_idle.is_some() && !streamed;
+            session.transfer.following = session.transfer.followed;
+
+            if compression != parameter.compression {
+                println!(
+                    "The server does not allow compression, blocks will be sent uncompressed."
+                );
+            }
 
-            // Return the server's UDP port to outside of the match block, so we can return it from
-            // the function later
-            udp_port
+            Ok(udp_port)
         }
         ServerToClient::FileRequestError(err) => {
             bail!(
@@ -223,11 +433,20 @@ pub fn open_transfer(
                 result
             );
         }
-    };
-
-    // we start out with every block yet to transfer
-    session.transfer.blocks_left = session.transfer.block_count;
+    }
+}
 
+/// Opens the local file of the transfer that has just been set up in the given session, i.e. the
+/// transfer's local filename, file size and block count must already be known. If the file is
+/// already present locally and resuming is enabled, returns `true` to indicate that the previous
+/// transfer should be resumed.
+///
+/// # Errors
+/// Returns an error on I/O failure.
+///
+/// # Panics
+/// Panics if no local path is set in the transfer object.
+pub fn open_local_file(session: &mut Session, parameter: &get::Parameter) -> anyhow::Result<bool> {
     // try to open the local file for writing
     let mut resume = false;
     let local_path = session
@@ -236,7 +455,49 @@ pub fn open_transfer(
         .as_ref()
         .expect("there should be a local path")
         .as_path();
-    if local_path.exists() {
+
+    // Data written to standard output is emitted in order as it arrives, and cannot be resumed
+    if local_path == Path::new(super::config::STDOUT_PATH) {
+        let output = Output::new(super::io::stdout()?, super::config::REORDER_WINDOW);
+        session.transfer.emitted_position = Some(output.emitted_position());
+        session.transfer.file = Some(Stream::Output(output));
+        initialise_block_counters(session, parameter);
+        return Ok(false);
+    }
+
+    // In delta mode, the existing local file becomes the basis for the new version, unless a
+    // basis is left over from an interrupted delta transfer, which then needs to be resumed.
+    // Streamed data can neither be compared with an existing file, nor be used for a delta.
+    if session.transfer.streamed {
+        if local_path.exists() {
+            println!(
+                "File '{}' is already present locally, but streamed data cannot be resumed. The existing file will be overwritten.",
+                local_path.display()
+            );
+        }
+    } else if parameter.delta && parameter.resume {
+        let basis_path = basis_path_for(local_path);
+        if basis_path.exists() {
+            println!(
+                "Previous version of '{}' is present — resuming delta transfer.",
+                local_path.display()
+            );
+            resume = true;
+        } else if local_path.exists() {
+            println!(
+                "File '{}' is already present locally — updating it using a delta transfer.",
+                local_path.display()
+            );
+            std::fs::rename(local_path, &basis_path)?;
+            resume = true;
+        }
+    } else if session.transfer.local_offset.is_some() && local_path.exists() {
+        println!(
+            "File '{}' is already present locally — writing the requested range into it.",
+            local_path.display()
+        );
+        resume = parameter.resume;
+    } else if local_path.exists() {
         resume = if parameter.resume {
             println!(
                 "File '{}' is already present locally — resuming previous transfer.",
@@ -251,33 +512,127 @@ pub fn open_transfer(
             false
         }
     }
-    let file = session.transfer.file.insert(
-        std::fs::File::options()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(false)
-            .open(local_path)?,
-    );
-    file.set_len(session.transfer.file_size.0)?;
+    let file = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(local_path)?;
+
+    // A range may be written into the matching region of the local file, leaving the rest of it
+    // alone
+    let existing_size = file.metadata()?.len();
+    session.transfer.file = Some(if let Some(offset) = session.transfer.local_offset {
+        let end = offset
+            .checked_add(session.transfer.file_size.0)
+            .expect("region end overflow");
+        session.transfer.local_size = Some(existing_size.saturating_sub(offset));
+        if existing_size < end {
+            file.set_len(end)?;
+        }
+        Stream::Region(Region::new(file, offset, session.transfer.file_size.0))
+    } else {
+        session.transfer.local_size = Some(existing_size);
+        file.set_len(session.transfer.file_size.0)?;
+        Stream::File(file)
+    });
+
+    initialise_block_counters(session, parameter);
+
+    // if we're doing a transcript
+    if parameter.transcript_yn {
+        crate::common::transcript_warn_error(super::transcript::open(session, parameter));
+    }
+
+    Ok(resume)
+}
+
+/// Initialises the block counters of the transfer, which depend on the block size.
+fn initialise_block_counters(session: &mut Session, parameter: &get::Parameter) {
+    // we start out with every block yet to transfer
+    session.transfer.blocks_left = session.transfer.block_count;
+
+    update_on_wire_estimate(session, parameter);
+}
+
+/// Estimates the number of blocks that are still on the wire when a request reaches the server,
+/// i.e. that it sends within the time a reply can be expected in. Until the round trip time has
+/// been measured, half a second is assumed.
+fn update_on_wire_estimate(session: &mut Session, parameter: &get::Parameter) {
+    let time = session
+        .transfer
+        .rtt
+        .timeout()
+        .unwrap_or(Duration::from_millis(500));
 
     #[allow(clippy::cast_precision_loss)]
     #[allow(clippy::cast_sign_loss)]
     #[allow(clippy::cast_possible_truncation)]
     let on_wire_estimate = BlockIndex(
-        (0.5_f64 * parameter.target_rate.0 as f64
-            / (f64::from(crate::common::BLOCK_SIZE) * 8.0_f64)) as u32,
+        (time.as_secs_f64() * parameter.target_rate.0 as f64
+            / (f64::from(session.transfer.block_size) * 8.0_f64)) as u32,
     );
     session.transfer.on_wire_estimate =
         BlockIndex::min(session.transfer.block_count, on_wire_estimate);
+}
 
-    // if we're doing a transcript
-    if parameter.transcript_yn {
-        crate::common::transcript_warn_error(super::transcript::open(session, parameter));
+/// Takes part in the path MTU probing performed by the sending side. Once the sending side tells
+/// us that all probe datagrams have been sent, we report the size of the largest one that arrived
+/// on our UDP socket, and receive the block size chosen based on that in return.
+///
+/// # Errors
+/// Returns an error on I/O failure, or if the sending side sends unexpected data.
+///
+/// # Panics
+/// Panics if no UDP socket has been opened.
+pub fn answer_mtu_probes(session: &mut Session, parameter: &get::Parameter) -> anyhow::Result<()> {
+    let ServerToClient::MtuProbesSent = session.server.read()? else {
+        bail!("Expected `MtuProbesSent`");
+    };
+
+    // The probes should be waiting in the socket's receive buffer by now. Give late probes a
+    // little more time to arrive.
+    let udp_socket = session
+        .transfer
+        .udp_socket
+        .as_ref()
+        .expect("UDP socket should be present");
+    udp_socket.set_read_timeout(Some(Duration::from_millis(
+        super::config::MTU_PROBE_TIMEOUT_MS,
+    )))?;
+    let mut buffer = vec![0_u8; usize::from(u16::MAX)];
+    let mut largest_received = 0_u16;
+    while let Ok(len) = udp_socket.recv(&mut buffer) {
+        if buffer.starts_with(crate::common::MTU_PROBE_MAGIC) {
+            largest_received = largest_received.max(len.try_into().unwrap_or(u16::MAX));
+        }
     }
+    udp_socket.set_read_timeout(None)?;
 
-    // indicate success, and let the outside know of the server's UDP port
-    Ok((remote_udp_port, resume))
+    session
+        .server
+        .write(ClientToServer::MtuProbeReport(largest_received))?;
+
+    let ServerToClient::BlockSize(block_size) = session.server.read()? else {
+        bail!("Expected `BlockSize`");
+    };
+    if parameter.verbose_yn {
+        println!(
+            "Largest MTU probe received: {largest_received} bytes, using block size {block_size}"
+        );
+    }
+
+    session.transfer.block_size = block_size;
+    if session.transfer.following {
+        session.transfer.block_count =
+            crate::common::complete_block_count(session.transfer.file_size, block_size);
+    } else if !session.transfer.streamed {
+        session.transfer.block_count =
+            crate::common::block_count(session.transfer.file_size, block_size);
+    }
+    initialise_block_counters(session, parameter);
+
+    Ok(())
 }
 
 /// Creates a new UDP socket for receiving the file data associated with our pending transfer and
@@ -339,108 +694,367 @@ pub fn open_port(
     Ok(())
 }
 
-/// Receives chunk-wise checksum data from the server, compares the data with the file we already
-/// have stored locally, and sends the result back to the server
+/// Finds out which parts of the file we already have stored locally, by comparing checksums of
+/// chunks of it with those calculated by the server. Chunks that do not match are split into
+/// smaller chunks and compared again, down to the level of single blocks, so that only the data
+/// that actually differs has to be transmitted. The result is sent back to the server. If the
+/// server did not use the checksum algorithm we requested, no data is considered to match.
 ///
 /// # Errors
-/// Returns an error on I/O failure.
+/// Returns an error on I/O failure, or if the server sends unexpected data.
 ///
 /// # Panics
 /// Panics if no file has been opened.
-pub fn resume(session: &mut Session) -> anyhow::Result<()> {
-    let ServerToClient::Checksums(remote_checksums) = session.server.read()? else {
-        bail!("Expected checksums");
-    };
-
+pub fn resume(session: &mut Session, parameter: &get::Parameter) -> anyhow::Result<()> {
+    let block_count = session.transfer.block_count;
+    let block_size = session.transfer.block_size;
+    let local_size = session.transfer.local_size;
     let file = session
         .transfer
         .file
         .as_mut()
         .expect("File should have been opened");
 
-    let our_checksums = crate::common::calculate_checksums(
-        file,
-        session.transfer.file_size,
-        session.transfer.block_count,
-        remote_checksums.chunk_blocks,
-    )?;
+    let mut matching = vec![];
+    let mut chunk_blocks = crate::common::initial_chunk_blocks(block_count);
+    let mut chunks: Vec<u32> = (0..block_count.0.div_ceil(chunk_blocks)).collect();
+
+    'levels: loop {
+        // Only compare chunks of which we had at least some data locally before the transfer
+        chunks.retain(|chunk| {
+            BlockRange::of_chunk(*chunk, chunk_blocks, block_count).is_some_and(|range| {
+                local_size.is_none_or(|local_size| {
+                    u64::from(range.first.safe_sub(BlockIndex(1)).0)
+                        .checked_mul(u64::from(block_size))
+                        .expect("chunk start overflow")
+                        < local_size
+                })
+            })
+        });
+        if chunks.is_empty() {
+            break;
+        }
+
+        let mut mismatched = vec![];
+        for batch in chunks.chunks(crate::common::MAX_CHECKSUMS_PER_REQUEST) {
+            let request = ChecksumRequest {
+                chunk_blocks,
+                chunks: batch.to_vec(),
+            };
+            session
+                .server
+                .write(ClientToServer::ChecksumRequest(request.clone()))?;
+            let ServerToClient::Checksums(remote_checksums) = session.server.read()? else {
+                bail!("Expected checksums");
+            };
 
-    let skip_chunks = remote_checksums.compare(&our_checksums);
-    let block_count = skip_chunks.count_blocks();
-    let mut matching_bytes = block_count.saturating_mul(u64::from(crate::common::BLOCK_SIZE));
+            let remote_algorithm = remote_checksums.checksums.algorithm();
+            if remote_algorithm != parameter.checksum_algorithm {
+                println!(
+                    "WARNING: Requested {} checksums for resuming, but received {remote_algorithm} checksums. The whole file will be transferred again.",
+                    parameter.checksum_algorithm
+                );
+                matching.clear();
+                break 'levels;
+            }
+            if remote_checksums.chunk_blocks != chunk_blocks
+                || remote_checksums.checksums.len() != batch.len()
+            {
+                bail!("Received checksums do not match the request");
+            }
+
+            let our_checksums = crate::common::calculate_checksums(
+                file,
+                block_size,
+                &request,
+                parameter.checksum_algorithm,
+            )?;
+            for (position, chunk) in batch.iter().enumerate() {
+                if our_checksums
+                    .checksums
+                    .chunk_matches(&remote_checksums.checksums, position)
+                    == Some(true)
+                {
+                    matching.extend(BlockRange::of_chunk(*chunk, chunk_blocks, block_count));
+                } else {
+                    mismatched.push(*chunk);
+                }
+            }
+        }
+
+        if chunk_blocks == 1 {
+            break;
+        }
+
+        // Split the chunks that do not match into smaller ones for the next round. To limit the
+        // effort for files that differ almost everywhere, only a limited number of chunks is
+        // refined; the others are transmitted in full.
+        chunk_blocks = chunk_blocks
+            .checked_div(crate::common::CHECKSUM_REFINEMENT_FACTOR)
+            .expect("refinement factor is 0");
+        mismatched.truncate(super::config::MAX_REFINED_CHUNKS);
+        chunks = mismatched
+            .iter()
+            .flat_map(|chunk| {
+                let first = chunk
+                    .checked_mul(crate::common::CHECKSUM_REFINEMENT_FACTOR)
+                    .expect("chunk index overflow #1");
+                let end = first
+                    .checked_add(crate::common::CHECKSUM_REFINEMENT_FACTOR)
+                    .expect("chunk index overflow #2");
+                first..end
+            })
+            .collect();
+    }
+
+    skip_blocks(session, matching)
+}
+
+/// Returns the path under which the previous version of the given local file is kept during a delta
+/// transfer.
+#[must_use]
+pub fn basis_path_for(local_filename: &Path) -> PathBuf {
+    let mut path = local_filename.to_path_buf().into_os_string();
+    path.push(super::config::DELTA_BASIS_SUFFIX);
+    PathBuf::from(path)
+}
+
+/// Resumes a transfer using the previous version of the file, stored at the given path. We send
+/// the signatures of its blocks to the server, which tells us where these blocks are located in the
+/// new version. We then copy them into the local file, and let the server know that it can skip
+/// all blocks that are covered completely by copied data.
+///
+/// # Errors
+/// Returns an error on I/O failure, or if the server sends invalid copy instructions.
+///
+/// # Panics
+/// Panics if no local file has been opened, or on arithmetic overflow.
+pub fn resume_delta(session: &mut Session, basis_path: &Path) -> anyhow::Result<()> {
+    let mut basis = std::fs::File::open(basis_path)?;
+    let basis_size = basis.metadata()?.len();
+    let delta_block_size = crate::delta::block_size_for(basis_size, session.transfer.block_size);
+
+    // The server accepts only a limited number of signatures; blocks beyond that are transmitted
+    let mut signatures = crate::delta::signatures(&mut basis, delta_block_size)?;
+    signatures.truncate(crate::delta::max_signatures(session.transfer.file_size.0));
+    for batch in signatures.chunks(crate::delta::MAX_SIGNATURES_PER_MESSAGE) {
+        session
+            .server
+            .write(ClientToServer::DeltaSignatures(batch.to_vec()))?;
+    }
+    session
+        .server
+        .write(ClientToServer::DeltaRequest(delta_block_size))?;
+
+    let file_size = session.transfer.file_size;
+    let file = session
+        .transfer
+        .file
+        .as_mut()
+        .expect("file should have been opened");
+    let mut buffer = vec![0_u8; delta_block_size as usize];
+    let mut present = vec![];
+    let mut reused_bytes = 0_u64;
+    loop {
+        let copies = match session.server.read()? {
+            ServerToClient::DeltaCopies(copies) => copies,
+            ServerToClient::DeltaComplete => break,
+            other => bail!("Expected delta copy instructions, but got: {other:?}"),
+        };
+
+        for copy in copies {
+            let source_end = copy.source_offset.checked_add(copy.length);
+            let target_end = copy.target_offset.checked_add(copy.length);
+            let (Some(source_end), Some(target_end)) = (source_end, target_end) else {
+                bail!("Invalid copy instruction: {copy:?}");
+            };
+            if source_end > basis_size || target_end > file_size.0 {
+                bail!("Invalid copy instruction: {copy:?}");
+            }
+
+            basis.seek(SeekFrom::Start(copy.source_offset))?;
+            file.seek(SeekFrom::Start(copy.target_offset))?;
+            let mut remaining = copy.length;
+            while remaining > 0 {
+                let length = remaining.min(buffer.len() as u64);
+                let chunk = &mut buffer[..length.try_into()?];
+                basis.read_exact(chunk)?;
+                file.write_all(chunk)?;
+                remaining = remaining
+                    .checked_sub(length)
+                    .expect("copy length underflow");
+            }
+
+            reused_bytes = reused_bytes
+                .checked_add(copy.length)
+                .expect("reused bytes overflow");
+            present.extend(BlockRange::within_bytes(
+                copy.target_offset,
+                target_end,
+                session.transfer.block_size,
+                file_size,
+                session.transfer.block_count,
+            ));
+        }
+    }
+
+    println!("Delta transfer: reusing {reused_bytes} bytes of the previous version.");
+    skip_blocks(session, present)
+}
+
+/// Resumes a transfer using the ranges of blocks that are already present locally according to
+/// the saved state of a previous transfer, without comparing any checksums. The result is sent to
+/// the server.
+///
+/// # Errors
+/// Returns an error on I/O failure.
+pub fn resume_from_state(session: &mut Session, present: Vec<BlockRange>) -> anyhow::Result<()> {
+    println!("Resuming previous transfer using its saved state.");
+    skip_blocks(session, present)
+}
+
+/// Stores the given ranges of blocks that are already present locally as received, and lets the
+/// server know that it can skip them. Data that previously failed verification is always
+/// transmitted again.
+///
+/// # Panics
+/// Panics on arithmetic overflow.
+fn skip_blocks(session: &mut Session, present: Vec<BlockRange>) -> anyhow::Result<()> {
+    let block_count = session.transfer.block_count;
+    let block_size = session.transfer.block_size;
+    let mut skip_chunks = SkipChunks::from_ranges(present);
+
+    // Make sure data that previously failed verification is transmitted again, even if the
+    // checksums claim that it matches
+    for range in &session.transfer.invalid_ranges {
+        if range.is_empty() {
+            continue;
+        }
+        let first = range
+            .start
+            .checked_div(u64::from(block_size))
+            .expect("block_size is 0");
+        let last = (range.end.checked_sub(1).expect("range end underflow"))
+            .checked_div(u64::from(block_size))
+            .expect("block_size is 0");
+        skip_chunks.invalidate_blocks(
+            BlockIndex((first.checked_add(1).expect("BlockIndex overflow #1")).try_into()?),
+            BlockIndex((last.checked_add(1).expect("BlockIndex overflow #2")).try_into()?),
+        );
+    }
+    let matching_block_count = skip_chunks.count_blocks();
+    let mut matching_bytes = matching_block_count.saturating_mul(u64::from(block_size));
     let final_block_size = session
         .transfer
         .file_size
         .0
-        .checked_rem(u64::from(crate::common::BLOCK_SIZE))
+        .checked_rem(u64::from(block_size))
         .expect("block size is 0");
-    if skip_chunks.has_block(session.transfer.block_count) && final_block_size > 0 {
+    if skip_chunks.has_block(block_count) && final_block_size > 0 {
         matching_bytes = matching_bytes
             .wrapping_add(final_block_size)
-            .wrapping_sub(u64::from(crate::common::BLOCK_SIZE));
+            .wrapping_sub(u64::from(block_size));
     }
 
     #[allow(clippy::min_ident_chars)]
-    let s = if block_count == 1 { "" } else { "s" };
-    println!("Resuming previous transfer: found {block_count} matching block{s} ({matching_bytes} bytes)",);
+    let s = if matching_block_count == 1 { "" } else { "s" };
+    println!("Resuming previous transfer: found {matching_block_count} matching block{s} ({matching_bytes} bytes)");
 
     // Store the number of blocks we already have
-    session.transfer.blocks_left = session.transfer.block_count.safe_sub(BlockIndex(
-        block_count.try_into().expect("BlockIndex overflow"),
+    session.transfer.blocks_left = block_count.safe_sub(BlockIndex(
+        matching_block_count
+            .try_into()
+            .expect("BlockIndex overflow"),
     ));
 
-    // Set gapless_to_block and next_block to the first block that we do not have yet
-    let first_missing = skip_chunks
-        .matches
-        .iter()
-        .copied()
-        .enumerate()
-        .find(|(_, present)| !*present)
-        .map_or(session.transfer.block_count, |(i, _)| {
-            BlockIndex(
-                (i as u64)
-                    .checked_mul(remote_checksums.chunk_blocks)
-                    .expect("BlockIndex overflow #1")
-                    .try_into()
-                    .expect("BlockIndex overflow #2"),
-            )
-        });
-    session.transfer.gapless_to_block = first_missing;
-    session.transfer.next_block = first_missing;
+    // Set gapless_to_block and next_block to the last block before the first one that we do not
+    // have yet
+    let gapless_to_block = skip_chunks.first_missing().safe_sub(BlockIndex(1));
+    session.transfer.gapless_to_block = gapless_to_block;
+    session.transfer.next_block = gapless_to_block;
 
     // Store the exact indices of blocks we already have
-    for (i, _) in skip_chunks
-        .matches
-        .iter()
-        .copied()
-        .enumerate()
-        .filter(|(_, present)| *present)
-    {
-        let block_index_low = (i as u64)
-            .checked_mul(remote_checksums.chunk_blocks)
-            .expect("block_index_low overflow");
-
-        if block_index_low > u64::from(session.transfer.block_count.0) {
-            break;
+    for range in skip_chunks.ranges() {
+        for block_index_num in range.first.0..=range.last.0 {
+            session.transfer.received.set(BlockIndex(block_index_num));
         }
+    }
+
+    // Let the server know which blocks it can skip
+    for batch in skip_chunks
+        .ranges()
+        .chunks(crate::common::MAX_SKIP_RANGES_PER_MESSAGE)
+    {
+        session
+            .server
+            .write(ClientToServer::SkipBlocks(batch.to_vec()))?;
+    }
+    session.server.write(ClientToServer::ResumeComplete)?;
 
-        let block_index_high = block_index_low
-            .checked_add(remote_checksums.chunk_blocks)
-            .and_then(|res| res.checked_sub(1)) // inclusive
-            .expect("block_index_high overflow")
-            .min(u64::from(session.transfer.block_count.0));
+    Ok(())
+}
 
-        for block_index_num in block_index_low..=block_index_high {
-            let block_index = BlockIndex(block_index_num.try_into().expect("BlockIndex overflow"));
-            session.transfer.received.set(block_index);
-        }
+/// Verifies the integrity of the file that has just been received, by comparing the digests
+/// calculated by the sending side with those of the data we have written. The result is reported
+/// back to the sending side. If verification fails, the byte ranges that do not match are stored
+/// in the transfer, so they can be fetched again when the transfer is resumed.
+///
+/// # Errors
+/// Returns an error on I/O failure, or if the sending side sends unexpected data.
+///
+/// # Panics
+/// Panics if no local path is set in the transfer object.
+pub fn verify(session: &mut Session) -> anyhow::Result<bool> {
+    session.server.write(ClientToServer::DigestRequest)?;
+    let ServerToClient::Digests(remote_digests) = session.server.read()? else {
+        bail!("Expected digests");
+    };
+    if remote_digests.chunk_size == 0 {
+        bail!("Received digests with a chunk size of 0");
     }
 
+    let local_path = session
+        .transfer
+        .local_filename
+        .as_ref()
+        .expect("there should be a local path");
+    let mut file = if let Some(offset) = session.transfer.local_offset {
+        let file = std::fs::File::open(local_path)?;
+        Stream::Region(Region::new(file, offset, session.transfer.file_size.0))
+    } else if session.transfer.batch_filenames.is_empty() {
+        Stream::File(std::fs::File::open(local_path)?)
+    } else {
+        Stream::Batch(Batch::open(&session.transfer.batch_filenames)?)
+    };
+    let local_digests = crate::common::calculate_digests(
+        &mut file,
+        session.transfer.file_size,
+        remote_digests.chunk_size,
+    )?;
+
+    let invalid_ranges =
+        remote_digests.mismatched_ranges(&local_digests, session.transfer.file_size);
+    let verified = invalid_ranges.is_empty();
     session
         .server
-        .write(ClientToServer::SkipChunks(skip_chunks))?;
+        .write(ClientToServer::VerificationResult(verified))?;
 
-    Ok(())
+    if verified {
+        println!("File integrity verified.");
+    } else {
+        let invalid_bytes: u64 = invalid_ranges
+            .iter()
+            .map(|range| range.end.saturating_sub(range.start))
+            .sum();
+        println!(
+            "WARNING: File '{}' failed integrity verification: {} chunk(s) ({invalid_bytes} bytes) do not match the source.",
+            local_path.display(),
+            invalid_ranges.len()
+        );
+    }
+
+    session.transfer.invalid_ranges = invalid_ranges;
+    Ok(verified)
 }
 
 /// Tries to repeat all of the outstanding retransmit requests for the current transfer on the
@@ -461,22 +1075,18 @@ pub fn repeat_retransmit(session: &mut Session) -> anyhow::Result<()> {
     // in this function as a temporary buffer.
 
     // Discard received blocks from the list, by iterating over the `previous_table` and inserting
-    // all blocks we don't yet have into a pristine `next_table`.
+    // all blocks we don't yet have into a pristine `next_table`, which is then sorted, so that
+    // the missing blocks can be requested as ranges.
     session.transfer.retransmit.next_table.clear();
     for block in &session.transfer.retransmit.previous_table {
-        if session.transfer.retransmit.next_table.len()
-            >= Retransmit::MAX_RETRANSMISSION_BUFFER as usize
-        {
-            break;
-        }
-
         if !block.is_zero() && !session.got_block(*block) {
             session.transfer.retransmit.next_table.push(*block);
         }
     }
+    session.transfer.retransmit.next_table.sort_unstable();
+    session.transfer.retransmit.next_table.dedup();
 
-    // How many blocks were left over after filtering. If this is MAX_RETRANSMISSION_BUFFER
-    // (or more) then we need to restart the transfer entirely.
+    // How many blocks were left over after filtering
     let next_table_len = session.transfer.retransmit.next_table.len();
     let count = BlockIndex(
         next_table_len
@@ -484,72 +1094,111 @@ pub fn repeat_retransmit(session: &mut Session) -> anyhow::Result<()> {
             .expect("retransmit count overflow"),
     );
 
-    // if there are too many entries, restart transfer from earlier point
-    if next_table_len >= Retransmit::MAX_RETRANSMISSION_BUFFER as usize {
-        // restart from first missing block
-        let block = BlockIndex::min(
-            session.transfer.block_count,
-            session.transfer.gapless_to_block.safe_add(BlockIndex(1)),
-        );
-
-        // send out the request
-        session
-            .server
-            .write(TransmissionControl::RestartAt(block))?;
+    let request = retransmit_request(
+        &session.transfer.retransmit.next_table,
+        session.transfer.retransmit.previous_table.len() > MAX_RETRANSMIT_TABLE_LENGTH,
+    );
+    match request {
+        RetransmitRequest::Restart { last_missing } => {
+            // restart from first missing block
+            let block = BlockIndex::min(
+                session.transfer.block_count,
+                session.transfer.gapless_to_block.safe_add(BlockIndex(1)),
+            );
 
-        // remember the request so we can then ignore blocks that are still on the wire
-        session.transfer.restart_pending = true;
-        session.transfer.restart_lastidx = session
-            .transfer
-            .retransmit
-            .previous_table
-            .last()
-            .copied()
-            .unwrap_or(BlockIndex(0));
-        session.transfer.restart_wireclearidx = BlockIndex::min(
-            session.transfer.block_count,
+            // send out the request
             session
-                .transfer
-                .restart_lastidx
-                .safe_add(session.transfer.on_wire_estimate),
-        );
-
-        // reset the retransmission table and head block
-        session.transfer.retransmit.previous_table.clear();
-        session.transfer.retransmit.next_table.clear();
-        session.transfer.next_block = block;
-        session.transfer.stats.this_retransmits = BlockIndex(Retransmit::MAX_RETRANSMISSION_BUFFER);
-    } else {
-        // update statistics
-        session.transfer.stats.this_retransmits = count;
-        session.transfer.stats.total_retransmits =
-            session.transfer.stats.total_retransmits.safe_add(count);
-
-        // send out the requests
-        if next_table_len > 0 {
-            let mut retransmits = Vec::with_capacity(session.transfer.retransmit.next_table.len());
-            for block_index in &session.transfer.retransmit.next_table {
-                retransmits.push(TransmissionControl::Retransmit(*block_index));
-            }
+                .server
+                .write(TransmissionControl::RestartAt(block))?;
+
+            // remember the request so we can then ignore blocks that are still on the wire
+            session.transfer.restart_pending = true;
+            session.transfer.restart_lastidx = last_missing;
+            session.transfer.restart_wireclearidx = BlockIndex::min(
+                session.transfer.block_count,
+                last_missing.safe_add(session.transfer.on_wire_estimate),
+            );
 
-            for retransmit in retransmits {
-                session.server.write(retransmit)?;
+            // reset the retransmission table and head block
+            session.transfer.retransmit.previous_table.clear();
+            session.transfer.retransmit.next_table.clear();
+            session.transfer.next_block = block;
+            session.transfer.stats.this_retransmits =
+                BlockIndex(Retransmit::MAX_RETRANSMISSION_BUFFER);
+        }
+        RetransmitRequest::Ranges(ranges) => {
+            // update statistics
+            session.transfer.stats.this_retransmits = count;
+            session.transfer.stats.total_retransmits =
+                session.transfer.stats.total_retransmits.safe_add(count);
+
+            // send out the requests, the ranges following their count
+            if !ranges.is_empty() {
+                session.server.write(TransmissionControl::RetransmitRanges(
+                    ranges.len().try_into()?,
+                ))?;
+                session.server.write(ranges)?;
+
+                // let the server know that we're done sending out retransmits
+                session
+                    .server
+                    .write(TransmissionControl::RetransmitOver(0))?;
             }
 
-            // let the server know that we're done sending out retransmits
-            session
-                .server
-                .write(TransmissionControl::RetransmitOver(0))?;
+            // clear the previous table which has now become invalid, and swap it for the next
+            // table
+            session.transfer.retransmit.previous_table.clear();
+            session.transfer.retransmit.swap_tables();
         }
-
-        // clear the previous table which has now become invalid, and swap it for the next table
-        session.transfer.retransmit.previous_table.clear();
-        session.transfer.retransmit.swap_tables();
     }
     session.server.flush()?;
     Ok(())
 }
 
+/// How the missing blocks of a transfer are requested from the server.
+#[derive(Debug, PartialEq, Eq)]
+enum RetransmitRequest {
+    /// Retransmit the given ranges of blocks.
+    Ranges(Vec<BlockRange>),
+
+    /// Restart the transfer from the first missing block, as too many blocks are missing to
+    /// request them as ranges. Blocks up to `last_missing`, the highest block known to be missing,
+    /// may still be on the wire.
+    Restart { last_missing: BlockIndex },
+}
+
+/// Decides how the given sorted missing blocks are requested. If they form too many ranges, or the
+/// retransmit table has `overflowed`, so that some missing blocks have not been recorded, the
+/// transfer needs to be restarted entirely.
+fn retransmit_request(missing: &[BlockIndex], overflowed: bool) -> RetransmitRequest {
+    let ranges = block_ranges(missing);
+    if ranges.len() > Retransmit::MAX_RANGES || overflowed {
+        RetransmitRequest::Restart {
+            last_missing: missing.last().copied().unwrap_or(BlockIndex(0)),
+        }
+    } else {
+        RetransmitRequest::Ranges(ranges)
+    }
+}
+
+/// Combines the given sorted blocks into ranges of consecutive blocks. Duplicate blocks are
+/// merged into the same range.
+fn block_ranges(blocks: &[BlockIndex]) -> Vec<BlockRange> {
+    let mut ranges: Vec<BlockRange> = vec![];
+    for block in blocks {
+        match ranges.last_mut() {
+            Some(range) if *block <= range.last.safe_add(BlockIndex(1)) => {
+                range.last = range.last.max(*block);
+            }
+            _ => ranges.push(BlockRange {
+                first: *block,
+                last: *block,
+            }),
+        }
+    }
+    ranges
+}
+
 const MAX_RETRANSMIT_TABLE_LENGTH: usize = 32 * Retransmit::MAX_RETRANSMISSION_BUFFER as usize;
 
 /// Requests a retransmission of the given block in the current transfer.
@@ -568,6 +1217,147 @@ pub fn request_retransmit(session: &mut Session, block: BlockIndex) {
     session.transfer.retransmit.previous_table.push(block);
 }
 
+/// Lets the server know that all blocks up to the end of the gapless section have been received,
+/// so that it can move the window of streamed data it keeps for retransmissions forward.
+///
+/// # Errors
+/// Returns an error on I/O failure.
+pub fn acknowledge(session: &mut Session) -> anyhow::Result<()> {
+    session.server.write(TransmissionControl::Acknowledge(
+        session.transfer.gapless_to_block,
+    ))?;
+    Ok(())
+}
+
+/// Applies the messages the server has sent during the current transfer, if any have arrived:
+/// reports about the growth of a followed file, so that the blocks appended to it are received as
+/// well, and replies to our pings.
+///
+/// # Errors
+/// Returns an error on I/O failure, or if the server sent unexpected data.
+pub fn handle_server_messages(
+    session: &mut Session,
+    parameter: &get::Parameter,
+) -> anyhow::Result<()> {
+    while session.server.has_pending_data()? {
+        let message = session.server.read()?;
+        apply_server_message(session, parameter, &message, true)?;
+    }
+
+    Ok(())
+}
+
+/// Waits for the replies to the pings that the server has not replied to yet, once the transfer
+/// has ended, so that they are not mistaken for replies to later requests. Gives up after a few
+/// round trip times, but at least `PING_REPLY_TIMEOUT_SECS`, as the connection cannot be used for
+/// further requests then.
+///
+/// # Errors
+/// Returns an error on I/O failure, if the server sent unexpected data, or if it has not replied
+/// in time.
+///
+/// # Panics
+/// Panics on arithmetic overflow.
+pub fn finish_pings(session: &mut Session, parameter: &get::Parameter) -> anyhow::Result<()> {
+    let timeout = session
+        .transfer
+        .rtt
+        .timeout()
+        .unwrap_or_default()
+        .saturating_mul(4)
+        .max(Duration::from_secs(super::config::PING_REPLY_TIMEOUT_SECS));
+    let deadline = Instant::now()
+        .checked_add(timeout)
+        .expect("ping deadline overflow");
+
+    let result = receive_ping_replies(session, parameter, deadline);
+    session.server.socket.set_read_timeout(None)?;
+    result
+}
+
+/// Applies the messages the server sends until it has replied to all our pings, or until the
+/// given deadline has passed.
+fn receive_ping_replies(
+    session: &mut Session,
+    parameter: &get::Parameter,
+    deadline: Instant,
+) -> anyhow::Result<()> {
+    while session.transfer.pings_outstanding > 0 {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            bail!(
+                "The server has not replied to {} pings in time",
+                session.transfer.pings_outstanding
+            );
+        }
+        session.server.socket.set_read_timeout(Some(remaining))?;
+        let message = session.server.read()?;
+        apply_server_message(session, parameter, &message, false)?;
+    }
+
+    Ok(())
+}
+
+/// Applies a message the server has sent during the current transfer. The server's timestamp in
+/// a reply to a ping is only echoed if `echo` is set, i.e. while the server still accepts
+/// transmission control requests.
+fn apply_server_message(
+    session: &mut Session,
+    parameter: &get::Parameter,
+    message: &ServerToClient,
+    echo: bool,
+) -> anyhow::Result<()> {
+    match *message {
+        ServerToClient::FileGrown {
+            file_size,
+            block_count,
+            complete,
+        } => {
+            session.transfer.blocks_left = session
+                .transfer
+                .blocks_left
+                .safe_add(block_count.safe_sub(session.transfer.block_count));
+            session.transfer.file_size = file_size;
+            session.transfer.block_count = block_count;
+            session.transfer.following = !complete;
+        }
+        ServerToClient::Pong {
+            echo: our_stamp,
+            stamp,
+        } => {
+            session.transfer.pings_outstanding =
+                session.transfer.pings_outstanding.saturating_sub(1);
+            session.transfer.rtt.update(our_stamp);
+            update_on_wire_estimate(session, parameter);
+            if echo {
+                session.server.write(TransmissionControl::Pong(stamp))?;
+            }
+        }
+        _ => bail!("Expected `FileGrown` or `Pong`"),
+    }
+
+    Ok(())
+}
+
+/// Sends a ping to the server, whose reply lets us measure the round trip time.
+///
+/// # Errors
+/// Returns an error on I/O failure.
+///
+/// # Panics
+/// Panics on arithmetic overflow.
+pub fn ping(session: &mut Session) -> anyhow::Result<()> {
+    session
+        .server
+        .write(TransmissionControl::Ping(session.transfer.rtt.stamp()))?;
+    session.transfer.pings_outstanding = session
+        .transfer
+        .pings_outstanding
+        .checked_add(1)
+        .expect("ping count overflow");
+    Ok(())
+}
+
 /// Requests that the server stop transmitting data for the current file transfer in the given
 /// session. This is done by sending a transmission control request with a type of
 /// `EndTransmission`.
@@ -626,8 +1416,8 @@ pub fn update_stats(
 
     // find the amount of data transferred (bytes)
     let data_total =
-        f64::from(crate::common::BLOCK_SIZE) * f64::from(session.transfer.stats.total_blocks.0);
-    let data_this = f64::from(crate::common::BLOCK_SIZE)
+        f64::from(session.transfer.block_size) * f64::from(session.transfer.stats.total_blocks.0);
+    let data_this = f64::from(session.transfer.block_size)
         * f64::from(
             (session
                 .transfer
@@ -636,17 +1426,21 @@ pub fn update_stats(
                 .safe_sub(session.transfer.stats.this_blocks))
             .0,
         );
-    let data_this_rexmit = f64::from(crate::common::BLOCK_SIZE)
+    let data_this_rexmit = f64::from(session.transfer.block_size)
         * f64::from(session.transfer.stats.this_flow_retransmitteds.0);
 
     // update the UDP receive error count reported by the operating system
     session.transfer.stats.udp_errors.update();
 
-    // precalculate some fractions
-    let retransmits_fraction = f64::from(session.transfer.stats.this_retransmits.0)
-        / (1.0_f64
-            + f64::from(session.transfer.stats.this_retransmits.0)
-            + f64::from(session.transfer.stats.total_blocks.0)
+    // precalculate some fractions. Blocks that have been reconstructed using forward error
+    // correction have been lost as well, so they count like retransmissions.
+    let lost_blocks = session
+        .transfer
+        .stats
+        .this_retransmits
+        .safe_add(session.transfer.stats.this_recovered);
+    let retransmits_fraction = f64::from(lost_blocks.0)
+        / (1.0_f64 + f64::from(lost_blocks.0) + f64::from(session.transfer.stats.total_blocks.0)
             - f64::from(session.transfer.stats.this_blocks.0));
     #[allow(clippy::cast_precision_loss)]
     let ringfill_fraction = f64::from(
@@ -698,6 +1492,15 @@ pub fn update_stats(
             session.transfer.stats.error_rate as u32,
         )))?;
 
+    // as well as the rate at which data has arrived, in kbit/s
+    #[allow(clippy::cast_sign_loss)]
+    #[allow(clippy::cast_possible_truncation)]
+    session
+        .server
+        .write(TransmissionControl::SubmitDeliveryRate(
+            (session.transfer.stats.this_transmit_rate * 1000.0) as u32,
+        ))?;
+
     // build the stats string
     let stats_flags = format!(
         "{}{}",
@@ -710,7 +1513,7 @@ pub fn update_stats(
             .transfer
             .ring_buffer
             .as_ref()
-            .map_or(false, |ring| ring.is_full())
+            .is_some_and(|ring| ring.is_full())
         {
             'F' as i32
         } else {
@@ -718,7 +1521,7 @@ pub fn update_stats(
         },
     );
     let stats_line = format!(
-        "{:02}:{:02}:{:02}.{:03} {:4} {:6.2}M {:6.1}Mbps {:5.1}% {:7} {:6.1}G {:6.1}Mbps {:5.1}% {:5} {:5} {:7} {:8} {:8} {}\n",
+        "{:02}:{:02}:{:02}.{:03} {:4} {:6.2}M {:6.1}Mbps {:5.1}% {:7} {:6.1}G {:6.1}Mbps {:5.1}% {:5} {:5} {:7} {:8} {:8} {} {}\n",
         hours,
         minutes,
         seconds,
@@ -737,6 +1540,7 @@ pub fn update_stats(
         session.transfer.blocks_left.0,
         session.transfer.stats.this_retransmits.0,
         session.transfer.stats.udp_errors,
+        session.transfer.rtt,
         stats_flags,
     );
 
@@ -745,7 +1549,7 @@ pub fn update_stats(
         if parameter.output_mode == OutputMode::Screen {
             print!("\x1B[2J\x1B[H");
             println!("Current time:   {}", 0); // TODO
-            println!("Elapsed time:   {hours:02}:{minutes:02}:{seconds:02}.{milliseconds:03}",);
+            println!("Elapsed time:   {hours:02}:{minutes:02}:{seconds:02}.{milliseconds:03}");
             println!();
             println!("Last interval");
             println!("--------------------------------------------------");
@@ -775,8 +1579,8 @@ pub fn update_stats(
                 "Blocks count:     {}",
                 session.transfer.stats.total_blocks.0,
             );
-            println!("Data transferred: {:02} GB", data_total / u_giga,);
-            println!("Transfer rate:    {data_total_rate:02} Mbps",);
+            println!("Data transferred: {:02} GB", data_total / u_giga);
+            println!("Transfer rate:    {data_total_rate:02} Mbps");
             println!(
                 "Retransmissions:  {} ({:02}%)",
                 session.transfer.stats.total_retransmits.0,
@@ -785,16 +1589,17 @@ pub fn update_stats(
             println!("Flags          :  {stats_flags}");
             println!();
             println!("OS UDP rx errors: {}", session.transfer.stats.udp_errors);
+            println!("RTT, variance:    {} ms", session.transfer.rtt);
         } else {
             // print a header if necessary
             // TODO: Tsunami has a STATS_NOHEADER compile-time constant that is checked here.
             // It might be worth implementing this as a runtime flag
-            if *iteration % 23 == 0 {
+            if iteration.is_multiple_of(23) {
                 println!(
-                    "             last_interval                   transfer_total                   buffers      transfer_remaining  OS UDP"
+                    "             last_interval                   transfer_total                   buffers      transfer_remaining  OS UDP     RTT (ms)"
                 );
                 println!(
-                    "time          blk    data       rate rexmit     blk    data       rate rexmit queue  ring     blk   rt_len      err "
+                    "time          blk    data       rate rexmit     blk    data       rate rexmit queue  ring     blk   rt_len      err    srtt rttvar"
                 );
             }
             *iteration = iteration.wrapping_add(1);
@@ -817,9 +1622,91 @@ pub fn update_stats(
     // reset the statistics for the next interval
     session.transfer.stats.this_blocks = session.transfer.stats.total_blocks;
     session.transfer.stats.this_retransmits = BlockIndex(0);
+    session.transfer.stats.this_recovered = BlockIndex(0);
     session.transfer.stats.this_flow_originals = BlockIndex(0);
     session.transfer.stats.this_flow_retransmitteds = BlockIndex(0);
     session.transfer.stats.this_time = Some(Instant::now());
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{block_ranges, retransmit_request, RetransmitRequest};
+    use crate::{
+        client::Retransmit,
+        types::{BlockIndex, BlockRange},
+    };
+
+    fn blocks(blocks: &[u32]) -> Vec<BlockIndex> {
+        blocks.iter().copied().map(BlockIndex).collect()
+    }
+
+    fn range(first: u32, last: u32) -> BlockRange {
+        BlockRange {
+            first: BlockIndex(first),
+            last: BlockIndex(last),
+        }
+    }
+
+    #[test]
+    fn block_ranges_merge_adjacent_blocks() {
+        assert_eq!(block_ranges(&[]), vec![]);
+        assert_eq!(block_ranges(&blocks(&[5])), vec![range(5, 5)]);
+        assert_eq!(
+            block_ranges(&blocks(&[1, 2, 3, 5, 7, 8])),
+            vec![range(1, 3), range(5, 5), range(7, 8)]
+        );
+    }
+
+    #[test]
+    fn block_ranges_merge_duplicate_blocks() {
+        assert_eq!(
+            block_ranges(&blocks(&[1, 1, 2, 2, 4, 4, 4])),
+            vec![range(1, 2), range(4, 4)]
+        );
+    }
+
+    #[test]
+    fn retransmit_request_ranges() {
+        assert_eq!(
+            retransmit_request(&[], false),
+            RetransmitRequest::Ranges(vec![])
+        );
+        assert_eq!(
+            retransmit_request(&blocks(&[3, 4, 9]), false),
+            RetransmitRequest::Ranges(vec![range(3, 4), range(9, 9)])
+        );
+    }
+
+    #[test]
+    fn retransmit_request_restarts_on_too_many_ranges() {
+        // every other block is missing, so that each one is a range of its own
+        let max_ranges = u32::try_from(Retransmit::MAX_RANGES).expect("range count overflow");
+        let missing: Vec<BlockIndex> = (1..=max_ranges).map(|n| BlockIndex(n * 2)).collect();
+        assert!(matches!(
+            retransmit_request(&missing, false),
+            RetransmitRequest::Ranges(ranges) if ranges.len() == Retransmit::MAX_RANGES
+        ));
+
+        let mut missing = missing;
+        missing.push(BlockIndex(max_ranges * 2 + 2));
+        assert_eq!(
+            retransmit_request(&missing, false),
+            RetransmitRequest::Restart {
+                last_missing: BlockIndex(max_ranges * 2 + 2)
+            }
+        );
+    }
+
+    #[test]
+    fn retransmit_request_restarts_on_overflow() {
+        // the highest missing block bounds the blocks that may still be on the wire
+        assert_eq!(
+            retransmit_request(&blocks(&[3, 4, 9]), true),
+            RetransmitRequest::Restart {
+                last_missing: BlockIndex(9)
+            }
+        );
+    }
+}
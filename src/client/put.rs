@@ -0,0 +1,379 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
+
+use anyhow::bail;
+
+use crate::{
+    message::{ClientToServer, FileRequest, ServerToClient, UploadRequest},
+    server,
+    stream::Stream,
+    types::{ChecksumAlgorithm, Compression, ErrorRate, Fraction, TargetRate},
+};
+
+use super::get::{parse_fraction, parse_rate};
+
+#[derive(Clone, clap::Args)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Parameter {
+    /// The server to connect to. May be specified as IP address or hostname. A remote TCP port may
+    /// also be specified using the `host:port` notation. If no port is specified, the default port
+    /// will be used (51038).
+    #[arg(long = "server", short = 's')]
+    pub server: String,
+
+    /// If this flag is present, the client will not encrypt the connection. The same flag must also
+    /// be specified on the server.
+    #[arg(long = "unencrypted", action = clap::ArgAction::SetFalse)]
+    pub encrypted: bool,
+
+    /// specifies the desired size for UDP socket send buffer (in bytes)
+    #[arg(long = "buffer", default_value_t = server::config::DEFAULT_UDP_BUFFER)]
+    pub udp_buffer: u32,
+
+    #[arg(long = "quiet", action = clap::ArgAction::SetFalse)]
+    pub verbose_yn: bool,
+
+    #[arg(long = "transcript")]
+    pub transcript_yn: bool,
+
+    #[arg(long = "rate", value_parser = clap::builder::ValueParser::new(parse_rate), default_value_t = super::config::DEFAULT_TARGET_RATE)]
+    pub target_rate: TargetRate,
+
+    #[arg(long = "error", default_value_t = super::config::DEFAULT_ERROR_RATE)]
+    pub error_rate: ErrorRate,
+
+    #[arg(long = "slower", value_parser = clap::builder::ValueParser::new(parse_fraction), default_value_t = super::config::DEFAULT_SLOWER)]
+    pub slower: Fraction,
+
+    #[arg(long = "faster", value_parser = clap::builder::ValueParser::new(parse_fraction), default_value_t = super::config::DEFAULT_FASTER)]
+    pub faster: Fraction,
+
+    /// The block size (in bytes) to request from the server. Each UDP datagram carries one block,
+    /// so on links with a large MTU, a larger block size reduces the overhead. The server may
+    /// choose a smaller block size if it does not allow the requested one.
+    ///
+    /// If not specified, the largest block size that fits through the path MTU is determined
+    /// automatically by probing. If probing fails, 1024 byte blocks are used.
+    #[arg(long = "blocksize", value_parser = clap::value_parser!(u16).range(i64::from(crate::common::MIN_BLOCK_SIZE)..=i64::from(crate::common::MAX_BLOCK_SIZE)))]
+    pub block_size: Option<u16>,
+
+    /// specifies the timeout in seconds for aborting the upload after the server's heartbeat is
+    /// lost
+    #[arg(long = "hbtimeout", default_value_t = server::config::DEFAULT_HEARTBEAT_TIMEOUT)]
+    pub hb_timeout: u16,
+
+    /// Specifies the path to a file from which the pre-shared key will be loaded.
+    ///
+    /// The pre-shared key should be 32 bytes long. If the file contains more data, only the first
+    /// 32 bytes will be used. If the file does not contain at least 32 bytes, there will be an
+    /// error on startup. If this argument is not specified, a hard-coded key will be used;
+    /// this is not recommended.
+    #[arg(long = "secret")]
+    pub secret_file: Option<PathBuf>,
+
+    /// The path, relative to the server's upload directory, under which the file should be stored.
+    ///
+    /// This will only work if exactly one file is being uploaded, otherwise the command will fail!
+    /// By default, files are stored under their local file name.
+    #[arg(long = "remote")]
+    pub remote_filename: Option<PathBuf>,
+
+    /// Do not resume an existing transfer.
+    ///
+    /// By default, namida will try to resume an existing upload, skipping the transfer of blocks
+    /// that are already present on the server. If this behaviour is not desired, this option can
+    /// be specified to always transfer everything.
+    #[arg(long = "no-resume", action = clap::ArgAction::SetFalse)]
+    pub resume: bool,
+
+    /// The checksum algorithm used to find the parts of a file that are already present on the
+    /// server when resuming an upload.
+    ///
+    /// BLAKE3 is cryptographically strong, so data is only skipped if it really matches. xxh3 is
+    /// faster, but only detects accidental damage.
+    #[arg(long = "checksum", value_enum, default_value_t = ChecksumAlgorithm::Blake3)]
+    pub checksum_algorithm: ChecksumAlgorithm,
+
+    /// Compress blocks on the wire using the given algorithm.
+    ///
+    /// This speeds up the transfer of compressible data (e.g. text, logs or uncompressed images)
+    /// if the link is slower than compression. Blocks that do not become smaller are sent
+    /// uncompressed.
+    #[arg(long = "compress", value_enum, default_value_t = Compression::None)]
+    pub compression: Compression,
+
+    /// Protect the transfer against losses using forward error correction.
+    ///
+    /// A parity block is sent after every group of this many data blocks (16 if no value is
+    /// given), from which a single lost block of the group can be reconstructed without waiting a
+    /// round trip for its retransmission. This is useful on links with a long round trip time. The
+    /// group size is adapted to the losses during the transfer.
+    #[arg(long = "fec", value_name = "BLOCKS", num_args = 0..=1, default_missing_value = "16", value_parser = clap::value_parser!(u8).range(i64::from(crate::fec::MIN_GROUP_SIZE)..=i64::from(crate::fec::MAX_GROUP_SIZE)))]
+    pub fec_group_size: Option<u8>,
+
+    /// The congestion control algorithm, which adjusts the rate at which data is sent to the
+    /// feedback of the server.
+    #[arg(long = "congestion", default_value_t, value_enum)]
+    pub congestion: server::congestion::CongestionControl,
+
+    #[arg(skip = *crate::common::DEFAULT_SECRET)]
+    pub secret: [u8; 32],
+
+    /// The local files to upload to the server.
+    #[arg()]
+    pub files: Vec<PathBuf>,
+}
+
+#[allow(clippy::missing_errors_doc)]
+#[allow(clippy::missing_panics_doc)]
+pub fn run(mut parameter: Parameter) -> anyhow::Result<()> {
+    crate::common::load_secret(&parameter.secret_file, &mut parameter.secret);
+    super::print_intro(parameter.encrypted);
+
+    if parameter.files.is_empty() {
+        bail!("No files are specified. Specify a list of files to be uploaded.");
+    }
+
+    if parameter.files.len() > 1 && parameter.remote_filename.is_some() {
+        bail!("A remote filename can only be specified if only one file is to be uploaded.");
+    }
+
+    // Connect to the server. The connection is established the same way as for downloads; only
+    // afterwards do we take over the role of the sending side.
+    let client_session = super::protocol::connect(
+        &parameter.server,
+        parameter.encrypted,
+        &parameter.secret,
+        false,
+    )?;
+    let mut session = server::Session {
+        transfer: server::Transfer::default(),
+        properties: server::Properties::default(),
+        client: client_session.server,
+        session_id: 0,
+        group: Arc::default(),
+        failed_upload: None,
+    };
+    let ipv6 = session.client.socket.peer_addr()?.is_ipv6();
+    let sender_parameter = sender_parameter(&parameter, ipv6);
+
+    for local_filename in &parameter.files {
+        let remote_filename = match &parameter.remote_filename {
+            Some(remote_filename) => remote_filename.clone(),
+            None => remote_filename_for(local_filename)?,
+        };
+
+        let mut retries = 0;
+        loop {
+            // When retrying an upload that failed verification, always resume it, so that only
+            // the mismatching chunks are transmitted again.
+            let resume = parameter.resume || retries > 0;
+            match upload_file(
+                &mut session,
+                &parameter,
+                &sender_parameter,
+                local_filename,
+                remote_filename.clone(),
+                resume,
+            ) {
+                Ok(true) => break,
+                Ok(false) if retries < super::config::MAX_VERIFICATION_RETRIES => {
+                    retries = retries.checked_add(1).expect("retries overflow");
+                    println!("Server reported that the upload failed verification, retrying.");
+                }
+                Ok(false) => {
+                    eprintln!("Transfer not successful.");
+                    eprintln!();
+                    bail!(
+                        "Upload of '{}' still failed integrity verification after {retries} retries",
+                        local_filename.display()
+                    );
+                }
+                Err(err) => {
+                    eprintln!("Transfer not successful.");
+                    eprintln!();
+                    bail!("Upload of '{}' failed: {err}", local_filename.display());
+                }
+            }
+        }
+    }
+
+    eprintln!("All transfers were successful!");
+    session.client.write(ClientToServer::Close)?;
+
+    Ok(())
+}
+
+/// Uploads a single local file to the given remote path, acting as the sending side of the
+/// transfer. Returns whether the server could verify the integrity of the uploaded file.
+fn upload_file(
+    session: &mut server::Session,
+    parameter: &Parameter,
+    sender_parameter: &server::Parameter,
+    local_filename: &Path,
+    remote_filename: PathBuf,
+    resume: bool,
+) -> anyhow::Result<bool> {
+    session.transfer = server::Transfer::default();
+
+    // open the local file, and determine its size
+    let file = match std::fs::File::open(local_filename) {
+        Ok(file) => file,
+        Err(err) => bail!(
+            "File '{}' does not exist or cannot be read: {err}",
+            local_filename.display()
+        ),
+    };
+    let mtime = crate::common::file_mtime(&file);
+    let sample_digest = crate::common::sample_digest(&file)?;
+    session.transfer.file = Some(Stream::File(file));
+    session.transfer.filename = Some(local_filename.to_path_buf());
+    server::protocol::determine_file_size(session)?;
+
+    // store the transfer properties that the server will use when controlling the transmission
+    session.properties.target_rate = parameter.target_rate;
+    session.properties.error_rate = parameter.error_rate;
+    session.properties.slower = parameter.slower;
+    session.properties.faster = parameter.faster;
+    session.properties.checksum_algorithm = parameter.checksum_algorithm;
+    session.transfer.compressor = Some(crate::compression::Compressor::new(parameter.compression)?);
+    session.transfer.fec = parameter.fec_group_size.map(crate::fec::Encoder::new);
+
+    if parameter.verbose_yn {
+        println!(
+            "Uploading '{}' to '{}' ({} bytes)",
+            local_filename.display(),
+            remote_filename.display(),
+            session.properties.file_size.0
+        );
+    }
+
+    // submit the upload request, using the opportunity to measure the round trip time
+    let ping_start = Instant::now();
+    session
+        .client
+        .write(ClientToServer::UploadRequest(UploadRequest {
+            request: FileRequest {
+                path: remote_filename,
+                target_rate: parameter.target_rate,
+                error_rate: parameter.error_rate,
+                slowdown: parameter.slower,
+                speedup: parameter.faster,
+                block_size: parameter.block_size,
+                checksum_algorithm: parameter.checksum_algorithm,
+                compression: parameter.compression,
+                fec_group_size: parameter.fec_group_size,
+                follow_idle: None,
+                offset: None,
+                length: None,
+            },
+            file_size: session.properties.file_size,
+            resume,
+            mtime,
+            sample_digest: Some(sample_digest),
+        }))?;
+
+    let result = session.client.read()?;
+    let ping_end = Instant::now();
+    let (block_size, udp_port, resume) = match result {
+        ServerToClient::UploadRequestSuccess {
+            block_size,
+            udp_port,
+            resume,
+        } => (block_size, udp_port, resume),
+        ServerToClient::FileRequestError(err) => {
+            bail!("Server: File cannot be uploaded: {:?}", err);
+        }
+        _ => {
+            bail!(
+                "Expected `UploadRequestSuccess` or `FileRequestError` but got: {:?}",
+                result
+            );
+        }
+    };
+
+    // use the block size chosen by the server
+    session.properties.block_size = block_size;
+    session.properties.block_count =
+        crate::common::block_count(session.properties.file_size, block_size);
+
+    // open the UDP socket to send the data from, and send it to the server's address with the port
+    // it told us about
+    session.transfer.udp_socket = Some(server::network::create_udp_socket(sender_parameter)?);
+    let mut udp_address = session.client.socket.peer_addr()?;
+    udp_address.set_port(udp_port);
+    session.transfer.udp_address = Some(udp_address);
+
+    // If we did not request a specific block size, find the largest one that fits through the path
+    // to the server. The block size chosen by the server is the upper bound.
+    if parameter.block_size.is_none() {
+        server::protocol::probe_path_mtu(session, sender_parameter)?;
+    }
+    server::protocol::start_transfer_timing(session, sender_parameter, ping_start, ping_end);
+
+    // If the server wants to resume, send it some checksums, and wait for it to tell us which
+    // blocks we can skip
+    if resume {
+        server::protocol::resume(session)?;
+    }
+
+    server::main::transmit(session, sender_parameter)?;
+
+    // make the control connection blocking again for the next request
+    session.client.socket.set_nonblocking(false)?;
+
+    // the server verifies the uploaded file by requesting our digests
+    let ClientToServer::DigestRequest = session.client.read()? else {
+        bail!("Expected digest request");
+    };
+    server::protocol::send_digests(session)
+}
+
+/// Determines the path under which a local file is stored on the server if no explicit remote
+/// filename has been given, which is simply its file name.
+fn remote_filename_for(local_filename: &Path) -> anyhow::Result<PathBuf> {
+    match local_filename.file_name() {
+        Some(file_name) => Ok(PathBuf::from(file_name)),
+        None => bail!(
+            "Could not determine a remote filename for '{}'",
+            local_filename.display()
+        ),
+    }
+}
+
+/// Builds the parameters for sending a file, based on the parameters given on the command line.
+/// The UDP socket will use the same IP version as the control connection.
+fn sender_parameter(parameter: &Parameter, ipv6: bool) -> server::Parameter {
+    let catch_all_host = crate::common::catch_all_host(ipv6);
+
+    server::Parameter {
+        verbose_yn: parameter.verbose_yn,
+        transcript_yn: parameter.transcript_yn,
+        bind: SocketAddr::new(catch_all_host, 0).to_string(),
+        encrypted: parameter.encrypted,
+        index: server::IndexMode::Never,
+        udp_buffer: parameter.udp_buffer,
+        max_block_size: crate::common::MAX_BLOCK_SIZE,
+        compression: true,
+        max_rate: None,
+        session_rate: None,
+        congestion: parameter.congestion,
+        max_sessions: 1,
+        max_session_connections: 1,
+        session_queue: 0,
+        max_per_ip: None,
+        retry_after: server::config::DEFAULT_RETRY_AFTER,
+        hb_timeout: parameter.hb_timeout,
+        secret_file: None,
+        client: None,
+        finishhook: None,
+        upload_dir: None,
+        stdin_name: None,
+        file_names: vec![],
+        secret: parameter.secret,
+    }
+}
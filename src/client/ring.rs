@@ -1,11 +1,15 @@
 use std::sync::{Condvar, Mutex};
 
-use crate::{datagram, types::BlockIndex};
+use crate::{
+    datagram,
+    types::{BlockIndex, Compression},
+};
 
 pub const MAX_BLOCKS_QUEUED: u32 = 4096;
 
 #[derive(Debug)]
 pub struct Buffer {
+    block_size: u32,
     mutex: Mutex<Internal>,
     data_ready_cond: Condvar,
     space_ready_cond: Condvar,
@@ -13,8 +17,12 @@ pub struct Buffer {
 
 #[derive(Debug)]
 struct Internal {
-    headers: Box<[datagram::Header; MAX_BLOCKS_QUEUED as usize]>,
+    headers: Box<[datagram::Header]>,
     blocks: Box<[u8]>,
+
+    /// The length of the data stored in each slot, which is shorter than the block size for
+    /// compressed blocks
+    lengths: Box<[u16]>,
     base_data: u32,
     count_data: u32,
     count_reserved: u32,
@@ -98,19 +106,22 @@ impl Buffer {
     /// # Panics
     /// Panics if there is an overflow in the amount of data.
     #[must_use]
-    pub fn create() -> Self {
-        let blocks_len = (crate::common::BLOCK_SIZE as usize)
+    pub fn create(block_size: u16) -> Self {
+        let blocks_len = usize::from(block_size)
             .checked_mul(MAX_BLOCKS_QUEUED as usize)
             .expect("ring buffer size overflow");
 
         let zero_header = datagram::Header {
             block_index: BlockIndex(0),
             block_type: datagram::BlockType::Original,
+            compression: Compression::None,
+            group_size: 0,
         };
 
         let internal = Internal {
-            headers: Box::new([zero_header; MAX_BLOCKS_QUEUED as usize]),
+            headers: vec![zero_header; MAX_BLOCKS_QUEUED as usize].into_boxed_slice(),
             blocks: allocate_zeroed_boxed_slice(blocks_len),
+            lengths: vec![block_size; MAX_BLOCKS_QUEUED as usize].into_boxed_slice(),
             base_data: 0,
             count_data: 0,
             count_reserved: 0,
@@ -119,6 +130,7 @@ impl Buffer {
         };
 
         Self {
+            block_size: u32::from(block_size),
             mutex: Mutex::new(internal),
             data_ready_cond: Condvar::new(),
             space_ready_cond: Condvar::new(),
@@ -144,12 +156,13 @@ impl Buffer {
         }
 
         // find the slice we want
-        let first_index = u32::from(crate::common::BLOCK_SIZE)
+        let first_index = self
+            .block_size
             .checked_mul(guard.base_data)
             .expect("first_index overflow") as usize;
-        let last_index = u32::from(crate::common::BLOCK_SIZE)
-            .checked_mul(guard.base_data.checked_add(1).expect("base_data overflow"))
-            .expect("last_index overflow") as usize;
+        let last_index = first_index
+            .checked_add(usize::from(guard.lengths[guard.base_data as usize]))
+            .expect("last_index overflow");
 
         // call the callback with the datagram
         callback(datagram::View {
@@ -188,16 +201,22 @@ impl Buffer {
         self.space_ready_cond.notify_all();
     }
 
-    /// Reserves a slot in the ring buffer, and stores the given datagram in it. This will block if
-    /// no space is available in the ring buffer.
+    /// Reserves a slot in the ring buffer, and stores the given datagram in it. The block of the
+    /// datagram may be shorter than the block size if it is compressed. This will block if no space
+    /// is available in the ring buffer.
     ///
     /// # Panics
     /// Panics if there is an attempt to reserve two slots at once, if the mutex is poisoned, or
     /// if there is an arithmetic overflow.
     pub fn reserve(&self, datagram: datagram::View) {
-        self.reserve_internal(|header, block| {
+        self.reserve_internal(|header, block, length| {
             *header = datagram.header;
-            block.copy_from_slice(datagram.block);
+            block[..datagram.block.len()].copy_from_slice(datagram.block);
+            *length = datagram
+                .block
+                .len()
+                .try_into()
+                .expect("block length overflow");
         });
     }
 
@@ -208,7 +227,7 @@ impl Buffer {
     /// Panics if there is an attempt to reserve two slots at once, if the mutex is poisoned, or
     /// if there is an arithmetic overflow.
     pub fn reserve_zero(&self) {
-        self.reserve_internal(|header, _block| {
+        self.reserve_internal(|header, _block, _length| {
             header.block_index = BlockIndex(0);
         });
     }
@@ -216,7 +235,7 @@ impl Buffer {
     // The significant_drop_tightening is a false positive; clippy does not recognise that the
     // borrow of the guarded data survives until after the callback returns
     #[allow(clippy::significant_drop_tightening)]
-    fn reserve_internal(&self, callback: impl FnOnce(&mut datagram::Header, &mut [u8])) {
+    fn reserve_internal(&self, callback: impl FnOnce(&mut datagram::Header, &mut [u8], &mut u16)) {
         // get a lock on the ring buffer
         let mut guard = self.mutex.lock().expect("mutex should not be poisoned");
 
@@ -253,10 +272,12 @@ impl Buffer {
         }
 
         // find the slice we want
-        let first_index = u32::from(crate::common::BLOCK_SIZE)
+        let first_index = self
+            .block_size
             .checked_mul(next)
             .expect("first_index overflow") as usize;
-        let last_index = u32::from(crate::common::BLOCK_SIZE)
+        let last_index = self
+            .block_size
             .checked_mul(after_next)
             .expect("last_index overflow") as usize;
         let internal = &mut *guard;
@@ -265,6 +286,7 @@ impl Buffer {
         callback(
             &mut internal.headers[next as usize],
             &mut internal.blocks[first_index..last_index],
+            &mut internal.lengths[next as usize],
         );
     }
 }
@@ -0,0 +1,296 @@
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+
+use crate::{
+    stream::Stream,
+    types::{BlockIndex, BlockRange, FileSize, ReceivedMap},
+};
+
+/// Identifies the version of the remote file that a partial transfer belongs to. Besides the
+/// modification time, which may be preserved when a file is rewritten, the digest of samples of its
+/// data is compared.
+#[derive(Debug, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct FileIdentity {
+    pub remote_path: PathBuf,
+    pub file_size: FileSize,
+    pub mtime: Duration,
+    pub sample_digest: [u8; 32],
+}
+
+/// The contents of a state file, which is stored next to the local file of a partial transfer.
+#[derive(bincode::Encode, bincode::Decode)]
+struct Contents {
+    identity: FileIdentity,
+    block_size: u16,
+    written: Vec<BlockRange>,
+}
+
+/// Keeps track of the blocks of a transfer that have been written to disk, and periodically
+/// persists them to the state file, so that an interrupted transfer can be resumed without having
+/// to compare checksums of the whole file.
+pub struct State {
+    path: PathBuf,
+    identity: FileIdentity,
+    block_size: u16,
+    block_count: BlockIndex,
+    written: ReceivedMap,
+    last_save: Instant,
+    save_interval: Duration,
+}
+
+impl State {
+    /// Creates a new state for the transfer of the remote file with the given identity into the
+    /// given local file. `written` contains the blocks that are already present locally. The state
+    /// is saved at most once per `save_interval`.
+    #[must_use]
+    pub fn new(
+        local_filename: &Path,
+        identity: FileIdentity,
+        block_size: u16,
+        block_count: BlockIndex,
+        written: ReceivedMap,
+        save_interval: Duration,
+    ) -> Self {
+        Self {
+            path: path_for(local_filename),
+            identity,
+            block_size,
+            block_count,
+            written,
+            last_save: Instant::now(),
+            save_interval,
+        }
+    }
+
+    /// Records that the given block has been written to disk.
+    pub fn set(&mut self, block_index: BlockIndex) {
+        self.written.set(block_index);
+    }
+
+    /// Saves the state if the last save is long enough ago.
+    ///
+    /// # Errors
+    /// Returns an error on I/O failure.
+    pub fn save_if_due(&mut self, file: &Stream) -> anyhow::Result<()> {
+        if self.last_save.elapsed() < self.save_interval {
+            return Ok(());
+        }
+
+        self.save(file)
+    }
+
+    /// Saves the state. The given local file is synced first, so that the saved state never claims
+    /// blocks that are not actually stored on disk yet. This blocks the disk thread until all data
+    /// written so far has reached the disk.
+    ///
+    /// The state file is preceded by its BLAKE3 digest, so that a damaged state file is not used.
+    /// It is written to a temporary file first, which then replaces the previous state file.
+    ///
+    /// # Errors
+    /// Returns an error on I/O failure.
+    pub fn save(&mut self, file: &Stream) -> anyhow::Result<()> {
+        file.sync_data()?;
+
+        let contents = Contents {
+            identity: self.identity.clone(),
+            block_size: self.block_size,
+            written: self.written.ranges(self.block_count),
+        };
+        let encoded = bincode::encode_to_vec(contents, crate::common::BINCODE_CONFIG)?;
+
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let mut state_file = File::create(&temp_path)?;
+        state_file.write_all(blake3::hash(&encoded).as_bytes())?;
+        state_file.write_all(&encoded)?;
+        state_file.sync_data()?;
+        std::fs::rename(&temp_path, &self.path)?;
+
+        self.last_save = Instant::now();
+        Ok(())
+    }
+}
+
+/// Returns the path of the state file belonging to the given local file.
+#[must_use]
+pub fn path_for(local_filename: &Path) -> PathBuf {
+    let mut path = local_filename.to_path_buf().into_os_string();
+    path.push(super::config::STATE_FILE_SUFFIX);
+    PathBuf::from(path)
+}
+
+/// Loads the state of a previous transfer into the given local file. Returns the ranges of blocks
+/// that are already present locally, converted to the given block size, or `None` if there is no
+/// state file, or if it belongs to a different version of the remote file.
+///
+/// # Errors
+/// Returns an error on I/O failure, or if the state file is damaged.
+///
+/// # Panics
+/// Panics on arithmetic overflow.
+pub fn load(
+    local_filename: &Path,
+    identity: &FileIdentity,
+    block_size: u16,
+    block_count: BlockIndex,
+) -> anyhow::Result<Option<Vec<BlockRange>>> {
+    let data = match std::fs::read(path_for(local_filename)) {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    let Some((digest, encoded)) = data.split_first_chunk::<32>() else {
+        bail!("State file is too short");
+    };
+    if blake3::hash(encoded).as_bytes() != digest {
+        bail!("State file digest does not match");
+    }
+    let (contents, _): (Contents, usize) =
+        bincode::decode_from_slice(encoded, crate::common::BINCODE_CONFIG)?;
+
+    if contents.identity != *identity || contents.block_size == 0 {
+        return Ok(None);
+    }
+    if contents.block_size == block_size {
+        return Ok(Some(contents.written));
+    }
+
+    // The block size has changed since the state was saved, so convert the ranges into ranges of
+    // blocks with the new size. Only blocks that are covered completely are present.
+    let old_block_size = u64::from(contents.block_size);
+    let ranges = contents
+        .written
+        .iter()
+        .filter_map(|range| {
+            let start = u64::from(range.first.0.checked_sub(1)?).checked_mul(old_block_size)?;
+            let end = u64::from(range.last.0).checked_mul(old_block_size)?;
+            BlockRange::within_bytes(start, end, block_size, identity.file_size, block_count)
+        })
+        .collect();
+
+    Ok(Some(ranges))
+}
+
+/// Removes the state file belonging to the given local file, if it exists.
+///
+/// # Errors
+/// Returns an error on I/O failure.
+pub fn remove(local_filename: &Path) -> anyhow::Result<()> {
+    match std::fs::remove_file(path_for(local_filename)) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK_SIZE: u16 = 100;
+    const BLOCK_COUNT: BlockIndex = BlockIndex(10);
+
+    fn identity() -> FileIdentity {
+        FileIdentity {
+            remote_path: PathBuf::from("remote/file.bin"),
+            file_size: FileSize(1000),
+            mtime: Duration::from_secs(1_700_000_000),
+            sample_digest: [7; 32],
+        }
+    }
+
+    /// Saves a state with blocks 1 to 3 and 7 written for a new local file in the given
+    /// directory, and returns the path of the local file.
+    fn save_state(directory: &Path) -> anyhow::Result<PathBuf> {
+        let local_filename = directory.join("file.bin");
+        let file = Stream::File(File::create(&local_filename)?);
+
+        let mut state = State::new(
+            &local_filename,
+            identity(),
+            BLOCK_SIZE,
+            BLOCK_COUNT,
+            ReceivedMap::new(BLOCK_COUNT),
+            Duration::ZERO,
+        );
+        for block in [1, 2, 3, 7] {
+            state.set(BlockIndex(block));
+        }
+        state.save(&file)?;
+        Ok(local_filename)
+    }
+
+    fn range(first: u32, last: u32) -> BlockRange {
+        BlockRange {
+            first: BlockIndex(first),
+            last: BlockIndex(last),
+        }
+    }
+
+    #[test]
+    fn saved_state_is_loaded() -> anyhow::Result<()> {
+        let directory = tempfile::tempdir()?;
+        let local_filename = save_state(directory.path())?;
+
+        let ranges = load(&local_filename, &identity(), BLOCK_SIZE, BLOCK_COUNT)?;
+        assert_eq!(ranges, Some(vec![range(1, 3), range(7, 7)]));
+
+        // with twice the block size, only blocks covered completely by the saved ones are present
+        let ranges = load(&local_filename, &identity(), BLOCK_SIZE * 2, BlockIndex(5))?;
+        assert_eq!(ranges, Some(vec![range(1, 1)]));
+
+        remove(&local_filename)?;
+        assert_eq!(
+            load(&local_filename, &identity(), BLOCK_SIZE, BLOCK_COUNT)?,
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stale_state_is_ignored() -> anyhow::Result<()> {
+        let directory = tempfile::tempdir()?;
+        let local_filename = save_state(directory.path())?;
+
+        let rewritten = FileIdentity {
+            sample_digest: [8; 32],
+            ..identity()
+        };
+        let modified = FileIdentity {
+            mtime: identity().mtime + Duration::from_secs(1),
+            ..identity()
+        };
+        for other in [rewritten, modified] {
+            assert_eq!(
+                load(&local_filename, &other, BLOCK_SIZE, BLOCK_COUNT)?,
+                None
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_state_is_rejected() -> anyhow::Result<()> {
+        let directory = tempfile::tempdir()?;
+        let local_filename = save_state(directory.path())?;
+        let state_path = path_for(&local_filename);
+
+        let mut data = std::fs::read(&state_path)?;
+        let last = data.last_mut().expect("state file is empty");
+        *last ^= 1;
+        std::fs::write(&state_path, &data)?;
+        load(&local_filename, &identity(), BLOCK_SIZE, BLOCK_COUNT)
+            .expect_err("a damaged state file was accepted");
+
+        std::fs::write(&state_path, &data[..16])?;
+        load(&local_filename, &identity(), BLOCK_SIZE, BLOCK_COUNT)
+            .expect_err("a truncated state file was accepted");
+        Ok(())
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    collections::HashMap,
+    hash::BuildHasher,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::bail;
+
+use crate::types::{EntryType, FileMetadata};
+
+/// Checks that the given path from the server's listing lies within the local mirror of the
+/// served tree, i.e. that it is relative and does not lead out of a directory using `..`.
+///
+/// # Errors
+/// Returns an error if the path may lie outside of the local mirror.
+pub fn check_path(path: &Path) -> anyhow::Result<()> {
+    let within = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !within {
+        bail!(
+            "The server listed the path '{}', which lies outside of the local mirror",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Checks that the given target of the symbolic link at the given path, which has been checked
+/// using `check_path`, stays within the local mirror, so that the mirror cannot be used to reach
+/// files outside of it. Absolute targets are refused, as are relative ones leading above the root
+/// of the mirror using `..`.
+///
+/// # Errors
+/// Returns an error if the target may lie outside of the local mirror.
+///
+/// # Panics
+/// Panics on arithmetic overflow.
+pub fn check_link_target(path: &Path, target: &Path) -> anyhow::Result<()> {
+    let mut depth = path.parent().map_or(0, |parent| {
+        parent
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .count()
+    });
+
+    for component in target.components() {
+        let within = match component {
+            Component::Normal(_) => {
+                depth = depth.checked_add(1).expect("path depth overflow");
+                true
+            }
+            Component::CurDir => true,
+            Component::ParentDir => depth.checked_sub(1).map(|parent| depth = parent).is_some(),
+            Component::RootDir | Component::Prefix(_) => false,
+        };
+        if !within {
+            bail!(
+                "The symbolic link points to '{}', which lies outside of the local mirror",
+                target.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Checks all paths of the given server listing using `check_path`.
+///
+/// # Errors
+/// Returns an error if any of the paths may lie outside of the local mirror.
+pub fn check_listing<S: BuildHasher>(
+    listing: &HashMap<PathBuf, FileMetadata, S>,
+) -> anyhow::Result<()> {
+    listing.keys().try_for_each(|path| check_path(path))
+}
+
+/// Returns whether the local copy of the given remote file is up to date, i.e. whether a regular
+/// file exists at its path that has the same size and modification time as the remote one. Files
+/// without a known remote modification time are never considered up to date.
+#[must_use]
+pub fn is_unchanged(metadata: &FileMetadata) -> bool {
+    let Some(remote_mtime) = metadata.mtime else {
+        return false;
+    };
+    let Ok(local) = std::fs::symlink_metadata(&metadata.path) else {
+        return false;
+    };
+
+    local.is_file()
+        && local.len() == metadata.size.0
+        && crate::common::metadata_mtime(&local) == Some(remote_mtime)
+}
+
+/// Deletes all local entries within the directories of the given server listing that do not exist
+/// on the server anymore, in the local mirror of the served tree at the given root. Local entries
+/// whose type differs from the one on the server are deleted as well, so that they can be replaced.
+/// Directories that are reached through a symbolic link are skipped, so that nothing outside of
+/// the mirror is deleted. Returns the number of deleted entries.
+///
+/// Errors deleting single entries are reported as warnings, and do not stop the deletion of the
+/// other entries.
+///
+/// # Errors
+/// Returns an error if any path of the listing may lie outside of the local mirror, before
+/// anything is deleted.
+///
+/// # Panics
+/// Panics on deletion count overflow.
+pub fn delete_extraneous<S: BuildHasher>(
+    root: &Path,
+    listing: &HashMap<PathBuf, FileMetadata, S>,
+) -> anyhow::Result<u64> {
+    check_listing(listing)?;
+
+    let mut deleted = 0_u64;
+
+    for directory in listing
+        .values()
+        .filter(|metadata| metadata.entry_type == EntryType::Directory)
+    {
+        if crosses_symlink(root, &directory.path) {
+            continue;
+        }
+        let Ok(entries) = std::fs::read_dir(root.join(&directory.path)) else {
+            // the directory does not exist locally yet, so there is nothing to delete
+            continue;
+        };
+
+        for entry in entries.flatten() {
+            let path = directory.path.join(entry.file_name());
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+
+            let keep = listing
+                .get(&path)
+                .is_some_and(|remote| match remote.entry_type {
+                    EntryType::File => !file_type.is_dir() && !file_type.is_symlink(),
+                    EntryType::Directory => file_type.is_dir(),
+                    EntryType::Symlink(_) => file_type.is_symlink(),
+                });
+            if keep {
+                continue;
+            }
+
+            println!("Deleting {}", path.display());
+            let result = if file_type.is_dir() {
+                std::fs::remove_dir_all(entry.path())
+            } else {
+                std::fs::remove_file(entry.path())
+            };
+            match result {
+                Ok(()) => deleted = deleted.checked_add(1).expect("deletion count overflow"),
+                Err(err) => println!("WARNING: Could not delete '{}': {err}", path.display()),
+            }
+        }
+    }
+
+    Ok(deleted)
+}
+
+/// Returns whether the given relative path passes through a symbolic link below the given root,
+/// including whether it is one itself.
+fn crosses_symlink(root: &Path, path: &Path) -> bool {
+    let mut local = root.to_path_buf();
+    path.components().any(|component| {
+        local.push(component);
+        std::fs::symlink_metadata(&local).is_ok_and(|metadata| metadata.file_type().is_symlink())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, path::PathBuf};
+
+    use super::{check_link_target, check_path, delete_extraneous};
+    use crate::types::{EntryType, FileMetadata, FileSize};
+
+    fn entry(path: &str, entry_type: EntryType) -> (PathBuf, FileMetadata) {
+        let metadata = FileMetadata {
+            path: PathBuf::from(path),
+            size: FileSize(0),
+            entry_type,
+            mode: 0o644,
+            mtime: None,
+        };
+        (metadata.path.clone(), metadata)
+    }
+
+    #[test]
+    fn paths_must_stay_within_the_mirror() {
+        for path in ["data/a.h5", "./data", "data/./sub/", "a"] {
+            check_path(path.as_ref()).expect("path within the mirror rejected");
+        }
+        for path in ["/etc/passwd", "/", "..", "data/../../x", "data/.."] {
+            check_path(path.as_ref()).expect_err("path outside of the mirror accepted");
+        }
+    }
+
+    #[test]
+    fn link_targets_must_stay_within_the_mirror() {
+        for (path, target) in [
+            ("link", "file"),
+            ("link", "./data/../file"),
+            ("data/link", "../file"),
+            ("data/sub/link", "../../data/./file"),
+        ] {
+            check_link_target(path.as_ref(), target.as_ref())
+                .expect("target within the mirror rejected");
+        }
+        for (path, target) in [
+            ("link", "/etc/passwd"),
+            ("link", ".."),
+            ("data/link", "../../file"),
+            ("data/link", "sub/../../../file"),
+            ("./data/link", "../.."),
+        ] {
+            check_link_target(path.as_ref(), target.as_ref())
+                .expect_err("target outside of the mirror accepted");
+        }
+    }
+
+    #[test]
+    fn hostile_listing_deletes_nothing() -> anyhow::Result<()> {
+        let outside = tempfile::tempdir()?;
+        std::fs::write(outside.path().join("victim"), b"keep")?;
+        let root = tempfile::tempdir()?;
+        std::fs::create_dir_all(root.path().join("data"))?;
+        std::fs::write(root.path().join("data/extraneous"), b"")?;
+
+        // an absolute path among otherwise legitimate entries rejects the whole listing
+        let listing: HashMap<_, _> = [
+            entry("data", EntryType::Directory),
+            entry(&outside.path().to_string_lossy(), EntryType::Directory),
+        ]
+        .into_iter()
+        .collect();
+        delete_extraneous(root.path(), &listing).expect_err("hostile listing accepted");
+
+        let listing: HashMap<_, _> = [
+            entry("data", EntryType::Directory),
+            entry("data/../..", EntryType::Directory),
+        ]
+        .into_iter()
+        .collect();
+        delete_extraneous(root.path(), &listing).expect_err("hostile listing accepted");
+
+        assert!(outside.path().join("victim").exists());
+        assert!(root.path().join("data/extraneous").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn deletion_does_not_follow_symlinks() -> anyhow::Result<()> {
+        let outside = tempfile::tempdir()?;
+        std::fs::write(outside.path().join("victim"), b"keep")?;
+        let root = tempfile::tempdir()?;
+        std::fs::create_dir_all(root.path().join("data"))?;
+        std::fs::write(root.path().join("data/extraneous"), b"")?;
+        std::fs::write(root.path().join("data/kept"), b"")?;
+        std::os::unix::fs::symlink(outside.path(), root.path().join("data/link"))?;
+
+        // the server claims that the local symbolic link is a directory
+        let listing: HashMap<_, _> = [
+            entry("data", EntryType::Directory),
+            entry("data/kept", EntryType::File),
+            entry("data/link", EntryType::Directory),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(delete_extraneous(root.path(), &listing)?, 2);
+
+        assert!(!root.path().join("data/extraneous").exists());
+        assert!(root.path().join("data/kept").exists());
+        assert!(!root.path().join("data/link").is_symlink());
+        assert!(outside.path().join("victim").exists());
+        Ok(())
+    }
+}
@@ -14,11 +14,11 @@ use super::{get, Session};
 pub fn close(session: &mut Session, delta: u64) -> anyhow::Result<()> {
     // File sizes in megabytes, not mibibytes as Tsunami used
     let mb_thru = f64::from(session.transfer.stats.total_blocks.0)
-        * f64::from(crate::common::BLOCK_SIZE)
+        * f64::from(session.transfer.block_size)
         / 1_000_000.0;
     let mb_good = mb_thru
         - f64::from(session.transfer.stats.total_recvd_retransmits.0)
-            * f64::from(crate::common::BLOCK_SIZE)
+            * f64::from(session.transfer.block_size)
             / 1_000_000.0;
     #[allow(clippy::cast_precision_loss)]
     let mb_file = session.transfer.file_size.0 as f64 / 1_000_000.0;
@@ -37,13 +37,16 @@ pub fn close(session: &mut Session, delta: u64) -> anyhow::Result<()> {
     writeln!(transcript, "mbyte_usable = {mb_good:0>.2}")?;
     writeln!(transcript, "mbyte_file = {mb_file:0>.2}")?;
     writeln!(transcript, "duration = {secs:0>.2}")?;
-    writeln!(transcript, "throughput = {:0>.2}", 8.0_f64 * mb_thru / secs,)?;
+    writeln!(transcript, "throughput = {:0>.2}", 8.0_f64 * mb_thru / secs)?;
     writeln!(
         transcript,
         "goodput_with_restarts = {:0>.2}",
         8.0_f64 * mb_good / secs,
     )?;
     writeln!(transcript, "file_rate = {:0>.2}", 8.0_f64 * mb_file / secs)?;
+    if let Some(srtt) = session.transfer.rtt.srtt() {
+        writeln!(transcript, "srtt_ms = {:0>.2}", srtt.as_secs_f64() * 1000.0)?;
+    }
 
     session.transfer.transcript.take();
     Ok(())
@@ -11,7 +11,10 @@ use snow::StatelessTransportState;
 
 use crate::{
     message::NoiseHeader,
-    types::{BlockIndex, FileChecksums, FileSize},
+    types::{
+        BlockIndex, ChecksumAlgorithm, ChecksumRequest, ChunkChecksums, FileChecksums, FileDigests,
+        FileSize,
+    },
 };
 
 pub static BINCODE_CONFIG: bincode::config::Configuration<
@@ -28,7 +31,49 @@ pub static DEFAULT_SECRET: &[u8; 32] = &[
     0x25, 0x48, 0xdb, 0x99, 0xec, 0x04, 0x6e, 0x5d, 0xf7, 0x53, 0x3d, 0xdd, 0x60, 0x1d, 0xa2, 0x79,
 ];
 
-pub const BLOCK_SIZE: u16 = 1024;
+/// The block size the client requests if none has been specified by the user.
+pub const DEFAULT_BLOCK_SIZE: u16 = 1024;
+
+/// The smallest block size that may be negotiated.
+pub const MIN_BLOCK_SIZE: u16 = 256;
+
+/// The prefix of the padded datagrams that are sent to probe the path MTU.
+pub static MTU_PROBE_MAGIC: &[u8; 8] = b"namidaPM";
+
+/// The largest block size that may be negotiated. A block, together with the datagram header and
+/// the encryption overhead, has to fit into a single UDP datagram.
+pub const MAX_BLOCK_SIZE: u16 = 65_000;
+
+/// When resuming a transfer, the file is first compared using at most this many chunks.
+pub const MAX_INITIAL_CHUNKS: u32 = 1024;
+
+/// When resuming a transfer, each chunk that does not match is split into this many smaller chunks,
+/// which are then compared again, down to the level of single blocks.
+pub const CHECKSUM_REFINEMENT_FACTOR: u32 = 16;
+
+/// The maximum number of chunks whose checksums are requested in a single message, which keeps the
+/// messages within the size limit of the control connection.
+pub const MAX_CHECKSUMS_PER_REQUEST: usize = 1024;
+
+/// The maximum number of block ranges that are sent in a single `SkipBlocks` message.
+pub const MAX_SKIP_RANGES_PER_MESSAGE: usize = 4096;
+
+/// The largest number of ranges of missing blocks that are requested for retransmission at once.
+pub const MAX_RETRANSMIT_RANGES: usize = 2048;
+
+/// The maximum number of files that are transferred together in one batch.
+pub const MAX_BATCH_FILES: usize = 1024;
+
+/// The maximum total length of the paths of the files in one batch, which keeps the batch request
+/// within the size limit of the control connection.
+pub const MAX_BATCH_PATH_BYTES: usize = 32 * 1024;
+
+/// The provisional block count of a streamed transfer, whose actual block count only becomes known
+/// once the end of the data has been reached.
+pub const STREAM_BLOCK_COUNT: BlockIndex = BlockIndex(u32::MAX);
+
+/// The size of the buffer used for reading file data when calculating checksums.
+const CHECKSUM_BUFFER_SIZE: usize = 1 << 20;
 
 pub fn transcript_warn_error(result: anyhow::Result<()>) {
     if let Err(err) = result {
@@ -74,6 +119,21 @@ pub fn epoch() -> Duration {
         .unwrap_or_default()
 }
 
+/// Returns the modification time of the given file, as time since the UNIX epoch, or `None` if it
+/// is not available.
+#[must_use]
+pub fn file_mtime(file: &File) -> Option<Duration> {
+    metadata_mtime(&file.metadata().ok()?)
+}
+
+/// Returns the modification time in the given metadata, as time since the UNIX epoch, or `None` if
+/// it is not available.
+#[must_use]
+pub fn metadata_mtime(metadata: &std::fs::Metadata) -> Option<Duration> {
+    let modified = metadata.modified().ok()?;
+    modified.duration_since(std::time::UNIX_EPOCH).ok()
+}
+
 /// Returns the IPv6 or IPv4 universal bind host (e.g. 0.0.0.0 for IPv4) depending on the given
 /// parameter.
 #[must_use]
@@ -135,70 +195,219 @@ pub fn get_udp_in_errors() -> anyhow::Result<u64> {
     Ok(in_errors_value)
 }
 
-/// Determine the amount of blocks each chunk of a file with the given size should contain.
+/// Calculates the number of blocks of the given size needed to transfer a file with the given
+/// size.
 ///
 /// # Panics
-/// Panics if the block size is 0.
+/// Panics if the block count does not fit into a `BlockIndex`.
 #[must_use]
-pub fn chunk_blocks(file_size: FileSize) -> u64 {
-    let chunk_size = file_size.0 >> 8; // use 128-256 chunks per file
-    chunk_size
-        .checked_div(u64::from(BLOCK_SIZE))
-        .expect("block size is 0")
+pub fn block_count(file_size: FileSize, block_size: u16) -> BlockIndex {
+    let block_count = file_size.0.div_ceil(u64::from(block_size));
+    BlockIndex(block_count.try_into().expect("block count overflow"))
 }
 
-/// Calculate the chunk-wise checksum for the data in the given file. Returns one checksum value
-/// for each chunk.
+/// Calculates the number of complete blocks of the given size within a file with the given size,
+/// i.e. without a final block that is shorter than the others.
 ///
-/// # Errors
-/// Returns an error on file I/O failure.
+/// # Panics
+/// Panics if the block size is zero, or if the block count does not fit into a `BlockIndex`.
+#[must_use]
+pub fn complete_block_count(file_size: FileSize, block_size: u16) -> BlockIndex {
+    let block_count = file_size
+        .0
+        .checked_div(u64::from(block_size))
+        .expect("block size is zero");
+    BlockIndex(block_count.try_into().expect("block count overflow"))
+}
+
+/// Determine the amount of blocks each chunk should contain in the first, coarsest round of
+/// checksums when resuming a transfer of a file with the given number of blocks. The result is a
+/// power of `CHECKSUM_REFINEMENT_FACTOR`, so that chunks can later be split evenly into smaller
+/// ones. Files with few blocks are compared block by block straight away.
 ///
 /// # Panics
 /// Panics on arithmetic overflow.
-pub fn calculate_checksums(
-    file: &mut File,
-    file_size: FileSize,
-    block_count: BlockIndex,
-    chunk_blocks: u64,
-) -> anyhow::Result<FileChecksums> {
-    let chunk_size = u64::from(BLOCK_SIZE)
-        .checked_mul(chunk_blocks)
-        .expect("chunk size overflow #1");
-    let chunk_size_usize: usize = chunk_size.try_into().expect("chunk size overflow #2");
-    let num_chunks = file_size
-        .0
-        .checked_div(chunk_size)
-        .expect("chunk size is 0");
-    let last_chunk_blocks = u64::from(block_count.0)
-        .checked_rem(chunk_blocks)
-        .expect("chunk_blocks is 0");
-    let mut checksums: Vec<u64> = Vec::with_capacity(
-        num_chunks
-            .checked_add(1)
-            .expect("capacity overflow #1")
-            .try_into()
-            .expect("capacity overflow #2"),
-    );
-    let mut data_buffer = vec![0_u8; chunk_size_usize];
+#[must_use]
+pub fn initial_chunk_blocks(block_count: BlockIndex) -> u32 {
+    let mut chunk_blocks = 1_u32;
+    while block_count.0.div_ceil(chunk_blocks) > MAX_INITIAL_CHUNKS {
+        chunk_blocks = chunk_blocks
+            .checked_mul(CHECKSUM_REFINEMENT_FACTOR)
+            .expect("chunk_blocks overflow");
+    }
+    chunk_blocks
+}
 
-    for i in 0..=num_chunks {
-        let start_pos = i.checked_mul(chunk_size).expect("start pos overflow");
+/// Calculate the checksums of the chunks of the given file that are listed in the request, using
+/// the given algorithm. Returns one checksum value for each requested chunk. Data beyond the end of
+/// the file is treated as missing, so a chunk that is only partially present will not match.
+///
+/// # Errors
+/// Returns an error on file I/O failure, or if a requested chunk lies beyond any possible file
+/// position.
+pub fn calculate_checksums<F: Read + Seek>(
+    file: &mut F,
+    block_size: u16,
+    request: &ChecksumRequest,
+    algorithm: ChecksumAlgorithm,
+) -> anyhow::Result<FileChecksums> {
+    let Some(chunk_size) = u64::from(block_size).checked_mul(u64::from(request.chunk_blocks))
+    else {
+        bail!("Chunk size overflow in checksum request");
+    };
+    let mut checksums = ChunkChecksums::with_capacity(algorithm, request.chunks.len());
+    let mut buffer = vec![0_u8; CHECKSUM_BUFFER_SIZE];
+
+    for chunk in &request.chunks {
+        let Some(start_pos) = u64::from(*chunk).checked_mul(chunk_size) else {
+            bail!("Chunk {chunk} of {chunk_size} bytes lies beyond any possible file position");
+        };
         file.seek(std::io::SeekFrom::Start(start_pos))?;
-        let read_count = file.read(&mut data_buffer)?;
-        if read_count < chunk_size_usize && i != num_chunks {
-            eprintln!("WARNING: Read only {read_count} instead of {chunk_size} bytes for chunk {i} out of {num_chunks}");
+        let mut reader = (&mut *file).take(chunk_size);
+
+        match &mut checksums {
+            ChunkChecksums::Xxh3(values) => {
+                let mut hasher = xxhash_rust::xxh3::Xxh3::new();
+                hash_all(&mut reader, &mut buffer, |data| hasher.update(data))?;
+                values.push(hasher.digest());
+            }
+            ChunkChecksums::Blake3(values) => {
+                let mut hasher = blake3::Hasher::new();
+                hash_all(&mut reader, &mut buffer, |data| {
+                    hasher.update(data);
+                })?;
+                values.push(*hasher.finalize().as_bytes());
+            }
         }
-        let checksum = xxhash_rust::xxh3::xxh3_64(&data_buffer);
-        checksums.push(checksum);
     }
 
     Ok(FileChecksums {
-        chunk_blocks,
-        last_chunk_blocks,
+        chunk_blocks: request.chunk_blocks,
         checksums,
     })
 }
 
+/// Reads all data from the given reader, using the given buffer, and passes it to the given
+/// hashing function piece by piece.
+fn hash_all<R: Read, F: FnMut(&[u8])>(
+    reader: &mut R,
+    buffer: &mut [u8],
+    mut update: F,
+) -> anyhow::Result<()> {
+    loop {
+        match reader.read(buffer) {
+            Ok(0) => return Ok(()),
+            Ok(read_count) => update(&buffer[..read_count]),
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// The number of bytes at the start and at the end of a file from which its sample digest is
+/// calculated.
+pub const SAMPLE_DIGEST_SIZE: u64 = 1 << 20;
+
+/// Calculates the BLAKE3 digest of the first and the last `SAMPLE_DIGEST_SIZE` bytes of the given
+/// file (or of all of it, if it is smaller). Together with its size and modification time, it
+/// recognises the version of a file when resuming a transfer, without having to read all of it.
+/// The file is positioned at its start afterwards.
+///
+/// # Errors
+/// Returns an error on file I/O failure.
+pub fn sample_digest(mut file: &File) -> anyhow::Result<[u8; 32]> {
+    let file_size = file.metadata()?.len();
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0_u8; CHECKSUM_BUFFER_SIZE];
+
+    let head_size = file_size.min(SAMPLE_DIGEST_SIZE);
+    file.seek(std::io::SeekFrom::Start(0))?;
+    hash_all(&mut file.take(head_size), &mut buffer, |data| {
+        hasher.update(data);
+    })?;
+
+    let tail_start = file_size.saturating_sub(SAMPLE_DIGEST_SIZE).max(head_size);
+    file.seek(std::io::SeekFrom::Start(tail_start))?;
+    hash_all(&mut file.take(SAMPLE_DIGEST_SIZE), &mut buffer, |data| {
+        hasher.update(data);
+    })?;
+
+    file.seek(std::io::SeekFrom::Start(0))?;
+    Ok(*hasher.finalize().as_bytes())
+}
+
+/// Determines the size (in bytes) of the chunks for which digests are calculated to verify a file
+/// with the given size after the transfer. We use up to 1024 chunks per file, with each chunk being
+/// at least 1 MiB in size.
+#[must_use]
+pub fn digest_chunk_size(file_size: FileSize) -> u64 {
+    (file_size.0 >> 10).max(1 << 20)
+}
+
+/// Calculates the BLAKE3 digests of the data in the given file, both of the file as a whole and of
+/// each chunk of `chunk_size` bytes.
+///
+/// # Errors
+/// Returns an error on file I/O failure, or if the file is shorter than `file_size`.
+///
+/// # Panics
+/// Panics on arithmetic overflow, or if the chunk size is 0.
+pub fn calculate_digests<F: Read + Seek>(
+    file: &mut F,
+    file_size: FileSize,
+    chunk_size: u64,
+) -> anyhow::Result<FileDigests> {
+    const BUFFER_SIZE: u64 = 1 << 20;
+
+    let mut file_hasher = blake3::Hasher::new();
+    let mut chunk_digests = Vec::with_capacity(
+        file_size
+            .0
+            .div_ceil(chunk_size)
+            .try_into()
+            .expect("capacity overflow"),
+    );
+    let mut buffer = vec![
+        0_u8;
+        BUFFER_SIZE
+            .min(chunk_size)
+            .try_into()
+            .expect("buffer size overflow")
+    ];
+
+    file.seek(std::io::SeekFrom::Start(0))?;
+    let mut position = 0_u64;
+    while position < file_size.0 {
+        let chunk_end = position
+            .checked_add(chunk_size)
+            .expect("chunk end overflow")
+            .min(file_size.0);
+        let mut chunk_hasher = blake3::Hasher::new();
+
+        while position < chunk_end {
+            let read_size: usize = (chunk_end.checked_sub(position).expect("position underflow"))
+                .min(buffer.len() as u64)
+                .try_into()
+                .expect("read size overflow");
+            let data = &mut buffer[..read_size];
+            file.read_exact(data)?;
+            chunk_hasher.update(data);
+            file_hasher.update(data);
+            position = position
+                .checked_add(read_size as u64)
+                .expect("position overflow");
+        }
+
+        chunk_digests.push(*chunk_hasher.finalize().as_bytes());
+    }
+
+    Ok(FileDigests {
+        file_digest: *file_hasher.finalize().as_bytes(),
+        chunk_size,
+        chunk_digests,
+    })
+}
+
 /// Wraps a `TcpStream` to be able to conveniently read `bincode` de-/encodable objects.
 pub struct SocketWrapper {
     pub socket: TcpStream,
@@ -243,24 +452,32 @@ impl SocketWrapper {
         payload: &[u8],
     ) -> anyhow::Result<T> {
         let noise = self.noise.as_mut().expect("decryption should be available");
-        decrypt_decode(&noise.state, &mut noise.write_buffer, nonce, payload)
+        decrypt_decode(
+            &noise.state,
+            &mut noise.write_buffer,
+            nonce,
+            payload,
+            BINCODE_CONFIG,
+        )
     }
 
-    /// Try to decrypt the given payload, and borrow-decode the result as one instance of type `T`.
+    /// Try to decrypt the given payload into the given buffer. If successful, the slice of the
+    /// buffer containing the decrypted message is returned.
     ///
     /// # Errors
-    /// Returns an error if decryption or decoding was unsuccessful.
+    /// Returns an error if decryption was unsuccessful.
     ///
     /// # Panics
     /// Panics if decryption is not available (noise not initialised)
-    pub fn decrypt_borrow_decode<'a, T: bincode::BorrowDecode<'a>>(
+    pub fn decrypt<'a>(
         &mut self,
         nonce: u64,
         payload: &[u8],
         write_buffer: &'a mut [u8],
-    ) -> anyhow::Result<T> {
+    ) -> anyhow::Result<&'a [u8]> {
         let noise = self.noise.as_mut().expect("decryption should be available");
-        decrypt_borrow_decode(&noise.state, write_buffer, nonce, payload)
+        let message_len = noise.state.read_message(nonce, payload, write_buffer)?;
+        Ok(&write_buffer[..message_len])
     }
 
     /// Encode the given object using bincode and encrypt the resulting data as a noise message. The
@@ -297,10 +514,17 @@ impl SocketWrapper {
     pub fn read<T: bincode::Decode>(&mut self) -> anyhow::Result<T> {
         match &mut self.noise {
             Some(noise) => {
-                let NoiseHeader { length, nonce } = read_unencrypted(&mut self.socket)?;
+                let NoiseHeader { length, nonce } =
+                    read_unencrypted(&mut self.socket, BINCODE_CONFIG)?;
                 let payload = &mut noise.read_buffer[..(length as usize)];
                 self.socket.read_exact(payload)?;
-                decrypt_decode(&noise.state, &mut noise.write_buffer, nonce, payload)
+                decrypt_decode(
+                    &noise.state,
+                    &mut noise.write_buffer,
+                    nonce,
+                    payload,
+                    BINCODE_CONFIG,
+                )
             }
             None => {
                 // No encryption is available
@@ -309,13 +533,39 @@ impl SocketWrapper {
         }
     }
 
+    /// Like `read`, but fails instead of decoding an instance that takes up more than `LIMIT`
+    /// bytes, so that the peer cannot make us allocate arbitrary amounts of memory by sending a
+    /// huge length prefix.
+    ///
+    /// # Errors
+    /// Returns an error if the reading process terminated prematurely (e.g. due to EOF), or if the
+    /// instance exceeds the limit.
+    pub fn read_limited<T: bincode::Decode, const LIMIT: usize>(&mut self) -> anyhow::Result<T> {
+        let config = BINCODE_CONFIG.with_limit::<LIMIT>();
+        match &mut self.noise {
+            Some(noise) => {
+                let NoiseHeader { length, nonce } = read_unencrypted(&mut self.socket, config)?;
+                let payload = &mut noise.read_buffer[..(length as usize)];
+                self.socket.read_exact(payload)?;
+                decrypt_decode(
+                    &noise.state,
+                    &mut noise.write_buffer,
+                    nonce,
+                    payload,
+                    config,
+                )
+            }
+            None => read_unencrypted(&mut self.socket, config),
+        }
+    }
+
     /// Try to read one instance of the given type from the unencrypted TCP stream. Blocks until one
     /// complete instance is read.
     ///
     /// # Errors
     /// Returns an error if the reading process terminated prematurely (e.g. due to EOF)
     pub fn read_unencrypted<T: bincode::Decode>(&mut self) -> anyhow::Result<T> {
-        read_unencrypted(&mut self.socket)
+        read_unencrypted(&mut self.socket, BINCODE_CONFIG)
     }
 
     /// Write the given object into the TCP stream.
@@ -374,10 +624,30 @@ impl SocketWrapper {
         self.socket.flush()?;
         Ok(())
     }
+
+    /// Returns whether data has arrived on the TCP stream, so that reading from it would not
+    /// block. The end of the stream counts as data, so that reading reports it.
+    ///
+    /// # Errors
+    /// Returns an error on I/O failure.
+    pub fn has_pending_data(&self) -> anyhow::Result<bool> {
+        self.socket.set_nonblocking(true)?;
+        let result = self.socket.peek(&mut [0_u8; 1]);
+        self.socket.set_nonblocking(false)?;
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
 }
 
-fn read_unencrypted<T: bincode::Decode>(socket: &mut TcpStream) -> anyhow::Result<T> {
-    Ok(bincode::decode_from_std_read(socket, BINCODE_CONFIG)?)
+fn read_unencrypted<T: bincode::Decode, C: bincode::config::Config>(
+    socket: &mut TcpStream,
+    config: C,
+) -> anyhow::Result<T> {
+    Ok(bincode::decode_from_std_read(socket, config)?)
 }
 
 fn write_unencrypted<T: bincode::Encode>(
@@ -391,31 +661,16 @@ fn write_unencrypted<T: bincode::Encode>(
     )?)
 }
 
-fn decrypt_decode<T: bincode::Decode>(
+fn decrypt_decode<T: bincode::Decode, C: bincode::config::Config>(
     state: &StatelessTransportState,
     write_buffer: &mut [u8],
     nonce: u64,
     payload: &[u8],
+    config: C,
 ) -> anyhow::Result<T> {
     let message_len = state.read_message(nonce, payload, write_buffer)?;
     let message = &write_buffer[..message_len];
-    match bincode::decode_from_slice(message, BINCODE_CONFIG) {
-        Ok((decoded, _)) => Ok(decoded),
-        Err(err) => {
-            bail!("Failed to decode data {message:x?}, error: {err}");
-        }
-    }
-}
-
-fn decrypt_borrow_decode<'a, T: bincode::BorrowDecode<'a>>(
-    state: &StatelessTransportState,
-    write_buffer: &'a mut [u8],
-    nonce: u64,
-    payload: &[u8],
-) -> anyhow::Result<T> {
-    let message_len = state.read_message(nonce, payload, write_buffer)?;
-    let message = &write_buffer[..message_len];
-    match bincode::borrow_decode_from_slice(message, BINCODE_CONFIG) {
+    match bincode::decode_from_slice(message, config) {
         Ok((decoded, _)) => Ok(decoded),
         Err(err) => {
             bail!("Failed to decode data {message:x?}, error: {err}");
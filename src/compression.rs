@@ -0,0 +1,188 @@
+use anyhow::bail;
+
+use crate::types::Compression;
+
+/// The zstd compression level used for blocks. Higher levels compress only slightly better, but
+/// are much slower, which would limit the transfer rate.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compresses blocks before they are sent, using one of the supported algorithms.
+pub struct Compressor {
+    algorithm: Compression,
+    zstd: Option<zstd::bulk::Compressor<'static>>,
+    buffer: Vec<u8>,
+}
+
+impl Compressor {
+    /// Creates a compressor for the given algorithm.
+    ///
+    /// # Errors
+    /// Returns an error if the compression context cannot be created.
+    pub fn new(algorithm: Compression) -> anyhow::Result<Self> {
+        let zstd = match algorithm {
+            Compression::Zstd => Some(zstd::bulk::Compressor::new(ZSTD_LEVEL)?),
+            Compression::None | Compression::Lz4 => None,
+        };
+
+        Ok(Self {
+            algorithm,
+            zstd,
+            buffer: vec![],
+        })
+    }
+
+    #[must_use]
+    pub fn algorithm(&self) -> Compression {
+        self.algorithm
+    }
+
+    /// Compresses the given block. Returns the compressed data, or `None` if the block does not
+    /// become smaller by compressing it, in which case it should be sent uncompressed.
+    pub fn compress(&mut self, block: &[u8]) -> Option<&[u8]> {
+        let compressed_len = match self.algorithm {
+            Compression::None => return None,
+            Compression::Lz4 => {
+                self.buffer
+                    .resize(lz4_flex::block::get_maximum_output_size(block.len()), 0);
+                lz4_flex::block::compress_into(block, &mut self.buffer).ok()?
+            }
+            Compression::Zstd => {
+                self.buffer
+                    .resize(zstd::zstd_safe::compress_bound(block.len()), 0);
+                self.zstd
+                    .as_mut()?
+                    .compress_to_buffer(block, self.buffer.as_mut_slice())
+                    .ok()?
+            }
+        };
+
+        (compressed_len < block.len()).then(|| &self.buffer[..compressed_len])
+    }
+}
+
+/// Decompresses received blocks. The algorithm is determined for each block by its datagram
+/// header, so one decompressor can handle blocks compressed with any of the supported algorithms.
+pub struct Decompressor {
+    zstd: Option<zstd::bulk::Decompressor<'static>>,
+    buffer: Vec<u8>,
+}
+
+impl Decompressor {
+    /// Creates a decompressor for blocks of up to the given size.
+    #[must_use]
+    pub fn new(block_size: u16) -> Self {
+        Self {
+            zstd: None,
+            buffer: vec![0_u8; usize::from(block_size)],
+        }
+    }
+
+    /// Decompresses the given block payload, which has been compressed using the given algorithm.
+    /// Returns the decompressed block.
+    ///
+    /// # Errors
+    /// Returns an error if the payload cannot be decompressed, or if it does not decompress to a
+    /// complete block.
+    pub fn decompress<'a>(
+        &'a mut self,
+        algorithm: Compression,
+        payload: &'a [u8],
+    ) -> anyhow::Result<&'a [u8]> {
+        let decompressed_len = match algorithm {
+            Compression::None => return Ok(payload),
+            Compression::Lz4 => lz4_flex::block::decompress_into(payload, &mut self.buffer)?,
+            Compression::Zstd => {
+                let zstd = match &mut self.zstd {
+                    Some(zstd) => zstd,
+                    None => self.zstd.insert(zstd::bulk::Decompressor::new()?),
+                };
+                zstd.decompress_to_buffer(payload, self.buffer.as_mut_slice())?
+            }
+        };
+
+        if decompressed_len != self.buffer.len() {
+            bail!(
+                "Compressed block has the wrong size: {decompressed_len} instead of {} bytes",
+                self.buffer.len()
+            );
+        }
+
+        Ok(&self.buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::RngCore;
+
+    use super::*;
+
+    const BLOCK_SIZE: u16 = 1024;
+
+    /// Returns a block that compresses well, but is not trivial.
+    fn compressible_block() -> Vec<u8> {
+        (0..BLOCK_SIZE)
+            .map(|index| b"namida transfers files "[usize::from(index) % 23])
+            .collect()
+    }
+
+    fn round_trip(algorithm: Compression) -> anyhow::Result<()> {
+        let block = compressible_block();
+        let mut compressor = Compressor::new(algorithm)?;
+        let compressed = compressor
+            .compress(&block)
+            .expect("compressible block was not compressed")
+            .to_vec();
+        assert!(compressed.len() < block.len());
+
+        let mut decompressor = Decompressor::new(BLOCK_SIZE);
+        assert_eq!(decompressor.decompress(algorithm, &compressed)?, block);
+        Ok(())
+    }
+
+    #[test]
+    fn zstd_round_trip() -> anyhow::Result<()> {
+        round_trip(Compression::Zstd)
+    }
+
+    #[test]
+    fn lz4_round_trip() -> anyhow::Result<()> {
+        round_trip(Compression::Lz4)
+    }
+
+    #[test]
+    fn incompressible_blocks_are_sent_raw() -> anyhow::Result<()> {
+        let mut block = vec![0_u8; usize::from(BLOCK_SIZE)];
+        rand::thread_rng().fill_bytes(&mut block);
+
+        for algorithm in [Compression::None, Compression::Lz4, Compression::Zstd] {
+            let mut compressor = Compressor::new(algorithm)?;
+            assert_eq!(compressor.compress(&block), None, "{algorithm:?}");
+        }
+
+        // raw blocks are passed through as they are
+        let mut decompressor = Decompressor::new(BLOCK_SIZE);
+        assert_eq!(decompressor.decompress(Compression::None, &block)?, block);
+        Ok(())
+    }
+
+    #[test]
+    fn incomplete_blocks_are_rejected() -> anyhow::Result<()> {
+        let block = compressible_block();
+        let half = usize::from(BLOCK_SIZE / 2);
+
+        for algorithm in [Compression::Lz4, Compression::Zstd] {
+            let mut compressor = Compressor::new(algorithm)?;
+            let compressed = compressor
+                .compress(&block[..half])
+                .expect("compressible block was not compressed")
+                .to_vec();
+
+            let mut decompressor = Decompressor::new(BLOCK_SIZE);
+            decompressor
+                .decompress(algorithm, &compressed)
+                .expect_err("a block of half the size was accepted");
+        }
+        Ok(())
+    }
+}
@@ -1,15 +1,30 @@
-use bincode::{de::read::BorrowReader, enc::write::Writer};
+use bincode::enc::write::Writer;
 
-use crate::types::BlockIndex;
+use crate::types::{BlockIndex, Compression};
 
 #[derive(Debug, Clone, Copy)]
 pub struct Header {
     pub block_index: BlockIndex,
     pub block_type: BlockType,
+
+    /// The algorithm the block has been compressed with. Compressed blocks are shorter than the
+    /// block size, so the datagram is shorter as well.
+    pub compression: Compression,
+
+    /// For parity blocks, the number of consecutive data blocks covered by the parity, starting
+    /// with the block index. Zero for other blocks.
+    pub group_size: u8,
 }
 
 impl Header {
     pub const SIZE: usize = 6;
+
+    /// The block type field consists of the block type in its lowest 4 bits, the compression
+    /// algorithm in the next 4 bits, and the group size of parity blocks in the upper byte.
+    const BLOCK_TYPE_MASK: u16 = 0xf;
+    const COMPRESSION_SHIFT: u16 = 4;
+    const COMPRESSION_MASK: u16 = 0xf;
+    const GROUP_SIZE_SHIFT: u16 = 8;
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -18,6 +33,10 @@ pub enum BlockType {
     Original,
     Final,
     Retransmission,
+
+    /// A parity block for forward error correction: the XOR of a group of data blocks, from which
+    /// a single lost block of the group can be reconstructed.
+    Parity,
 }
 
 impl TryFrom<u16> for BlockType {
@@ -28,6 +47,24 @@ impl TryFrom<u16> for BlockType {
             Ok(BlockType::Final)
         } else if value == BlockType::Retransmission as u16 {
             Ok(BlockType::Retransmission)
+        } else if value == BlockType::Parity as u16 {
+            Ok(BlockType::Parity)
+        } else {
+            Err(())
+        }
+    }
+
+    type Error = ();
+}
+
+impl TryFrom<u16> for Compression {
+    fn try_from(value: u16) -> Result<Self, ()> {
+        if value == Compression::None as u16 {
+            Ok(Compression::None)
+        } else if value == Compression::Lz4 as u16 {
+            Ok(Compression::Lz4)
+        } else if value == Compression::Zstd as u16 {
+            Ok(Compression::Zstd)
         } else {
             Err(())
         }
@@ -42,40 +79,63 @@ pub struct View<'v> {
     pub block: &'v [u8],
 }
 
-impl<'v> bincode::Encode for View<'v> {
+impl bincode::Encode for View<'_> {
     fn encode<E: bincode::enc::Encoder>(
         &self,
         encoder: &mut E,
     ) -> Result<(), bincode::error::EncodeError> {
         bincode::Encode::encode(&self.header.block_index.0, encoder)?;
-        bincode::Encode::encode(&(self.header.block_type as u16), encoder)?;
+        let type_field = (self.header.block_type as u16)
+            | ((self.header.compression as u16) << Header::COMPRESSION_SHIFT)
+            | (u16::from(self.header.group_size) << Header::GROUP_SIZE_SHIFT);
+        bincode::Encode::encode(&type_field, encoder)?;
         encoder.writer().write(self.block)?;
 
         Ok(())
     }
 }
 
-impl<'v, 'de: 'v> bincode::BorrowDecode<'de> for View<'v> {
-    fn borrow_decode<D: bincode::de::BorrowDecoder<'de>>(
-        decoder: &mut D,
-    ) -> Result<Self, bincode::error::DecodeError> {
-        let block_index = BlockIndex(bincode::BorrowDecode::borrow_decode(decoder)?);
-        let block_type_value: u16 = bincode::BorrowDecode::borrow_decode(decoder)?;
-        let block_type = BlockType::try_from(block_type_value).or(Err(
+impl<'v> View<'v> {
+    /// Decodes a datagram from the given buffer. Since the block size is negotiated for each
+    /// transfer, everything following the header is considered to be the block.
+    ///
+    /// # Errors
+    /// Returns an error if the buffer is too short to contain a header, or if the header is
+    /// invalid.
+    pub fn decode(buffer: &'v [u8]) -> Result<Self, bincode::error::DecodeError> {
+        let Some((header_bytes, block)) = buffer.split_at_checked(Header::SIZE) else {
+            return Err(bincode::error::DecodeError::UnexpectedEnd {
+                additional: Header::SIZE.saturating_sub(buffer.len()),
+            });
+        };
+
+        let ((block_index, type_field), _): ((u32, u16), usize) =
+            bincode::decode_from_slice(header_bytes, crate::common::BINCODE_CONFIG)?;
+        let block_type_value = type_field & Header::BLOCK_TYPE_MASK;
+        let compression_value =
+            (type_field >> Header::COMPRESSION_SHIFT) & Header::COMPRESSION_MASK;
+        let [group_size, _] = type_field.to_be_bytes();
+        let block_type = BlockType::try_from(block_type_value).map_err(|()| {
             bincode::error::DecodeError::UnexpectedVariant {
                 type_name: "BlockType",
-                allowed: &bincode::error::AllowedEnumVariants::Range { min: 0, max: 2 },
+                allowed: &bincode::error::AllowedEnumVariants::Range { min: 0, max: 3 },
                 found: u32::from(block_type_value),
-            },
-        ))?;
-        let block = decoder
-            .borrow_reader()
-            .take_bytes(crate::common::BLOCK_SIZE as usize)?;
+            }
+        })?;
+        let compression = Compression::try_from(compression_value).map_err(|()| {
+            bincode::error::DecodeError::UnexpectedVariant {
+                type_name: "Compression",
+                allowed: &bincode::error::AllowedEnumVariants::Range { min: 0, max: 2 },
+                found: u32::from(compression_value),
+            }
+        })?;
 
         Ok(Self {
             header: Header {
-                block_index,
+                block_index: BlockIndex(block_index),
                 block_type,
+                compression,
+                group_size,
             },
             block,
         })
@@ -0,0 +1,377 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek},
+};
+
+/// The maximum number of block signatures that are sent in a single message, which keeps the
+/// messages within the size limit of the control connection.
+pub const MAX_SIGNATURES_PER_MESSAGE: usize = 2048;
+
+/// The maximum number of copy instructions that are sent in a single message.
+pub const MAX_COPIES_PER_MESSAGE: usize = 2048;
+
+/// The largest size of the blocks into which the previous version of a file is split for a delta
+/// transfer.
+pub const MAX_DELTA_BLOCK_SIZE: u32 = 1 << 17;
+
+/// The amount of data that is read at once while searching for matching blocks.
+const READ_SIZE: usize = 1 << 20;
+
+/// The signature of one block of the previous version of a file: a weak rolling checksum, which
+/// can be updated cheaply when moving through a file byte by byte, and a strong checksum to confirm
+/// that a block with a matching weak checksum really matches.
+#[derive(Debug, Clone, Copy, bincode::Encode, bincode::Decode)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: [u8; 16],
+}
+
+/// An instruction to copy data from the previous version of a file into the new version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct CopyInstruction {
+    pub source_offset: u64,
+    pub target_offset: u64,
+    pub length: u64,
+}
+
+/// The rolling checksum used by rsync, which consists of two 16-bit sums over the bytes in a
+/// window.
+#[derive(Debug, Clone, Copy)]
+struct Rolling {
+    sum: u32,
+    weighted_sum: u32,
+    length: u32,
+}
+
+impl Rolling {
+    /// Calculates the checksum of the given window.
+    fn new(window: &[u8]) -> Self {
+        let mut sum = 0_u32;
+        let mut weighted_sum = 0_u32;
+        for byte in window {
+            sum = sum.wrapping_add(u32::from(*byte));
+            weighted_sum = weighted_sum.wrapping_add(sum);
+        }
+
+        Self {
+            sum,
+            weighted_sum,
+            length: u32::try_from(window.len()).expect("window length overflow"),
+        }
+    }
+
+    /// Moves the window forward by one byte, removing `outgoing` at the front and adding
+    /// `incoming` at the back.
+    fn roll(&mut self, outgoing: u8, incoming: u8) {
+        self.sum = self
+            .sum
+            .wrapping_sub(u32::from(outgoing))
+            .wrapping_add(u32::from(incoming));
+        self.weighted_sum = self
+            .weighted_sum
+            .wrapping_sub(self.length.wrapping_mul(u32::from(outgoing)))
+            .wrapping_add(self.sum);
+    }
+
+    fn digest(self) -> u32 {
+        (self.sum & 0xffff) | (self.weighted_sum << 16)
+    }
+}
+
+fn strong_checksum(data: &[u8]) -> [u8; 16] {
+    let mut checksum = [0_u8; 16];
+    checksum.copy_from_slice(&blake3::hash(data).as_bytes()[..16]);
+    checksum
+}
+
+/// Chooses the size of the blocks into which the previous version of a file with the given size
+/// is split, similar to rsync: about the square root of the file size, but no smaller than the
+/// block size of the transfer, since smaller matches could not save any transmitted blocks.
+#[must_use]
+pub fn block_size_for(basis_size: u64, transfer_block_size: u16) -> u32 {
+    let root = u32::try_from(basis_size.isqrt()).unwrap_or(u32::MAX);
+    root.next_multiple_of(8)
+        .min(MAX_DELTA_BLOCK_SIZE)
+        .max(u32::from(transfer_block_size))
+}
+
+/// Returns the largest number of block signatures that are accepted for a delta transfer of a file
+/// with the given size. Blocks are never smaller than the smallest block size of a transfer, so no
+/// more matching blocks than that can be copied into the file; this bounds the memory the receiving
+/// side can make the sending side use.
+///
+/// # Panics
+/// Panics if the minimum block size is 0.
+#[must_use]
+pub fn max_signatures(file_size: u64) -> usize {
+    let smallest_blocks = file_size
+        .checked_div(u64::from(crate::common::MIN_BLOCK_SIZE))
+        .expect("minimum block size is 0");
+    usize::try_from(smallest_blocks)
+        .unwrap_or(usize::MAX)
+        .saturating_add(1)
+}
+
+/// Calculates the signatures of all complete blocks of the given size in the given file.
+///
+/// # Errors
+/// Returns an error on I/O failure.
+pub fn signatures(file: &mut File, block_size: u32) -> anyhow::Result<Vec<BlockSignature>> {
+    file.seek(std::io::SeekFrom::Start(0))?;
+    let mut reader = std::io::BufReader::with_capacity(READ_SIZE, file);
+    let mut block = vec![0_u8; block_size as usize];
+    let mut signatures = vec![];
+
+    loop {
+        match reader.read_exact(&mut block) {
+            Ok(()) => signatures.push(BlockSignature {
+                weak: Rolling::new(&block).digest(),
+                strong: strong_checksum(&block),
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(signatures)
+}
+
+/// Searches the given file for blocks that match one of the given signatures, at any offset.
+/// Returns instructions to copy the matching blocks from the previous version of the file, with
+/// instructions for adjacent blocks being merged.
+///
+/// # Errors
+/// Returns an error on I/O failure.
+///
+/// # Panics
+/// Panics on arithmetic overflow.
+pub fn find_copies<F: Read + Seek>(
+    file: &mut F,
+    block_size: u32,
+    signatures: &[BlockSignature],
+) -> anyhow::Result<Vec<CopyInstruction>> {
+    let mut copies: Vec<CopyInstruction> = vec![];
+    if signatures.is_empty() {
+        return Ok(copies);
+    }
+
+    let mut table: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (index, signature) in signatures.iter().enumerate() {
+        table.entry(signature.weak).or_default().push(index);
+    }
+
+    file.seek(std::io::SeekFrom::Start(0))?;
+    let window_size = block_size as usize;
+    let mut buffer: Vec<u8> = Vec::with_capacity(READ_SIZE.max(window_size).saturating_mul(2));
+    let mut buffer_offset = 0_u64; // the file offset of the start of the buffer
+    let mut position = 0_usize; // the start of the current window within the buffer
+    let mut end_of_file = false;
+    let mut rolling: Option<Rolling> = None;
+
+    loop {
+        // make sure the buffer contains the current window, plus the byte after it
+        let needed = position
+            .checked_add(window_size)
+            .and_then(|end| end.checked_add(1))
+            .expect("window end overflow");
+        if buffer.len() < needed && !end_of_file {
+            buffer.drain(..position);
+            buffer_offset = buffer_offset
+                .checked_add(position as u64)
+                .expect("buffer offset overflow");
+            position = 0;
+
+            let read_count = file
+                .by_ref()
+                .take(READ_SIZE as u64)
+                .read_to_end(&mut buffer)?;
+            end_of_file = read_count == 0;
+            continue;
+        }
+
+        let window_end = position.checked_add(window_size).expect("window overflow");
+        let Some(window) = buffer.get(position..window_end) else {
+            // the rest of the file is shorter than a block
+            break;
+        };
+
+        let checksum = rolling.get_or_insert_with(|| Rolling::new(window));
+        let matching_index = table.get(&checksum.digest()).and_then(|candidates| {
+            let strong = strong_checksum(window);
+            candidates
+                .iter()
+                .copied()
+                .find(|index| signatures[*index].strong == strong)
+        });
+
+        if let Some(index) = matching_index {
+            let copy = CopyInstruction {
+                source_offset: (index as u64)
+                    .checked_mul(u64::from(block_size))
+                    .expect("source offset overflow"),
+                target_offset: buffer_offset
+                    .checked_add(position as u64)
+                    .expect("target offset overflow"),
+                length: u64::from(block_size),
+            };
+            push_merged(&mut copies, copy);
+
+            // continue after the matching block
+            position = window_end;
+            rolling = None;
+            continue;
+        }
+
+        // no match, move forward by one byte
+        let Some(incoming) = buffer.get(window_end).copied() else {
+            break;
+        };
+        checksum.roll(buffer[position], incoming);
+        position = position.checked_add(1).expect("position overflow");
+    }
+
+    Ok(copies)
+}
+
+/// Appends the given copy instruction, merging it with the previous one if both the source and
+/// the target ranges are adjacent.
+fn push_merged(copies: &mut Vec<CopyInstruction>, copy: CopyInstruction) {
+    if let Some(previous) = copies.last_mut() {
+        let previous_source_end = previous.source_offset.checked_add(previous.length);
+        let previous_target_end = previous.target_offset.checked_add(previous.length);
+        if previous_source_end == Some(copy.source_offset)
+            && previous_target_end == Some(copy.target_offset)
+        {
+            previous.length = previous
+                .length
+                .checked_add(copy.length)
+                .expect("copy length overflow");
+            return;
+        }
+    }
+
+    copies.push(copy);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{find_copies, strong_checksum, BlockSignature, CopyInstruction, Rolling};
+
+    const BLOCK_SIZE: u32 = 64;
+
+    /// Returns reproducible data that does not repeat itself.
+    fn data(length: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        (0..length)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state.to_le_bytes()[0]
+            })
+            .collect()
+    }
+
+    /// Calculates the signatures of the complete blocks of the given data.
+    fn signatures(basis: &[u8]) -> Vec<BlockSignature> {
+        basis
+            .chunks_exact(BLOCK_SIZE as usize)
+            .map(|block| BlockSignature {
+                weak: Rolling::new(block).digest(),
+                strong: strong_checksum(block),
+            })
+            .collect()
+    }
+
+    fn copy(source_offset: u64, target_offset: u64, length: u64) -> CopyInstruction {
+        CopyInstruction {
+            source_offset,
+            target_offset,
+            length,
+        }
+    }
+
+    fn copies(basis: &[u8], target: &[u8]) -> anyhow::Result<Vec<CopyInstruction>> {
+        find_copies(&mut Cursor::new(target), BLOCK_SIZE, &signatures(basis))
+    }
+
+    #[test]
+    fn rolling_matches_fresh_checksum() {
+        let data = data(1000, 1);
+        let window = BLOCK_SIZE as usize;
+        let mut rolling = Rolling::new(&data[..window]);
+        for start in 1..=data.len() - window {
+            rolling.roll(data[start - 1], data[start + window - 1]);
+            assert_eq!(
+                rolling.digest(),
+                Rolling::new(&data[start..start + window]).digest(),
+                "window at {start}"
+            );
+        }
+    }
+
+    #[test]
+    fn shifted_insert() -> anyhow::Result<()> {
+        let basis = data(8 * BLOCK_SIZE as usize, 2);
+        let mut target = basis[..200].to_vec();
+        target.extend_from_slice(b"inserted");
+        target.extend_from_slice(&basis[200..]);
+
+        // the block containing the insertion cannot be copied, the others are found at their
+        // shifted positions
+        assert_eq!(
+            copies(&basis, &target)?,
+            [copy(0, 0, 192), copy(256, 264, 256)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn shifted_delete() -> anyhow::Result<()> {
+        let basis = data(8 * BLOCK_SIZE as usize, 3);
+        let mut target = basis[..200].to_vec();
+        target.extend_from_slice(&basis[210..]);
+
+        assert_eq!(
+            copies(&basis, &target)?,
+            [copy(0, 0, 192), copy(256, 246, 256)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn block_appearing_twice() -> anyhow::Result<()> {
+        let basis = data(4 * BLOCK_SIZE as usize, 4);
+        let block = &basis[64..128];
+        let mut target = block.to_vec();
+        target.extend_from_slice(&data(10, 5));
+        target.extend_from_slice(block);
+
+        assert_eq!(
+            copies(&basis, &target)?,
+            [copy(64, 0, 64), copy(64, 74, 64)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn short_final_block() -> anyhow::Result<()> {
+        // the incomplete final block of the basis has no signature, and the short rest of the
+        // target cannot match any block
+        let basis = data(3 * BLOCK_SIZE as usize + 20, 6);
+        assert_eq!(signatures(&basis).len(), 3);
+
+        let target = basis[..3 * BLOCK_SIZE as usize + 20].to_vec();
+        assert_eq!(copies(&basis, &target)?, [copy(0, 0, 192)]);
+
+        let target = basis[..100].to_vec();
+        assert_eq!(copies(&basis, &target)?, [copy(0, 0, 64)]);
+
+        let target = basis[..30].to_vec();
+        assert_eq!(copies(&basis, &target)?, []);
+        Ok(())
+    }
+}
@@ -0,0 +1,418 @@
+use crate::{
+    compression::Decompressor,
+    datagram,
+    types::{BlockIndex, Compression, ErrorRate},
+};
+
+/// The smallest number of data blocks covered by one parity block.
+pub const MIN_GROUP_SIZE: u8 = 2;
+
+/// The largest number of data blocks covered by one parity block.
+pub const MAX_GROUP_SIZE: u8 = 64;
+
+/// The number of data blocks covered by one parity block if FEC is requested without specifying
+/// a ratio.
+pub const DEFAULT_GROUP_SIZE: u8 = 16;
+
+/// The number of recently received blocks the receiving side keeps in memory, so that a missing
+/// block can be reconstructed once the parity block of its group arrives.
+const RECOVERY_CACHE_BLOCKS: u32 = 2 * MAX_GROUP_SIZE as u32;
+
+/// Chooses the number of data blocks covered by each parity block, based on the error rate
+/// reported by the receiving side. The error rate is roughly 50 000 times the fraction of blocks
+/// that are lost (see `client::protocol::update_stats`). Since one parity block can only repair
+/// one lost block in its group, groups are made small enough that about one in ten of them is
+/// expected to lose a block.
+#[must_use]
+pub fn group_size_for(error_rate: ErrorRate) -> u8 {
+    if error_rate.0 == 0 {
+        return MAX_GROUP_SIZE;
+    }
+
+    let group_size = 5000_u32.checked_div(error_rate.0).unwrap_or(u32::MAX);
+    u8::try_from(group_size)
+        .unwrap_or(MAX_GROUP_SIZE)
+        .clamp(MIN_GROUP_SIZE, MAX_GROUP_SIZE)
+}
+
+/// XORs the given data into the given parity buffer. If the data is shorter than the buffer, it
+/// is treated as if it was padded with zeroes.
+fn xor_into(parity: &mut [u8], data: &[u8]) {
+    for (parity_byte, data_byte) in parity.iter_mut().zip(data) {
+        *parity_byte ^= data_byte;
+    }
+}
+
+/// Calculates parity blocks on the sending side. Each parity block is the XOR of a group of
+/// consecutive data blocks, which allows the receiving side to reconstruct any single block of
+/// the group that was lost, without waiting a round trip for its retransmission.
+pub struct Encoder {
+    group_size: u8,
+    first: BlockIndex,
+    count: u8,
+    parity: Vec<u8>,
+    complete: bool,
+}
+
+impl Encoder {
+    /// Creates an encoder, starting with the given group size.
+    #[must_use]
+    pub fn new(group_size: u8) -> Self {
+        Self {
+            group_size: group_size.clamp(MIN_GROUP_SIZE, MAX_GROUP_SIZE),
+            first: BlockIndex(1),
+            count: 0,
+            parity: vec![],
+            complete: false,
+        }
+    }
+
+    /// Adapts the group size to the given error rate reported by the receiving side. The new
+    /// group size takes effect with the next group.
+    pub fn adapt(&mut self, error_rate: ErrorRate) {
+        self.group_size = group_size_for(error_rate);
+    }
+
+    /// Adds the raw data of a block that is transmitted for the first time. `is_last` indicates
+    /// the last block of the file, which always completes a group. Only blocks that
+    /// directly follow the current group are added; if the transmission skips ahead, the current
+    /// group is discarded and a new one is started, and blocks that have been added before (e.g.
+    /// the final block, which is sent repeatedly) are ignored.
+    ///
+    /// # Panics
+    /// Panics on arithmetic overflow.
+    pub fn add(&mut self, block_index: BlockIndex, block: &[u8], is_last: bool) {
+        if self.complete {
+            self.start_next_group();
+        }
+
+        let next = self.first.safe_add(BlockIndex(u32::from(self.count)));
+        if block_index < next {
+            return;
+        }
+        if block_index > next {
+            self.first = block_index;
+            self.count = 0;
+        }
+
+        if self.count == 0 {
+            self.parity.clear();
+            self.parity.resize(block.len(), 0);
+        }
+        xor_into(&mut self.parity, block);
+        self.count = self.count.checked_add(1).expect("group size overflow");
+        self.complete = self.count >= self.group_size || is_last;
+    }
+
+    /// Returns the parity block of the group that has just been completed by the most recently
+    /// added block, together with the index of the first block of the group and the number of
+    /// blocks in it. Returns `None` if the group is not complete yet, or if its parity block has
+    /// already been taken.
+    pub fn take_parity(&mut self) -> Option<(BlockIndex, u8, &[u8])> {
+        if !self.complete || self.count == 0 {
+            return None;
+        }
+
+        let group = (self.first, self.count);
+        self.start_next_group();
+        Some((group.0, group.1, self.parity.as_slice()))
+    }
+
+    fn start_next_group(&mut self) {
+        self.first = self.first.safe_add(BlockIndex(u32::from(self.count)));
+        self.count = 0;
+        self.complete = false;
+    }
+}
+
+/// One block kept in memory by the `Recovery`.
+struct CachedBlock {
+    block_index: BlockIndex,
+    compression: Compression,
+    data: Vec<u8>,
+}
+
+/// Reconstructs lost blocks on the receiving side from the parity blocks sent along with the
+/// data, using the recently received blocks that are kept in memory.
+pub struct Recovery {
+    cache: Vec<CachedBlock>,
+    decompressor: Decompressor,
+    scratch: Vec<u8>,
+}
+
+impl Recovery {
+    /// Creates the recovery state for a transfer with the given block size.
+    #[must_use]
+    pub fn new(block_size: u16) -> Self {
+        let cache = (0..RECOVERY_CACHE_BLOCKS)
+            .map(|_| CachedBlock {
+                block_index: BlockIndex(0),
+                compression: Compression::None,
+                data: vec![],
+            })
+            .collect();
+
+        Self {
+            cache,
+            decompressor: Decompressor::new(block_size),
+            scratch: vec![0_u8; usize::from(block_size)],
+        }
+    }
+
+    /// Keeps a copy of the given received data block, in case it is needed to reconstruct another
+    /// block of its group later.
+    pub fn remember(&mut self, datagram: &datagram::View) {
+        let block_index = datagram.header.block_index;
+        let slot = &mut self.cache[(block_index.0 % RECOVERY_CACHE_BLOCKS) as usize];
+        slot.block_index = block_index;
+        slot.compression = datagram.header.compression;
+        slot.data.clear();
+        slot.data.extend_from_slice(datagram.block);
+    }
+
+    /// Tries to reconstruct a missing block of the group covered by the given parity block.
+    /// `got_block` tells whether a block has already been received. This is only possible if
+    /// exactly one block of the group is missing, and all other blocks of the group are still in
+    /// memory. Returns the index and the data of the reconstructed block, if successful.
+    ///
+    /// # Errors
+    /// Returns an error if one of the blocks cannot be decompressed.
+    pub fn recover<F: Fn(BlockIndex) -> bool>(
+        &mut self,
+        parity: &datagram::View,
+        got_block: F,
+    ) -> anyhow::Result<Option<(BlockIndex, &[u8])>> {
+        let first = parity.header.block_index;
+        let Some(last) = u32::from(parity.header.group_size)
+            .checked_sub(1)
+            .and_then(|offset| first.0.checked_add(offset))
+            .map(BlockIndex)
+        else {
+            return Ok(None);
+        };
+        if first.is_zero() {
+            return Ok(None);
+        }
+
+        // find the single missing block, and make sure we still have all others
+        let mut missing = None;
+        for block_index in (first.0..=last.0).map(BlockIndex) {
+            let slot = &self.cache[(block_index.0 % RECOVERY_CACHE_BLOCKS) as usize];
+            if slot.block_index == block_index {
+                continue;
+            }
+            if missing.is_some() || got_block(block_index) {
+                return Ok(None);
+            }
+            missing = Some(block_index);
+        }
+        let Some(missing) = missing else {
+            return Ok(None);
+        };
+
+        // the missing block is the XOR of the parity block and all other blocks of the group
+        let parity_data = self
+            .decompressor
+            .decompress(parity.header.compression, parity.block)?;
+        self.scratch.clear();
+        self.scratch.extend_from_slice(parity_data);
+        for block_index in (first.0..=last.0).map(BlockIndex) {
+            if block_index == missing {
+                continue;
+            }
+            let slot = &self.cache[(block_index.0 % RECOVERY_CACHE_BLOCKS) as usize];
+            let data = self.decompressor.decompress(slot.compression, &slot.data)?;
+            xor_into(&mut self.scratch, data);
+        }
+
+        Ok(Some((missing, &self.scratch)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom};
+
+    use super::{group_size_for, Encoder, Recovery, MAX_GROUP_SIZE, MIN_GROUP_SIZE};
+    use crate::{
+        compression::Decompressor,
+        datagram::{BlockType, Header, View},
+        stream::Stream,
+        types::{BlockIndex, Compression, ErrorRate, FileSize},
+    };
+
+    const BLOCK_SIZE: u16 = 16;
+
+    /// Returns the data of the given block, which differs from that of all other blocks.
+    fn block(index: u32) -> Vec<u8> {
+        let fill = u8::try_from(index).expect("block index overflow");
+        (0..16_u8).map(|byte| byte ^ fill.rotate_left(4)).collect()
+    }
+
+    fn view(index: u32, block_type: BlockType, group_size: u8, data: &[u8]) -> View<'_> {
+        View {
+            header: Header {
+                block_index: BlockIndex(index),
+                block_type,
+                compression: Compression::None,
+                group_size,
+            },
+            block: data,
+        }
+    }
+
+    /// Feeds the given blocks to the encoder, and returns the parity blocks it produces along
+    /// with the first block and the size of their groups.
+    fn encode(
+        encoder: &mut Encoder,
+        blocks: &[(u32, Vec<u8>)],
+        last: u32,
+    ) -> Vec<(u32, u8, Vec<u8>)> {
+        let mut parities = vec![];
+        for (index, data) in blocks {
+            encoder.add(BlockIndex(*index), data, *index == last);
+            if let Some((first, group_size, parity)) = encoder.take_parity() {
+                parities.push((first.0, group_size, parity.to_vec()));
+            }
+        }
+        parities
+    }
+
+    #[test]
+    fn group_size_follows_error_rate() {
+        assert_eq!(group_size_for(ErrorRate(0)), MAX_GROUP_SIZE);
+        assert_eq!(group_size_for(ErrorRate(50)), MAX_GROUP_SIZE);
+        assert_eq!(group_size_for(ErrorRate(500)), 10);
+        assert_eq!(group_size_for(ErrorRate(5000)), MIN_GROUP_SIZE);
+        assert_eq!(group_size_for(ErrorRate(100_000)), MIN_GROUP_SIZE);
+    }
+
+    #[test]
+    fn recovers_missing_block_in_the_middle() -> anyhow::Result<()> {
+        let blocks: Vec<_> = (1..=4).map(|index| (index, block(index))).collect();
+        let parities = encode(&mut Encoder::new(4), &blocks, 8);
+        let [(first, group_size, parity)] = parities.as_slice() else {
+            panic!("expected a single parity block, got {parities:?}");
+        };
+        assert_eq!((*first, *group_size), (1, 4));
+
+        let mut recovery = Recovery::new(BLOCK_SIZE);
+        for (index, data) in blocks.iter().filter(|(index, _)| *index != 3) {
+            recovery.remember(&view(*index, BlockType::Original, 0, data));
+        }
+        let parity = view(1, BlockType::Parity, 4, parity);
+        let recovered = recovery.recover(&parity, |_| false)?;
+        assert_eq!(recovered, Some((BlockIndex(3), block(3).as_slice())));
+        Ok(())
+    }
+
+    #[test]
+    fn recovers_final_short_block() -> anyhow::Result<()> {
+        // the file ends 5 bytes into its third block, whose buffer still holds stale data after
+        // that, which is included in the parity
+        let file_size = FileSize(2 * u64::from(BLOCK_SIZE) + 5);
+        let mut final_block = block(3);
+        final_block[5..].fill(0xaa);
+        let blocks = vec![(1, block(1)), (2, block(2)), (3, final_block)];
+        let parities = encode(&mut Encoder::new(16), &blocks, 3);
+        let [(first, group_size, parity)] = parities.as_slice() else {
+            panic!("expected a single parity block, got {parities:?}");
+        };
+        assert_eq!((*first, *group_size), (1, 3));
+
+        let mut recovery = Recovery::new(BLOCK_SIZE);
+        recovery.remember(&view(1, BlockType::Original, 0, &blocks[0].1));
+        recovery.remember(&view(2, BlockType::Original, 0, &blocks[1].1));
+        let parity = view(1, BlockType::Parity, 3, parity);
+        let Some((index, recovered)) = recovery.recover(&parity, |_| false)? else {
+            panic!("final block not recovered");
+        };
+        assert_eq!(index, BlockIndex(3));
+
+        // writing the recovered block cuts it off at the end of the file
+        let mut file = Stream::File(tempfile::tempfile()?);
+        let mut decompressor = Decompressor::new(BLOCK_SIZE);
+        for (index, data) in [
+            (1, blocks[0].1.as_slice()),
+            (2, &blocks[1].1),
+            (3, recovered),
+        ] {
+            crate::client::io::accept_block(
+                view(index, BlockType::Original, 0, data),
+                BLOCK_SIZE,
+                BlockIndex(3),
+                file_size,
+                &mut file,
+                &mut decompressor,
+            )?;
+        }
+        let mut written = vec![];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut written)?;
+        let mut expected = [block(1), block(2), block(3)].concat();
+        expected.truncate(usize::try_from(file_size.0)?);
+        assert_eq!(written, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn two_missing_blocks_cannot_be_recovered() -> anyhow::Result<()> {
+        let blocks: Vec<_> = (1..=4).map(|index| (index, block(index))).collect();
+        let parities = encode(&mut Encoder::new(4), &blocks, 8);
+        let mut recovery = Recovery::new(BLOCK_SIZE);
+        recovery.remember(&view(1, BlockType::Original, 0, &blocks[0].1));
+        recovery.remember(&view(4, BlockType::Original, 0, &blocks[3].1));
+
+        let parity = view(1, BlockType::Parity, 4, &parities[0].2);
+        assert_eq!(recovery.recover(&parity, |_| false)?, None);
+
+        // nor is anything recovered if the only block that is not in memory has been received
+        // already
+        recovery.remember(&view(2, BlockType::Original, 0, &blocks[1].1));
+        assert_eq!(
+            recovery.recover(&parity, |index| index == BlockIndex(3))?,
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn skipping_ahead_abandons_the_incomplete_group() -> anyhow::Result<()> {
+        // blocks 3 to 6 are skipped, e.g. because the receiving side had them already
+        let blocks: Vec<_> = [1, 2, 7, 8, 9, 10]
+            .into_iter()
+            .map(|index| (index, block(index)))
+            .collect();
+        let parities = encode(&mut Encoder::new(4), &blocks, 10);
+        let [(first, group_size, parity)] = parities.as_slice() else {
+            panic!("expected a single parity block, got {parities:?}");
+        };
+        assert_eq!((*first, *group_size), (7, 4));
+
+        // the parity only covers the new group
+        let mut recovery = Recovery::new(BLOCK_SIZE);
+        for (index, data) in blocks.iter().filter(|(index, _)| *index != 8) {
+            recovery.remember(&view(*index, BlockType::Original, 0, data));
+        }
+        let parity = view(7, BlockType::Parity, 4, parity);
+        let recovered = recovery.recover(&parity, |_| false)?;
+        assert_eq!(recovered, Some((BlockIndex(8), block(8).as_slice())));
+        Ok(())
+    }
+
+    #[test]
+    fn high_error_rate_shrinks_groups() {
+        let mut encoder = Encoder::new(16);
+        let blocks: Vec<_> = (1..=3).map(|index| (index, block(index))).collect();
+        assert!(encode(&mut encoder, &blocks, 100).is_empty());
+
+        // the current group is completed as soon as it has reached the new size
+        encoder.adapt(ErrorRate(5000));
+        let blocks: Vec<_> = (4..=8).map(|index| (index, block(index))).collect();
+        let groups: Vec<_> = encode(&mut encoder, &blocks, 100)
+            .into_iter()
+            .map(|(first, group_size, _)| (first, group_size))
+            .collect();
+        assert_eq!(groups, vec![(1, 4), (5, 2), (7, 2)]);
+    }
+}
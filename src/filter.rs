@@ -0,0 +1,341 @@
+use std::path::Path;
+
+use crate::types::{EntryType, FileMetadata};
+
+/// Selects entries of the server's file index by their paths and sizes. The client sends it along
+/// with a file list request, so that the server only needs to send the matching entries.
+#[derive(Debug, Clone, Default, clap::Args, bincode::Encode, bincode::Decode)]
+pub struct FileFilter {
+    /// Only include entries whose path matches one of these glob patterns (can be given multiple
+    /// times), e.g. `**/*.h5`. `*` and `?` do not match `/`, `**/` matches any number of
+    /// directories, and `[a-z]` matches one character of a set.
+    #[arg(long = "include", value_name = "GLOB")]
+    pub include: Vec<String>,
+
+    /// Exclude entries whose path matches one of these glob patterns (can be given multiple times),
+    /// even if they match an `--include` pattern.
+    #[arg(long = "exclude", value_name = "GLOB")]
+    pub exclude: Vec<String>,
+
+    /// Only include files of at least this size (in bytes).
+    #[arg(long = "min-size", value_name = "BYTES")]
+    pub min_size: Option<u64>,
+
+    /// Only include files of at most this size (in bytes).
+    #[arg(long = "max-size", value_name = "BYTES")]
+    pub max_size: Option<u64>,
+}
+
+impl FileFilter {
+    /// Returns whether the filter selects all entries, i.e. no criteria have been given.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty()
+            && self.exclude.is_empty()
+            && self.min_size.is_none()
+            && self.max_size.is_none()
+    }
+
+    /// Returns a function that tells whether an entry is selected by the filter. Entries are
+    /// selected if their path matches one of the include patterns (or there are none), and none of
+    /// the exclude patterns. The size limits only apply to files.
+    pub fn matcher(&self) -> impl Fn(&FileMetadata) -> bool + '_ {
+        let include: Vec<Glob> = self
+            .include
+            .iter()
+            .map(|pattern| Glob::new(pattern))
+            .collect();
+        let exclude: Vec<Glob> = self
+            .exclude
+            .iter()
+            .map(|pattern| Glob::new(pattern))
+            .collect();
+
+        move |metadata| {
+            if metadata.entry_type == EntryType::File
+                && (self
+                    .min_size
+                    .is_some_and(|min_size| metadata.size.0 < min_size)
+                    || self
+                        .max_size
+                        .is_some_and(|max_size| metadata.size.0 > max_size))
+            {
+                return false;
+            }
+
+            (include.is_empty() || include.iter().any(|glob| glob.matches(&metadata.path)))
+                && !exclude.iter().any(|glob| glob.matches(&metadata.path))
+        }
+    }
+}
+
+/// One element of a glob pattern.
+enum Token {
+    Literal(char),
+
+    /// `?`, matching any character except `/`.
+    AnyChar,
+
+    /// `[...]`, matching any character except `/` that is (or, if negated, is not) within one of
+    /// the given inclusive ranges.
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+
+    /// `*`, matching any sequence of characters within one path component.
+    Star,
+
+    /// `**`, matching any sequence of characters, including `/`.
+    GlobStar,
+
+    /// `**/`, matching nothing, or any sequence of characters that ends with `/`, i.e. any number
+    /// of directories.
+    GlobStarSlash,
+}
+
+/// A compiled glob pattern, which is matched against whole paths.
+struct Glob {
+    tokens: Vec<Token>,
+}
+
+impl Glob {
+    /// Compiles the given pattern. Special characters can be escaped with `\`; a `[` without a
+    /// matching `]` is taken literally.
+    fn new(pattern: &str) -> Self {
+        let mut tokens = vec![];
+        let mut chars = pattern.chars().peekable();
+
+        while let Some(char) = chars.next() {
+            let token = match char {
+                '*' if chars.next_if_eq(&'*').is_some() => {
+                    if chars.next_if_eq(&'/').is_some() {
+                        Token::GlobStarSlash
+                    } else {
+                        Token::GlobStar
+                    }
+                }
+                '*' => Token::Star,
+                '?' => Token::AnyChar,
+                '[' => {
+                    let mut class = chars.clone();
+                    match parse_class(&mut class) {
+                        Some(token) => {
+                            chars = class;
+                            token
+                        }
+                        None => Token::Literal('['),
+                    }
+                }
+                '\\' => Token::Literal(chars.next().unwrap_or('\\')),
+                other => Token::Literal(other),
+            };
+            tokens.push(token);
+        }
+
+        Self { tokens }
+    }
+
+    /// Returns whether the given path matches the pattern as a whole. The positions in the path at
+    /// which the part of the pattern processed so far can end are tracked for each token, so that
+    /// the time taken only grows with the product of the pattern and path lengths.
+    fn matches(&self, path: &Path) -> bool {
+        let text: Vec<char> = path.to_string_lossy().chars().collect();
+        let mut current = vec![false; text.len().saturating_add(1)];
+        current[0] = true;
+
+        for token in &self.tokens {
+            let mut next = vec![false; current.len()];
+            match token {
+                Token::Literal(_) | Token::AnyChar | Token::Class { .. } => {
+                    for ((next, current), char) in next[1..].iter_mut().zip(&current).zip(&text) {
+                        *next = *current && token.matches_char(*char);
+                    }
+                }
+                Token::Star | Token::GlobStar => {
+                    let mut reachable = false;
+                    for (position, next) in next.iter_mut().enumerate() {
+                        reachable |= current[position];
+                        *next = reachable;
+                        if matches!(token, Token::Star) && text.get(position) == Some(&'/') {
+                            reachable = false;
+                        }
+                    }
+                }
+                Token::GlobStarSlash => {
+                    let mut reachable_before = false;
+                    let mut after_slash = false;
+                    for (position, next) in next.iter_mut().enumerate() {
+                        *next = current[position] || (reachable_before && after_slash);
+                        reachable_before |= current[position];
+                        after_slash = text.get(position) == Some(&'/');
+                    }
+                }
+            }
+            current = next;
+        }
+
+        current.last().copied().unwrap_or(false)
+    }
+}
+
+impl Token {
+    /// Returns whether a token that matches a single character matches the given one.
+    fn matches_char(&self, char: char) -> bool {
+        match self {
+            Self::Literal(literal) => char == *literal,
+            Self::AnyChar => char != '/',
+            Self::Class { negated, ranges } => {
+                char != '/'
+                    && ranges
+                        .iter()
+                        .any(|(first, last)| (*first..=*last).contains(&char))
+                        != *negated
+            }
+            Self::Star | Self::GlobStar | Self::GlobStarSlash => false,
+        }
+    }
+}
+
+/// Parses a character class following a `[`, up to and including the closing `]`. A `]` directly
+/// at the start of the class is taken literally. Returns `None` if the class is not closed.
+fn parse_class(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<Token> {
+    let negated = chars.next_if(|char| matches!(char, '!' | '^')).is_some();
+    let mut ranges = vec![];
+    let mut first = true;
+
+    loop {
+        let char = chars.next()?;
+        if char == ']' && !first {
+            return Some(Token::Class { negated, ranges });
+        }
+        first = false;
+
+        let start = if char == '\\' { chars.next()? } else { char };
+        let end = match chars.clone().next() {
+            Some('-') if chars.clone().nth(1).is_some_and(|end| end != ']') => {
+                chars.next();
+                chars.next()?
+            }
+            _ => start,
+        };
+        ranges.push((start, end));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::types::{EntryType, FileMetadata, FileSize};
+
+    use super::{FileFilter, Glob};
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        Glob::new(pattern).matches(path.as_ref())
+    }
+
+    fn entry(path: &str, size: u64, entry_type: EntryType) -> FileMetadata {
+        FileMetadata {
+            path: PathBuf::from(path),
+            size: FileSize(size),
+            entry_type,
+            mode: 0o644,
+            mtime: None,
+        }
+    }
+
+    #[test]
+    fn star_does_not_cross_slash() {
+        assert!(matches("*.h5", "data.h5"));
+        assert!(!matches("*.h5", "run/data.h5"));
+        assert!(matches("run/*.h5", "run/data.h5"));
+        assert!(!matches("run/*", "run/1/data.h5"));
+        assert!(matches("run/*/*", "run/1/data.h5"));
+        assert!(!matches("?", "/"));
+        assert!(matches("**", "run/1/data.h5"));
+    }
+
+    #[test]
+    fn globstar_slash_matches_any_number_of_directories() {
+        assert!(matches("**/*.h5", "data.h5"));
+        assert!(matches("**/*.h5", "run/data.h5"));
+        assert!(matches("**/*.h5", "run/1/2/data.h5"));
+        assert!(!matches("**/*.h5", "run/data.txt"));
+        assert!(matches("run/**/data.h5", "run/data.h5"));
+        assert!(matches("run/**/data.h5", "run/1/2/data.h5"));
+        assert!(!matches("run/**/data.h5", "running/data.h5"));
+        assert!(!matches("run/**/data.h5", "other/run/data.h5"));
+    }
+
+    #[test]
+    fn character_classes() {
+        assert!(matches("[a-c]", "b"));
+        assert!(!matches("[a-c]", "d"));
+        assert!(matches("[!a-c]", "d"));
+        assert!(!matches("[!a-c]", "a"));
+        assert!(!matches("[!a-c]", "/"));
+        assert!(matches("[^a-c]x", "dx"));
+
+        // a `]` at the start of a class is taken literally
+        assert!(matches("[]]", "]"));
+        assert!(matches("[]a]", "a"));
+        assert!(!matches("[]]", "a"));
+        assert!(matches("[!]]", "a"));
+
+        // a `-` at the end of a class is taken literally
+        assert!(matches("[a-]", "-"));
+
+        // a `[` that is not closed is taken literally
+        assert!(matches("[abc", "[abc"));
+        assert!(!matches("[abc", "a"));
+        assert!(matches("data[1", "data[1"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(matches(r"\*.h5", "*.h5"));
+        assert!(!matches(r"\*.h5", "data.h5"));
+        assert!(matches(r"what\?", "what?"));
+        assert!(!matches(r"what\?", "whats"));
+        assert!(matches(r"\[a]", "[a]"));
+        assert!(!matches(r"\[a]", "a"));
+        assert!(matches(r"[\]]", "]"));
+        assert!(matches(r"trailing\", r"trailing\"));
+    }
+
+    #[test]
+    fn size_limits_only_apply_to_files() {
+        let filter = FileFilter {
+            min_size: Some(100),
+            max_size: Some(1000),
+            ..FileFilter::default()
+        };
+        let matcher = filter.matcher();
+
+        assert!(!matcher(&entry("small", 99, EntryType::File)));
+        assert!(matcher(&entry("medium", 100, EntryType::File)));
+        assert!(matcher(&entry("medium", 1000, EntryType::File)));
+        assert!(!matcher(&entry("large", 1001, EntryType::File)));
+        assert!(matcher(&entry("dir", 0, EntryType::Directory)));
+        assert!(matcher(&entry(
+            "link",
+            0,
+            EntryType::Symlink(PathBuf::from("large"))
+        )));
+    }
+
+    #[test]
+    fn include_and_exclude() {
+        let filter = FileFilter {
+            include: vec!["**/*.h5".to_owned()],
+            exclude: vec!["tmp/**".to_owned()],
+            ..FileFilter::default()
+        };
+        let matcher = filter.matcher();
+
+        assert!(matcher(&entry("run/data.h5", 10, EntryType::File)));
+        assert!(!matcher(&entry("tmp/data.h5", 10, EntryType::File)));
+        assert!(!matcher(&entry("run/data.txt", 10, EntryType::File)));
+    }
+}
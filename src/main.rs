@@ -61,7 +61,6 @@
 #![warn(clippy::significant_drop_tightening)]
 #![warn(clippy::str_to_string)]
 #![warn(clippy::string_lit_chars_any)]
-#![warn(clippy::string_to_string)]
 #![warn(clippy::suboptimal_flops)]
 #![warn(clippy::suspicious_operation_groupings)]
 #![warn(clippy::suspicious_xor_used_as_pow)]
@@ -99,9 +98,15 @@ use clap::{Parser, Subcommand};
 
 pub mod client;
 pub mod common;
+pub mod compression;
 pub mod datagram;
+pub mod delta;
+pub mod fec;
+pub mod filter;
 pub mod message;
+pub mod rtt;
 pub mod server;
+pub mod stream;
 pub mod types;
 pub mod version;
 
@@ -121,6 +126,9 @@ enum Commands {
     /// Download one or more files from a namida server.
     Get(client::get::Parameter),
 
+    /// Upload one or more files to a namida server.
+    Put(client::put::Parameter),
+
     /// Start a namida server process, serving the specified files.
     Serve(server::Parameter),
 }
@@ -133,6 +141,9 @@ pub fn main() -> anyhow::Result<()> {
         Commands::Get(parameter) => {
             client::get::run(parameter)?;
         }
+        Commands::Put(parameter) => {
+            client::put::run(parameter)?;
+        }
         Commands::Dir(parameter) => {
             client::dir::run(parameter)?;
         }
@@ -1,7 +1,12 @@
 use std::{borrow::Cow, path::PathBuf, time::Duration};
 
-use crate::types::{
-    BlockIndex, ErrorRate, FileChecksums, FileMetadata, FileSize, Fraction, SkipChunks, TargetRate,
+use crate::{
+    delta::{BlockSignature, CopyInstruction},
+    filter::FileFilter,
+    types::{
+        BlockIndex, BlockRange, ChecksumAlgorithm, ChecksumRequest, Compression, ErrorRate,
+        FileChecksums, FileDigests, FileMetadata, FileSize, Fraction, ListOrder, TargetRate,
+    },
 };
 
 #[derive(Debug, bincode::Encode, bincode::Decode)]
@@ -9,9 +14,32 @@ pub enum ClientToServer {
     ProtocolRevision(u32),
     AuthenticationResponse([u8; 16]),
     FileRequest(FileRequest),
+    BatchRequest(BatchRequest),
     UdpInit(UdpMethod, bool),
-    SkipChunks(SkipChunks),
-    FileListRequest,
+    ChecksumRequest(ChecksumRequest),
+    SkipBlocks(Vec<BlockRange>),
+    ResumeComplete,
+
+    /// Signatures of the blocks of the previous version of the file, for a delta transfer.
+    DeltaSignatures(Vec<BlockSignature>),
+
+    /// Requests the server to search the file for the blocks whose signatures have been sent,
+    /// which have the given size.
+    DeltaRequest(u32),
+
+    /// Requests a page of the entries of the server's file index.
+    FileListRequest(ListRequest),
+    UploadRequest(UploadRequest),
+    MtuProbeReport(u16),
+    DigestRequest,
+    VerificationResult(bool),
+
+    /// Requests a token with which further connections can join this session, to transfer files
+    /// in parallel.
+    SessionTokenRequest,
+
+    /// Joins the session identified by the given token, instead of starting a new one.
+    JoinSession(u64),
     Close,
 }
 
@@ -22,23 +50,154 @@ pub enum ServerToClient {
     AuthenticationStatus(bool),
     FileRequestSuccess {
         file_size: FileSize,
+        block_size: u16,
         block_count: BlockIndex,
         epoch: Duration,
         udp_port: u16,
+
+        /// The modification time of the file, as time since the UNIX epoch, if available. Used to
+        /// recognise the file when resuming a transfer later.
+        mtime: Option<Duration>,
+
+        /// The digest of samples of the file's data (see `common::sample_digest`), if available.
+        /// Also used to recognise the file when resuming a transfer later, in case it has been
+        /// rewritten without changing its size and modification time.
+        sample_digest: Option<[u8; 32]>,
+
+        /// The algorithm blocks will be compressed with, which may differ from the one requested
+        /// if the server does not allow compression.
+        compression: Compression,
+
+        /// Identifies the transfer among the transfers of the session.
+        transfer_id: u32,
+
+        /// Whether the data is streamed from a pipe, so that its size is unknown up front. In that
+        /// case, `file_size` is zero, and `block_count` is `STREAM_BLOCK_COUNT` until the final
+        /// block has been received. The final block is sent uncompressed, and is shorter than the
+        /// block size, so that it reveals the size of the data.
+        streamed: bool,
+
+        /// The offset within the file at which the transferred data starts. If only a range of
+        /// the file has been requested, `file_size` is the size of that range, and blocks are
+        /// numbered from its start.
+        offset: u64,
     },
     FileRequestError(FileRequestError),
     UdpDone,
     Checksums(FileChecksums),
-    FileCount(u64),
+
+    /// The number of entries selected by a file list request, `total`, and the number of them
+    /// that follow as `FileListEntry` messages, `count`, which is limited to the requested page.
+    FileCount {
+        total: u64,
+        count: u64,
+    },
     FileListEntry(FileMetadata),
+    UploadRequestSuccess {
+        block_size: u16,
+        udp_port: u16,
+        resume: bool,
+    },
+    MtuProbesSent,
+    BlockSize(u16),
+    Digests(FileDigests),
+    DeltaCopies(Vec<CopyInstruction>),
+    DeltaComplete,
+
+    /// The token with which further connections can join the session, and the number of
+    /// connections the session may have in total.
+    SessionToken {
+        token: u64,
+        max_connections: u16,
+    },
+
+    /// The sizes of the files of a requested batch, which is sent before `FileRequestSuccess`.
+    BatchSizes(Vec<FileSize>),
+
+    /// Whether the session to be joined was found.
+    SessionJoined(bool),
+
+    /// A followed file has grown, or is finished, so that the transfer now covers the given size
+    /// and number of blocks. While the file may still grow, only its complete blocks are counted.
+    /// Once it is `complete`, the last block is counted as well; if it is shorter than the block
+    /// size, it is sent uncompressed and shortened, like the final block of streamed data.
+    FileGrown {
+        file_size: FileSize,
+        block_count: BlockIndex,
+        complete: bool,
+    },
+
+    /// Sent after the protocol revision has been negotiated, if the server has room for the
+    /// connection. If the server is already serving as many sessions as it may, the connection's
+    /// first request then waits in the server's queue, unless it joins a session that is already
+    /// being served.
+    Admitted,
+
+    /// Sent after the protocol revision has been negotiated, instead of `Admitted`, if the server
+    /// is serving as many connections as it may, in total or from the client's address. The client
+    /// should try again after the given number of seconds; the connection is closed.
+    Busy {
+        retry_after: u32,
+    },
+
+    /// The reply to a `TransmissionControl::Ping` during a transfer, echoing its timestamp, along
+    /// with a timestamp of the sending side, which the receiving side echoes in turn.
+    Pong {
+        echo: u32,
+        stamp: u32,
+    },
+}
+
+/// Selects which entries of the server's file index are listed, in which order, and which page of
+/// them is sent.
+#[derive(Debug, Clone, Default, bincode::Encode, bincode::Decode)]
+pub struct ListRequest {
+    pub filter: FileFilter,
+
+    /// Only list the entries within this directory, which is given the way it appears in the
+    /// index.
+    pub directory: Option<PathBuf>,
+
+    /// Only list the direct children of `directory`, or the served paths themselves if no
+    /// directory is given, instead of all entries below them.
+    pub shallow: bool,
+    pub order: ListOrder,
+    pub reverse: bool,
+
+    /// The number of selected entries to skip, after sorting them.
+    pub offset: u64,
+
+    /// The maximum number of entries to send, or `None` to send all of them.
+    pub limit: Option<u64>,
 }
 
 #[derive(Debug, bincode::Encode, bincode::Decode)]
 pub enum TransmissionControl {
     RestartAt(BlockIndex),
-    Retransmit(BlockIndex),
     SubmitErrorRate(ErrorRate),
 
+    /// All blocks up to and including the given one have been received. Only sent for streamed
+    /// transfers, whose data the sending side can only keep within a limited window.
+    Acknowledge(BlockIndex),
+
+    /// The rate (in kbit/s) at which the receiving side has received data since its previous
+    /// report. Used by congestion control algorithms that estimate the bottleneck bandwidth.
+    SubmitDeliveryRate(u32),
+
+    /// Retransmit the blocks of the given number of ranges, which immediately follow as a
+    /// `Vec<BlockRange>`. This replaces the ranges of any earlier request that have not been
+    /// retransmitted yet, as the receiving side always requests all blocks it is missing.
+    RetransmitRanges(u32),
+
+    /// A timestamp of the receiving side, which the sending side echoes in a
+    /// `ServerToClient::Pong`, so that the receiving side can measure the round trip time. Sent
+    /// periodically during the transfer.
+    Ping(u32),
+
+    /// The echo of the timestamp of the sending side in a `ServerToClient::Pong`, so that the
+    /// sending side can measure the round trip time as well.
+    Pong(u32),
+
     // Dummy values to ensure all enum variants have the same length
     RetransmitOver(u32),
     EndTransmission(u32),
@@ -68,6 +227,61 @@ pub struct FileRequest {
     pub error_rate: ErrorRate,
     pub slowdown: Fraction,
     pub speedup: Fraction,
+
+    /// The block size the client would like to use. The server may choose a different one. If
+    /// `None`, the block size is determined by probing the path MTU after the UDP address of the
+    /// receiving side is known, with the block size in the server's response being the upper
+    /// bound.
+    pub block_size: Option<u16>,
+
+    /// The algorithm the client would like to be used for the chunk-wise checksums that are
+    /// compared when resuming the transfer.
+    pub checksum_algorithm: ChecksumAlgorithm,
+
+    /// The algorithm the client would like blocks to be compressed with.
+    pub compression: Compression,
+
+    /// If present, the server sends a parity block for forward error correction after every
+    /// group of this many data blocks. The group size is adapted to the error rate reported by
+    /// the client as the transfer progresses.
+    pub fec_group_size: Option<u8>,
+
+    /// If present, the file is followed while it grows: the server keeps extending the transfer
+    /// as data is appended to the file, until the file is closed by its writer, or has not grown
+    /// for this many seconds.
+    pub follow_idle: Option<u32>,
+
+    /// If present, only the range of the file starting at this byte offset is transferred.
+    pub offset: Option<u64>,
+
+    /// If present, at most this many bytes of the file are transferred, starting at `offset`.
+    pub length: Option<u64>,
+}
+
+/// Requests the server to receive a file from the client. The roles of the transfer are reversed
+/// compared to a `FileRequest`: the client sends the data over UDP, and the server handles it like
+/// a client downloading a file would. The path in the contained `FileRequest` is the destination
+/// path, relative to the server's upload directory.
+#[derive(Debug, Clone, bincode::Encode, bincode::Decode)]
+pub struct UploadRequest {
+    pub request: FileRequest,
+    pub file_size: FileSize,
+    pub resume: bool,
+
+    /// The modification time of the local file, as time since the UNIX epoch, if available.
+    pub mtime: Option<Duration>,
+
+    /// The digest of samples of the local file's data (see `common::sample_digest`), if available.
+    pub sample_digest: Option<[u8; 32]>,
+}
+
+/// Requests the server to transmit the given files as one batch, i.e. as a single stream consisting
+/// of the contents of the files concatenated in order. The path in the contained `FileRequest` is
+/// ignored.
+#[derive(Debug, Clone, bincode::Encode, bincode::Decode)]
+pub struct BatchRequest {
+    pub request: FileRequest,
+    pub paths: Vec<PathBuf>,
 }
 
 #[derive(Debug, Copy, Clone, bincode::Encode, bincode::Decode)]
@@ -79,6 +293,9 @@ pub enum UdpMethod {
 #[derive(Debug, Copy, Clone, bincode::Encode, bincode::Decode)]
 pub enum FileRequestError {
     Nonexistent,
+    UploadsDisabled,
+    InvalidPath,
+    InvalidRange,
 }
 
 #[cfg(test)]
@@ -120,7 +337,7 @@ mod tests {
         );
         assert_eq!(
             bincode::encode_into_slice(
-                TransmissionControl::Retransmit(BlockIndex(0)),
+                TransmissionControl::SubmitErrorRate(ErrorRate(0)),
                 &mut slice,
                 crate::common::BINCODE_CONFIG,
             )?,
@@ -128,7 +345,39 @@ mod tests {
         );
         assert_eq!(
             bincode::encode_into_slice(
-                TransmissionControl::SubmitErrorRate(ErrorRate(0)),
+                TransmissionControl::Acknowledge(BlockIndex(0)),
+                &mut slice,
+                crate::common::BINCODE_CONFIG,
+            )?,
+            TransmissionControl::SIZE
+        );
+        assert_eq!(
+            bincode::encode_into_slice(
+                TransmissionControl::SubmitDeliveryRate(0),
+                &mut slice,
+                crate::common::BINCODE_CONFIG,
+            )?,
+            TransmissionControl::SIZE
+        );
+        assert_eq!(
+            bincode::encode_into_slice(
+                TransmissionControl::RetransmitRanges(0),
+                &mut slice,
+                crate::common::BINCODE_CONFIG,
+            )?,
+            TransmissionControl::SIZE
+        );
+        assert_eq!(
+            bincode::encode_into_slice(
+                TransmissionControl::Ping(0),
+                &mut slice,
+                crate::common::BINCODE_CONFIG,
+            )?,
+            TransmissionControl::SIZE
+        );
+        assert_eq!(
+            bincode::encode_into_slice(
+                TransmissionControl::Pong(0),
                 &mut slice,
                 crate::common::BINCODE_CONFIG,
             )?,
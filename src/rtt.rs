@@ -0,0 +1,165 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// Estimates the round trip time of a connection from echoed timestamps during a transfer. Like
+/// TCP (RFC 6298), it keeps a smoothed RTT, which follows the samples with a gain of 1/8, and an
+/// RTT variance, which follows their deviation from the smoothed RTT with a gain of 1/4.
+///
+/// Timestamps are the microseconds since the estimator has been created, truncated to 32 bits, so
+/// that they only need to be meaningful to the side that created them. They wrap around after about
+/// 71 minutes, which does not matter for measuring round trip times.
+pub struct RttEstimator {
+    epoch: Instant,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+}
+
+impl Default for RttEstimator {
+    fn default() -> Self {
+        Self {
+            epoch: Instant::now(),
+            srtt: None,
+            rttvar: Duration::ZERO,
+        }
+    }
+}
+
+impl RttEstimator {
+    /// The shortest time within which a reply is expected, which allows for the granularity of
+    /// the timers on both sides, even if the round trip time is much shorter.
+    pub const MIN_TIMEOUT: Duration = Duration::from_millis(1);
+
+    /// The longest time within which a reply is expected, like the upper bound of RFC 6298.
+    pub const MAX_TIMEOUT: Duration = Duration::from_mins(1);
+
+    /// Returns a timestamp for the current time, to be echoed by the other side.
+    #[must_use]
+    pub fn stamp(&self) -> u32 {
+        #[allow(clippy::cast_possible_truncation)]
+        let stamp = self.epoch.elapsed().as_micros() as u32;
+        stamp
+    }
+
+    /// Takes the round trip time since the given timestamp has been created as a sample, and
+    /// returns it.
+    pub fn update(&mut self, stamp: u32) -> Duration {
+        let sample = Duration::from_micros(u64::from(self.stamp().wrapping_sub(stamp)));
+        self.add_sample(sample);
+        sample
+    }
+
+    /// Updates the smoothed round trip time and its variance with the given sample.
+    fn add_sample(&mut self, sample: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample.mul_f64(0.5);
+            }
+            Some(srtt) => {
+                self.rttvar = self
+                    .rttvar
+                    .mul_f64(0.75)
+                    .saturating_add(srtt.abs_diff(sample).mul_f64(0.25));
+                self.srtt = Some(srtt.mul_f64(0.875).saturating_add(sample.mul_f64(0.125)));
+            }
+        }
+    }
+
+    /// Returns the smoothed round trip time, or `None` if there have not been any samples yet.
+    #[must_use]
+    pub const fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Returns the time within which a reply can be expected, i.e. the smoothed round trip time
+    /// plus four times its variance, but between `MIN_TIMEOUT` and `MAX_TIMEOUT`, or `None` if
+    /// there have not been any samples yet.
+    #[must_use]
+    pub fn timeout(&self) -> Option<Duration> {
+        self.srtt.map(|srtt| {
+            srtt.saturating_add(self.rttvar.saturating_mul(4))
+                .clamp(Self::MIN_TIMEOUT, Self::MAX_TIMEOUT)
+        })
+    }
+}
+
+/// Shows the smoothed round trip time and its variance in milliseconds, as columns of a statistics
+/// line.
+impl fmt::Display for RttEstimator {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self.srtt {
+            Some(srtt) => write!(
+                formatter,
+                "{:7.2} {:6.2}",
+                srtt.as_secs_f64() * 1000.0,
+                self.rttvar.as_secs_f64() * 1000.0
+            ),
+            None => write!(formatter, "{:>7} {:>6}", "n/a", "n/a"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::RttEstimator;
+
+    const fn millis(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn first_sample_sets_estimate() {
+        let mut rtt = RttEstimator::default();
+        assert_eq!(rtt.srtt(), None);
+        assert_eq!(rtt.timeout(), None);
+
+        rtt.add_sample(millis(100));
+        assert_eq!(rtt.srtt(), Some(millis(100)));
+        assert_eq!(rtt.rttvar, millis(50));
+        assert_eq!(rtt.timeout(), Some(millis(300)));
+    }
+
+    #[test]
+    fn later_samples_are_smoothed() {
+        let mut rtt = RttEstimator::default();
+        rtt.add_sample(millis(100));
+        rtt.add_sample(millis(180));
+
+        // srtt = 7/8 * 100 + 1/8 * 180, rttvar = 3/4 * 50 + 1/4 * |100 - 180|
+        assert_eq!(rtt.srtt(), Some(millis(110)));
+        assert_eq!(rtt.rttvar, Duration::from_micros(57_500));
+
+        // a steady round trip time makes the variance decay
+        for _ in 0..100 {
+            rtt.add_sample(millis(110));
+        }
+        let srtt = rtt.srtt().expect("estimate missing");
+        assert!(srtt.abs_diff(millis(110)) < Duration::from_micros(1));
+        assert!(rtt.rttvar < Duration::from_micros(1));
+    }
+
+    #[test]
+    fn timeout_is_bounded() {
+        let mut rtt = RttEstimator::default();
+        rtt.add_sample(Duration::from_micros(20));
+        assert_eq!(rtt.timeout(), Some(RttEstimator::MIN_TIMEOUT));
+
+        let mut rtt = RttEstimator::default();
+        rtt.add_sample(Duration::from_secs(40));
+        assert_eq!(rtt.timeout(), Some(RttEstimator::MAX_TIMEOUT));
+    }
+
+    #[test]
+    fn update_measures_time_since_stamp() {
+        let mut rtt = RttEstimator::default();
+        let stamp = rtt.stamp();
+        std::thread::sleep(millis(5));
+        let sample = rtt.update(stamp);
+        assert!(sample >= millis(5));
+        assert_eq!(rtt.srtt(), Some(sample));
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, TcpStream},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{common::SocketWrapper, message::ServerToClient};
+
+use super::Parameter;
+
+/// Decides which connections are served, so that only a limited number of sessions run at a time,
+/// and a single client cannot take up all of them. Connections beyond the limit wait in a queue of
+/// limited length for a session to become free; once the queue is full, or a client has as many
+/// connections as it may, further connections are told to retry later.
+pub struct Admission {
+    limit: u32,
+    max_per_ip: Option<u32>,
+
+    /// The number of connections that are being served or are queued, in total and by address.
+    connections: Mutex<(u32, HashMap<IpAddr, u32>)>,
+}
+
+/// Counts a connection as admitted in its `Admission` while it exists.
+pub struct Ticket {
+    admission: Arc<Admission>,
+    address: IpAddr,
+}
+
+impl Admission {
+    /// # Panics
+    /// Panics on arithmetic overflow.
+    #[must_use]
+    pub fn new(parameter: &Parameter) -> Arc<Self> {
+        Arc::new(Self {
+            limit: u32::from(parameter.max_sessions)
+                .checked_add(u32::from(parameter.session_queue))
+                .expect("connection limit overflow"),
+            max_per_ip: parameter.max_per_ip.map(u32::from),
+            connections: Mutex::default(),
+        })
+    }
+
+    /// Admits a connection from the given address, if there is room for it.
+    ///
+    /// # Panics
+    /// Panics if the lock has been poisoned, or on arithmetic overflow.
+    #[must_use]
+    pub fn admit(self: &Arc<Self>, address: IpAddr) -> Option<Ticket> {
+        let mut connections = self.connections.lock().expect("admission lock poisoned");
+        let (total, per_ip) = &mut *connections;
+
+        let from_address = per_ip.entry(address).or_default();
+        if *total >= self.limit || self.max_per_ip.is_some_and(|max| *from_address >= max) {
+            if *from_address == 0 {
+                per_ip.remove(&address);
+            }
+            return None;
+        }
+
+        *from_address = from_address
+            .checked_add(1)
+            .expect("connection count overflow");
+        *total = total.checked_add(1).expect("connection count overflow");
+        drop(connections);
+
+        Some(Ticket {
+            admission: Arc::clone(self),
+            address,
+        })
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut connections = self
+            .admission
+            .connections
+            .lock()
+            .expect("admission lock poisoned");
+        let (total, per_ip) = &mut *connections;
+
+        *total = total.saturating_sub(1);
+        if let Some(from_address) = per_ip.get_mut(&self.address) {
+            *from_address = from_address.saturating_sub(1);
+            if *from_address == 0 {
+                per_ip.remove(&self.address);
+            }
+        }
+        drop(connections);
+    }
+}
+
+/// Tells the client of a connection that has not been admitted to retry after the given number of
+/// seconds. This is done once the protocol revision has been negotiated, but before
+/// authenticating, so that refusing clients is cheap.
+///
+/// # Errors
+/// Returns an error on I/O failure, or if the protocol negotiation failed.
+pub fn refuse(socket: TcpStream, parameter: &Parameter) -> anyhow::Result<()> {
+    // a client that does not respond must not hold up the clients refused after it
+    let timeout = Some(Duration::from_secs(super::config::REFUSAL_TIMEOUT_SECS));
+    socket.set_read_timeout(timeout)?;
+    socket.set_write_timeout(timeout)?;
+
+    let mut client = SocketWrapper::new(socket);
+    super::protocol::negotiate(&mut client, parameter)?;
+    client.write(ServerToClient::Busy {
+        retry_after: parameter.retry_after,
+    })?;
+
+    Ok(())
+}
@@ -5,3 +5,61 @@ pub const DEFAULT_VERBOSE_YN: u8 = 1;
 pub const DEFAULT_TRANSCRIPT_YN: u8 = 0;
 pub const DEFAULT_IPV6_YN: u8 = 0;
 pub const DEFAULT_HEARTBEAT_TIMEOUT: u16 = 15;
+
+/// The default number of sessions that are served at the same time.
+pub const DEFAULT_MAX_SESSIONS: u16 = 64;
+
+/// The default number of connections a single session may have.
+pub const DEFAULT_MAX_SESSION_CONNECTIONS: u16 = 16;
+
+/// The default number of connections that wait for a session to become free, before further
+/// connections are refused.
+pub const DEFAULT_SESSION_QUEUE: u16 = 64;
+
+/// The default number of seconds after which refused clients are told to try again.
+pub const DEFAULT_RETRY_AFTER: u32 = 10;
+
+/// The number of refused connections that wait for being told so. Further connections are closed
+/// right away.
+pub const REFUSAL_QUEUE: usize = 16;
+
+/// How long to wait for a refused client to negotiate the protocol revision, so that it can be
+/// told to retry later.
+pub const REFUSAL_TIMEOUT_SECS: u64 = 5;
+
+/// How long to wait for an admitted client to authenticate and send its first request, before it
+/// is queued for a session or joins one.
+pub const GREETING_TIMEOUT_SECS: u64 = 30;
+
+/// How long to wait for the remainder of a control request that has started arriving during a
+/// transfer, or for a control message to be written to the client, before giving up on it.
+pub const CONTROL_TIMEOUT_SECS: u64 = 10;
+
+/// The default for the largest block size clients may request: a datagram with a block of this
+/// size, plus headers and encryption overhead, fits into a 9000 byte jumbo frame.
+pub const DEFAULT_MAX_BLOCK_SIZE: u16 = 8_922;
+
+/// The link MTUs we try when probing the path MTU, in decreasing order.
+pub const MTU_PROBE_SIZES: [u16; 6] = [u16::MAX, 9_000, 4_470, 1_500, 1_492, 1_280];
+
+/// How often each probe datagram is sent, to be robust against ordinary packet loss.
+pub const MTU_PROBE_REPETITIONS: u32 = 3;
+
+/// How much of the data streamed from standard input is buffered beyond the data the client has
+/// acknowledged, so that lost blocks can be retransmitted.
+pub const STREAM_WINDOW: u64 = 64 * 1024 * 1024;
+
+/// How long to wait before checking again whether more streamed data may be sent, if the client
+/// has not acknowledged enough of the data sent so far.
+pub const STREAM_WAIT_US: u64 = 1_000;
+
+/// How often a followed file is checked for having grown.
+pub const FOLLOW_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// How long to wait before checking again whether a followed file has grown, once all of its data
+/// has been sent.
+pub const FOLLOW_WAIT_US: u64 = 1_000;
+
+/// How long to collect file system changes before applying them to a watched index, so that a
+/// burst of changes, e.g. while a file is written, only updates the index once.
+pub const WATCH_BATCH_INTERVAL: std::time::Duration = std::time::Duration::from_millis(200);
@@ -0,0 +1,366 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::types::ErrorRate;
+
+use super::Properties;
+
+/// The algorithm that controls the rate at which the blocks of a transfer are sent.
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+pub enum CongestionControl {
+    /// The algorithm of the original Tsunami: the receiving side reports an error rate, which
+    /// combines its losses with how full its buffer is. While it is above the threshold requested
+    /// by the client, the inter-packet delay is increased by the client's `slower` fraction, and
+    /// otherwise decreased by its `faster` fraction.
+    #[default]
+    Tsunami,
+
+    /// A BBR-like algorithm: the bottleneck bandwidth is estimated from the rates at which the
+    /// receiving side has recently received data, and data is sent at about that rate, probing for
+    /// more bandwidth periodically. It backs off when the round trip time rises well above its
+    /// minimum, i.e. when queues build up along the path, instead of waiting for losses, which
+    /// makes it fairer to TCP traffic.
+    Bbr,
+}
+
+impl CongestionControl {
+    /// Creates a controller for a new transfer.
+    #[must_use]
+    pub fn controller(self) -> Box<dyn Controller> {
+        match self {
+            Self::Tsunami => Box::new(Tsunami),
+            Self::Bbr => Box::<Bbr>::default(),
+        }
+    }
+}
+
+/// Decides how fast a transfer is sent, based on the feedback of the receiving side. The rate is
+/// expressed as the inter-packet delay (IPD) in microseconds. Each method is given the current IPD,
+/// and returns the new one, which the caller keeps within the limits of the session.
+pub trait Controller: Send {
+    /// Called with each error rate reported by the receiving side, and with an error rate of
+    /// 100 000 if the receiving side has not been heard from for a while.
+    fn on_error_rate(&mut self, properties: &Properties, ipd: f64, error_rate: ErrorRate) -> f64;
+
+    /// Called with the rate (in bits per second) at which the receiving side has received data
+    /// since its previous report.
+    fn on_delivery_rate(&mut self, _properties: &Properties, ipd: f64, _rate: f64) -> f64 {
+        ipd
+    }
+
+    /// Called with each round trip time sample of the control connection, i.e. the time from
+    /// replying to a ping of the receiving side until it has echoed the reply.
+    fn on_rtt(&mut self, _properties: &Properties, ipd: f64, _rtt: Duration) -> f64 {
+        ipd
+    }
+}
+
+/// See `CongestionControl::Tsunami`.
+pub struct Tsunami;
+
+impl Controller for Tsunami {
+    fn on_error_rate(&mut self, properties: &Properties, ipd: f64, error_rate: ErrorRate) -> f64 {
+        if error_rate > properties.error_rate {
+            let factor1: f64 = 1.0_f64 * f64::from(properties.slower.numerator)
+                / f64::from(properties.slower.denominator)
+                - 1.0_f64;
+            let factor2: f64 = (1.0_f64 + f64::from(error_rate.0)
+                - f64::from(properties.error_rate.0))
+                / (100_000.0_f64 - f64::from(properties.error_rate.0));
+            ipd * factor1.mul_add(factor2, 1.0_f64)
+        } else {
+            ipd * f64::from(properties.faster.numerator) / f64::from(properties.faster.denominator)
+        }
+    }
+}
+
+/// The largest inter-packet delay (in microseconds) a transfer is slowed down to, unless the
+/// session's smallest one is even larger.
+const MAX_IPD: f64 = 10_000.0;
+
+/// Returns the given inter-packet delay, kept within the range allowed for a session whose smallest
+/// one is `min_ipd`.
+#[must_use]
+pub fn clamp_ipd(ipd: f64, min_ipd: f64) -> f64 {
+    ipd.clamp(min_ipd, min_ipd.max(MAX_IPD))
+}
+
+/// The phases of the BBR-like controller.
+#[derive(Default)]
+enum Phase {
+    /// The rate is doubled with every report, until the bandwidth estimate stops growing, or
+    /// queues start to build up.
+    #[default]
+    Startup,
+
+    /// The rate is kept below the bandwidth estimate for one report, to drain the queues built up
+    /// during startup.
+    Drain,
+
+    /// The rate follows the bandwidth estimate, cycling through the gains of `PROBE_GAINS`.
+    ProbeBandwidth(usize),
+}
+
+/// See `CongestionControl::Bbr`.
+#[derive(Default)]
+pub struct Bbr {
+    phase: Phase,
+
+    /// The most recent delivery rate samples, the largest of which is the bandwidth estimate.
+    delivery_rates: VecDeque<f64>,
+
+    /// The bandwidth estimate during startup the last time it has grown significantly, and the
+    /// number of reports since then.
+    startup_best: f64,
+    startup_stalls: u32,
+
+    /// The smallest round trip time seen recently, and when it has been seen.
+    min_rtt: Option<(Duration, Instant)>,
+
+    /// The most recent round trip time sample.
+    rtt: Option<Duration>,
+}
+
+/// The gains applied to the bandwidth estimate in turn while probing for more bandwidth: one
+/// report probes above it, the next one drains the queue this may have built up.
+const PROBE_GAINS: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+
+/// The number of delivery rate samples the bandwidth estimate is taken from. Reports are sent about
+/// three times per second.
+const BANDWIDTH_WINDOW: usize = 10;
+
+/// How long the minimum round trip time is kept, before it is taken from newer samples again, so
+/// that route changes are noticed.
+const MIN_RTT_WINDOW: Duration = Duration::from_secs(10);
+
+/// The gain applied to the bandwidth estimate during startup.
+const STARTUP_GAIN: f64 = 2.0;
+
+/// The gain applied to the bandwidth estimate while draining the queue built up during startup.
+const DRAIN_GAIN: f64 = 0.75;
+
+/// The gain applied to the bandwidth estimate while queues are building up.
+const QUEUEING_GAIN: f64 = 0.9;
+
+impl Bbr {
+    /// Returns whether queues are building up along the path, i.e. the round trip time is well
+    /// above its minimum.
+    fn queueing(&self) -> bool {
+        let (Some(rtt), Some((min_rtt, _))) = (self.rtt, self.min_rtt) else {
+            return false;
+        };
+        let allowed = min_rtt.mul_f64(0.5).max(Duration::from_millis(5));
+        rtt.saturating_sub(min_rtt) > allowed
+    }
+}
+
+impl Controller for Bbr {
+    fn on_error_rate(&mut self, _properties: &Properties, ipd: f64, error_rate: ErrorRate) -> f64 {
+        // losses are not taken into account, unless nothing seems to get through anymore
+        if error_rate.0 >= 100_000 {
+            ipd * 2.0
+        } else {
+            ipd
+        }
+    }
+
+    fn on_delivery_rate(&mut self, properties: &Properties, ipd: f64, rate: f64) -> f64 {
+        if self.delivery_rates.len() == BANDWIDTH_WINDOW {
+            self.delivery_rates.pop_front();
+        }
+        self.delivery_rates.push_back(rate);
+        let bandwidth = self.delivery_rates.iter().copied().fold(0.0, f64::max);
+        if bandwidth <= 0.0 {
+            return ipd;
+        }
+
+        let queueing = self.queueing();
+        let gain = match self.phase {
+            Phase::Startup => {
+                if bandwidth >= self.startup_best * 1.25 {
+                    self.startup_best = bandwidth;
+                    self.startup_stalls = 0;
+                } else {
+                    self.startup_stalls = self.startup_stalls.saturating_add(1);
+                }
+                if queueing || self.startup_stalls >= 3 {
+                    self.phase = Phase::Drain;
+                    DRAIN_GAIN
+                } else {
+                    STARTUP_GAIN
+                }
+            }
+            Phase::Drain => {
+                self.phase = Phase::ProbeBandwidth(0);
+                PROBE_GAINS[0]
+            }
+            Phase::ProbeBandwidth(cycle) => {
+                let cycle = cycle
+                    .wrapping_add(1)
+                    .checked_rem(PROBE_GAINS.len())
+                    .unwrap_or(0);
+                self.phase = Phase::ProbeBandwidth(cycle);
+                PROBE_GAINS[cycle]
+            }
+        };
+        let gain = if queueing && !matches!(self.phase, Phase::Startup) {
+            gain.min(QUEUEING_GAIN)
+        } else {
+            gain
+        };
+
+        // convert the rate into the delay between blocks
+        f64::from(properties.block_size) * 8_000_000.0 / (gain * bandwidth)
+    }
+
+    fn on_rtt(&mut self, _properties: &Properties, ipd: f64, rtt: Duration) -> f64 {
+        let now = Instant::now();
+        let expired = self.min_rtt.is_none_or(|(min_rtt, seen)| {
+            rtt <= min_rtt || now.duration_since(seen) > MIN_RTT_WINDOW
+        });
+        if expired {
+            self.min_rtt = Some((rtt, now));
+        }
+        self.rtt = Some(rtt);
+
+        ipd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{clamp_ipd, Bbr, Controller};
+    use crate::{server::Properties, types::ErrorRate};
+
+    /// Returns the inter-packet delay (in microseconds) of 1000 byte blocks at the given rate (in
+    /// bits per second).
+    fn ipd(rate: f64) -> f64 {
+        1000.0 * 8_000_000.0 / rate
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "{actual} is not {expected}"
+        );
+    }
+
+    fn properties() -> Properties {
+        Properties {
+            block_size: 1000,
+            ..Properties::default()
+        }
+    }
+
+    #[test]
+    fn clamp_ipd_keeps_session_range() {
+        assert_close(clamp_ipd(50.0, 100.0), 100.0);
+        assert_close(clamp_ipd(500.0, 100.0), 500.0);
+        assert_close(clamp_ipd(20_000.0, 100.0), 10_000.0);
+
+        // sessions slower than the largest IPD keep their smallest one
+        assert_close(clamp_ipd(5_000.0, 15_000.0), 15_000.0);
+        assert_close(clamp_ipd(20_000.0, 15_000.0), 15_000.0);
+    }
+
+    #[test]
+    fn bbr_phases() {
+        let properties = properties();
+        let mut bbr = Bbr::default();
+        let rate = 10_000_000.0;
+
+        // startup doubles the rate until the bandwidth estimate stops growing for three reports
+        for _ in 0..3 {
+            assert_close(
+                bbr.on_delivery_rate(&properties, 0.0, rate),
+                ipd(2.0 * rate),
+            );
+        }
+        assert_close(
+            bbr.on_delivery_rate(&properties, 0.0, rate),
+            ipd(0.75 * rate),
+        );
+
+        // then it cycles through the probing gains
+        for gain in [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.25, 0.75] {
+            assert_close(
+                bbr.on_delivery_rate(&properties, 0.0, rate),
+                ipd(gain * rate),
+            );
+        }
+    }
+
+    #[test]
+    fn bbr_bandwidth_estimate_is_recent_maximum() {
+        let properties = properties();
+        let mut bbr = Bbr::default();
+
+        // grows during startup
+        assert_close(bbr.on_delivery_rate(&properties, 0.0, 1e6), ipd(2e6));
+        assert_close(bbr.on_delivery_rate(&properties, 0.0, 2e6), ipd(4e6));
+        for _ in 0..3 {
+            bbr.on_delivery_rate(&properties, 0.0, 1e6);
+        }
+
+        // the largest of the last ten samples is kept, also when smaller ones follow
+        for gain in [1.25, 0.75, 1.0, 1.0, 1.0, 1.0] {
+            assert_close(bbr.on_delivery_rate(&properties, 0.0, 1e6), ipd(gain * 2e6));
+        }
+        assert_close(bbr.on_delivery_rate(&properties, 0.0, 1e6), ipd(1e6));
+    }
+
+    #[test]
+    fn bbr_backs_off_when_queueing() {
+        let properties = properties();
+        let mut bbr = Bbr::default();
+        let rate = 10_000_000.0;
+
+        bbr.on_rtt(&properties, 0.0, Duration::from_millis(20));
+        assert_close(
+            bbr.on_delivery_rate(&properties, 0.0, rate),
+            ipd(2.0 * rate),
+        );
+
+        // a round trip time well above the minimum ends startup...
+        bbr.on_rtt(&properties, 0.0, Duration::from_millis(50));
+        assert_close(
+            bbr.on_delivery_rate(&properties, 0.0, rate),
+            ipd(0.75 * rate),
+        );
+
+        // ...and keeps the rate below the bandwidth estimate while probing
+        assert_close(
+            bbr.on_delivery_rate(&properties, 0.0, rate),
+            ipd(0.9 * rate),
+        );
+
+        // until the queues have drained
+        bbr.on_rtt(&properties, 0.0, Duration::from_millis(25));
+        assert_close(
+            bbr.on_delivery_rate(&properties, 0.0, rate),
+            ipd(0.75 * rate),
+        );
+        assert_close(bbr.on_delivery_rate(&properties, 0.0, rate), ipd(rate));
+    }
+
+    #[test]
+    fn bbr_error_rate() {
+        let properties = properties();
+        let mut bbr = Bbr::default();
+        assert_close(
+            bbr.on_error_rate(&properties, 100.0, ErrorRate(50_000)),
+            100.0,
+        );
+        assert_close(
+            bbr.on_error_rate(&properties, 100.0, ErrorRate(100_000)),
+            200.0,
+        );
+
+        // no samples leave the IPD unchanged
+        assert_close(bbr.on_delivery_rate(&properties, 100.0, 0.0), 100.0);
+    }
+}
@@ -0,0 +1,97 @@
+use std::{
+    fs::File,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use inotify::{Inotify, WatchMask};
+
+use crate::types::FileSize;
+
+/// Keeps track of a file that is being transferred while it is still growing. The file is
+/// considered finished once it has been closed by a writer, or once it has not grown for the idle
+/// time requested by the client.
+pub struct Follow {
+    idle_time: Duration,
+    last_growth: Instant,
+    last_poll: Instant,
+    size: FileSize,
+    finished: bool,
+
+    /// Notifies us when a writer closes the file. If it cannot be set up, only the idle time is
+    /// used to decide when the file is finished.
+    inotify: Option<Inotify>,
+}
+
+impl Follow {
+    /// Starts following the file at the given path, which currently has the given size.
+    #[must_use]
+    pub fn new(path: &Path, size: FileSize, idle_time: Duration) -> Self {
+        let inotify = Inotify::init().and_then(|inotify| {
+            inotify.watches().add(path, WatchMask::CLOSE_WRITE)?;
+            Ok(inotify)
+        });
+        let inotify = match inotify {
+            Ok(inotify) => Some(inotify),
+            Err(err) => {
+                println!(
+                    "WARNING: Cannot watch '{}' for being closed, only its idle time will be used: {err}",
+                    path.display()
+                );
+                None
+            }
+        };
+
+        let now = Instant::now();
+        Self {
+            idle_time,
+            last_growth: now,
+            last_poll: now,
+            size,
+            finished: false,
+            inotify,
+        }
+    }
+
+    /// Returns whether the file is finished, so that it will not be checked for growth anymore.
+    #[must_use]
+    pub const fn finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Checks whether the given file has grown, or is finished, unless it has been checked only
+    /// recently. Returns its current size if it has grown, or has just become finished.
+    ///
+    /// # Errors
+    /// Returns an error on I/O failure.
+    pub fn poll(&mut self, file: &File) -> anyhow::Result<Option<FileSize>> {
+        if self.finished || self.last_poll.elapsed() < super::config::FOLLOW_POLL_INTERVAL {
+            return Ok(None);
+        }
+        self.last_poll = Instant::now();
+
+        // look for close events before determining the size, so that the size includes everything
+        // that has been written before the file was closed
+        let mut closed = false;
+        if let Some(inotify) = self.inotify.as_mut() {
+            let mut buffer = [0_u8; 1024];
+            match inotify.read_events(&mut buffer) {
+                Ok(mut events) => closed = events.next().is_some(),
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        // data that has been sent cannot be taken back, so a file that shrinks is not followed
+        // any further until it has grown beyond its previous size again
+        let size = FileSize(file.metadata()?.len());
+        let grown = size.0 > self.size.0;
+        if grown {
+            self.size = size;
+            self.last_growth = self.last_poll;
+        }
+
+        self.finished = closed || self.last_growth.elapsed() >= self.idle_time;
+        Ok((grown || self.finished).then_some(self.size))
+    }
+}
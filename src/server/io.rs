@@ -2,19 +2,22 @@ use std::{
     borrow::Cow,
     fs::DirEntry,
     io::{Read, Seek, SeekFrom},
-    path::PathBuf,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
 };
 
 use crate::{
     datagram::{self, BlockType},
-    types::{BlockIndex, FileMetadata, FileSize},
+    stream::Stream,
+    types::{BlockIndex, Compression, EntryType, FileMetadata, FileSize},
 };
 
 use super::Session;
 
 /// Tries to read the given block from the currently open file. If successful, a datagram view is
 /// created based on the data read. The caller must supply a buffer exactly big enough to fit one
-/// block.
+/// block. If compression has been negotiated and the block becomes smaller by compressing it, the
+/// compressed data replaces the block in the buffer, and the view only covers the compressed data.
 ///
 /// # Errors
 /// Returns an error on I/O failure.
@@ -27,7 +30,10 @@ pub fn build_datagram<'a>(
     block_type: BlockType,
     block_buffer: &'a mut [u8],
 ) -> anyhow::Result<datagram::View<'a>> {
-    assert_eq!(block_buffer.len(), crate::common::BLOCK_SIZE as usize);
+    assert_eq!(
+        block_buffer.len(),
+        usize::from(session.properties.block_size)
+    );
 
     // move the file pointer to the appropriate location
     let file = session
@@ -36,52 +42,197 @@ pub fn build_datagram<'a>(
         .as_mut()
         .expect("a file should be present");
     file.seek(SeekFrom::Start(
-        u64::from(crate::common::BLOCK_SIZE)
+        u64::from(session.properties.block_size)
             .checked_mul(u64::from((block_index.safe_sub(BlockIndex(1))).0))
             .expect("file position overflow"),
     ))?;
 
-    // try to read in the block
-    let read_amount = file.read(block_buffer)?;
-    if read_amount < crate::common::BLOCK_SIZE as usize
+    // try to read in the block. The data of a batch may have to be read from several files.
+    let mut read_amount = 0;
+    while read_amount < block_buffer.len() {
+        match file.read(&mut block_buffer[read_amount..])? {
+            0 => break,
+            read_count => {
+                read_amount = read_amount
+                    .checked_add(read_count)
+                    .expect("read amount overflow");
+            }
+        }
+    }
+    // The end of streamed data is only found when reading it. Its final block is the first one
+    // that is not complete; it is sent uncompressed and shortened, so that the receiving side can
+    // tell the size of the data from it.
+    if let Some(Stream::Pipe(pipe)) = session.transfer.file.as_ref() {
+        if let Some(size) = pipe.size() {
+            if read_amount < block_buffer.len() {
+                session.properties.file_size = FileSize(size);
+                session.properties.block_count = block_index;
+                let block_type = match block_type {
+                    BlockType::Original => BlockType::Final,
+                    other => other,
+                };
+                return Ok(datagram::View {
+                    header: datagram::Header {
+                        block_index,
+                        block_type,
+                        compression: Compression::None,
+                        group_size: 0,
+                    },
+                    block: &block_buffer[..read_amount],
+                });
+            }
+        }
+    }
+
+    // Similarly, the receiving side only learns the final size of a followed file during the
+    // transfer, so its last block is sent the same way if it is incomplete
+    if session.transfer.follow.is_some() && block_index == session.properties.block_count {
+        let block_start = u64::from(session.properties.block_size)
+            .checked_mul(u64::from(block_index.safe_sub(BlockIndex(1)).0))
+            .expect("file position overflow");
+        let block_len = session.properties.file_size.0.saturating_sub(block_start);
+        if block_len < u64::from(session.properties.block_size) {
+            return Ok(datagram::View {
+                header: datagram::Header {
+                    block_index,
+                    block_type,
+                    compression: Compression::None,
+                    group_size: 0,
+                },
+                block: &block_buffer[..usize::try_from(block_len)?],
+            });
+        }
+    }
+
+    if read_amount < usize::from(session.properties.block_size)
         && block_index < session.properties.block_count
     {
         println!(
             "WARNING: only read {} instead of {} bytes for block {} out of {}",
             read_amount,
-            crate::common::BLOCK_SIZE,
+            session.properties.block_size,
             block_index.0,
             session.properties.block_count.0
         );
     }
 
+    // include the block in the parity of its group, unless it is retransmitted
+    if let Some(encoder) = session.transfer.fec.as_mut() {
+        if matches!(block_type, BlockType::Original | BlockType::Final) {
+            encoder.add(
+                block_index,
+                block_buffer,
+                block_index == session.properties.block_count,
+            );
+        }
+    }
+
     // build the datagram & return success
+    let (compression, block_len) = compress_block(session, block_buffer);
     Ok(datagram::View {
         header: datagram::Header {
             block_index,
             block_type,
+            compression,
+            group_size: 0,
         },
-        block: block_buffer,
+        block: &block_buffer[..block_len],
     })
 }
 
+/// Builds a datagram containing the parity block of the group that has been completed by the
+/// block most recently built using `build_datagram`, if any. The caller must supply a buffer
+/// exactly big enough to fit one block.
+///
+/// # Panics
+/// Panics if the buffer does not have the size of one block.
+pub fn build_parity_datagram<'a>(
+    session: &mut Session,
+    block_buffer: &'a mut [u8],
+) -> Option<datagram::View<'a>> {
+    let (block_index, group_size, parity) = session.transfer.fec.as_mut()?.take_parity()?;
+    block_buffer.copy_from_slice(parity);
+
+    let (compression, block_len) = compress_block(session, block_buffer);
+    Some(datagram::View {
+        header: datagram::Header {
+            block_index,
+            block_type: BlockType::Parity,
+            compression,
+            group_size,
+        },
+        block: &block_buffer[..block_len],
+    })
+}
+
+/// Compresses the block in the given buffer, if compression has been negotiated and the block
+/// becomes smaller by compressing it. The compressed data replaces the beginning of the buffer.
+/// Returns the compression algorithm used, and the length of the resulting block.
+fn compress_block(session: &mut Session, block_buffer: &mut [u8]) -> (Compression, usize) {
+    if let Some(compressor) = session.transfer.compressor.as_mut() {
+        let algorithm = compressor.algorithm();
+        if let Some(compressed) = compressor.compress(block_buffer) {
+            let block_len = compressed.len();
+            block_buffer[..block_len].copy_from_slice(compressed);
+            return (algorithm, block_len);
+        }
+    }
+
+    (Compression::None, block_buffer.len())
+}
+
 /// Recursively index files and subdirectories, starting with the given initial list of
 /// files/directories. The resulting file metadata objects will be stored in the given `Vec`.
+///
+/// Directories are listed themselves, before their contents, so that empty directories are
+/// included. Symbolic links within directories are listed as such, without following them; the
+/// given initial paths are followed if they are symbolic links.
 pub fn index_files(paths: &[PathBuf], files: &mut Vec<FileMetadata>) {
-    index_files_internal(paths.iter().map(Cow::Borrowed), files);
+    index_files_internal(
+        paths.iter().map(|path| Cow::Borrowed(path.as_path())),
+        files,
+        true,
+        true,
+    );
+}
+
+/// Indexes the entries within the given directory, without the directory itself. Subdirectories
+/// are only indexed as well if `recursive` is set. Symbolic links are not followed.
+pub fn index_directory(directory: &Path, recursive: bool, files: &mut Vec<FileMetadata>) {
+    match std::fs::read_dir(directory) {
+        Ok(read_dir) => {
+            let paths = read_dir
+                .zip(std::iter::repeat(Cow::Borrowed(directory)))
+                .filter_map(entry_filter_map_func);
+            index_files_internal(paths, files, false, recursive);
+        }
+        Err(err) => {
+            eprintln!(
+                "Could not index directory: '{}', error: {err}",
+                directory.display(),
+            );
+        }
+    }
 }
 
 fn index_files_internal<'a>(
-    paths: impl Iterator<Item = Cow<'a, PathBuf>>,
+    paths: impl Iterator<Item = Cow<'a, Path>>,
     files: &mut Vec<FileMetadata>,
+    follow_links: bool,
+    recursive: bool,
 ) {
     for path in paths {
-        match std::fs::metadata(path.as_path()) {
-            Ok(metadata) => {
-                if metadata.is_dir() {
+        match entry_metadata(&path, follow_links) {
+            Ok(Some(metadata)) => {
+                let is_directory = metadata.entry_type == EntryType::Directory;
+
+                // append the entry's path and metadata
+                files.push(metadata);
+
+                if recursive && is_directory {
                     // We found a directory — try to recursively index files and subdirectories
                     // within this directory
-                    match std::fs::read_dir(path.as_path()) {
+                    match std::fs::read_dir(&path) {
                         Ok(read_dir) => {
                             // We need to use `zip` and a separate function, instead of a closure,
                             // because of type recursion limits
@@ -89,7 +240,7 @@ fn index_files_internal<'a>(
                             let paths = read_dir
                                 .zip(std::iter::repeat(path))
                                 .filter_map(entry_filter_map_func);
-                            index_files_internal(paths, files);
+                            index_files_internal(paths, files, false, true);
                         }
                         Err(err) => {
                             eprintln!(
@@ -98,14 +249,10 @@ fn index_files_internal<'a>(
                             );
                         }
                     }
-                } else if metadata.is_file() {
-                    // We found a file, append its path and size
-                    files.push(FileMetadata {
-                        path: path.into_owned(),
-                        size: FileSize(metadata.len()),
-                    });
                 }
             }
+            // sockets, devices etc. cannot be transferred
+            Ok(None) => {}
             Err(err) => {
                 eprintln!(
                     "Could not get metadata of file to be indexed: '{}', error: {err}",
@@ -116,9 +263,44 @@ fn index_files_internal<'a>(
     }
 }
 
+/// Obtains the index entry for the given path. Returns `None` if it is neither a file, a directory
+/// nor a symbolic link, as such entries cannot be transferred.
+///
+/// # Errors
+/// Returns an error if the metadata of the path, or the target of a symbolic link, cannot be read.
+pub fn entry_metadata(path: &Path, follow_links: bool) -> std::io::Result<Option<FileMetadata>> {
+    let metadata = if follow_links {
+        std::fs::metadata(path)?
+    } else {
+        std::fs::symlink_metadata(path)?
+    };
+
+    let entry_type = if metadata.is_dir() {
+        EntryType::Directory
+    } else if metadata.is_file() {
+        EntryType::File
+    } else if metadata.is_symlink() {
+        EntryType::Symlink(std::fs::read_link(path)?)
+    } else {
+        return Ok(None);
+    };
+
+    Ok(Some(FileMetadata {
+        path: path.to_path_buf(),
+        size: FileSize(if metadata.is_file() {
+            metadata.len()
+        } else {
+            0
+        }),
+        entry_type,
+        mode: metadata.permissions().mode() & 0o7777,
+        mtime: crate::common::metadata_mtime(&metadata),
+    }))
+}
+
 fn entry_filter_map_func(
-    tuple: (std::io::Result<DirEntry>, Cow<'_, PathBuf>),
-) -> Option<Cow<'static, PathBuf>> {
+    tuple: (std::io::Result<DirEntry>, Cow<'_, Path>),
+) -> Option<Cow<'static, Path>> {
     let (maybe_entry, path) = tuple;
     match maybe_entry {
         Ok(entry) => Some(Cow::Owned(entry.path())),
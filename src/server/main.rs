@@ -1,18 +1,34 @@
 use std::{
     cmp::Ordering,
     io::{ErrorKind, Read},
+    net::TcpStream,
+    panic::AssertUnwindSafe,
     path::PathBuf,
-    time::Instant,
+    sync::{
+        mpsc::{Receiver, Sender},
+        Arc, Mutex, RwLock, Weak,
+    },
+    time::{Duration, Instant},
 };
 
-use super::{IndexMode, Parameter, Session, Transfer};
+use super::{
+    admission::{Admission, Ticket},
+    Bandwidth, FileIndex, IndexMode, Parameter, Session, SessionRegistry, Transfer, TransferGroup,
+};
 
 use crate::{
+    client,
     common::SocketWrapper,
     datagram::BlockType,
-    message::{ClientToServer, FileRequest, NoiseHeader, TransmissionControl},
+    filter::FileFilter,
+    message::{
+        ClientToServer, FileRequest, NoiseHeader, ServerToClient, TransmissionControl,
+        UploadRequest,
+    },
+    rtt::RttEstimator,
     server::Properties,
-    types::{BlockIndex, ErrorRate, FileMetadata},
+    stream::Stream,
+    types::{BlockIndex, EntryType, FileMetadata},
 };
 use anyhow::bail;
 
@@ -22,9 +38,8 @@ use anyhow::bail;
 pub fn serve(mut parameter: Parameter) -> anyhow::Result<()> {
     // show version / build information
     eprintln!(
-        "namida server for protocol revision {} (block size = {}, magic = 0x{:x})\nVersion: {} (revision {})\nCompiled: {}",
+        "namida server for protocol revision {} (magic = 0x{:x})\nVersion: {} (revision {})\nCompiled: {}",
         crate::version::NAMIDA_PROTOCOL_REVISION,
-        crate::common::BLOCK_SIZE,
         crate::version::magic(parameter.encrypted),
         crate::version::NAMIDA_VERSION,
         &crate::version::GIT_HASH[0..7],
@@ -33,68 +48,182 @@ pub fn serve(mut parameter: Parameter) -> anyhow::Result<()> {
     eprintln!();
 
     // process our command-line options
-    let files = process_options(&mut parameter);
+    let files = FileIndex::new(RwLock::new(process_options(&mut parameter)));
+    if matches!(parameter.index, IndexMode::Watch) {
+        super::watch::spawn(&parameter.file_names, &files)?;
+    }
 
     // obtain our server socket
     let listener = super::network::create_tcp_socket(&parameter)?;
     eprintln!("Waiting for clients to connect.");
 
+    // the sessions further connections of a client can join, and the rate they share
+    let sessions = SessionRegistry::default();
+    let bandwidth = Arc::new(Bandwidth::new(parameter.max_rate));
+
+    // create a fixed number of threads to serve the sessions of admitted clients, which wait in a
+    // queue until one of the threads is free. (We use threads here instead of sub-processes like
+    // Tsunami originally did)
+    let admission = Admission::new(&parameter);
+    let (queue, queued) = std::sync::mpsc::channel();
+    let queued = Arc::new(Mutex::new(queued));
+    for _ in 0..parameter.max_sessions {
+        let parameter_cloned = parameter.clone();
+        let files_cloned = Arc::clone(&files);
+        let sessions_cloned = Arc::clone(&sessions);
+        let queued_cloned = Arc::clone(&queued);
+        std::thread::spawn(move || {
+            serve_queued(
+                &queued_cloned,
+                &parameter_cloned,
+                &files_cloned,
+                &sessions_cloned,
+            );
+        });
+    }
+
+    // Refused clients are told when to retry by a separate thread, so that clients which are slow
+    // to respond cannot keep us from accepting further connections. If too many of them are
+    // waiting already, the connection is just closed.
+    let (refusals, refused) = std::sync::mpsc::sync_channel(super::config::REFUSAL_QUEUE);
+    let parameter_cloned = parameter.clone();
+    std::thread::spawn(move || {
+        for socket in refused {
+            if let Err(err) = super::admission::refuse(socket, &parameter_cloned) {
+                eprintln!("Could not tell refused client to retry later: {err}");
+            }
+        }
+    });
+
     // “while our little world keeps turning”...
     for (session_id, result) in listener.incoming().enumerate() {
         // accept a new client connection
         let socket = result?;
-        eprintln!("New client connecting from {}...", socket.peer_addr()?);
-
-        // create a new thread to handle the client connection. (We use threads here instead of
-        // sub-processes like Tsunami originally did)
-        let parameter_cloned = parameter.clone();
-        let files_cloned = files.clone();
-        std::thread::spawn(move || {
-            // set up the session structure
-            let session = Session {
-                transfer: Transfer::default(),
-                properties: Properties::default(),
-                client: SocketWrapper::new(socket),
+        let address = socket.peer_addr()?;
+        eprintln!("New client connecting from {address}...");
+
+        if let Some(ticket) = admission.admit(address.ip()) {
+            // Admitted clients are greeted on threads of their own, as their first request decides
+            // whether they need to wait for a session thread at all
+            let greeting = Greeting {
+                socket,
+                ticket,
                 session_id,
             };
-
-            // and run the client handler, catching any panics so we can inform the user about what
-            // happened
-            let result = client_handler(session, &parameter_cloned, files_cloned);
-
-            match result {
-                Ok(()) => eprintln!("Child server thread terminated successfully."),
-                Err(err) => eprintln!("Child server thread terminated with error: {err}"),
-            };
-        });
+            let parameter_cloned = parameter.clone();
+            let files_cloned = Arc::clone(&files);
+            let sessions_cloned = Arc::clone(&sessions);
+            let bandwidth_cloned = Arc::clone(&bandwidth);
+            let queue_cloned = queue.clone();
+            std::thread::spawn(move || {
+                let result = greet(
+                    greeting,
+                    &parameter_cloned,
+                    &files_cloned,
+                    &sessions_cloned,
+                    &bandwidth_cloned,
+                    &queue_cloned,
+                );
+                if let Err(err) = result {
+                    eprintln!("Could not greet client {address}: {err}");
+                }
+            });
+        } else {
+            eprintln!("Server is busy, telling client {address} to retry later.");
+            refusals.try_send(socket).ok();
+        }
     }
 
     Ok(())
 }
 
-/// This routine is run by the client processes that are created in response to incoming
-/// connections.
-#[allow(clippy::missing_errors_doc)]
-#[allow(clippy::missing_panics_doc)]
-pub fn client_handler(
-    mut session: Session,
+/// A newly admitted client connection, along with its ticket, which is kept until the client has
+/// been served, so that it counts as admitted until then.
+struct Greeting {
+    socket: TcpStream,
+    ticket: Ticket,
+    session_id: usize,
+}
+
+/// A client session waiting for a thread to serve it, along with its first request.
+type Queued = (Session, ClientToServer, Ticket);
+
+/// Handles the queued client sessions one after another.
+fn serve_queued(
+    queued: &Mutex<Receiver<Queued>>,
+    parameter: &Parameter,
+    files: &FileIndex,
+    sessions: &SessionRegistry,
+) {
+    loop {
+        let next = queued
+            .lock()
+            .expect("connection queue lock poisoned")
+            .recv();
+        let Ok((session, request, _ticket)) = next else {
+            return;
+        };
+
+        run_client_handler(session, Some(request), parameter, files, sessions);
+    }
+}
+
+/// Negotiates the protocol revision with a newly admitted client, authenticates it, and reads its
+/// first request. A connection that joins a session which is already being served is then served
+/// right away on the current thread, and counts as part of that session rather than as a session
+/// of its own, while still holding its admission; otherwise, its session is queued to be served by one of the session threads. The new
+/// session shares the given bandwidth with the others.
+///
+/// # Errors
+/// Returns an error on I/O failure, if the client fails to authenticate, or if it tries to join a
+/// session that does not exist or cannot be joined by further connections.
+fn greet(
+    greeting: Greeting,
     parameter: &Parameter,
-    mut files: Vec<FileMetadata>,
+    files: &FileIndex,
+    sessions: &SessionRegistry,
+    bandwidth: &Arc<Bandwidth>,
+    queue: &Sender<Queued>,
 ) -> anyhow::Result<()> {
+    let Greeting {
+        socket,
+        ticket,
+        session_id,
+    } = greeting;
+
+    // disable Nagle's algorithm, so that small messages such as replies to pings are not delayed
+    socket.set_nodelay(true)?;
+
+    // a client that does not get to its first request must not take up an admission for long
+    socket.set_read_timeout(Some(Duration::from_secs(
+        super::config::GREETING_TIMEOUT_SECS,
+    )))?;
+
+    // set up the session structure
+    let mut session = Session {
+        transfer: Transfer::default(),
+        properties: Properties::default(),
+        client: SocketWrapper::new(socket),
+        session_id,
+        group: Arc::new(TransferGroup::new(bandwidth)),
+        failed_upload: None,
+    };
+
     // negotiate the connection parameters
     // We call it negotiation, but we unilaterally impose our parameters on the client!
-    super::protocol::negotiate(&mut session, parameter)?;
+    super::protocol::negotiate(&mut session.client, parameter)?;
+    session.client.write(ServerToClient::Admitted)?;
 
     // have the client try to authenticate to us, and potentially initiate an encrypted connection
     if parameter.encrypted {
         super::protocol::authenticate_encrypted(&mut session, &parameter.secret)?;
     } else {
         super::protocol::authenticate_unencrypted(&mut session, &parameter.secret)?;
-    };
+    }
 
     if parameter.verbose_yn {
         println!("Client authenticated. Negotiated parameters are:");
-        println!("Block size: {}", crate::common::BLOCK_SIZE);
+        println!("Maximum block size: {}", parameter.max_block_size);
         println!("Buffer size: {}", parameter.udp_buffer);
         println!(
             "Encryption: {}",
@@ -102,6 +231,73 @@ pub fn client_handler(
         );
     }
 
+    let request = session.client.read()?;
+    session.client.socket.set_read_timeout(None)?;
+
+    let ClientToServer::JoinSession(token) = request else {
+        if queue.send((session, request, ticket)).is_err() {
+            bail!("The session threads have stopped");
+        }
+        return Ok(());
+    };
+
+    // The session to join is already being served, so its further connections do not need to
+    // wait for a session thread, which could otherwise be held up by the session itself
+    let joined = sessions
+        .lock()
+        .expect("session registry lock poisoned")
+        .get(&token)
+        .and_then(Weak::upgrade)
+        .and_then(|group| {
+            let connection = group.join(parameter.max_session_connections)?;
+            Some((group, connection))
+        });
+    session
+        .client
+        .write(ServerToClient::SessionJoined(joined.is_some()))?;
+    let Some((group, _connection)) = joined else {
+        bail!("Client tried to join a session that does not exist or has too many connections");
+    };
+    session.group = group;
+
+    // the connection still counts as admitted while it is served, including towards the limit of
+    // its address
+    run_client_handler(session, None, parameter, files, sessions);
+    drop(ticket);
+    Ok(())
+}
+
+/// Runs the client handler for the given session, catching any panics so we can inform the user
+/// about what happened, and keep the thread for further clients.
+fn run_client_handler(
+    session: Session,
+    request: Option<ClientToServer>,
+    parameter: &Parameter,
+    files: &FileIndex,
+    sessions: &SessionRegistry,
+) {
+    let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        client_handler(session, request, parameter, files, sessions)
+    }));
+
+    match result {
+        Ok(Ok(())) => eprintln!("Child server thread terminated successfully."),
+        Ok(Err(err)) => eprintln!("Child server thread terminated with error: {err}"),
+        Err(_) => eprintln!("Child server thread panicked."),
+    }
+}
+
+/// Handles the requests of an authenticated client, starting with the given one, if any, until it
+/// closes the connection.
+#[allow(clippy::missing_errors_doc)]
+#[allow(clippy::missing_panics_doc)]
+pub fn client_handler(
+    mut session: Session,
+    mut pending_request: Option<ClientToServer>,
+    parameter: &Parameter,
+    files: &FileIndex,
+    sessions: &SessionRegistry,
+) -> anyhow::Result<()> {
     // while we haven't been told to stop
     loop {
         // Make the client socket blocking (for the case that it has been set to non-blocking
@@ -109,14 +305,40 @@ pub fn client_handler(
         session.client.socket.set_nonblocking(false)?;
 
         // negotiate another transfer
-        let request = session.client.read()?;
+        let request = match pending_request.take() {
+            Some(request) => request,
+            None => session.client.read()?,
+        };
 
         match request {
             ClientToServer::FileRequest(file_request) => {
-                handle_transfer(&mut session, parameter, file_request)?;
+                let opened = super::protocol::open_transfer(&mut session, parameter, file_request);
+                handle_transfer(&mut session, parameter, opened)?;
             }
-            ClientToServer::FileListRequest => {
-                super::protocol::send_file_list(&mut session, parameter, &mut files)?;
+            ClientToServer::BatchRequest(batch_request) => {
+                let opened = super::protocol::open_batch(&mut session, parameter, batch_request);
+                handle_transfer(&mut session, parameter, opened)?;
+            }
+            ClientToServer::FileListRequest(request) => {
+                super::protocol::send_file_list(&mut session, parameter, files, &request)?;
+            }
+            ClientToServer::DigestRequest => {
+                if session.transfer.file.is_none() {
+                    bail!("Client requested digests, but no file has been transferred");
+                }
+                if !super::protocol::send_digests(&mut session)? {
+                    eprintln!("Client reported that the transferred file failed verification.");
+                }
+            }
+            ClientToServer::UploadRequest(upload_request) => {
+                session = handle_upload(session, parameter, upload_request)?;
+            }
+            ClientToServer::SessionTokenRequest => {
+                let token = register_session(&session, sessions);
+                session.client.write(ServerToClient::SessionToken {
+                    token,
+                    max_connections: parameter.max_session_connections,
+                })?;
             }
             ClientToServer::Close => return Ok(()),
             _ => bail!("Expected a request from the client but got: {request:?}"),
@@ -124,12 +346,28 @@ pub fn client_handler(
     }
 }
 
+/// Makes the given session joinable by further connections, and returns the token with which they
+/// can join it. The session stays joinable as long as one of its connections exists.
+fn register_session(session: &Session, sessions: &SessionRegistry) -> u64 {
+    let mut sessions = sessions.lock().expect("session registry lock poisoned");
+    sessions.retain(|_, group| group.strong_count() > 0);
+
+    let mut token: u64 = rand::random();
+    while sessions.contains_key(&token) {
+        token = rand::random();
+    }
+    sessions.insert(token, Arc::downgrade(&session.group));
+    token
+}
+
+/// Transmits the file or batch of files that has been opened for the client's request, if opening
+/// it was successful (as given by `opened`).
 fn handle_transfer(
     session: &mut Session,
     parameter: &Parameter,
-    file_request: FileRequest,
+    opened: anyhow::Result<()>,
 ) -> anyhow::Result<()> {
-    if let Err(err) = super::protocol::open_transfer(session, parameter, file_request) {
+    if let Err(err) = opened {
         println!("WARNING: Invalid file request, error: {err:?}");
         bail!("Closing connection to client.");
     }
@@ -141,7 +379,6 @@ fn handle_transfer(
         bail!("Expected UdpInit");
     };
     let ping_end = Instant::now();
-    super::protocol::start_transfer_timing(session, parameter, ping_start, ping_end);
 
     // Get the client's UDP address
     if let Err(err) = super::protocol::determine_client_udp_address(session, parameter, udp_method)
@@ -149,18 +386,215 @@ fn handle_transfer(
         bail!("UDP address determination failed: {err:?}");
     }
 
+    // If the client did not request a specific block size, find the largest one that fits through
+    // the path to it. This needs to happen before we calculate the inter-packet delay.
+    if session.transfer.probe_mtu {
+        super::protocol::probe_path_mtu(session, parameter)?;
+    }
+    super::protocol::start_transfer_timing(session, parameter, ping_start, ping_end);
+
     // If the client wants to resume, send it some checksums, and wait for it to tell us which
     // blocks we can skip
     if resume {
         super::protocol::resume(session)?;
     }
 
+    transmit(session, parameter)
+}
+
+/// Receives a file uploaded by the client. As the roles of the transfer are reversed, we hand our
+/// control connection over to a receiving session, and run the same logic a client downloading a
+/// file would. The session is returned afterwards, so that the client can send further requests.
+fn handle_upload(
+    mut session: Session,
+    parameter: &Parameter,
+    upload_request: UploadRequest,
+) -> anyhow::Result<Session> {
+    let UploadRequest {
+        request,
+        file_size,
+        resume,
+        mtime,
+        sample_digest,
+    } = upload_request;
+
+    // Check whether we accept the upload, and where the file should be stored
+    let local_filename = match super::protocol::upload_destination(parameter, &request.path) {
+        Ok(local_filename) => local_filename,
+        Err(err) => {
+            session
+                .client
+                .write(ServerToClient::FileRequestError(err))?;
+            bail!("Refusing upload to '{}': {err:?}", request.path.display());
+        }
+    };
+
+    if parameter.verbose_yn {
+        println!(
+            "Request to upload file: '{}' ({} bytes)",
+            local_filename.display(),
+            file_size.0
+        );
+    }
+
+    // set up the receiving session
+    let block_size = super::protocol::negotiate_block_size(parameter, request.block_size);
+    let probe_mtu = request.block_size.is_none();
+    let mut receive_parameter = receive_parameter(parameter, &request, block_size, resume);
+    let mut receiver = client::Session {
+        transfer: client::Transfer::default(),
+        server: session.client,
+    };
+    receiver.transfer.remote_filename = Some(request.path);
+    receiver.transfer.local_filename = Some(local_filename.clone());
+    receiver.transfer.file_size = file_size;
+    receiver.transfer.block_size = block_size;
+    receiver.transfer.block_count = crate::common::block_count(file_size, block_size);
+    receiver.transfer.epoch = crate::common::epoch();
+    receiver.transfer.remote_mtime = mtime;
+    receiver.transfer.remote_sample_digest = sample_digest;
+    if let Some((failed_filename, invalid_ranges)) = session.failed_upload.take() {
+        if failed_filename == local_filename {
+            receiver.transfer.invalid_ranges = invalid_ranges;
+        }
+    }
+    let resume = client::protocol::open_local_file(&mut receiver, &receive_parameter)?;
+
+    // open a UDP socket for the client to send the data to, and let it know of the port
+    let ipv6 = receiver.server.socket.local_addr()?.is_ipv6();
+    let udp_socket = receiver
+        .transfer
+        .udp_socket
+        .insert(client::network::create_udp_socket(
+            &receive_parameter,
+            ipv6,
+        )?);
+    let udp_port = udp_socket.local_addr()?.port();
+    receiver
+        .server
+        .write(ServerToClient::UploadRequestSuccess {
+            block_size,
+            udp_port,
+            resume,
+        })?;
+
+    // If the client did not request a specific block size, it will probe the path MTU now
+    if probe_mtu {
+        client::protocol::answer_mtu_probes(&mut receiver, &receive_parameter)?;
+    }
+
+    let mut stats_iteration = 0;
+    let successful = client::get::receive_file(
+        &mut receiver,
+        &mut receive_parameter,
+        resume,
+        &mut stats_iteration,
+    )?;
+    if !successful {
+        bail!("Upload was not successful");
+    }
+
+    // Verify the integrity of the uploaded file. If it does not match, the client will retry the
+    // upload, resuming it so that only mismatching data is transmitted again.
+    if client::protocol::verify(&mut receiver)? {
+        let local_filename = receiver
+            .transfer
+            .local_filename
+            .as_ref()
+            .expect("local_filename should be present");
+        if let Err(err) = client::state::remove(local_filename) {
+            eprintln!("Could not remove the saved transfer state: {err}");
+        }
+    } else {
+        eprintln!("Uploaded file failed verification, expecting the client to retry.");
+        session.failed_upload = Some((
+            local_filename,
+            std::mem::take(&mut receiver.transfer.invalid_ranges),
+        ));
+    }
+
+    // take back our control connection
+    session.client = receiver.server;
+    Ok(session)
+}
+
+/// Builds the parameters for receiving an uploaded file, based on the server's parameters and the
+/// transfer parameters requested by the client.
+fn receive_parameter(
+    parameter: &Parameter,
+    request: &FileRequest,
+    block_size: u16,
+    resume: bool,
+) -> client::get::Parameter {
+    client::get::Parameter {
+        server: String::new(),
+        client_port: None,
+        discovery: false,
+        encrypted: parameter.encrypted,
+        udp_buffer: parameter.udp_buffer,
+        verbose_yn: parameter.verbose_yn,
+        transcript_yn: parameter.transcript_yn,
+        ipv6_yn: false,
+        output_mode: client::OutputMode::Line,
+        target_rate: request.target_rate,
+        rate_adjust: false,
+        error_rate: request.error_rate,
+        slower: request.slowdown,
+        faster: request.speedup,
+        block_size: Some(block_size),
+        history: client::config::DEFAULT_HISTORY,
+        lossless: true,
+        losswindow_ms: client::config::DEFAULT_LOSSWINDOW_MS,
+        blockdump: false,
+        secret_file: None,
+        local_filename: None,
+        tree: false,
+        resume,
+        state_interval_ms: client::config::STATE_SAVE_INTERVAL_MS,
+        checksum_algorithm: request.checksum_algorithm,
+        verify: true,
+        delta: false,
+        compression: request.compression,
+        fec_group_size: request.fec_group_size,
+        follow_idle: None,
+        offset: None,
+        length: None,
+        in_place: false,
+        filter: FileFilter::default(),
+        parallel: 1,
+        batch: false,
+        secret: parameter.secret,
+        files: vec![],
+        sync: false,
+        delete: false,
+        all: false,
+    }
+}
+
+/// Transmits the file for which a transfer has been set up in the given session (i.e. the file has
+/// been opened, and the UDP address of the receiving side is known), while handling the
+/// transmission control requests of the receiving side. Returns once the receiving side has
+/// signalled the end of the transmission, or the heartbeat timeout has been reached.
+///
+/// This is used both for serving files to a client, and, on the client side, for uploading files
+/// to a server.
+///
+/// # Errors
+/// Returns an error on I/O failure, or if the receiving side sent invalid data.
+///
+/// # Panics
+/// Panics on arithmetic overflow, or if no file or UDP socket is present.
+pub fn transmit(session: &mut Session, parameter: &Parameter) -> anyhow::Result<()> {
     // Make the client socket non-blocking, to be able to skip reading a transmission control
     // request if none has been sent.
     session.client.socket.set_nonblocking(true)?;
 
-    // Start timing
+    // Start timing, and count this transfer as active, so that parallel transfers of the session
+    // share its rate
     let start = Instant::now();
+    let _active_transfer = session.group.start_transfer(session.properties.target_rate);
+    session.transfer.controller = parameter.congestion.controller();
+    session.transfer.rtt = RttEstimator::default();
     if parameter.transcript_yn {
         crate::common::transcript_warn_error(super::transcript::data_start(session));
     }
@@ -180,11 +614,12 @@ fn handle_transfer(
 
     let mut retransmit_accept_iteration = 0;
 
-    let mut datagram_block_buffer: Vec<u8> = vec![0_u8; crate::common::BLOCK_SIZE as usize];
+    let block_size = usize::from(session.properties.block_size);
+    let mut datagram_block_buffer: Vec<u8> = vec![0_u8; block_size];
     let datagram_buffer_extra_length = if parameter.encrypted { 30 } else { 6 };
     let mut datagram_buffer: Vec<u8> = vec![
         0_u8;
-        (crate::common::BLOCK_SIZE as usize)
+        block_size
             .checked_add(datagram_buffer_extra_length)
             .expect("datagram buffer size overflow")
     ];
@@ -200,8 +635,10 @@ fn handle_transfer(
         // default: flag as retransmitted block
         let mut block_type = BlockType::Retransmission;
 
-        // precalculate time to wait after sending the next packet
+        // precalculate time to wait after sending the next packet. If another transfer of the
+        // session has started in the meantime, slow down to leave it its share of the rate.
         let current_packet_time = Instant::now();
+        session.transfer.ipd_current = session.transfer.ipd_current.max(session.min_ipd());
 
         // Only perform time adjustment if we actually ended up sending something before
         if !cont {
@@ -302,15 +739,23 @@ fn handle_transfer(
                     .filename
                     .as_ref()
                     .expect("filename should be available");
-                eprintln!("Transmission of {} complete.", filename.display());
+                let filenames = if session.transfer.batch_filenames.is_empty() {
+                    std::slice::from_ref(filename)
+                } else {
+                    session.transfer.batch_filenames.as_slice()
+                };
+
+                for filename in filenames {
+                    eprintln!("Transmission of {} complete.", filename.display());
 
-                if let Some(finishhook) = &parameter.finishhook {
-                    eprintln!("Executing: {} {}", finishhook.display(), filename.display());
+                    if let Some(finishhook) = &parameter.finishhook {
+                        eprintln!("Executing: {} {}", finishhook.display(), filename.display());
 
-                    let spawned = std::process::Command::new(finishhook).arg(filename).spawn();
+                        let spawned = std::process::Command::new(finishhook).arg(filename).spawn();
 
-                    if let Err(err) = spawned {
-                        eprintln!("Could not execute finish hook: {err}");
+                        if let Err(err) = spawned {
+                            eprintln!("Could not execute finish hook: {err}");
+                        }
                     }
                 }
                 break;
@@ -321,14 +766,22 @@ fn handle_transfer(
                 session,
                 parameter,
                 &transmission_control,
-                datagram_block_buffer.as_mut_slice(),
-                datagram_buffer.as_mut_slice(),
                 &mut retransmit_accept_iteration,
             ) {
                 println!("WARNING: Retransmission error: {err:?}");
             }
 
             maybe_transmission_control = None; // wait for the next one
+        } else if !session.transfer.retransmits.is_empty() {
+            // requested blocks are retransmitted before any new blocks
+            if let Err(err) = super::protocol::send_queued_retransmission(
+                session,
+                parameter,
+                datagram_block_buffer.as_mut_slice(),
+                datagram_buffer.as_mut_slice(),
+            ) {
+                println!("WARNING: Retransmission error: {err:?}");
+            }
         } else {
             // we could not read a full transmission control request so far, so, send out
             // some blocks that haven't yet been sent, or resend the final block.
@@ -336,6 +789,7 @@ fn handle_transfer(
             // send those out if the client is currently sending us retransmit requests
             if session.properties.retransmit_phase
                 && session.transfer.block == session.properties.block_count
+                && !session.transfer.following()
             {
                 continue;
             }
@@ -365,17 +819,7 @@ fn handle_transfer(
 
             lasthblostreport = Instant::now();
 
-            let retransmission = TransmissionControl::SubmitErrorRate(ErrorRate(100_000));
-            if let Err(err) = super::protocol::accept_retransmit(
-                session,
-                parameter,
-                &retransmission,
-                datagram_block_buffer.as_mut_slice(),
-                datagram_buffer.as_mut_slice(),
-                &mut retransmit_accept_iteration,
-            ) {
-                println!("Error in accept_retransmit: {err:?}");
-            }
+            super::protocol::heartbeat_lost(session, parameter, &mut retransmit_accept_iteration);
 
             delta_µs = crate::common::get_µs_since(lastfeedback);
             #[allow(clippy::cast_precision_loss)]
@@ -383,7 +827,8 @@ fn handle_transfer(
 
             // show an (additional) statistics line
             let stats_line = format!(
-                "   n/a     n/a     n/a {:7} {:6.2} {:3} -- no heartbeat since {:3.2}s\n",
+                "   n/a     n/a     n/a {} {:7} {:6.2} {:3} -- no heartbeat since {:3.2}s\n",
+                session.transfer.rtt,
                 session.transfer.block.0,
                 100.0_f64 * f64::from(session.transfer.block.0)
                     / f64::from(session.properties.block_count.0),
@@ -497,12 +942,42 @@ fn send_next_block(
     datagram_block_buffer: &mut [u8],
     datagram_buffer: &mut [u8],
 ) -> anyhow::Result<bool> {
+    // once all data of a followed file has been sent, wait for it to grow
+    if session.transfer.following() && session.transfer.block >= session.properties.block_count {
+        super::protocol::follow_file(session)?;
+        if session.transfer.following() && session.transfer.block >= session.properties.block_count
+        {
+            crate::common::µsleep_that_works(super::config::FOLLOW_WAIT_US);
+            return Ok(true);
+        }
+    }
+
+    // an empty file has no blocks to send
+    if session.properties.block_count.is_zero() {
+        return Ok(true);
+    }
+
+    // streamed data can only be read as far ahead of the data acknowledged by the client as its
+    // window allows, so wait for further acknowledgements if necessary
+    if let Some(Stream::Pipe(pipe)) = session.transfer.file.as_ref() {
+        let block_end = u64::from(session.properties.block_size)
+            .checked_mul(u64::from(session.transfer.block.0).saturating_add(1))
+            .expect("block position overflow");
+        if !pipe.can_read_to(block_end) {
+            crate::common::µsleep_that_works(super::config::STREAM_WAIT_US);
+            return Ok(true);
+        }
+    }
+
     // increment block index for the next datagram
     let incremented = session.transfer.block.safe_add(BlockIndex(1));
     session.transfer.block = BlockIndex::min(incremented, session.properties.block_count);
 
-    // check whether we're sending the final block
-    *block_type = if session.transfer.block == session.properties.block_count {
+    // check whether we're sending the final block. The last block of a followed file that may
+    // still grow is not final yet.
+    *block_type = if session.transfer.block == session.properties.block_count
+        && !session.transfer.following()
+    {
         BlockType::Final
     } else {
         BlockType::Original
@@ -524,6 +999,7 @@ fn send_next_block(
     let block_index = session.transfer.block;
     let datagram =
         super::io::build_datagram(session, block_index, *block_type, datagram_block_buffer)?;
+    *block_type = datagram.header.block_type;
     bincode::encode_into_slice(datagram, datagram_buffer, crate::common::BINCODE_CONFIG)?;
 
     // transmit the datagram
@@ -536,6 +1012,19 @@ fn send_next_block(
         return Ok(true);
     }
 
+    // if this block completed a group of blocks protected by forward error correction, transmit
+    // the parity block of the group as well
+    if let Some(parity) = super::io::build_parity_datagram(session, datagram_block_buffer) {
+        if let Err(err) =
+            super::protocol::send_datagram(session, parameter, parity, datagram_buffer)
+        {
+            println!(
+                "WARNING: Could not transmit parity block for block #{}: {}",
+                parity.header.block_index.0, err
+            );
+        }
+    }
+
     Ok(false)
 }
 
@@ -557,7 +1046,10 @@ pub fn process_options(parameter: &mut Parameter) -> Vec<FileMetadata> {
 
         // The user specified some files to serve. Try to open them to check whether they exist,
         // and get their sizes if they do
-        let total_files = files.len();
+        let total_files = files
+            .iter()
+            .filter(|metadata| metadata.entry_type == EntryType::File)
+            .count();
 
         if parameter.verbose_yn {
             match total_files {
@@ -567,12 +1059,25 @@ pub fn process_options(parameter: &mut Parameter) -> Vec<FileMetadata> {
             }
 
             for (index, metadata) in files.iter().enumerate() {
-                eprintln!(
-                    " {:3}   {:<20}  {} bytes",
-                    index.saturating_add(1),
-                    metadata.path.display(),
-                    metadata.size.0,
-                );
+                match &metadata.entry_type {
+                    EntryType::File => eprintln!(
+                        " {:3}   {:<20}  {} bytes",
+                        index.saturating_add(1),
+                        metadata.path.display(),
+                        metadata.size.0,
+                    ),
+                    EntryType::Directory => eprintln!(
+                        " {:3}   {}/",
+                        index.saturating_add(1),
+                        metadata.path.display()
+                    ),
+                    EntryType::Symlink(target) => eprintln!(
+                        " {:3}   {} -> {}",
+                        index.saturating_add(1),
+                        metadata.path.display(),
+                        target.display()
+                    ),
+                }
             }
         } else {
             match total_files {
@@ -1,22 +1,40 @@
 use std::{
+    collections::{HashMap, VecDeque},
     net::{SocketAddr, UdpSocket},
+    ops::Range,
     path::PathBuf,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, RwLock, Weak,
+    },
     time::Duration,
 };
 
 use crate::{
     common::SocketWrapper,
-    types::{BlockIndex, ErrorRate, FileSize, Fraction, SkipChunks, TargetRate},
+    compression::Compressor,
+    fec,
+    rtt::RttEstimator,
+    stream::Stream,
+    types::{
+        BlockIndex, BlockRange, ChecksumAlgorithm, ErrorRate, FileMetadata, FileSize, Fraction,
+        SkipChunks, TargetRate,
+    },
 };
 
+pub mod admission;
 pub mod config;
+pub mod congestion;
+pub mod follow;
 pub mod io;
 pub mod main;
 pub mod network;
 pub mod protocol;
 pub mod transcript;
+pub mod watch;
 
 #[derive(Clone, clap::Args)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct Parameter {
     /// turns on verbose output mode
     #[arg(long = "verbose", short = 'v')]
@@ -37,8 +55,9 @@ pub struct Parameter {
     pub encrypted: bool,
 
     /// Defines the indexing mode — whether input files and directories are never indexed (which
-    /// means file listing will be unsupported), only indexed at startup, or reindexed whenever the
-    /// client requests a file list.
+    /// means file listing will be unsupported), only indexed at startup, reindexed whenever the
+    /// client requests a file list, or indexed at startup and then kept up to date by watching
+    /// them for changes.
     #[arg(long = "index", default_value_t, value_enum)]
     pub index: IndexMode,
 
@@ -46,6 +65,57 @@ pub struct Parameter {
     #[arg(long = "buffer", short = 'b', default_value_t = config::DEFAULT_UDP_BUFFER)]
     pub udp_buffer: u32,
 
+    /// The largest block size (in bytes) clients may request. Larger block sizes reduce the
+    /// per-datagram overhead, but the resulting datagrams should fit into the path MTU.
+    #[arg(long = "max-blocksize", default_value_t = config::DEFAULT_MAX_BLOCK_SIZE, value_parser = clap::value_parser!(u16).range(i64::from(crate::common::MIN_BLOCK_SIZE)..=i64::from(crate::common::MAX_BLOCK_SIZE)))]
+    pub max_block_size: u16,
+
+    /// Do not compress blocks, even if the client requests it. Compression costs CPU time, which
+    /// may be undesirable on a busy server.
+    #[arg(long = "no-compression", action = clap::ArgAction::SetFalse)]
+    pub compression: bool,
+
+    /// The number of sessions that are served at the same time. The additional connections of
+    /// parallel transfers count as part of the session they join.
+    #[arg(long = "max-sessions", default_value_t = config::DEFAULT_MAX_SESSIONS, value_parser = clap::value_parser!(u16).range(1..))]
+    pub max_sessions: u16,
+
+    /// The number of connections a single session may have, including the additional connections
+    /// of parallel transfers that join it.
+    #[arg(long = "max-session-connections", default_value_t = config::DEFAULT_MAX_SESSION_CONNECTIONS, value_parser = clap::value_parser!(u16).range(1..))]
+    pub max_session_connections: u16,
+
+    /// The number of connections that may wait for a session to become free. Once the queue is
+    /// full, further clients are told that the server is busy, and when to retry.
+    #[arg(long = "queue", default_value_t = config::DEFAULT_SESSION_QUEUE)]
+    pub session_queue: u16,
+
+    /// The number of connections that may be served or queued for a single IP address at the
+    /// same time. Unlimited if not specified.
+    #[arg(long = "max-per-ip")]
+    pub max_per_ip: Option<u16>,
+
+    /// The number of seconds after which refused clients are told to try again.
+    #[arg(long = "retry-after", value_name = "SECONDS", default_value_t = config::DEFAULT_RETRY_AFTER)]
+    pub retry_after: u32,
+
+    /// The total rate (in bits per second, with optional k/M/G/T suffix) at which data may be sent
+    /// to all clients. It is shared among the sessions that are transmitting at the same time:
+    /// sessions targeting less than an even split get their target rates, and the others split the
+    /// rest evenly. Each session's share grows again as others finish. Unlimited if not specified.
+    #[arg(long = "max-rate", value_parser = clap::builder::ValueParser::new(crate::client::get::parse_rate))]
+    pub max_rate: Option<TargetRate>,
+
+    /// The highest rate (in bits per second, with optional k/M/G/T suffix) a single session may
+    /// request. Higher rates requested by clients are reduced to it. Unlimited if not specified.
+    #[arg(long = "session-rate", value_parser = clap::builder::ValueParser::new(crate::client::get::parse_rate))]
+    pub session_rate: Option<TargetRate>,
+
+    /// The congestion control algorithm, which adjusts the rate at which data is sent to the
+    /// feedback of the client.
+    #[arg(long = "congestion", default_value_t, value_enum)]
+    pub congestion: congestion::CongestionControl,
+
     /// specifies the timeout in seconds for disconnect after client heartbeat lost
     #[arg(long = "hbtimeout", default_value_t = config::DEFAULT_HEARTBEAT_TIMEOUT)]
     pub hb_timeout: u16,
@@ -67,6 +137,17 @@ pub struct Parameter {
     #[arg(long = "finishhook", short = 'f')]
     pub finishhook: Option<PathBuf>,
 
+    /// Accept files uploaded by clients using `namida put`, and store them in the given directory.
+    /// If this option is not specified, uploads will be refused.
+    #[arg(long = "upload-dir")]
+    pub upload_dir: Option<PathBuf>,
+
+    /// Serve the data read from standard input under the given name, e.g. to serve the output of
+    /// a command. As the data can only be read once, only the first client requesting it will
+    /// receive it. Its size is not known up front, and it cannot be resumed or verified.
+    #[arg(long = "stdin", value_name = "NAME")]
+    pub stdin_name: Option<PathBuf>,
+
     /// list of files to share for downloaded via a client 'GET *'
     #[arg()]
     pub file_names: Vec<PathBuf>,
@@ -89,11 +170,16 @@ pub enum IndexMode {
 
     /// Every time the client requests a list of files, the input folder(s) will be reindexed.
     Always,
+
+    /// Indexing will be performed at startup, and the index will then be kept up to date by
+    /// watching the input folder(s) for changes using inotify, without walking them again.
+    Watch,
 }
 
 pub struct Properties {
     pub epoch: Duration,
     pub file_size: FileSize,
+    pub block_size: u16,
     pub block_count: BlockIndex,
     pub target_rate: TargetRate,
     pub error_rate: ErrorRate,
@@ -106,6 +192,7 @@ pub struct Properties {
     pub samplerate: i32,
     pub wait_µs: i64,
     pub retransmit_phase: bool,
+    pub checksum_algorithm: ChecksumAlgorithm,
 }
 
 impl Default for Properties {
@@ -113,6 +200,7 @@ impl Default for Properties {
         Self {
             epoch: Duration::default(),
             file_size: FileSize::default(),
+            block_size: crate::common::DEFAULT_BLOCK_SIZE,
             block_count: BlockIndex::default(),
             target_rate: TargetRate(0),
             error_rate: ErrorRate(0),
@@ -131,25 +219,56 @@ impl Default for Properties {
             samplerate: 0,
             wait_µs: 0,
             retransmit_phase: false,
+            checksum_algorithm: ChecksumAlgorithm::default(),
         }
     }
 }
 
 pub struct Transfer {
     pub filename: Option<PathBuf>,
-    pub file: Option<std::fs::File>,
+    pub batch_filenames: Vec<PathBuf>,
+    pub file: Option<Stream>,
     pub transcript: Option<std::fs::File>,
     pub udp_socket: Option<UdpSocket>,
     pub udp_address: Option<SocketAddr>,
     pub ipd_current: libc::c_double,
     pub block: BlockIndex,
     pub skip_chunks: Option<SkipChunks>,
+    pub probe_mtu: bool,
+    pub compressor: Option<Compressor>,
+    pub fec: Option<fec::Encoder>,
+    pub transfer_id: u32,
+
+    /// Adjusts the inter-packet delay to the feedback of the receiving side.
+    pub controller: Box<dyn congestion::Controller>,
+
+    /// The round trip time of the control connection, measured by echoing the client's pings.
+    pub rtt: RttEstimator,
+
+    /// The ranges of blocks the client has requested to be retransmitted, which are sent before
+    /// any further new blocks.
+    pub retransmits: VecDeque<BlockRange>,
+
+    /// Keeps track of the file while it is followed as it grows, if the client requested that.
+    /// It is kept after the file is finished, as its last block is sent differently then.
+    pub follow: Option<follow::Follow>,
+}
+
+impl Transfer {
+    /// Returns whether the file is followed, and may still grow.
+    #[must_use]
+    pub fn following(&self) -> bool {
+        self.follow
+            .as_ref()
+            .is_some_and(|follow| !follow.finished())
+    }
 }
 
 impl Default for Transfer {
     fn default() -> Self {
         Self {
             filename: None,
+            batch_filenames: vec![],
             file: None,
             transcript: None,
             udp_socket: None,
@@ -157,6 +276,14 @@ impl Default for Transfer {
             ipd_current: 0.0,
             block: BlockIndex(0),
             skip_chunks: None,
+            probe_mtu: false,
+            compressor: None,
+            fec: None,
+            transfer_id: 0,
+            controller: Box::new(congestion::Tsunami),
+            rtt: RttEstimator::default(),
+            retransmits: VecDeque::new(),
+            follow: None,
         }
     }
 }
@@ -166,4 +293,317 @@ pub struct Session {
     pub properties: Properties,
     pub client: SocketWrapper,
     pub session_id: usize,
+    pub group: Arc<TransferGroup>,
+
+    /// The destination of the last file uploaded in this session, if it failed verification, and
+    /// the byte ranges that did not match. They are received again when the client retries the
+    /// upload, even if their checksums claim that they match.
+    pub failed_upload: Option<(PathBuf, Vec<Range<u64>>)>,
+}
+
+impl Session {
+    /// Returns the smallest inter-packet delay the current transfer may use. See
+    /// `TransferGroup::min_ipd`.
+    #[must_use]
+    pub fn min_ipd(&self) -> f64 {
+        self.group.min_ipd(&self.properties)
+    }
+}
+
+/// The transfers of one client session. A client may open several connections that join the same
+/// session, to transfer multiple files in parallel; their transfers then share the session's rate
+/// budget, and are numbered consecutively.
+#[derive(Default)]
+pub struct TransferGroup {
+    active: AtomicU32,
+    last_transfer_id: AtomicU32,
+    bandwidth: Arc<Bandwidth>,
+
+    /// The number of further connections that have joined the session.
+    joined: AtomicU32,
+
+    /// The target rates of the transfers that are currently transmitting data.
+    target_rates: Mutex<Vec<u64>>,
+
+    /// The rate (in bits per second) the session may currently use, if the total rate of the server
+    /// is limited, as assigned by its `Bandwidth`.
+    share: AtomicU64,
+}
+
+impl TransferGroup {
+    /// Creates the group for a new session, which shares the given bandwidth with the other
+    /// sessions of the server.
+    #[must_use]
+    pub fn new(bandwidth: &Arc<Bandwidth>) -> Self {
+        Self {
+            active: AtomicU32::default(),
+            last_transfer_id: AtomicU32::default(),
+            bandwidth: Arc::clone(bandwidth),
+            joined: AtomicU32::default(),
+            target_rates: Mutex::default(),
+            share: AtomicU64::default(),
+        }
+    }
+
+    /// Lets a further connection join the session, unless the session would then have more than
+    /// `max_connections` connections. The connection counts as joined until the returned guard is
+    /// dropped.
+    pub fn join(self: &Arc<Self>, max_connections: u16) -> Option<JoinedConnection> {
+        self.joined
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |joined| {
+                // the session's first connection, the ones that have joined it, and this one
+                let connections = joined.checked_add(2)?;
+                (connections <= u32::from(max_connections)).then(|| joined.saturating_add(1))
+            })
+            .ok()?;
+        Some(JoinedConnection(Arc::clone(self)))
+    }
+
+    /// Returns the ID for a new transfer in this session.
+    pub fn next_transfer_id(&self) -> u32 {
+        self.last_transfer_id
+            .fetch_add(1, Ordering::Relaxed)
+            .wrapping_add(1)
+    }
+
+    /// Returns the number of transfers in this session that are currently transmitting data, but
+    /// at least one.
+    #[must_use]
+    pub fn active_transfers(&self) -> u32 {
+        self.active.load(Ordering::Relaxed).max(1)
+    }
+
+    /// Returns the smallest inter-packet delay a transfer with the given properties may use. The
+    /// target rate is shared by all transfers of the session that are active at the same time, so
+    /// the delay grows with their number. If the server limits its total rate, the session's
+    /// target rate is further limited to its share of it, which changes as other sessions start
+    /// and stop transmitting.
+    #[must_use]
+    pub fn min_ipd(&self, properties: &Properties) -> f64 {
+        let ipd_time = f64::from(properties.ipd_time);
+        #[allow(clippy::cast_precision_loss)]
+        let ipd_time = if self.bandwidth.max_rate.is_some() {
+            let share = self.share.load(Ordering::Relaxed).max(1);
+            ipd_time.max(f64::from(properties.block_size) * 8_000_000.0 / share as f64)
+        } else {
+            ipd_time
+        };
+        ipd_time * f64::from(self.active_transfers())
+    }
+
+    /// Marks a transfer with the given target rate as transmitting data, until the returned guard
+    /// is dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the lock of the target rates is poisoned.
+    #[must_use]
+    pub fn start_transfer(self: &Arc<Self>, target_rate: TargetRate) -> ActiveTransfer {
+        let mut target_rates = self
+            .target_rates
+            .lock()
+            .expect("target rates lock poisoned");
+        target_rates.push(target_rate.0);
+        self.active.fetch_add(1, Ordering::Relaxed);
+        // the lock is held until the bandwidth has been updated, so that the updates of a session
+        // are applied in order
+        self.bandwidth
+            .update(self, target_rates.iter().copied().max());
+        drop(target_rates);
+        ActiveTransfer(Arc::clone(self), target_rate)
+    }
+}
+
+/// Counts a connection as joined to its `TransferGroup` while it exists.
+pub struct JoinedConnection(Arc<TransferGroup>);
+
+impl Drop for JoinedConnection {
+    fn drop(&mut self) {
+        self.0.joined.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts a transfer with the given target rate as active in its `TransferGroup` while it exists,
+/// and the group as transmitting in its `Bandwidth` while it has active transfers.
+pub struct ActiveTransfer(Arc<TransferGroup>, TargetRate);
+
+impl Drop for ActiveTransfer {
+    fn drop(&mut self) {
+        let Self(group, target_rate) = self;
+        let mut target_rates = group
+            .target_rates
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        if let Some(position) = target_rates.iter().position(|rate| *rate == target_rate.0) {
+            target_rates.swap_remove(position);
+        }
+        group.active.fetch_sub(1, Ordering::Relaxed);
+        group
+            .bandwidth
+            .update(group, target_rates.iter().copied().max());
+    }
+}
+
+/// The total rate the server may send data at, which is shared among the sessions that are
+/// transmitting at the same time. Unlimited by default.
+///
+/// The rate is shared max-min fairly: sessions whose target rates are below an even split get
+/// their target rates, and the rest left over is split evenly among the others. The shares are
+/// recalculated whenever a session starts or stops transmitting.
+#[derive(Default)]
+pub struct Bandwidth {
+    max_rate: Option<TargetRate>,
+
+    /// The sessions with active transfers, and the rates they are targeting.
+    transmitting: Mutex<Vec<(Weak<TransferGroup>, u64)>>,
+}
+
+impl Bandwidth {
+    #[must_use]
+    pub fn new(max_rate: Option<TargetRate>) -> Self {
+        Self {
+            max_rate,
+            transmitting: Mutex::default(),
+        }
+    }
+
+    /// Records the rate the given session is targeting, or that it is not transmitting anymore if
+    /// it is `None`, and assigns all transmitting sessions their new shares.
+    fn update(&self, group: &Arc<TransferGroup>, target_rate: Option<u64>) {
+        let Some(max_rate) = self.max_rate else {
+            return;
+        };
+        let mut transmitting = self
+            .transmitting
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        transmitting.retain(|(other, _)| {
+            other.strong_count() > 0 && !std::ptr::eq(other.as_ptr(), Arc::as_ptr(group))
+        });
+        if let Some(target_rate) = target_rate {
+            transmitting.push((Arc::downgrade(group), target_rate));
+        }
+
+        let target_rates: Vec<u64> = transmitting.iter().map(|(_, rate)| *rate).collect();
+        let shares = fair_shares(max_rate.0, &target_rates);
+        for ((other, _), share) in transmitting.iter().zip(shares) {
+            if let Some(other) = other.upgrade() {
+                other.share.store(share, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Shares `total` max-min fairly among parties asking for the given amounts: each one gets what it
+/// asks for, up to an even split of what the ones asking for less have left over.
+fn fair_shares(total: u64, demands: &[u64]) -> Vec<u64> {
+    let mut order: Vec<usize> = (0..demands.len()).collect();
+    order.sort_unstable_by_key(|index| demands[*index]);
+
+    let mut shares = vec![0; demands.len()];
+    let mut remaining = total;
+    for (position, index) in order.into_iter().enumerate() {
+        let parties = u64::try_from(demands.len().saturating_sub(position)).unwrap_or(u64::MAX);
+        let share = demands[index].min(remaining.checked_div(parties).unwrap_or(0));
+        shares[index] = share.max(1);
+        remaining = remaining.saturating_sub(share);
+    }
+    shares
+}
+
+/// The sessions that further connections can join, by their session tokens.
+pub type SessionRegistry = Arc<Mutex<HashMap<u64, Weak<TransferGroup>>>>;
+
+/// The index of the served files, which is shared by all sessions, so that they all see it being
+/// updated when reindexing or watching for changes.
+pub type FileIndex = Arc<RwLock<Vec<FileMetadata>>>;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{fair_shares, Bandwidth, Properties, TransferGroup};
+    use crate::types::TargetRate;
+
+    /// Returns the properties of a transfer of 1000 byte blocks at the given target rate.
+    fn properties(target_rate: u64) -> Properties {
+        Properties {
+            block_size: 1000,
+            target_rate: TargetRate(target_rate),
+            ipd_time: u32::try_from(
+                8_000_000_000_u64
+                    .checked_div(target_rate)
+                    .expect("target rate is zero"),
+            )
+            .expect("IPD overflow"),
+            ..Properties::default()
+        }
+    }
+
+    #[test]
+    fn fair_shares_redistribute_leftover() {
+        assert_eq!(fair_shares(100, &[]), Vec::<u64>::new());
+        assert_eq!(fair_shares(100, &[30, 30, 30]), vec![30, 30, 30]);
+        assert_eq!(fair_shares(90, &[100, 100, 100]), vec![30, 30, 30]);
+        assert_eq!(fair_shares(100, &[80, 10, 100]), vec![45, 10, 45]);
+        assert_eq!(fair_shares(100, &[10, 20, 100]), vec![10, 20, 70]);
+    }
+
+    #[test]
+    fn min_ipd_follows_sessions_starting_and_stopping() {
+        let bandwidth = Arc::new(Bandwidth::new(Some(TargetRate(100_000_000))));
+        let first = Arc::new(TransferGroup::new(&bandwidth));
+        let second = Arc::new(TransferGroup::new(&bandwidth));
+        let third = Arc::new(TransferGroup::new(&bandwidth));
+        let (first_properties, second_properties, third_properties) = (
+            properties(80_000_000),
+            properties(10_000_000),
+            properties(100_000_000),
+        );
+
+        // alone, a session gets its target rate
+        let first_transfer = first.start_transfer(first_properties.target_rate);
+        assert!((first.min_ipd(&first_properties) - 100.0).abs() < 1e-6);
+
+        // a session asking for less than an even split leaves the rest to the others
+        let second_transfer = second.start_transfer(second_properties.target_rate);
+        assert!((first.min_ipd(&first_properties) - 100.0).abs() < 1e-6);
+        assert!((second.min_ipd(&second_properties) - 800.0).abs() < 1e-6);
+
+        // which split it evenly once they ask for more than there is
+        let third_transfer = third.start_transfer(third_properties.target_rate);
+        assert!((first.min_ipd(&first_properties) - 8000.0 / 45.0).abs() < 1e-6);
+        assert!((second.min_ipd(&second_properties) - 800.0).abs() < 1e-6);
+        assert!((third.min_ipd(&third_properties) - 8000.0 / 45.0).abs() < 1e-6);
+
+        // the shares grow again as sessions stop
+        drop(second_transfer);
+        assert!((first.min_ipd(&first_properties) - 160.0).abs() < 1e-6);
+        assert!((third.min_ipd(&third_properties) - 160.0).abs() < 1e-6);
+
+        // parallel transfers of a session split its share
+        let first_second_transfer = first.start_transfer(first_properties.target_rate);
+        assert!((first.min_ipd(&first_properties) - 320.0).abs() < 1e-6);
+        assert!((third.min_ipd(&third_properties) - 160.0).abs() < 1e-6);
+
+        drop(third_transfer);
+        assert!((first.min_ipd(&first_properties) - 200.0).abs() < 1e-6);
+        drop(first_second_transfer);
+        assert!((first.min_ipd(&first_properties) - 100.0).abs() < 1e-6);
+        drop(first_transfer);
+        assert!(bandwidth
+            .transmitting
+            .lock()
+            .expect("lock poisoned")
+            .is_empty());
+    }
+
+    #[test]
+    fn min_ipd_without_limit() {
+        let group = Arc::new(TransferGroup::new(&Arc::new(Bandwidth::new(None))));
+        let properties = properties(80_000_000);
+        let _first = group.start_transfer(properties.target_rate);
+        let _second = group.start_transfer(properties.target_rate);
+        assert!((group.min_ipd(&properties) - 200.0).abs() < 1e-6);
+    }
 }
@@ -67,3 +67,81 @@ fn set_udp_transmit_buffer(
 
     Ok(())
 }
+
+/// Enables or disables path MTU discovery on the given UDP socket, i.e. whether the “don't
+/// fragment” bit is set on outgoing datagrams. Returns the previous setting, which can be restored
+/// using `restore_path_mtu_discovery`. Currently only works on Linux.
+///
+/// # Errors
+/// Returns an error if the socket option could not be read or changed.
+pub fn set_path_mtu_discovery(socket: &UdpSocket, enabled: bool) -> anyhow::Result<libc::c_int> {
+    let (level, option, mode) = if socket.local_addr()?.is_ipv6() {
+        (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            if enabled {
+                libc::IPV6_PMTUDISC_DO
+            } else {
+                libc::IPV6_PMTUDISC_DONT
+            },
+        )
+    } else {
+        (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            if enabled {
+                libc::IP_PMTUDISC_DO
+            } else {
+                libc::IP_PMTUDISC_DONT
+            },
+        )
+    };
+
+    let mut previous_mode: libc::c_int = 0;
+    unsafe {
+        #[allow(clippy::cast_possible_truncation)]
+        let mut length = ::core::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let status = libc::getsockopt(
+            socket.as_raw_fd(),
+            level,
+            option,
+            std::ptr::addr_of_mut!(previous_mode).cast(),
+            std::ptr::addr_of_mut!(length),
+        );
+        if status < 0 as libc::c_int {
+            bail!("Could not read path MTU discovery mode");
+        }
+    }
+
+    restore_path_mtu_discovery(socket, mode)?;
+    Ok(previous_mode)
+}
+
+/// Sets the path MTU discovery mode of the given UDP socket to the given raw value, as returned by
+/// `set_path_mtu_discovery`. Currently only works on Linux.
+///
+/// # Errors
+/// Returns an error if the socket option could not be changed.
+pub fn restore_path_mtu_discovery(socket: &UdpSocket, mode: libc::c_int) -> anyhow::Result<()> {
+    let (level, option) = if socket.local_addr()?.is_ipv6() {
+        (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER)
+    };
+
+    unsafe {
+        #[allow(clippy::cast_possible_truncation)]
+        let status = libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            option,
+            std::ptr::addr_of!(mode).cast(),
+            ::core::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        );
+        if status < 0 as libc::c_int {
+            bail!("Could not set path MTU discovery mode");
+        }
+    }
+
+    Ok(())
+}
@@ -3,95 +3,92 @@ use std::{
     io::{Seek, SeekFrom},
     net::ToSocketAddrs,
     os::unix::ffi::OsStrExt,
-    path::Path,
-    time::Instant,
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
 };
 
 use crate::{
+    common::SocketWrapper,
+    compression::Compressor,
     datagram::{self, BlockType},
+    fec,
     message::{
-        self, ClientToServer, FileRequest, FileRequestError, ServerToClient, TransmissionControl,
-        UdpMethod,
+        self, BatchRequest, ClientToServer, FileRequest, FileRequestError, ListRequest,
+        ServerToClient, TransmissionControl, UdpMethod,
+    },
+    stream::{Batch, Pipe, Region, Stream},
+    types::{
+        BlockIndex, BlockRange, Compression, EntryType, ErrorRate, FileMetadata, FileSize,
+        ListOrder, SkipChunks, TargetRate,
     },
-    types::{BlockIndex, FileMetadata, FileSize},
 };
 
 use anyhow::{anyhow, bail};
 
-use super::{IndexMode, Parameter, Session, Transfer};
+use super::{follow::Follow, FileIndex, IndexMode, Parameter, Session, Transfer};
+
+/// Whether the data from standard input has been served already, as it can only be read once.
+static STDIN_SERVED: AtomicBool = AtomicBool::new(false);
+
+/// The largest encoded size of the ranges following a `RetransmitRanges` request: their number,
+/// followed by the first and the last block of each range.
+const RANGES_LIMIT: usize = 8 + crate::common::MAX_RETRANSMIT_RANGES * 8;
 
 /// Handles the given transmission control request. The actions taken depend on the nature of the
 /// request:
 ///
-///  * `Retransmit`: Retransmit the given block.
 ///  * `RestartAt`: Restart the transfer at the given block.
-///  * `SubmitErrorRate`: Use the given error rate to adjust the IPD.
-///
-/// For `Retransmit` messsages, the given buffer must be large enough to hold `block_size + 6`
-/// bytes. For other messages, the datagram parameters are ignored.
+///  * `SubmitErrorRate`: Use the given error rate to adjust the IPD, and the number of parity
+///    blocks sent for forward error correction.
+///  * `SubmitDeliveryRate`: Pass the delivery rate to the congestion controller, which may adjust
+///    the IPD.
+///  * `RetransmitRanges`: Read the ranges that follow, and queue their blocks for retransmission.
+///  * `Ping`: Echo the timestamp, along with our own one.
+///  * `Pong`: Update the round trip time estimate with the echo of our timestamp, and pass the
+///    sample to the congestion controller, which may adjust the IPD.
 ///
 /// # Errors
 /// Returns an error on I/O failure.
 ///
 /// # Panics
-/// Panics if we receive a `Retransmit` request, but no UDP socket is present.
+/// Panics on arithmetic overflow.
 pub fn accept_retransmit(
     session: &mut Session,
     parameter: &Parameter,
     retransmission: &TransmissionControl,
-    datagram_block_buffer: &mut [u8],
-    datagram_buffer: &mut [u8],
     iteration: &mut u32,
 ) -> anyhow::Result<()> {
     #[allow(clippy::match_wildcard_for_single_variants)]
     match *retransmission {
         TransmissionControl::SubmitErrorRate(error_rate) => {
-            // if it's an error rate notification: calculate a new IPD
-            if error_rate > session.properties.error_rate {
-                let factor1: f64 = 1.0_f64 * f64::from(session.properties.slower.numerator)
-                    / f64::from(session.properties.slower.denominator)
-                    - 1.0_f64;
-                let factor2: f64 = (1.0_f64 + f64::from(error_rate.0)
-                    - f64::from(session.properties.error_rate.0))
-                    / (100_000.0_f64 - f64::from(session.properties.error_rate.0));
-                session.transfer.ipd_current *= factor1.mul_add(factor2, 1.0_f64);
-            } else {
-                session.transfer.ipd_current *= f64::from(session.properties.faster.numerator)
-                    / f64::from(session.properties.faster.denominator);
+            // protect the data with more or fewer parity blocks, depending on the losses
+            if let Some(encoder) = session.transfer.fec.as_mut() {
+                encoder.adapt(error_rate);
             }
 
-            // make sure the IPD is still in range, for later calculations
-            session.transfer.ipd_current = session
-                .transfer
-                .ipd_current
-                .clamp(f64::from(session.properties.ipd_time), 10000.0);
-
-            // build the stats string
-            let stats_line = format!(
-                "{:6} {:3.2}µs {:5}µs {:7} {:6.2} {:3}\n",
-                error_rate.0,
+            apply_error_rate(session, parameter, error_rate, iteration);
+        }
+        TransmissionControl::SubmitDeliveryRate(rate) => {
+            let ipd = session.transfer.controller.on_delivery_rate(
+                &session.properties,
                 session.transfer.ipd_current,
-                session.properties.ipd_time,
-                session.transfer.block.0,
-                100.0_f64 * f64::from(session.transfer.block.0)
-                    / f64::from(session.properties.block_count.0),
-                session.session_id,
+                f64::from(rate) * 1000.0,
             );
-
-            // print a status report
-            if *iteration % 23 == 0 {
-                println!(" erate     ipd  target   block   %done srvNr");
-            }
-            *iteration = iteration.wrapping_add(1);
-            print!("{stats_line}");
-
-            // print to the transcript if the user wants
-            if parameter.transcript_yn {
-                crate::common::transcript_warn_error(super::transcript::data_log(
-                    session,
-                    stats_line.as_str(),
-                ));
-            }
+            set_ipd(session, ipd);
+        }
+        TransmissionControl::Ping(echo) => {
+            let stamp = session.transfer.rtt.stamp();
+            write_control(session, ServerToClient::Pong { echo, stamp })?;
+        }
+        TransmissionControl::Pong(stamp) => {
+            let rtt = session.transfer.rtt.update(stamp);
+            let ipd = session.transfer.controller.on_rtt(
+                &session.properties,
+                session.transfer.ipd_current,
+                rtt,
+            );
+            set_ipd(session, ipd);
         }
         TransmissionControl::RestartAt(block) => {
             // if it's a restart request: do range-checking first
@@ -100,24 +97,61 @@ pub fn accept_retransmit(
             }
 
             session.transfer.block = block;
+            session.transfer.retransmits.clear();
         }
-        TransmissionControl::Retransmit(block) => {
+        TransmissionControl::RetransmitRanges(count) => {
             session.properties.retransmit_phase = true;
 
-            // if it's a retransmit request: build the retransmission
-            let datagram = super::io::build_datagram(
-                session,
-                block,
-                BlockType::Retransmission,
-                datagram_block_buffer,
-            )?;
+            let count = usize::try_from(count)?;
+            if count > crate::common::MAX_RETRANSMIT_RANGES {
+                bail!("Client requested retransmission of {count} ranges, which is too many");
+            }
+
+            // The ranges follow right away, so they are read without further ado, but a client
+            // that does not send them must not hold up the transfer forever. Transmission control
+            // requests are read in full, so the ranges are next on the connection.
+            session.client.socket.set_nonblocking(false)?;
+            session
+                .client
+                .socket
+                .set_read_timeout(Some(Duration::from_secs(
+                    super::config::CONTROL_TIMEOUT_SECS,
+                )))?;
+            let ranges: anyhow::Result<Vec<BlockRange>> =
+                session.client.read_limited::<_, RANGES_LIMIT>();
+            session.client.socket.set_read_timeout(None)?;
+            session.client.socket.set_nonblocking(true)?;
+            let ranges = ranges?;
+            if ranges.len() != count {
+                bail!(
+                    "Expected {count} retransmission ranges, but received {}",
+                    ranges.len()
+                );
+            }
 
-            // Send the block away
-            send_datagram(session, parameter, datagram, datagram_buffer)?;
+            // only keep the blocks within the file
+            let block_count = session.properties.block_count;
+            session.transfer.retransmits = ranges
+                .into_iter()
+                .map(|range| BlockRange {
+                    first: range.first.max(BlockIndex(1)),
+                    last: range.last.min(block_count),
+                })
+                .filter(|range| range.first <= range.last)
+                .collect();
         }
         TransmissionControl::RetransmitOver(_) => {
             session.properties.retransmit_phase = false;
         }
+        TransmissionControl::Acknowledge(block) => {
+            if let Some(Stream::Pipe(pipe)) = session.transfer.file.as_mut() {
+                pipe.acknowledge(
+                    u64::from(session.properties.block_size)
+                        .checked_mul(u64::from(block.0))
+                        .expect("acknowledged position overflow"),
+                );
+            }
+        }
         _ => {
             // if it's another kind of request
             bail!(
@@ -131,8 +165,99 @@ pub fn accept_retransmit(
     Ok(())
 }
 
+/// Slows the transfer down as if the receiving side had reported that all blocks were lost,
+/// because it has not sent any transmission control requests for a while. Unlike a reported error
+/// rate, this leaves the forward error correction alone: the lack of requests says nothing about
+/// the losses of the blocks that do arrive, and would make every parity block cover the smallest
+/// possible group.
+pub fn heartbeat_lost(session: &mut Session, parameter: &Parameter, iteration: &mut u32) {
+    apply_error_rate(session, parameter, ErrorRate(100_000), iteration);
+}
+
+/// Uses the given error rate to adjust the IPD, and prints a statistics line.
+fn apply_error_rate(
+    session: &mut Session,
+    parameter: &Parameter,
+    error_rate: ErrorRate,
+    iteration: &mut u32,
+) {
+    let ipd = session.transfer.controller.on_error_rate(
+        &session.properties,
+        session.transfer.ipd_current,
+        error_rate,
+    );
+    set_ipd(session, ipd);
+
+    // build the stats string
+    let stats_line = format!(
+        "{:6} {:3.2}µs {:5}µs {} {:7} {:6.2} {:3}\n",
+        error_rate.0,
+        session.transfer.ipd_current,
+        session.properties.ipd_time,
+        session.transfer.rtt,
+        session.transfer.block.0,
+        100.0_f64 * f64::from(session.transfer.block.0)
+            / f64::from(session.properties.block_count.0),
+        session.session_id,
+    );
+
+    // print a status report
+    if iteration.is_multiple_of(23) {
+        println!(" erate     ipd  target srtt_ms rttvar   block   %done srvNr");
+    }
+    *iteration = iteration.wrapping_add(1);
+    print!("{stats_line}");
+
+    // print to the transcript if the user wants
+    if parameter.transcript_yn {
+        crate::common::transcript_warn_error(super::transcript::data_log(
+            session,
+            stats_line.as_str(),
+        ));
+    }
+}
+
+/// Sends the next block that the client has requested to be retransmitted by a
+/// `RetransmitRanges` request, if any.
+///
+/// # Errors
+/// Returns an error on I/O failure.
+pub fn send_queued_retransmission(
+    session: &mut Session,
+    parameter: &Parameter,
+    datagram_block_buffer: &mut [u8],
+    datagram_buffer: &mut [u8],
+) -> anyhow::Result<()> {
+    let Some(range) = session.transfer.retransmits.front_mut() else {
+        return Ok(());
+    };
+    let block = range.first;
+    if range.first < range.last {
+        range.first = range.first.safe_add(BlockIndex(1));
+    } else {
+        session.transfer.retransmits.pop_front();
+    }
+
+    let datagram = super::io::build_datagram(
+        session,
+        block,
+        BlockType::Retransmission,
+        datagram_block_buffer,
+    )?;
+    send_datagram(session, parameter, datagram, datagram_buffer)?;
+
+    Ok(())
+}
+
+/// Sets the IPD of the current transfer to the given one, within the range allowed for the session.
+fn set_ipd(session: &mut Session, ipd: f64) {
+    // make sure the IPD is still in range, for later calculations
+    session.transfer.ipd_current = super::congestion::clamp_ipd(ipd, session.min_ipd());
+}
+
 /// Send the given `datagram` view as a UDP packet. The `datagram_buffer` is used as an intermediate
-/// and must be `BLOCK_SIZE + 6` bytes long if unencrypted or `BLOCK_SIZE + 30` bytes if encrypted.
+/// and must be `block_size + 6` bytes long if unencrypted or `block_size + 30` bytes if encrypted.
+/// Datagrams with a compressed block only use the beginning of the buffer.
 ///
 /// # Errors
 /// Returns an error on encoding, encryption, or I/O failure.
@@ -145,7 +270,7 @@ pub fn send_datagram(
     datagram: datagram::View,
     datagram_buffer: &mut [u8],
 ) -> anyhow::Result<()> {
-    if parameter.encrypted {
+    let datagram_len = if parameter.encrypted {
         let nonce = session.client.nonce();
 
         // Write the nonce into the first 8 bytes...
@@ -160,10 +285,13 @@ pub fn send_datagram(
         let message = session
             .client
             .encode_encrypt(message_buffer, nonce, datagram)?;
-        assert_eq!(message.len(), message_buffer.len());
+        message
+            .len()
+            .checked_add(8)
+            .expect("datagram length overflow")
     } else {
-        bincode::encode_into_slice(datagram, datagram_buffer, crate::common::BINCODE_CONFIG)?;
-    }
+        bincode::encode_into_slice(datagram, datagram_buffer, crate::common::BINCODE_CONFIG)?
+    };
 
     // try to send out the block
     session
@@ -172,7 +300,7 @@ pub fn send_datagram(
         .as_ref()
         .expect("an UDP socket should have been opened")
         .send_to(
-            datagram_buffer,
+            &datagram_buffer[..datagram_len],
             session
                 .transfer
                 .udp_address
@@ -277,14 +405,14 @@ pub fn authenticate_encrypted(session: &mut Session, secret: &[u8]) -> anyhow::R
 ///
 /// # Errors
 /// Returns an error on I/O failure, or when negotiation was unsuccessful.
-pub fn negotiate(session: &mut Session, parameter: &Parameter) -> anyhow::Result<()> {
+pub fn negotiate(client: &mut SocketWrapper, parameter: &Parameter) -> anyhow::Result<()> {
     let server_revision = crate::version::magic(parameter.encrypted);
 
     // send our protocol revision number to the client
-    session.client.write(server_revision)?;
+    client.write(server_revision)?;
 
     // read the protocol revision number from the client
-    let client_revision: u32 = session.client.read()?;
+    let client_revision: u32 = client.read()?;
 
     // compare the numbers
     if client_revision != server_revision {
@@ -364,34 +492,100 @@ pub fn determine_client_udp_address(
     Ok(())
 }
 
-/// Sends a list of available files to the client.
+/// Sends the client the page of the available files that it has requested, sorted as requested.
 ///
 /// # Errors
 /// Returns an error on I/O failure.
+///
+/// # Panics
+/// Panics if the index lock has been poisoned.
 pub fn send_file_list(
     session: &mut Session,
     parameter: &Parameter,
-    files: &mut Vec<FileMetadata>,
+    files: &FileIndex,
+    request: &ListRequest,
 ) -> anyhow::Result<()> {
+    // Listing a single directory does not require the whole index, so with reindexing enabled,
+    // only that directory is read, which keeps browsing huge trees fast
+    let mut directory_files = vec![];
+    let always = matches!(parameter.index, IndexMode::Always);
+    if let (Some(directory), true) = (request.directory.as_deref(), always) {
+        if file_accessible(parameter, directory) {
+            super::io::index_directory(directory, !request.shallow, &mut directory_files);
+        }
+    }
     // The list of files on the system might have changed since the server has started. However,
     // reindexing is expensive, so we only want to do it if the user actually desires this
     // behaviour.
-    if matches!(parameter.index, IndexMode::Always) {
-        files.clear();
-        super::io::index_files(&parameter.file_names, files);
+    else if always {
+        let mut reindexed = vec![];
+        super::io::index_files(&parameter.file_names, &mut reindexed);
+        let file_count = reindexed
+            .iter()
+            .filter(|metadata| metadata.entry_type == EntryType::File)
+            .count();
         #[allow(clippy::min_ident_chars)]
-        let s = if files.len() == 1 { "" } else { "s" };
-        eprintln!("Found {} file{s} after reindexing.", files.len());
+        let s = if file_count == 1 { "" } else { "s" };
+        eprintln!("Found {file_count} file{s} after reindexing.");
+        *files.write().expect("file index lock poisoned") = reindexed;
     }
 
-    session
-        .client
-        .write(ServerToClient::FileCount(files.len() as u64))?;
+    // only send the entries the client is interested in, so that a huge index does not need to
+    // be sent in full
+    let contained = |metadata: &&FileMetadata| match (request.directory.as_deref(), request.shallow)
+    {
+        (Some(directory), true) => metadata.path.parent() == Some(directory),
+        (Some(directory), false) => {
+            metadata.path != directory && metadata.path.starts_with(directory)
+        }
+        (None, true) => parameter.file_names.contains(&metadata.path),
+        (None, false) => true,
+    };
+    let selected = request.filter.matcher();
+    let index = files.read().expect("file index lock poisoned");
+    let source = if always && request.directory.is_some() {
+        &directory_files
+    } else {
+        &*index
+    };
+    let mut entries: Vec<&FileMetadata> = source
+        .iter()
+        .filter(contained)
+        .filter(|metadata| selected(metadata))
+        .collect();
+
+    // the sort is stable, so that entries which compare equal stay in index order
+    match request.order {
+        ListOrder::Index => {}
+        ListOrder::Name => entries.sort_by(|first, second| first.path.cmp(&second.path)),
+        ListOrder::Size => entries.sort_by_key(|metadata| metadata.size.0),
+        ListOrder::Mtime => entries.sort_by_key(|metadata| metadata.mtime),
+    }
+    if request.reverse {
+        entries.reverse();
+    }
+
+    let total = entries.len() as u64;
+    let page: Vec<FileMetadata> = entries
+        .into_iter()
+        .skip(usize::try_from(request.offset).unwrap_or(usize::MAX))
+        .take(request.limit.map_or(usize::MAX, |limit| {
+            usize::try_from(limit).unwrap_or(usize::MAX)
+        }))
+        .cloned()
+        .collect();
+
+    // don't keep the index from being updated while the client receives the entries
+    drop(index);
+    session.client.write(ServerToClient::FileCount {
+        total,
+        count: page.len() as u64,
+    })?;
 
-    for file_metadata in files {
+    for file_metadata in page {
         session
             .client
-            .write(ServerToClient::FileListEntry(file_metadata.clone()))?;
+            .write(ServerToClient::FileListEntry(file_metadata))?;
     }
 
     Ok(())
@@ -409,25 +603,34 @@ pub fn send_file_list(
 pub fn open_transfer(
     session: &mut Session,
     parameter: &Parameter,
-    request: FileRequest,
+    mut request: FileRequest,
 ) -> anyhow::Result<()> {
     session.transfer = Transfer::default();
 
-    let FileRequest {
-        path,
-        target_rate,
-        error_rate,
-        slowdown,
-        speedup,
-    } = request;
-
     // store the filename in the transfer object
-    let requested_path = session.transfer.filename.insert(path);
+    let requested_path = session
+        .transfer
+        .filename
+        .insert(std::mem::take(&mut request.path));
 
     // make a note of the request
     if parameter.verbose_yn {
         println!("Request for file: '{}'", requested_path.display());
-    };
+    }
+
+    // the data read from standard input is not a file at all
+    if parameter.stdin_name.as_ref() == Some(requested_path) {
+        if STDIN_SERVED.swap(true, Ordering::AcqRel) {
+            session.client.write(ServerToClient::FileRequestError(
+                FileRequestError::Nonexistent,
+            ))?;
+            bail!("The data from standard input has already been served");
+        }
+
+        let pipe = Pipe::new(Box::new(std::io::stdin()), super::config::STREAM_WINDOW);
+        session.transfer.file = Some(Stream::Pipe(pipe));
+        return prepare_transfer(session, parameter, &request, None, None);
+    }
 
     // Check if the file is within one of the served paths, to prevent the client from retrieving
     // files it is not supposed to (files outside of explicitly specified paths, or
@@ -445,7 +648,7 @@ pub fn open_transfer(
 
     // try to open the file for reading
     let file = match std::fs::File::open(&requested_path) {
-        Ok(opened_file) => session.transfer.file.insert(opened_file),
+        Ok(opened_file) => opened_file,
         Err(err) => {
             session.client.write(ServerToClient::FileRequestError(
                 FileRequestError::Nonexistent,
@@ -458,40 +661,171 @@ pub fn open_transfer(
         }
     };
 
-    // store other requested property values
-    session.properties.target_rate = target_rate;
-    session.properties.error_rate = error_rate;
-    session.properties.slower = slowdown;
-    session.properties.faster = speedup;
+    // let the client know the modification time, so it can recognise the file when resuming
+    let mtime = crate::common::file_mtime(&file);
 
-    // determine the file size, and calculate the number of blocks based on that
-    session.properties.file_size = FileSize(file.seek(SeekFrom::End(0))?);
-    file.seek(SeekFrom::Start(0))?;
+    // if only a range of the file has been requested, transfer it as if it were a file of its own
+    if request.offset.is_some() || request.length.is_some() {
+        let size = file.metadata()?.len();
+        let offset = request.offset.unwrap_or(0);
+        let Some(available) = size.checked_sub(offset) else {
+            session.client.write(ServerToClient::FileRequestError(
+                FileRequestError::InvalidRange,
+            ))?;
+            bail!("Requested offset {offset} is beyond the end of the file ({size} bytes)");
+        };
+        let length = request
+            .length
+            .map_or(available, |length| length.min(available));
+        if parameter.verbose_yn {
+            println!("Requested range: {length} bytes starting at offset {offset}");
+        }
 
-    let mut block_count_base = session
-        .properties
-        .file_size
-        .0
-        .checked_div(u64::from(crate::common::BLOCK_SIZE))
-        .expect("block size is zero");
-    let tail_size = session
-        .properties
-        .file_size
-        .0
-        .checked_rem(u64::from(crate::common::BLOCK_SIZE))
-        .expect("block size is zero");
+        session.transfer.file = Some(Stream::Region(Region::new(file, offset, length)));
+        return prepare_transfer(session, parameter, &request, mtime, None);
+    }
 
-    if tail_size != 0 {
-        block_count_base = block_count_base
-            .checked_add(1)
-            .expect("block count overflow");
+    if let Some(idle_seconds) = request.follow_idle {
+        let size = FileSize(file.metadata()?.len());
+        session.transfer.follow = Some(Follow::new(
+            requested_path,
+            size,
+            Duration::from_secs(idle_seconds.into()),
+        ));
     }
 
-    session.properties.block_count =
-        BlockIndex(block_count_base.try_into().expect("block count overflow"));
-    session.properties.epoch = crate::common::epoch();
+    // A whole file that does not grow can be resumed later, and is recognised by a digest of
+    // samples of its data as well
+    let sample_digest = if session.transfer.follow.is_none() {
+        Some(crate::common::sample_digest(&file)?)
+    } else {
+        None
+    };
+    session.transfer.file = Some(Stream::File(file));
+
+    prepare_transfer(session, parameter, &request, mtime, sample_digest)
+}
+
+/// Opens the files of a batch requested by the client, which are transmitted as one stream, and
+/// sends the client their sizes, followed by the usual metadata of the transfer.
+///
+/// # Errors
+/// Returns an error on I/O failure, or if the client requested files that cannot be transmitted.
+///
+/// # Panics
+/// Panics on file or block count overflow.
+pub fn open_batch(
+    session: &mut Session,
+    parameter: &Parameter,
+    request: BatchRequest,
+) -> anyhow::Result<()> {
+    session.transfer = Transfer::default();
+
+    let BatchRequest { request, paths } = request;
+    if parameter.verbose_yn {
+        println!("Request for a batch of {} files", paths.len());
+    }
+
+    let Some(first_path) = paths.first() else {
+        session.client.write(ServerToClient::FileRequestError(
+            FileRequestError::InvalidPath,
+        ))?;
+        bail!("Requested batch is empty");
+    };
+    if paths.len() > crate::common::MAX_BATCH_FILES {
+        session.client.write(ServerToClient::FileRequestError(
+            FileRequestError::InvalidPath,
+        ))?;
+        bail!("Requested batch of {} files is too large", paths.len());
+    }
+
+    // the same checks apply to every file of the batch as to individually requested files
+    if let Some(path) = paths.iter().find(|path| !file_accessible(parameter, path)) {
+        session.client.write(ServerToClient::FileRequestError(
+            FileRequestError::Nonexistent,
+        ))?;
+        bail!(
+            "Requested path '{}' is outside the served directories",
+            path.display()
+        );
+    }
+    let batch = match Batch::open(&paths) {
+        Ok(batch) => batch,
+        Err(err) => {
+            session.client.write(ServerToClient::FileRequestError(
+                FileRequestError::Nonexistent,
+            ))?;
+            return Err(err);
+        }
+    };
+
+    session.transfer.filename = Some(first_path.clone());
+    session
+        .client
+        .write(ServerToClient::BatchSizes(batch.sizes()))?;
+    session.transfer.file = Some(Stream::Batch(batch));
+    session.transfer.batch_filenames = paths;
+
+    // batches cannot be recognised by a modification time when resuming
+    prepare_transfer(session, parameter, &request, None, None)
+}
+
+/// Sets up the transfer of the data that has been opened for the given request, and signals
+/// success to the client, sending it the required metadata fields.
+///
+/// # Errors
+/// Returns an error on I/O failure.
+///
+/// # Panics
+/// Panics on file or block count overflow.
+fn prepare_transfer(
+    session: &mut Session,
+    parameter: &Parameter,
+    request: &FileRequest,
+    mtime: Option<Duration>,
+    sample_digest: Option<[u8; 32]>,
+) -> anyhow::Result<()> {
+    // store other requested property values, limiting the rate to what we allow per session
+    session.properties.target_rate = match parameter.session_rate {
+        Some(session_rate) if session_rate.0 < request.target_rate.0 => {
+            if parameter.verbose_yn {
+                println!(
+                    "Limiting the requested rate of {} bits/s to {} bits/s",
+                    request.target_rate.0, session_rate.0
+                );
+            }
+            TargetRate(session_rate.0.max(1))
+        }
+        _ => request.target_rate,
+    };
+    session.properties.error_rate = request.error_rate;
+    session.properties.slower = request.slowdown;
+    session.properties.faster = request.speedup;
+    session.properties.block_size = negotiate_block_size(parameter, request.block_size);
+    session.transfer.probe_mtu = request.block_size.is_none();
+    session.properties.checksum_algorithm = request.checksum_algorithm;
+    let compression = if parameter.compression {
+        request.compression
+    } else {
+        Compression::None
+    };
+    session.transfer.compressor = Some(Compressor::new(compression)?);
+
+    // determine the file size, and calculate the number of blocks based on that. Streamed data
+    // and followed files cannot be protected by parity blocks, as their final block may be
+    // shorter than the others.
+    determine_file_size(session)?;
+    let streamed = matches!(session.transfer.file, Some(Stream::Pipe(_)));
+    let offset = match &session.transfer.file {
+        Some(Stream::Region(region)) => region.offset(),
+        _ => 0,
+    };
+    if !streamed && session.transfer.follow.is_none() {
+        session.transfer.fec = request.fec_group_size.map(fec::Encoder::new);
+    }
 
     // open a UDP socket now, so we have a port number that the client can try to connect to
+    session.transfer.transfer_id = session.group.next_transfer_id();
     let udp_socket = session
         .transfer
         .udp_socket
@@ -500,14 +834,310 @@ pub fn open_transfer(
     // signal success to the client and send it the required metadata fields
     session.client.write(ServerToClient::FileRequestSuccess {
         file_size: session.properties.file_size,
+        block_size: session.properties.block_size,
         block_count: session.properties.block_count,
         epoch: session.properties.epoch,
         udp_port: udp_socket.local_addr()?.port(),
+        mtime,
+        sample_digest,
+        compression,
+        transfer_id: session.transfer.transfer_id,
+        streamed,
+        offset,
     })?;
 
     Ok(())
 }
 
+/// Chooses the block size for a transfer, which is the block size requested by the client, limited
+/// to the range we allow. If the client did not request a block size, the largest one we allow is
+/// returned, which serves as the upper bound for path MTU probing.
+#[must_use]
+pub fn negotiate_block_size(parameter: &Parameter, requested: Option<u16>) -> u16 {
+    requested.map_or(parameter.max_block_size, |requested| {
+        requested.clamp(crate::common::MIN_BLOCK_SIZE, parameter.max_block_size)
+    })
+}
+
+/// Probes the path MTU to the receiving side, and chooses the block size for the transfer based on
+/// the result. We send padded UDP datagrams of decreasing size, with fragmentation disabled, and
+/// the receiving side tells us the size of the largest one that arrived. The block size currently
+/// set in the session is used as the upper bound. If none of the probes arrive, the default block
+/// size is used.
+///
+/// # Errors
+/// Returns an error on I/O failure, or if the receiving side sends unexpected data.
+///
+/// # Panics
+/// Panics if no UDP socket or address is available, or on arithmetic overflow.
+pub fn probe_path_mtu(session: &mut Session, parameter: &Parameter) -> anyhow::Result<()> {
+    let udp_socket = session
+        .transfer
+        .udp_socket
+        .as_ref()
+        .expect("an UDP socket should have been opened");
+    let udp_address = session
+        .transfer
+        .udp_address
+        .expect("an UDP address should have been set");
+
+    // 8 for nonce + 16 for noise auth data + 6 for block header
+    let overhead: u16 = if parameter.encrypted { 30 } else { 6 };
+    let ip_overhead: u16 = if udp_address.is_ipv6() { 48 } else { 28 };
+    let largest_datagram = session
+        .properties
+        .block_size
+        .checked_add(overhead)
+        .expect("datagram size overflow");
+
+    let probe_sizes = mtu_probe_sizes(largest_datagram, ip_overhead);
+
+    let previous_mode = super::network::set_path_mtu_discovery(udp_socket, true)?;
+    let mut probe = vec![0_u8; usize::from(largest_datagram)];
+    probe[..crate::common::MTU_PROBE_MAGIC.len()].copy_from_slice(crate::common::MTU_PROBE_MAGIC);
+    for size in probe_sizes {
+        for _ in 0..super::config::MTU_PROBE_REPETITIONS {
+            // Sending datagrams larger than the MTU known to the kernel fails immediately
+            if let Err(err) = udp_socket.send_to(&probe[..usize::from(size)], udp_address) {
+                if parameter.verbose_yn {
+                    println!("Could not send MTU probe of {size} bytes: {err}");
+                }
+                break;
+            }
+        }
+    }
+    super::network::restore_path_mtu_discovery(udp_socket, previous_mode)?;
+    session.client.write(ServerToClient::MtuProbesSent)?;
+
+    let ClientToServer::MtuProbeReport(largest_received) = session.client.read()? else {
+        bail!("Expected `MtuProbeReport`");
+    };
+
+    let block_size = probed_block_size(largest_received, overhead, session.properties.block_size);
+    if parameter.verbose_yn {
+        println!(
+            "Largest MTU probe received: {largest_received} bytes, using block size {block_size}"
+        );
+    }
+
+    session.properties.block_size = block_size;
+    if !matches!(session.transfer.file, Some(Stream::Pipe(_))) {
+        update_block_count(session);
+    }
+    session
+        .client
+        .write(ServerToClient::BlockSize(block_size))?;
+
+    Ok(())
+}
+
+/// Returns the sizes of the datagrams to send when probing the path MTU, in decreasing order. The
+/// first probe is the largest datagram we would be willing to send, followed by datagrams that
+/// would fit into common link MTUs, given the size of the IP and UDP headers.
+fn mtu_probe_sizes(largest_datagram: u16, ip_overhead: u16) -> Vec<u16> {
+    let mut probe_sizes = vec![largest_datagram];
+    probe_sizes.extend(
+        super::config::MTU_PROBE_SIZES
+            .iter()
+            .map(|mtu| mtu.saturating_sub(ip_overhead))
+            .filter(|size| *size < largest_datagram),
+    );
+    probe_sizes
+}
+
+/// Chooses the block size based on the size of the largest probe datagram that has been received,
+/// leaving room for the given datagram overhead, and staying within the allowed block sizes. If no
+/// probe has been received, the default block size is used.
+fn probed_block_size(largest_received: u16, overhead: u16, max_block_size: u16) -> u16 {
+    if largest_received == 0 {
+        crate::common::DEFAULT_BLOCK_SIZE.min(max_block_size)
+    } else {
+        largest_received
+            .saturating_sub(overhead)
+            .clamp(crate::common::MIN_BLOCK_SIZE, max_block_size)
+    }
+}
+
+/// Determines the size of the file that is currently open for the transfer, and calculates the
+/// number of blocks of the negotiated size based on that. Also sets the transfer epoch. The size of
+/// data streamed from a pipe is unknown, so a provisional block count is used for it. Of a file
+/// that is followed, only the complete blocks are counted.
+///
+/// # Errors
+/// Returns an error on I/O failure.
+///
+/// # Panics
+/// Panics if no file has been opened.
+pub fn determine_file_size(session: &mut Session) -> anyhow::Result<()> {
+    let file = session
+        .transfer
+        .file
+        .as_mut()
+        .expect("File should have been opened");
+
+    if matches!(file, Stream::Pipe(_)) {
+        session.properties.file_size = FileSize(0);
+        session.properties.block_count = crate::common::STREAM_BLOCK_COUNT;
+    } else {
+        session.properties.file_size = FileSize(file.seek(SeekFrom::End(0))?);
+        file.seek(SeekFrom::Start(0))?;
+        update_block_count(session);
+    }
+    session.properties.epoch = crate::common::epoch();
+
+    Ok(())
+}
+
+/// Calculates the number of blocks of the current transfer from its file size. While the file is
+/// followed and may still grow, its last block is only counted once it is complete.
+///
+/// # Panics
+/// Panics on block count overflow.
+pub fn update_block_count(session: &mut Session) {
+    let file_size = session.properties.file_size;
+    let block_size = session.properties.block_size;
+    session.properties.block_count = if session.transfer.following() {
+        crate::common::complete_block_count(file_size, block_size)
+    } else {
+        crate::common::block_count(file_size, block_size)
+    };
+}
+
+/// Checks whether the followed file of the current transfer has grown or is finished, and if so,
+/// extends the transfer accordingly and informs the client about it.
+///
+/// # Errors
+/// Returns an error on I/O failure.
+pub fn follow_file(session: &mut Session) -> anyhow::Result<()> {
+    let (Some(follow), Some(Stream::File(file))) = (
+        session.transfer.follow.as_mut(),
+        session.transfer.file.as_ref(),
+    ) else {
+        return Ok(());
+    };
+    let Some(file_size) = follow.poll(file)? else {
+        return Ok(());
+    };
+
+    session.properties.file_size = file_size;
+    update_block_count(session);
+    let complete = !session.transfer.following();
+    let block_count = session.properties.block_count;
+    write_control(
+        session,
+        ServerToClient::FileGrown {
+            file_size,
+            block_count,
+            complete,
+        },
+    )
+}
+
+/// Writes the given message to the client during a transfer, while the control connection is
+/// non-blocking. The connection is made blocking for the write, so that the message is always
+/// written in full, rather than being cut off once the send buffer is full; a client that does not
+/// read it in time is given up on.
+fn write_control(session: &mut Session, message: ServerToClient) -> anyhow::Result<()> {
+    let socket = &session.client.socket;
+    socket.set_nonblocking(false)?;
+    socket.set_write_timeout(Some(Duration::from_secs(
+        super::config::CONTROL_TIMEOUT_SECS,
+    )))?;
+    let result = session.client.write(message);
+    let socket = &session.client.socket;
+    socket.set_write_timeout(None)?;
+    socket.set_nonblocking(true)?;
+    result?;
+    Ok(())
+}
+
+/// Determines the local path at which a file uploaded to the given path should be stored. Only
+/// relative paths that stay within the upload directory are accepted. Parent directories are
+/// created as necessary.
+///
+/// # Errors
+/// Returns the error to report to the client if uploads are disabled, or if the path is not
+/// acceptable.
+pub fn upload_destination(parameter: &Parameter, path: &Path) -> Result<PathBuf, FileRequestError> {
+    let Some(upload_dir) = &parameter.upload_dir else {
+        return Err(FileRequestError::UploadsDisabled);
+    };
+
+    // Reject absolute paths, and paths that could escape the upload directory
+    let valid = path.file_name().is_some()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !valid {
+        return Err(FileRequestError::InvalidPath);
+    }
+
+    let Ok(upload_dir_canonical) = upload_dir.canonicalize() else {
+        eprintln!(
+            "Could not canonicalise upload directory '{}'",
+            upload_dir.display()
+        );
+        return Err(FileRequestError::InvalidPath);
+    };
+
+    // Symbolic links within the upload directory could still redirect the upload elsewhere, so
+    // check where the existing part of the path really leads to, before creating any directories
+    let destination = upload_dir.join(path);
+    let parent = destination.parent().unwrap_or(upload_dir);
+    let existing = parent
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(upload_dir);
+    if !within_directory(existing, &upload_dir_canonical) {
+        return Err(FileRequestError::InvalidPath);
+    }
+
+    if let Err(err) = std::fs::create_dir_all(parent) {
+        eprintln!(
+            "Could not create upload directory '{}': {err}",
+            parent.display()
+        );
+        return Err(FileRequestError::InvalidPath);
+    }
+    if !within_directory(parent, &upload_dir_canonical) {
+        return Err(FileRequestError::InvalidPath);
+    }
+
+    // Do not write through a symbolic link in place of the file itself either
+    if destination
+        .symlink_metadata()
+        .is_ok_and(|metadata| metadata.file_type().is_symlink())
+    {
+        eprintln!(
+            "Refusing to upload to symbolic link '{}'",
+            destination.display()
+        );
+        return Err(FileRequestError::InvalidPath);
+    }
+
+    Ok(destination)
+}
+
+// Checks whether the given existing directory is located within the given canonical directory,
+// after resolving symbolic links
+fn within_directory(directory: &Path, base_canonical: &Path) -> bool {
+    let Ok(canonical) = directory.canonicalize() else {
+        eprintln!(
+            "Could not canonicalise upload path '{}'",
+            directory.display()
+        );
+        return false;
+    };
+    if !canonical.starts_with(base_canonical) {
+        eprintln!(
+            "Upload path '{}' leads outside of the upload directory",
+            directory.display()
+        );
+        return false;
+    }
+    true
+}
+
 // Checks whether the given file should be accessible to the client, i.e. whether it is located
 // within one of the served paths (or is itself one of the served paths)
 fn file_accessible(parameter: &Parameter, file: &Path) -> bool {
@@ -535,37 +1165,124 @@ fn file_accessible(parameter: &Parameter, file: &Path) -> bool {
     false
 }
 
-/// Send the client a list of checksums of chunks within the current file. Then, wait for the
-/// client to let us know which of the chunks it already has. We can then skip transmitting these
-/// blocks.
+/// Answer the client's requests for the checksums of chunks within the current file, calculated
+/// using the algorithm the client asked for. The client compares them with the data it already
+/// has, requesting the checksums of smaller chunks where they differ. In delta mode, the client
+/// instead sends the signatures of the blocks of its previous version of the file, and we tell it
+/// where in the current file these blocks can be found. Finally, it lets us know which blocks it
+/// already has, so we can skip transmitting them.
 ///
 /// # Errors
-/// Returns an error on I/O failure.
+/// Returns an error on I/O failure, or if the client sends an invalid request.
 ///
 /// # Panics
 /// Panics if no file has been opened.
 pub fn resume(session: &mut Session) -> anyhow::Result<()> {
+    let mut skip_ranges = vec![];
+    let mut signatures = vec![];
+
+    loop {
+        match session.client.read()? {
+            ClientToServer::ChecksumRequest(request) => {
+                if request.chunk_blocks == 0
+                    || request.chunks.len() > crate::common::MAX_CHECKSUMS_PER_REQUEST
+                {
+                    bail!("Invalid checksum request: {request:?}");
+                }
+                let block_count = u64::from(session.properties.block_count.0);
+                let out_of_range = request.chunks.iter().any(|chunk| {
+                    u64::from(*chunk)
+                        .checked_mul(u64::from(request.chunk_blocks))
+                        .is_none_or(|first_block| first_block >= block_count)
+                });
+                if out_of_range {
+                    bail!(
+                        "Checksum request for chunks beyond the end of the file ({} blocks): {request:?}",
+                        session.properties.block_count.0
+                    );
+                }
+
+                let file = session
+                    .transfer
+                    .file
+                    .as_mut()
+                    .expect("File should have been opened");
+                let checksums = crate::common::calculate_checksums(
+                    file,
+                    session.properties.block_size,
+                    &request,
+                    session.properties.checksum_algorithm,
+                )?;
+                session.client.write(ServerToClient::Checksums(checksums))?;
+            }
+            ClientToServer::DeltaSignatures(batch) => {
+                let max_signatures = crate::delta::max_signatures(session.properties.file_size.0);
+                if signatures.len().saturating_add(batch.len()) > max_signatures {
+                    bail!(
+                        "Client sent more than {max_signatures} delta signatures for a file of {} bytes",
+                        session.properties.file_size.0
+                    );
+                }
+                signatures.extend(batch);
+            }
+            ClientToServer::DeltaRequest(delta_block_size) => {
+                if delta_block_size == 0 || delta_block_size > crate::delta::MAX_DELTA_BLOCK_SIZE {
+                    bail!("Invalid delta block size: {delta_block_size}");
+                }
+
+                let file = session
+                    .transfer
+                    .file
+                    .as_mut()
+                    .expect("File should have been opened");
+                let copies = crate::delta::find_copies(file, delta_block_size, &signatures)?;
+                for batch in copies.chunks(crate::delta::MAX_COPIES_PER_MESSAGE) {
+                    session
+                        .client
+                        .write(ServerToClient::DeltaCopies(batch.to_vec()))?;
+                }
+                session.client.write(ServerToClient::DeltaComplete)?;
+                signatures = vec![];
+            }
+            ClientToServer::SkipBlocks(ranges) => skip_ranges.extend(ranges),
+            ClientToServer::ResumeComplete => break,
+            other => bail!("Expected checksum request or skipped blocks, but got: {other:?}"),
+        }
+    }
+
+    session.transfer.skip_chunks = Some(SkipChunks::from_ranges(skip_ranges));
+
+    Ok(())
+}
+
+/// Sends the receiving side the digests of the file that has just been transmitted, so it can
+/// verify the integrity of the data it has written. Returns the verification result reported back
+/// by the receiving side.
+///
+/// # Errors
+/// Returns an error on I/O failure, or if the receiving side sends unexpected data.
+///
+/// # Panics
+/// Panics if no file has been opened.
+pub fn send_digests(session: &mut Session) -> anyhow::Result<bool> {
     let file = session
         .transfer
         .file
         .as_mut()
         .expect("File should have been opened");
 
-    let chunk_blocks = crate::common::chunk_blocks(session.properties.file_size);
-    let checksums = crate::common::calculate_checksums(
+    let digests = crate::common::calculate_digests(
         file,
         session.properties.file_size,
-        session.properties.block_count,
-        chunk_blocks,
+        crate::common::digest_chunk_size(session.properties.file_size),
     )?;
-    session.client.write(ServerToClient::Checksums(checksums))?;
+    session.client.write(ServerToClient::Digests(digests))?;
 
-    let ClientToServer::SkipChunks(skip_chunks) = session.client.read()? else {
-        bail!("Expected `SkipChunks`");
+    let ClientToServer::VerificationResult(verified) = session.client.read()? else {
+        bail!("Expected `VerificationResult`");
     };
-    session.transfer.skip_chunks = Some(skip_chunks);
 
-    Ok(())
+    Ok(verified)
 }
 
 /// Takes the given ping `Instant`s and uses them to calculate an initial inter-packet delay, which
@@ -597,7 +1314,7 @@ pub fn start_transfer_timing(
         .expect("RTT safety margin overflow");
 
     // ...and store the inter-packet delay
-    session.properties.ipd_time = (u64::from(crate::common::BLOCK_SIZE)
+    session.properties.ipd_time = (u64::from(session.properties.block_size)
         .checked_mul(8_000_000_u64)
         .expect("IPD time calculation overflow (1)")
         .checked_div(session.properties.target_rate.0)
@@ -611,3 +1328,86 @@ pub fn start_transfer_timing(
         crate::common::transcript_warn_error(super::transcript::open(session, parameter));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::{mtu_probe_sizes, probed_block_size, RANGES_LIMIT};
+    use crate::{
+        common::{SocketWrapper, BINCODE_CONFIG, MAX_RETRANSMIT_RANGES},
+        types::{BlockIndex, BlockRange},
+    };
+
+    #[test]
+    fn mtu_probes_fit_common_links() {
+        // unencrypted blocks of the default maximum size over IPv4
+        assert_eq!(
+            mtu_probe_sizes(8_928, 28),
+            [8_928, 4_442, 1_472, 1_464, 1_252]
+        );
+
+        // encrypted blocks over IPv6, where only the smallest link MTU is below the block size
+        assert_eq!(mtu_probe_sizes(1_430, 48), [1_430, 1_232]);
+
+        // nothing smaller than the smallest common link MTU is probed besides the largest datagram
+        assert_eq!(mtu_probe_sizes(1_000, 28), [1_000]);
+    }
+
+    #[test]
+    fn block_size_follows_largest_probe() {
+        // an Ethernet link over IPv4 without encryption
+        assert_eq!(probed_block_size(1_472, 6, 8_922), 1_466);
+
+        // probes may not exceed the allowed block sizes either way
+        assert_eq!(probed_block_size(8_972, 6, 4_000), 4_000);
+        assert_eq!(
+            probed_block_size(100, 30, 8_922),
+            crate::common::MIN_BLOCK_SIZE
+        );
+
+        // without any probe arriving, the default block size is used, unless it is too large
+        assert_eq!(
+            probed_block_size(0, 6, 8_922),
+            crate::common::DEFAULT_BLOCK_SIZE
+        );
+        assert_eq!(probed_block_size(0, 6, 512), 512);
+    }
+
+    fn connected_pair() -> anyhow::Result<(TcpStream, SocketWrapper)> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let sender = TcpStream::connect(listener.local_addr()?)?;
+        let (receiver, _) = listener.accept()?;
+        Ok((sender, SocketWrapper::new(receiver)))
+    }
+
+    #[test]
+    fn largest_range_request_fits_the_limit() -> anyhow::Result<()> {
+        let range = BlockRange {
+            first: BlockIndex(1),
+            last: BlockIndex(2),
+        };
+        let ranges = vec![range; MAX_RETRANSMIT_RANGES];
+        let encoded = bincode::encode_to_vec(&ranges, BINCODE_CONFIG)?;
+        assert_eq!(encoded.len(), RANGES_LIMIT);
+
+        let (mut sender, mut receiver) = connected_pair()?;
+        sender.write_all(&encoded)?;
+        let decoded: Vec<BlockRange> = receiver.read_limited::<_, RANGES_LIMIT>()?;
+        assert_eq!(decoded, ranges);
+        Ok(())
+    }
+
+    #[test]
+    fn huge_range_count_is_rejected() -> anyhow::Result<()> {
+        let (mut sender, mut receiver) = connected_pair()?;
+        sender.write_all(&u64::MAX.to_be_bytes())?;
+        receiver
+            .read_limited::<Vec<BlockRange>, RANGES_LIMIT>()
+            .expect_err("huge range count accepted");
+        Ok(())
+    }
+}
@@ -34,6 +34,9 @@ pub fn close(session: &mut Session, delta: u64) -> anyhow::Result<()> {
         "throughput = {:0>.2}",
         session.properties.file_size.0 as f64 * 8.0_f64 / delta as f64,
     )?;
+    if let Some(srtt) = session.transfer.rtt.srtt() {
+        writeln!(transcript, "srtt_ms = {:0>.2}", srtt.as_secs_f64() * 1000.0)?;
+    }
 
     session.transfer.transcript.take();
     Ok(())
@@ -0,0 +1,444 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use inotify::{EventMask, Events, Inotify, WatchDescriptor, WatchMask};
+
+use crate::types::{EntryType, FileMetadata};
+
+use super::FileIndex;
+
+/// Keeps a shared file index up to date by watching the directories within it for changes, so
+/// that the served paths never need to be walked again. Each changed path is looked up again, so
+/// that the index reflects its current state, however it has changed.
+///
+/// A served path that is removed and created again is not noticed, as there is nothing left to
+/// watch, and neither are changes made while a new directory is still being indexed.
+struct Watcher {
+    inotify: Inotify,
+
+    /// The path of each watched directory, or served file, by its watch.
+    watches: HashMap<WatchDescriptor, PathBuf>,
+    file_names: Vec<PathBuf>,
+    index: FileIndex,
+
+    /// Whether the user has been warned that a watch could not be added.
+    warned: bool,
+}
+
+/// Starts watching the entries of the given index in a separate thread, which applies all
+/// changes to them to the index.
+///
+/// # Errors
+/// Returns an error if inotify cannot be set up.
+///
+/// # Panics
+/// Panics if the index lock has been poisoned.
+pub fn spawn(file_names: &[PathBuf], index: &FileIndex) -> anyhow::Result<()> {
+    let mut watcher = Watcher::new(file_names, index)?;
+    std::thread::spawn(move || {
+        if let Err(err) = watcher.run() {
+            eprintln!(
+                "Watching the served files failed, the index will not be updated anymore: {err}"
+            );
+        }
+    });
+
+    Ok(())
+}
+
+impl Watcher {
+    /// Creates a watcher for the entries of the given index.
+    ///
+    /// # Errors
+    /// Returns an error if inotify cannot be set up.
+    ///
+    /// # Panics
+    /// Panics if the index lock has been poisoned.
+    fn new(file_names: &[PathBuf], index: &FileIndex) -> anyhow::Result<Self> {
+        let mut watcher = Self {
+            inotify: Inotify::init()?,
+            watches: HashMap::new(),
+            file_names: file_names.to_vec(),
+            index: Arc::clone(index),
+            warned: false,
+        };
+
+        let files = index.read().expect("file index lock poisoned").clone();
+        watcher.watch_entries(&files);
+        Ok(watcher)
+    }
+
+    /// Waits for changes, and applies them to the index in batches.
+    fn run(&mut self) -> anyhow::Result<()> {
+        let mut buffer = vec![0_u8; 64 * 1024];
+
+        loop {
+            let mut changed = BTreeMap::new();
+
+            // wait for the first change, then collect the ones following it for a while
+            let events = self.inotify.read_events_blocking(&mut buffer)?;
+            let mut overflow = self.collect(events, &mut changed);
+            std::thread::sleep(super::config::WATCH_BATCH_INTERVAL);
+            overflow |= self.collect_pending(&mut buffer, &mut changed)?;
+
+            self.update(&changed, overflow);
+        }
+    }
+
+    /// Adds the paths changed by the events that are already queued to `changed`, without
+    /// waiting for more. Returns whether events have been dropped by the kernel.
+    fn collect_pending(
+        &mut self,
+        buffer: &mut [u8],
+        changed: &mut BTreeMap<PathBuf, bool>,
+    ) -> std::io::Result<bool> {
+        let mut overflow = false;
+        loop {
+            match self.inotify.read_events(buffer) {
+                Ok(events) => overflow |= self.collect(events, changed),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(overflow),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Applies a batch of changes to the index, or indexes everything again if changes have been
+    /// dropped.
+    fn update(&mut self, changed: &BTreeMap<PathBuf, bool>, overflow: bool) {
+        // if the kernel has dropped changes, we cannot tell what has changed anymore
+        if overflow {
+            eprintln!("Too many changes to the served files to keep track of, reindexing.");
+            self.reindex();
+        } else {
+            self.apply(changed);
+        }
+    }
+
+    /// Adds the paths changed by the given events to `changed`, along with whether they have been
+    /// created (or moved) there. Returns whether events have been dropped by the kernel.
+    fn collect(&self, events: Events, changed: &mut BTreeMap<PathBuf, bool>) -> bool {
+        let mut overflow = false;
+
+        for event in events {
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                overflow = true;
+                continue;
+            }
+            let Some(path) = self.watches.get(&event.wd) else {
+                continue;
+            };
+
+            let path = event
+                .name
+                .map_or_else(|| path.clone(), |name| path.join(name));
+            let created = event
+                .mask
+                .intersects(EventMask::CREATE | EventMask::MOVED_TO);
+            *changed.entry(path).or_default() |= created;
+        }
+
+        overflow
+    }
+
+    /// Looks up the current state of the changed paths, and updates the index accordingly.
+    /// Directories that have been created are indexed along with their contents.
+    fn apply(&mut self, changed: &BTreeMap<PathBuf, bool>) {
+        let states: BTreeMap<&Path, Option<FileMetadata>> = changed
+            .keys()
+            .map(|path| (path.as_path(), self.lookup(path)))
+            .collect();
+
+        // paths that are not directories (anymore) have no entries below them
+        let is_directory = |metadata: &Option<FileMetadata>| {
+            metadata
+                .as_ref()
+                .is_some_and(|metadata| metadata.entry_type == EntryType::Directory)
+        };
+        let removed: Vec<&Path> = states
+            .iter()
+            .filter(|(_, metadata)| !is_directory(metadata))
+            .map(|(path, _)| *path)
+            .collect();
+
+        // Created directories are indexed as a whole, replacing whatever was there before. The
+        // map is ordered, so that directories are found before their contents.
+        let mut created: BTreeMap<&Path, Vec<FileMetadata>> = BTreeMap::new();
+        for (path, metadata) in &states {
+            let below_created = created.keys().any(|directory| path.starts_with(directory));
+            if changed[*path] && is_directory(metadata) && !below_created {
+                created.insert(path, vec![]);
+            }
+        }
+
+        // Served files are watched themselves, so that they keep being watched as long as they
+        // exist. Watching them again covers them having been replaced by a new file.
+        let served: Vec<&Path> = states
+            .iter()
+            .filter(|(path, metadata)| {
+                metadata.is_some() && self.file_names.iter().any(|file_name| file_name == *path)
+            })
+            .map(|(path, _)| *path)
+            .collect();
+        let unwatched: Vec<&Path> = removed
+            .iter()
+            .filter(|path| !served.contains(path))
+            .chain(created.keys())
+            .copied()
+            .collect();
+        self.unwatch(&unwatched);
+        for path in served {
+            self.watch(path);
+        }
+        for (directory, entries) in &mut created {
+            // watch the directory before reading it, so that no entries created in the meantime
+            // are missed
+            self.watch(directory);
+            entries.extend(states[directory].clone());
+            super::io::index_directory(directory, true, entries);
+            self.watch_entries(&entries[1..]);
+        }
+
+        let mut files = self.index.write().expect("file index lock poisoned");
+        let mut updated = HashSet::new();
+        files.retain_mut(|entry| {
+            let replaced = entry
+                .path
+                .ancestors()
+                .any(|path| created.contains_key(path));
+            let below_removed = entry
+                .path
+                .ancestors()
+                .skip(1)
+                .any(|path| removed.contains(&path));
+            if replaced || below_removed {
+                return false;
+            }
+
+            match states.get(entry.path.as_path()) {
+                Some(Some(metadata)) => {
+                    *entry = metadata.clone();
+                    updated.insert(entry.path.clone());
+                    true
+                }
+                Some(None) => false,
+                None => true,
+            }
+        });
+
+        for (path, metadata) in states {
+            if let Some(entries) = created.remove(path) {
+                files.extend(entries);
+            } else if let Some(metadata) = metadata {
+                let below_created = created.keys().any(|directory| path.starts_with(directory));
+                if !below_created && !updated.contains(path) {
+                    files.push(metadata);
+                }
+            }
+        }
+    }
+
+    /// Indexes the served paths again, and watches the new index.
+    fn reindex(&mut self) {
+        let mut watches = self.inotify.watches();
+        for (watch, _) in self.watches.drain() {
+            watches.remove(watch).ok();
+        }
+
+        let mut files = vec![];
+        super::io::index_files(&self.file_names, &mut files);
+        self.watch_entries(&files);
+        *self.index.write().expect("file index lock poisoned") = files;
+    }
+
+    /// Returns the index entry for the given path, or `None` if it does not exist anymore, or
+    /// cannot be transferred. The served paths themselves are followed if they are symbolic links,
+    /// like when indexing them.
+    fn lookup(&self, path: &Path) -> Option<FileMetadata> {
+        let follow_links = self.file_names.iter().any(|file_name| file_name == path);
+        match super::io::entry_metadata(path, follow_links) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                if err.kind() != ErrorKind::NotFound {
+                    eprintln!(
+                        "Could not get metadata of changed file: '{}', error: {err}",
+                        path.display()
+                    );
+                }
+                None
+            }
+        }
+    }
+
+    /// Watches the directories among the given entries, as well as the served paths.
+    fn watch_entries(&mut self, entries: &[FileMetadata]) {
+        for entry in entries {
+            let served = self.file_names.contains(&entry.path);
+            if entry.entry_type == EntryType::Directory || served {
+                self.watch(&entry.path);
+            }
+        }
+    }
+
+    /// Watches the given directory, or served file, for changes.
+    fn watch(&mut self, path: &Path) {
+        let mask = WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MODIFY
+            | WatchMask::ATTRIB
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO
+            | WatchMask::DELETE_SELF
+            | WatchMask::MOVE_SELF;
+
+        match self.inotify.watches().add(path, mask) {
+            Ok(watch) => {
+                self.watches.insert(watch, path.to_path_buf());
+            }
+            Err(err) if !self.warned => {
+                eprintln!(
+                    "WARNING: Cannot watch '{}' for changes, so the index may become outdated: {err}. The number of watches is limited by `fs.inotify.max_user_watches`.",
+                    path.display()
+                );
+                self.warned = true;
+            }
+            Err(_) => {}
+        }
+    }
+
+    /// Stops watching the given paths, and everything below them.
+    fn unwatch(&mut self, paths: &[&Path]) {
+        let mut watches = self.inotify.watches();
+        self.watches.retain(|watch, watched| {
+            let remove = paths.iter().any(|path| watched.starts_with(path));
+            if remove {
+                // the watch has already been removed by the kernel if the directory was deleted
+                watches.remove(watch.clone()).ok();
+            }
+            !remove
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::RwLock;
+
+    use super::*;
+
+    /// Indexes the given paths, and creates a watcher for them.
+    fn watch(file_names: &[PathBuf]) -> anyhow::Result<Watcher> {
+        let mut files = vec![];
+        super::super::io::index_files(file_names, &mut files);
+        Watcher::new(file_names, &FileIndex::new(RwLock::new(files)))
+    }
+
+    /// Applies the changes made so far to the index, and returns the indexed paths with their
+    /// sizes, in order.
+    fn sync(watcher: &mut Watcher) -> anyhow::Result<Vec<(PathBuf, u64)>> {
+        let mut buffer = vec![0_u8; 64 * 1024];
+        let mut changed = BTreeMap::new();
+        let overflow = watcher.collect_pending(&mut buffer, &mut changed)?;
+        assert!(!overflow);
+        watcher.update(&changed, overflow);
+
+        let mut entries: Vec<_> = watcher
+            .index
+            .read()
+            .expect("file index lock poisoned")
+            .iter()
+            .map(|entry| (entry.path.clone(), entry.size.0))
+            .collect();
+        entries.sort();
+        Ok(entries)
+    }
+
+    #[test]
+    fn created_and_removed_files_are_indexed() -> anyhow::Result<()> {
+        let temp = tempfile::tempdir()?;
+        let root = temp.path().join("served");
+        std::fs::create_dir_all(root.join("existing"))?;
+        let mut watcher = watch(std::slice::from_ref(&root))?;
+
+        std::fs::write(root.join("new.bin"), [0_u8; 5])?;
+        std::fs::create_dir_all(root.join("directory"))?;
+        std::fs::write(root.join("directory/nested.bin"), [0_u8; 3])?;
+        assert_eq!(
+            sync(&mut watcher)?,
+            [
+                (root.clone(), 0),
+                (root.join("directory"), 0),
+                (root.join("directory/nested.bin"), 3),
+                (root.join("existing"), 0),
+                (root.join("new.bin"), 5),
+            ]
+        );
+
+        // files created within a new directory are seen once it is watched
+        std::fs::write(root.join("directory/later.bin"), [0_u8; 2])?;
+        std::fs::remove_file(root.join("new.bin"))?;
+        std::fs::remove_dir_all(root.join("existing"))?;
+        assert_eq!(
+            sync(&mut watcher)?,
+            [
+                (root.clone(), 0),
+                (root.join("directory"), 0),
+                (root.join("directory/later.bin"), 2),
+                (root.join("directory/nested.bin"), 3),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn renamed_entries_move_in_the_index() -> anyhow::Result<()> {
+        let temp = tempfile::tempdir()?;
+        let root = temp.path().join("served");
+        std::fs::create_dir_all(root.join("before"))?;
+        std::fs::write(root.join("before/file.bin"), [0_u8; 4])?;
+        std::fs::write(root.join("old.bin"), [0_u8; 1])?;
+        let mut watcher = watch(std::slice::from_ref(&root))?;
+
+        std::fs::rename(root.join("old.bin"), root.join("new.bin"))?;
+        std::fs::rename(root.join("before"), root.join("after"))?;
+        assert_eq!(
+            sync(&mut watcher)?,
+            [
+                (root.clone(), 0),
+                (root.join("after"), 0),
+                (root.join("after/file.bin"), 4),
+                (root.join("new.bin"), 1),
+            ]
+        );
+
+        // the moved directory is still watched under its new name
+        std::fs::write(root.join("after/added.bin"), [0_u8; 6])?;
+        assert!(sync(&mut watcher)?.contains(&(root.join("after/added.bin"), 6)));
+        Ok(())
+    }
+
+    #[test]
+    fn replaced_served_file_stays_watched() -> anyhow::Result<()> {
+        let temp = tempfile::tempdir()?;
+        let served = temp.path().join("served.bin");
+        std::fs::write(&served, [0_u8; 1])?;
+        let mut watcher = watch(std::slice::from_ref(&served))?;
+
+        // replace the file atomically, as editors and downloads do
+        let replacement = temp.path().join("replacement.bin");
+        std::fs::write(&replacement, [0_u8; 8])?;
+        std::fs::rename(&replacement, &served)?;
+        assert_eq!(sync(&mut watcher)?, [(served.clone(), 8)]);
+
+        // the new file is watched in turn
+        std::fs::write(&served, [0_u8; 2])?;
+        assert_eq!(sync(&mut watcher)?, [(served.clone(), 2)]);
+
+        std::fs::remove_file(&served)?;
+        assert_eq!(sync(&mut watcher)?, []);
+        Ok(())
+    }
+}
@@ -0,0 +1,957 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use crate::types::FileSize;
+
+/// The data of a transfer, which is either a single file, a range of a file, or a batch of files
+/// that is transferred as one stream. Data that is streamed rather than stored in files is read
+/// from a pipe, or written to an output in order.
+pub enum Stream {
+    File(File),
+    Region(Region),
+    Batch(Batch),
+    Pipe(Pipe),
+    Output(Output),
+}
+
+impl Stream {
+    /// Returns the size of the stream in bytes.
+    ///
+    /// # Errors
+    /// Returns an error on I/O failure.
+    pub fn size(&self) -> std::io::Result<u64> {
+        match self {
+            Self::File(file) => Ok(file.metadata()?.len()),
+            Self::Region(region) => Ok(region.size),
+            Self::Batch(batch) => Ok(batch.size().0),
+            Self::Pipe(pipe) => pipe.size().ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "the size of a pipe is unknown until it has been read completely",
+                )
+            }),
+            Self::Output(output) => Ok(output.emitted()),
+        }
+    }
+
+    /// Makes sure all data written to the stream has reached the disk.
+    ///
+    /// # Errors
+    /// Returns an error on I/O failure.
+    pub fn sync_data(&self) -> std::io::Result<()> {
+        match self {
+            Self::File(file) => file.sync_data(),
+            Self::Region(region) => region.file.sync_data(),
+            Self::Batch(batch) => batch
+                .files
+                .iter()
+                .try_for_each(|entry| entry.file.sync_data()),
+            Self::Pipe(_) | Self::Output(_) => Ok(()),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::File(file) => file.read(buf),
+            Self::Region(region) => region.read(buf),
+            Self::Batch(batch) => batch.read(buf),
+            Self::Pipe(pipe) => pipe.read(buf),
+            Self::Output(_) => Err(unsupported("reading from an output")),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::File(file) => file.write(buf),
+            Self::Region(region) => region.write(buf),
+            Self::Batch(batch) => batch.write(buf),
+            Self::Pipe(_) => Err(unsupported("writing to a pipe")),
+            Self::Output(output) => output.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::File(file) => file.flush(),
+            Self::Region(_) | Self::Batch(_) | Self::Pipe(_) => Ok(()),
+            Self::Output(output) => output.flush(),
+        }
+    }
+}
+
+impl Seek for Stream {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            Self::File(file) => file.seek(pos),
+            Self::Region(region) => region.seek(pos),
+            Self::Batch(batch) => batch.seek(pos),
+            Self::Pipe(pipe) => pipe.seek(pos),
+            Self::Output(output) => output.seek(pos),
+        }
+    }
+}
+
+fn unsupported(operation: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("{operation} is not supported"),
+    )
+}
+
+/// Returns the position that results from seeking to the given position, for streams that can only
+/// seek relative to their start, or to their current position.
+fn seek_position(pos: SeekFrom, position: u64) -> std::io::Result<u64> {
+    match pos {
+        SeekFrom::Start(offset) => Ok(offset),
+        SeekFrom::Current(offset) => position.checked_add_signed(offset).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        }),
+        SeekFrom::End(_) => Err(unsupported("seeking relative to the end of a stream")),
+    }
+}
+
+/// A range of a file, which is transferred as if it were a file of its own, e.g. to transfer only
+/// the header of a large file, or to write the range into the matching region of an existing file.
+/// Positions within the stream are relative to the start of the range.
+pub struct Region {
+    file: File,
+    offset: u64,
+    size: u64,
+    position: u64,
+}
+
+impl Region {
+    /// Creates a stream covering the given number of bytes of the given file, starting at the
+    /// given offset.
+    #[must_use]
+    pub const fn new(file: File, offset: u64, size: u64) -> Self {
+        Self {
+            file,
+            offset,
+            size,
+            position: 0,
+        }
+    }
+
+    /// Returns the offset of the range within the file.
+    #[must_use]
+    pub const fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the position within the file that corresponds to the current position, together
+    /// with the number of bytes of the range that follow.
+    fn current(&self) -> Option<(u64, u64)> {
+        let remaining = self.size.checked_sub(self.position)?;
+        let file_position = self.offset.checked_add(self.position)?;
+        Some((file_position, remaining))
+    }
+
+    fn advance(&mut self, count: usize) {
+        self.position = self
+            .position
+            .checked_add(count as u64)
+            .expect("region position overflow");
+    }
+}
+
+impl Read for Region {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some((file_position, remaining)) = self.current() else {
+            return Ok(0);
+        };
+
+        let length = buf
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        let read_count = self.file.read_at(&mut buf[..length], file_position)?;
+        self.advance(read_count);
+        Ok(read_count)
+    }
+}
+
+impl Write for Region {
+    /// Writes as much of the given data as fits into the range from the current position.
+    ///
+    /// # Errors
+    /// Returns an error on I/O failure, or if there is data to write but the current position is
+    /// at or beyond the end of the range, which would otherwise be reported as a zero-length write.
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let Some((file_position, remaining)) =
+            self.current().filter(|(_, remaining)| *remaining > 0)
+        else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "cannot write at position {} beyond the end of the range of {} bytes",
+                    self.position, self.size
+                ),
+            ));
+        };
+
+        let length = buf
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        let write_count = self.file.write_at(&buf[..length], file_position)?;
+        self.advance(write_count);
+        Ok(write_count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for Region {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        let Some(position) = position else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            ));
+        };
+
+        self.position = position;
+        Ok(position)
+    }
+}
+
+/// One file within a `Batch`.
+struct BatchFile {
+    file: File,
+    offset: u64,
+    size: u64,
+}
+
+/// A list of files that is transferred as one virtual stream, which consists of the contents of
+/// the files concatenated in order. This allows many small files to be transferred without setting
+/// up a transfer for each of them.
+pub struct Batch {
+    files: Vec<BatchFile>,
+    size: u64,
+    position: u64,
+}
+
+impl Batch {
+    /// Opens the given files for reading, in order.
+    ///
+    /// # Errors
+    /// Returns an error if one of the files cannot be opened, naming the file.
+    pub fn open(paths: &[PathBuf]) -> anyhow::Result<Self> {
+        let mut files = Vec::with_capacity(paths.len());
+        for path in paths {
+            let file = File::open(path)
+                .and_then(|file| Ok((file.metadata()?.len(), file)))
+                .map_err(|err| {
+                    anyhow::anyhow!(
+                        "File '{}' does not exist or cannot be read: {err}",
+                        path.display()
+                    )
+                })?;
+            files.push(file);
+        }
+
+        Ok(Self::new(files))
+    }
+
+    /// Creates the given files for writing, or opens them if they already exist, and sets their
+    /// sizes to the given ones.
+    ///
+    /// # Errors
+    /// Returns an error on I/O failure.
+    pub fn create(files: &[(&Path, FileSize)]) -> anyhow::Result<Self> {
+        let mut opened = Vec::with_capacity(files.len());
+        for (path, size) in files {
+            let file = File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            file.set_len(size.0)?;
+            opened.push((size.0, file));
+        }
+
+        Ok(Self::new(opened))
+    }
+
+    fn new(sized_files: Vec<(u64, File)>) -> Self {
+        let mut size = 0_u64;
+        let files = sized_files
+            .into_iter()
+            .map(|(file_size, file)| {
+                let offset = size;
+                size = size.checked_add(file_size).expect("batch size overflow");
+                BatchFile {
+                    file,
+                    offset,
+                    size: file_size,
+                }
+            })
+            .collect();
+
+        Self {
+            files,
+            size,
+            position: 0,
+        }
+    }
+
+    /// Returns the total size of the files in the batch.
+    #[must_use]
+    pub fn size(&self) -> FileSize {
+        FileSize(self.size)
+    }
+
+    /// Returns the sizes of the individual files in the batch.
+    #[must_use]
+    pub fn sizes(&self) -> Vec<FileSize> {
+        self.files
+            .iter()
+            .map(|entry| FileSize(entry.size))
+            .collect()
+    }
+
+    /// Returns the file containing the current position, together with the position within that
+    /// file and the number of bytes of it that follow.
+    fn current(&self) -> Option<(&BatchFile, u64, u64)> {
+        // empty files never contain the position, and are skipped this way
+        let index = self
+            .files
+            .partition_point(|entry| entry.offset.saturating_add(entry.size) <= self.position);
+        let entry = self.files.get(index)?;
+        let file_position = self.position.checked_sub(entry.offset)?;
+        let remaining = entry.size.checked_sub(file_position)?;
+        Some((entry, file_position, remaining))
+    }
+
+    fn advance(&mut self, count: usize) {
+        self.position = self
+            .position
+            .checked_add(count as u64)
+            .expect("batch position overflow");
+    }
+}
+
+impl Read for Batch {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some((entry, file_position, remaining)) = self.current() else {
+            return Ok(0);
+        };
+
+        let length = buf
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        let read_count = entry.file.read_at(&mut buf[..length], file_position)?;
+        self.advance(read_count);
+        Ok(read_count)
+    }
+}
+
+impl Write for Batch {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let Some((entry, file_position, remaining)) = self.current() else {
+            return Ok(0);
+        };
+
+        let length = buf
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        let write_count = entry.file.write_at(&buf[..length], file_position)?;
+        self.advance(write_count);
+        Ok(write_count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for Batch {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        let Some(position) = position else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            ));
+        };
+
+        self.position = position;
+        Ok(position)
+    }
+}
+
+/// Data read from a pipe, such as standard input, whose size is unknown until all of it has been
+/// read. The data is buffered, so that it can be read again from any position within a window
+/// behind the data that has been read furthest. The receiving side acknowledges the data it has
+/// received, which allows the window to move forward; data is only read from the source as far
+/// ahead of the acknowledged data as the window allows.
+pub struct Pipe {
+    source: Box<dyn Read + Send>,
+    buffer: Vec<u8>,
+
+    /// The position of the first byte in the buffer.
+    start: u64,
+    acknowledged: u64,
+    window: u64,
+    position: u64,
+    end_reached: bool,
+}
+
+impl Pipe {
+    /// Creates a pipe reading from the given source, buffering at most the given number of bytes
+    /// beyond the acknowledged data.
+    #[must_use]
+    pub fn new(source: Box<dyn Read + Send>, window: u64) -> Self {
+        Self {
+            source,
+            buffer: vec![],
+            start: 0,
+            acknowledged: 0,
+            window,
+            position: 0,
+            end_reached: false,
+        }
+    }
+
+    /// Returns the total size of the data, once the end of the source has been reached.
+    #[must_use]
+    pub fn size(&self) -> Option<u64> {
+        self.end_reached.then(|| self.buffered_end())
+    }
+
+    /// Returns whether the data up to the given position can be read without exceeding the window,
+    /// or whether it needs to be acknowledged further first.
+    #[must_use]
+    pub fn can_read_to(&self, position: u64) -> bool {
+        self.end_reached
+            || position <= self.buffered_end()
+            || position <= self.acknowledged.saturating_add(self.window)
+    }
+
+    /// Records that the receiving side has received all data before the given position, so that
+    /// it does not need to be buffered any longer.
+    ///
+    /// # Panics
+    /// Panics if the buffered data exceeds the address space.
+    pub fn acknowledge(&mut self, position: u64) {
+        self.acknowledged = self.acknowledged.max(position.min(self.buffered_end()));
+
+        // only discard data once a sizeable part of the buffer can be freed, to avoid moving the
+        // remaining data around all the time
+        let discardable = self.acknowledged.saturating_sub(self.start);
+        if discardable >= self.window / 4 || discardable == self.buffer.len() as u64 {
+            let discardable = usize::try_from(discardable).expect("buffer position overflow");
+            self.buffer.drain(..discardable);
+            self.start = self.acknowledged;
+        }
+    }
+
+    fn buffered_end(&self) -> u64 {
+        self.start
+            .checked_add(self.buffer.len() as u64)
+            .expect("pipe position overflow")
+    }
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position < self.start {
+            return Err(std::io::Error::other(format!(
+                "data at position {} is no longer buffered",
+                self.position
+            )));
+        }
+
+        // read more data from the source, if the requested data has not been buffered yet
+        let requested_end = self
+            .position
+            .checked_add(buf.len() as u64)
+            .expect("pipe position overflow");
+        while !self.end_reached && self.buffered_end() < requested_end {
+            let missing = usize::try_from(requested_end.saturating_sub(self.buffered_end()))
+                .expect("pipe buffer size overflow");
+            let buffered = self.buffer.len();
+            self.buffer.resize(
+                buffered.checked_add(missing).expect("pipe buffer overflow"),
+                0,
+            );
+            let result = self.source.read(&mut self.buffer[buffered..]);
+            let read_count = result.as_ref().map_or(0, |read_count| *read_count);
+            self.buffer.truncate(
+                buffered
+                    .checked_add(read_count)
+                    .expect("pipe buffer overflow"),
+            );
+            match result {
+                Ok(0) => self.end_reached = true,
+                Ok(_) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        let offset = usize::try_from(self.position.saturating_sub(self.start))
+            .expect("pipe buffer position overflow");
+        let available = self.buffer.get(offset..).unwrap_or_default();
+        let length = buf.len().min(available.len());
+        buf[..length].copy_from_slice(&available[..length]);
+        self.position = self
+            .position
+            .checked_add(length as u64)
+            .expect("pipe position overflow");
+        Ok(length)
+    }
+}
+
+impl Seek for Pipe {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = seek_position(pos, self.position)?;
+        Ok(self.position)
+    }
+}
+
+/// An output, such as standard output, to which data that is received in arbitrary order is
+/// written in order. Data that arrives ahead of the data that is due next is kept in memory until
+/// the gap before it has been filled, but only within a window following that data. The position up
+/// to which data has been written is shared, so that the receiving side can discard data beyond the
+/// window before writing it.
+pub struct Output {
+    sink: File,
+    pending: BTreeMap<u64, Vec<u8>>,
+    emitted: Arc<AtomicU64>,
+    window: u64,
+    position: u64,
+}
+
+impl Output {
+    /// Creates an output writing to the given sink, keeping data that arrives ahead of the data
+    /// due next in memory if it starts within the given number of bytes of it.
+    #[must_use]
+    pub fn new(sink: File, window: u64) -> Self {
+        Self {
+            sink,
+            pending: BTreeMap::new(),
+            emitted: Arc::new(AtomicU64::new(0)),
+            window,
+            position: 0,
+        }
+    }
+
+    /// Returns the position up to which data has been written to the sink. The returned value is
+    /// updated as more data is written.
+    #[must_use]
+    pub fn emitted_position(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.emitted)
+    }
+
+    fn emitted(&self) -> u64 {
+        self.emitted.load(Ordering::Acquire)
+    }
+
+    fn emit(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.sink.write_all(data)?;
+        self.emitted.fetch_add(data.len() as u64, Ordering::AcqRel);
+        Ok(())
+    }
+}
+
+/// Returns the part of the given data starting at the given position that lies at or beyond the
+/// given position, up to which data has been written already.
+fn unwritten(data: &[u8], position: u64, emitted: u64) -> &[u8] {
+    let written = usize::try_from(emitted.saturating_sub(position)).unwrap_or(usize::MAX);
+    data.get(written..).unwrap_or_default()
+}
+
+impl Write for Output {
+    /// Writes the given data if it is due next, or keeps it in memory if it lies ahead. Data that
+    /// has been written already, such as that of a block received twice, is ignored; if only its
+    /// beginning has been written, the rest is written.
+    ///
+    /// # Errors
+    /// Returns an error on I/O failure, or if the data starts beyond the window, as it cannot be
+    /// kept in memory.
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let emitted = self.emitted();
+        if self.position <= emitted {
+            self.emit(unwritten(buf, self.position, emitted))?;
+
+            // the gap before the data kept in memory may have been filled now
+            while let Some((position, data)) = self.pending.pop_first() {
+                let emitted = self.emitted();
+                if position > emitted {
+                    self.pending.insert(position, data);
+                    break;
+                }
+                self.emit(unwritten(&data, position, emitted))?;
+            }
+        } else if self.position.saturating_sub(emitted) < self.window {
+            self.pending.insert(self.position, buf.to_vec());
+        } else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "data at position {} lies too far ahead of the data due next at {emitted}",
+                    self.position
+                ),
+            ));
+        }
+
+        self.position = self
+            .position
+            .checked_add(buf.len() as u64)
+            .expect("output position overflow");
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.sink.flush()
+    }
+}
+
+impl Seek for Output {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = seek_position(pos, self.position)?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Cursor, Read, Seek, SeekFrom, Write},
+        path::{Path, PathBuf},
+    };
+
+    use super::{Batch, Output, Pipe, Region};
+    use crate::types::FileSize;
+
+    /// Returns test data whose bytes differ from their neighbours.
+    fn data(length: u8) -> Vec<u8> {
+        (0..length).collect()
+    }
+
+    /// The sizes of the files of the test batches: the boundary between the first and the last
+    /// file, with an empty file between them, lies in the middle of the second 8 byte block.
+    const BATCH_SIZES: [u8; 3] = [10, 0, 7];
+
+    fn batch_paths(directory: &Path) -> Vec<PathBuf> {
+        ["first", "empty", "last"]
+            .iter()
+            .map(|name| directory.join(name))
+            .collect()
+    }
+
+    #[test]
+    fn batch_reads_files_as_one_stream() -> anyhow::Result<()> {
+        let directory = tempfile::tempdir()?;
+        let paths = batch_paths(directory.path());
+        let contents = data(17);
+        std::fs::write(&paths[0], &contents[..10])?;
+        std::fs::write(&paths[1], b"")?;
+        std::fs::write(&paths[2], &contents[10..])?;
+
+        let mut batch = Batch::open(&paths)?;
+        assert_eq!(batch.size(), FileSize(17));
+        assert_eq!(
+            batch.sizes(),
+            BATCH_SIZES.map(|size| FileSize(size.into())).to_vec()
+        );
+
+        // the block spanning the file boundary is read from both files
+        let mut block = [0_u8; 8];
+        batch.seek(SeekFrom::Start(8))?;
+        batch.read_exact(&mut block)?;
+        assert_eq!(block, contents[8..16]);
+
+        // the final block is short
+        let mut rest = vec![];
+        batch.seek(SeekFrom::Start(16))?;
+        batch.read_to_end(&mut rest)?;
+        assert_eq!(rest, contents[16..]);
+
+        let mut all = vec![];
+        batch.seek(SeekFrom::Start(0))?;
+        batch.read_to_end(&mut all)?;
+        assert_eq!(all, contents);
+
+        batch.seek(SeekFrom::End(5))?;
+        assert_eq!(batch.read(&mut block)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn batch_writes_files_as_one_stream() -> anyhow::Result<()> {
+        let directory = tempfile::tempdir()?;
+        let paths = batch_paths(directory.path());
+        let files: Vec<_> = paths
+            .iter()
+            .zip(BATCH_SIZES)
+            .map(|(path, size)| (path.as_path(), FileSize(size.into())))
+            .collect();
+        let contents = data(17);
+
+        // blocks are written out of order, as they are received
+        let mut batch = Batch::create(&files)?;
+        for block_start in [8, 16, 0] {
+            let block_end = (block_start + 8).min(contents.len());
+            batch.seek(SeekFrom::Start(block_start as u64))?;
+            batch.write_all(&contents[block_start..block_end])?;
+        }
+
+        assert_eq!(std::fs::read(&paths[0])?, contents[..10]);
+        assert_eq!(std::fs::read(&paths[1])?, b"");
+        assert_eq!(std::fs::read(&paths[2])?, contents[10..]);
+
+        // nothing can be written past the end of the last file
+        batch.seek(SeekFrom::End(0))?;
+        assert_eq!(batch.write(b"x")?, 0);
+        assert_eq!(std::fs::read(&paths[2])?, contents[10..]);
+        Ok(())
+    }
+
+    #[test]
+    fn region_reads_at_its_offset() -> anyhow::Result<()> {
+        let mut file = tempfile::tempfile()?;
+        file.write_all(&data(40))?;
+
+        let mut region = Region::new(file, 10, 20);
+        assert_eq!(region.offset(), 10);
+        let mut contents = vec![];
+        region.read_to_end(&mut contents)?;
+        assert_eq!(contents, data(30)[10..]);
+
+        let mut block = [0_u8; 8];
+        region.seek(SeekFrom::Start(16))?;
+        assert_eq!(region.read(&mut block)?, 4);
+        assert_eq!(block[..4], data(30)[26..]);
+
+        // reading at or beyond the end of the region yields no data
+        assert_eq!(region.read(&mut block)?, 0);
+        region.seek(SeekFrom::End(5))?;
+        assert_eq!(region.read(&mut block)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn region_writes_at_its_offset() -> anyhow::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        std::fs::write(file.path(), [0xff; 40])?;
+
+        let mut region = Region::new(file.reopen()?, 10, 20);
+        region.seek(SeekFrom::Start(16))?;
+        region.write_all(&data(4))?;
+        region.seek(SeekFrom::Start(0))?;
+        region.write_all(&data(16))?;
+
+        let contents = std::fs::read(file.path())?;
+        assert_eq!(contents[..10], [0xff; 10]);
+        assert_eq!(contents[10..26], data(16));
+        assert_eq!(contents[26..30], data(4));
+        assert_eq!(contents[30..], [0xff; 10]);
+        Ok(())
+    }
+
+    #[test]
+    fn region_rejects_writes_beyond_its_end() -> anyhow::Result<()> {
+        let file = tempfile::NamedTempFile::new()?;
+        std::fs::write(file.path(), [0xff; 40])?;
+
+        // data that does not fit is cut off, and writing the rest fails
+        let mut region = Region::new(file.reopen()?, 10, 20);
+        region.seek(SeekFrom::Start(16))?;
+        assert_eq!(region.write(&data(8))?, 4);
+        assert_eq!(region.write(&[])?, 0);
+        let err = region
+            .write(&data(4))
+            .expect_err("write beyond the end accepted");
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("beyond the end of the range"));
+
+        region.seek(SeekFrom::Start(18))?;
+        let err = region
+            .write_all(&data(4))
+            .expect_err("write beyond the end accepted");
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        let contents = std::fs::read(file.path())?;
+        assert_eq!(contents[30..], [0xff; 10]);
+        Ok(())
+    }
+
+    fn pipe(length: u8, window: u64) -> Pipe {
+        Pipe::new(Box::new(Cursor::new(data(length))), window)
+    }
+
+    fn read_at(pipe: &mut Pipe, position: u64, length: usize) -> std::io::Result<Vec<u8>> {
+        let mut buffer = vec![0_u8; length];
+        pipe.seek(SeekFrom::Start(position))?;
+        let read_count = pipe.read(&mut buffer)?;
+        buffer.truncate(read_count);
+        Ok(buffer)
+    }
+
+    #[test]
+    fn pipe_reads_only_within_the_window() -> std::io::Result<()> {
+        let mut pipe = pipe(200, 100);
+        assert!(pipe.can_read_to(100));
+        assert!(!pipe.can_read_to(101));
+
+        assert_eq!(read_at(&mut pipe, 0, 60)?, data(60));
+        pipe.acknowledge(40);
+        assert!(pipe.can_read_to(140));
+        assert!(!pipe.can_read_to(141));
+
+        // the size is only known once the end of the source has been reached, after which all of
+        // the data can be read
+        assert_eq!(pipe.size(), None);
+        assert_eq!(read_at(&mut pipe, 60, 200)?, data(200)[60..]);
+        assert_eq!(pipe.size(), Some(200));
+        assert!(pipe.can_read_to(u64::MAX));
+        Ok(())
+    }
+
+    #[test]
+    fn pipe_discards_acknowledged_data_in_steps() -> std::io::Result<()> {
+        let mut pipe = pipe(200, 100);
+        assert_eq!(read_at(&mut pipe, 0, 80)?, data(80));
+
+        // less than a quarter of the window is kept, to avoid moving the buffer all the time
+        pipe.acknowledge(20);
+        assert_eq!((pipe.start, pipe.buffer.len()), (0, 80));
+        assert_eq!(read_at(&mut pipe, 10, 10)?, data(20)[10..]);
+
+        pipe.acknowledge(30);
+        assert_eq!((pipe.start, pipe.buffer.len()), (30, 50));
+        assert_eq!(read_at(&mut pipe, 30, 10)?, data(40)[30..]);
+
+        // acknowledgements beyond the buffered data, or behind earlier ones, change nothing
+        pipe.acknowledge(500);
+        assert_eq!(pipe.acknowledged, 80);
+        pipe.acknowledge(10);
+        assert_eq!(pipe.acknowledged, 80);
+        Ok(())
+    }
+
+    #[test]
+    fn pipe_cannot_seek_behind_buffered_data() -> std::io::Result<()> {
+        let mut pipe = pipe(200, 100);
+        assert_eq!(read_at(&mut pipe, 0, 80)?, data(80));
+        pipe.acknowledge(30);
+
+        let err = read_at(&mut pipe, 29, 10).expect_err("discarded data read");
+        assert!(err.to_string().contains("no longer buffered"));
+        assert_eq!(read_at(&mut pipe, 30, 10)?, data(40)[30..]);
+        Ok(())
+    }
+
+    fn output(window: u64) -> std::io::Result<Output> {
+        Ok(Output::new(tempfile::tempfile()?, window))
+    }
+
+    fn write_at(output: &mut Output, position: u64, data: &[u8]) -> std::io::Result<()> {
+        output.seek(SeekFrom::Start(position))?;
+        output.write_all(data)
+    }
+
+    fn written(output: &mut Output) -> std::io::Result<Vec<u8>> {
+        let mut contents = vec![];
+        output.sink.seek(SeekFrom::Start(0))?;
+        output.sink.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    #[test]
+    fn output_reorders_writes() -> std::io::Result<()> {
+        let data = data(40);
+        let mut output = output(100)?;
+        write_at(&mut output, 20, &data[20..30])?;
+        write_at(&mut output, 10, &data[10..20])?;
+        assert_eq!(written(&mut output)?, vec![]);
+        assert_eq!(output.emitted(), 0);
+
+        // filling the gap writes all data that has been kept back
+        write_at(&mut output, 0, &data[..10])?;
+        assert_eq!(written(&mut output)?, data[..30]);
+        assert_eq!(output.emitted(), 30);
+        assert!(output.pending.is_empty());
+
+        write_at(&mut output, 30, &data[30..])?;
+        assert_eq!(written(&mut output)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn output_ignores_duplicate_writes() -> std::io::Result<()> {
+        let data = data(40);
+        let mut output = output(100)?;
+        write_at(&mut output, 20, &data[20..30])?;
+        write_at(&mut output, 20, &data[20..30])?;
+        write_at(&mut output, 0, &data[..10])?;
+        write_at(&mut output, 0, &data[..10])?;
+        write_at(&mut output, 10, &data[10..20])?;
+        write_at(&mut output, 10, &data[10..20])?;
+        assert_eq!(written(&mut output)?, data[..30]);
+        Ok(())
+    }
+
+    #[test]
+    fn output_writes_only_the_new_part_of_overlapping_writes() -> std::io::Result<()> {
+        let data = data(40);
+        let mut output = output(100)?;
+        write_at(&mut output, 0, &data[..20])?;
+
+        // seeking behind the data that has been written already
+        write_at(&mut output, 10, &data[10..30])?;
+        assert_eq!(written(&mut output)?, data[..30]);
+
+        // the same applies to data kept back until the gap before it has been filled
+        write_at(&mut output, 35, &data[35..])?;
+        write_at(&mut output, 25, &data[25..37])?;
+        assert_eq!(written(&mut output)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn output_rejects_writes_beyond_the_window() -> std::io::Result<()> {
+        let data = data(40);
+        let mut output = output(20)?;
+        write_at(&mut output, 19, &data[19..21])?;
+        write_at(&mut output, 20, &data[20..22]).expect_err("write beyond the window accepted");
+        assert_eq!(output.pending.len(), 1);
+
+        // the window moves along with the data that has been written
+        write_at(&mut output, 0, &data[..19])?;
+        write_at(&mut output, 30, &data[30..32])?;
+        Ok(())
+    }
+}
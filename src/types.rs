@@ -1,4 +1,4 @@
-use std::{fmt::Display, path::PathBuf};
+use std::{fmt::Display, ops::Range, path::PathBuf, time::Duration};
 
 // Clap value parser and display implementations
 macro_rules! clapify {
@@ -73,7 +73,7 @@ clapify!(TargetRate, u64, TargetRateValueParser);
 pub struct ErrorRate(pub u32);
 clapify!(ErrorRate, u32, ErrorRateValueParser);
 
-#[derive(Debug, Clone, Copy, Default, bincode::Encode, bincode::Decode)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, bincode::Encode, bincode::Decode)]
 pub struct FileSize(pub u64);
 
 #[derive(Debug, Clone, Copy, bincode::Encode, bincode::Decode)]
@@ -91,12 +91,35 @@ impl Display for Fraction {
 #[derive(Debug, Clone, bincode::Encode, bincode::Decode)]
 pub struct FileMetadata {
     pub path: PathBuf,
+
+    /// The size of the file. Only meaningful for regular files.
     pub size: FileSize,
+    pub entry_type: EntryType,
+
+    /// The permission bits of the entry, including the setuid, setgid and sticky bits.
+    pub mode: u32,
+
+    /// The modification time of the entry, as time since the UNIX epoch, if available.
+    pub mtime: Option<Duration>,
+}
+
+/// The type of an entry in the file list of a server.
+#[derive(Debug, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub enum EntryType {
+    File,
+    Directory,
+
+    /// A symbolic link, which is not followed, with the path it points to.
+    Symlink(PathBuf),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub enum UdpErrors {
-    Available { initial: u64, current: u64 },
+    Available {
+        initial: u64,
+        current: u64,
+    },
+    #[default]
     Unavailable,
 }
 
@@ -117,12 +140,6 @@ impl Display for UdpErrors {
     }
 }
 
-impl Default for UdpErrors {
-    fn default() -> Self {
-        Self::Unavailable
-    }
-}
-
 impl UdpErrors {
     #[must_use]
     pub fn new() -> Self {
@@ -153,107 +170,390 @@ impl UdpErrors {
     }
 }
 
+/// The hash algorithm used for the chunk-wise checksums that are compared to decide which parts of
+/// a file can be skipped when resuming a transfer.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, bincode::Encode, bincode::Decode, clap::ValueEnum,
+)]
+pub enum ChecksumAlgorithm {
+    /// 64-bit xxh3. Very fast, but only suitable for detecting accidental damage: a tampered
+    /// local file, or a chance collision, may cause a differing chunk to be skipped.
+    Xxh3,
+
+    /// 256-bit BLAKE3. Cryptographically strong, and still fast.
+    #[default]
+    Blake3,
+}
+
+impl Display for ChecksumAlgorithm {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Xxh3 => write!(formatter, "xxh3"),
+            Self::Blake3 => write!(formatter, "BLAKE3"),
+        }
+    }
+}
+
+/// The algorithm used to compress the blocks of a transfer on the wire.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, bincode::Encode, bincode::Decode, clap::ValueEnum,
+)]
+#[repr(u8)]
+pub enum Compression {
+    /// Blocks are sent uncompressed.
+    #[default]
+    None,
+
+    /// LZ4, which is very fast, but compresses less.
+    Lz4,
+
+    /// Zstandard, which compresses better, at the cost of more CPU time.
+    Zstd,
+}
+
+/// The order in which the entries of a file listing are sent.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, bincode::Encode, bincode::Decode, clap::ValueEnum,
+)]
+pub enum ListOrder {
+    /// The order in which the server has indexed the entries, with directories before their
+    /// contents.
+    #[default]
+    Index,
+
+    /// By path.
+    Name,
+
+    /// By size, with directories and symbolic links counting as empty.
+    Size,
+
+    /// By modification time, with entries whose modification time is unknown first.
+    Mtime,
+}
+
+impl Display for Compression {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::None => write!(formatter, "none"),
+            Self::Lz4 => write!(formatter, "LZ4"),
+            Self::Zstd => write!(formatter, "zstd"),
+        }
+    }
+}
+
+/// The checksums of each chunk of a file, calculated using one of the `ChecksumAlgorithm`s.
+#[derive(Debug, Clone, bincode::Encode, bincode::Decode)]
+pub enum ChunkChecksums {
+    Xxh3(Vec<u64>),
+    Blake3(Vec<[u8; 32]>),
+}
+
+impl ChunkChecksums {
+    /// Creates an empty list of checksums for the given algorithm, with space for `capacity`
+    /// checksums.
+    #[must_use]
+    pub fn with_capacity(algorithm: ChecksumAlgorithm, capacity: usize) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Xxh3 => Self::Xxh3(Vec::with_capacity(capacity)),
+            ChecksumAlgorithm::Blake3 => Self::Blake3(Vec::with_capacity(capacity)),
+        }
+    }
+
+    #[must_use]
+    pub fn algorithm(&self) -> ChecksumAlgorithm {
+        match self {
+            Self::Xxh3(_) => ChecksumAlgorithm::Xxh3,
+            Self::Blake3(_) => ChecksumAlgorithm::Blake3,
+        }
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Xxh3(checksums) => checksums.len(),
+            Self::Blake3(checksums) => checksums.len(),
+        }
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Checks whether the checksum of the chunk with the given index matches the one in the other
+    /// list. Returns `None` if the chunk is not present in both lists, or if the lists were
+    /// calculated using different algorithms.
+    #[must_use]
+    pub fn chunk_matches(&self, other: &ChunkChecksums, index: usize) -> Option<bool> {
+        match (self, other) {
+            (Self::Xxh3(ours), Self::Xxh3(theirs)) => Some(ours.get(index)? == theirs.get(index)?),
+            (Self::Blake3(ours), Self::Blake3(theirs)) => {
+                Some(ours.get(index)? == theirs.get(index)?)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A request for the checksums of some of the chunks of a file. All chunks consist of
+/// `chunk_blocks` blocks, with chunk `n` starting at block `n * chunk_blocks + 1`; only the last
+/// chunk of the file may be shorter.
+#[derive(Debug, Clone, bincode::Encode, bincode::Decode)]
+pub struct ChecksumRequest {
+    pub chunk_blocks: u32,
+    pub chunks: Vec<u32>,
+}
+
+/// The checksums of the chunks listed in a `ChecksumRequest`, in the same order.
 #[derive(Debug, Clone, bincode::Encode, bincode::Decode)]
 pub struct FileChecksums {
-    pub chunk_blocks: u64,
-    pub last_chunk_blocks: u64,
-    pub checksums: Vec<u64>,
+    pub chunk_blocks: u32,
+    pub checksums: ChunkChecksums,
+}
+
+/// Strong digests of a file, used to verify the integrity of a transferred file. Contains the
+/// BLAKE3 digest of the whole file, as well as one of each chunk of `chunk_size` bytes.
+#[derive(Debug, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct FileDigests {
+    pub file_digest: [u8; 32],
+    pub chunk_size: u64,
+    pub chunk_digests: Vec<[u8; 32]>,
 }
 
-impl FileChecksums {
-    /// Find the chunks that match between the current object and the other given `FileChecksums`.
-    /// Returns a bit vector of which chunks match, and the number of matching chunks.
+impl FileDigests {
+    /// Finds the byte ranges of the file in which the current object and the other given
+    /// `FileDigests` differ. If the digests match, an empty `Vec` is returned.
     ///
     /// # Panics
-    /// Panics if the count overflows.
+    /// Panics on arithmetic overflow.
     #[must_use]
-    pub fn compare(&self, other: &FileChecksums) -> SkipChunks {
-        let mut res = vec![false; self.checksums.len()];
-        let mut count = 0_u64;
-
-        // It makes no sense to compare two `FileChecksums` with different chunk sizes, so in that
-        // case, we just assume that all chunks are different
-        if self.chunk_blocks != other.chunk_blocks {
-            // Return the all-`false` `Vec` we created before
-            return SkipChunks {
-                matches: res,
-                chunk_blocks: self.chunk_blocks,
-                last_chunk_blocks: self.last_chunk_blocks,
-            };
+    #[allow(clippy::single_range_in_vec_init)]
+    pub fn mismatched_ranges(&self, other: &FileDigests, file_size: FileSize) -> Vec<Range<u64>> {
+        if self == other {
+            return vec![];
         }
 
-        // Iterate and compare all checksums
-        for (index, checksum) in self.checksums.iter().enumerate() {
-            // Ignore chunks that are not present at all in the other object
-            if let Some(other_checksum) = other.checksums.get(index) {
-                count = count.checked_add(1).expect("count overflow");
-                res[index] = checksum == other_checksum;
-            }
+        // Without comparable chunks, we can only say that the file as a whole is different
+        if self.chunk_size != other.chunk_size
+            || self.chunk_digests.len() != other.chunk_digests.len()
+        {
+            return vec![0..file_size.0];
         }
 
-        SkipChunks {
-            matches: res,
-            chunk_blocks: self.chunk_blocks,
-            last_chunk_blocks: self.last_chunk_blocks,
+        let ranges: Vec<Range<u64>> = self
+            .chunk_digests
+            .iter()
+            .zip(&other.chunk_digests)
+            .enumerate()
+            .filter(|(_, (ours, theirs))| ours != theirs)
+            .map(|(index, _)| {
+                let start = (index as u64)
+                    .checked_mul(self.chunk_size)
+                    .expect("chunk start overflow");
+                let end = start
+                    .checked_add(self.chunk_size)
+                    .expect("chunk end overflow")
+                    .min(file_size.0);
+                start..end
+            })
+            .collect();
+
+        // The file digest differs, but all chunks match; this should be impossible
+        if ranges.is_empty() {
+            return vec![0..file_size.0];
         }
+
+        ranges
     }
 }
 
-#[derive(Debug, Clone, bincode::Encode, bincode::Decode)]
+/// An inclusive range of blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct BlockRange {
+    pub first: BlockIndex,
+    pub last: BlockIndex,
+}
+
+impl BlockRange {
+    /// Determines the range of blocks covered by the given chunk, if chunks consist of
+    /// `chunk_blocks` blocks each, in a file with the given number of blocks. Returns `None` if the
+    /// chunk lies entirely beyond the end of the file.
+    ///
+    /// # Panics
+    /// Panics on arithmetic overflow.
+    #[must_use]
+    pub fn of_chunk(chunk: u32, chunk_blocks: u32, block_count: BlockIndex) -> Option<Self> {
+        let first = u64::from(chunk)
+            .checked_mul(u64::from(chunk_blocks))
+            .and_then(|start| start.checked_add(1))
+            .expect("chunk start overflow");
+        let last = first
+            .checked_add(u64::from(chunk_blocks))
+            .and_then(|end| end.checked_sub(1))
+            .expect("chunk end overflow")
+            .min(u64::from(block_count.0));
+        if first > last {
+            return None;
+        }
+
+        Some(Self {
+            first: BlockIndex(first.try_into().expect("BlockIndex overflow #1")),
+            last: BlockIndex(last.try_into().expect("BlockIndex overflow #2")),
+        })
+    }
+
+    /// Returns the number of blocks in the range.
+    ///
+    /// # Panics
+    /// Panics if the range is reversed.
+    #[must_use]
+    pub fn block_count(&self) -> u64 {
+        u64::from(self.last.safe_sub(self.first).0)
+            .checked_add(1)
+            .expect("block count overflow")
+    }
+
+    /// Determines the range of blocks of the given size that lie completely within the byte range
+    /// from `start` to `end` (exclusive) of a file with the given size and number of blocks. The
+    /// last block of the file may be shorter than the others. Returns `None` if no block lies
+    /// completely within the byte range.
+    ///
+    /// # Panics
+    /// Panics if the block size is 0.
+    #[must_use]
+    pub fn within_bytes(
+        start: u64,
+        end: u64,
+        block_size: u16,
+        file_size: FileSize,
+        block_count: BlockIndex,
+    ) -> Option<Self> {
+        let block_size = u64::from(block_size);
+        let end = end.min(file_size.0);
+        let first = start.div_ceil(block_size).checked_add(1)?;
+        let last = if end == file_size.0 {
+            u64::from(block_count.0)
+        } else {
+            end.checked_div(block_size).expect("block size is 0")
+        };
+
+        (first <= last).then_some(Self {
+            first: BlockIndex(first.try_into().ok()?),
+            last: BlockIndex(last.try_into().ok()?),
+        })
+    }
+
+    #[must_use]
+    pub fn contains(&self, block_index: BlockIndex) -> bool {
+        self.first <= block_index && block_index <= self.last
+    }
+}
+
+/// The blocks that can be skipped when resuming a transfer, because the receiving side already has
+/// them. Stored as a sorted list of non-overlapping ranges.
+#[derive(Debug, Clone, Default)]
 pub struct SkipChunks {
-    pub chunk_blocks: u64,
-    pub last_chunk_blocks: u64,
-    pub matches: Vec<bool>,
+    ranges: Vec<BlockRange>,
 }
 
 impl SkipChunks {
+    /// Creates a new `SkipChunks` object from the given ranges, which may be in any order, and may
+    /// overlap. Reversed ranges are ignored.
+    ///
+    /// # Panics
+    /// Panics on arithmetic overflow.
+    #[must_use]
+    pub fn from_ranges(mut ranges: Vec<BlockRange>) -> Self {
+        ranges.retain(|range| range.first <= range.last);
+        ranges.sort_unstable_by_key(|range| range.first);
+
+        let mut merged: Vec<BlockRange> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(previous)
+                    if u64::from(range.first.0) <= u64::from(previous.last.0).saturating_add(1) =>
+                {
+                    previous.last = previous.last.max(range.last);
+                }
+                _ => merged.push(range),
+            }
+        }
+
+        Self { ranges: merged }
+    }
+
+    /// Returns the ranges of blocks that can be skipped, in ascending order.
+    #[must_use]
+    pub fn ranges(&self) -> &[BlockRange] {
+        &self.ranges
+    }
+
     /// Counts the number of matching blocks.
     ///
     /// # Panics
     /// Panics on arithmetic overflow.
     #[must_use]
     pub fn count_blocks(&self) -> u64 {
-        let Some(full_block_count) = self.matches.len().checked_sub(1) else {
-            // `matches` has 0 length, so the return value will always be 0
-            return 0;
-        };
-
-        let full_chunk_count = self
-            .matches
+        self.ranges
             .iter()
-            .take(full_block_count)
-            .copied()
-            .filter(|present| *present)
-            .count() as u64;
-        let last_chunk_blocks = if self.matches.last().is_some_and(|present| *present) {
-            self.last_chunk_blocks
-        } else {
-            0
-        };
-        full_chunk_count
-            .checked_mul(self.chunk_blocks)
-            .and_then(|full_chunk_blocks| full_chunk_blocks.checked_add(last_chunk_blocks))
+            .map(BlockRange::block_count)
+            .try_fold(0_u64, u64::checked_add)
             .expect("count_blocks overflow")
     }
 
-    /// Checks if the given block can be skipped.
+    /// Marks the blocks from `first` to `last` (inclusive) as not matching, so that these blocks
+    /// will be transmitted.
     ///
     /// # Panics
     /// Panics on arithmetic overflow.
+    pub fn invalidate_blocks(&mut self, first: BlockIndex, last: BlockIndex) {
+        let mut remaining = Vec::with_capacity(self.ranges.len());
+        for range in &self.ranges {
+            if range.last < first || range.first > last {
+                remaining.push(*range);
+                continue;
+            }
+
+            // keep the parts of the range outside the invalidated blocks
+            if range.first < first {
+                remaining.push(BlockRange {
+                    first: range.first,
+                    last: first.safe_sub(BlockIndex(1)),
+                });
+            }
+            if range.last > last {
+                remaining.push(BlockRange {
+                    first: last.safe_add(BlockIndex(1)),
+                    last: range.last,
+                });
+            }
+        }
+        self.ranges = remaining;
+    }
+
+    /// Returns the first block, starting from block 1, that cannot be skipped.
+    #[must_use]
+    pub fn first_missing(&self) -> BlockIndex {
+        match self.ranges.first() {
+            Some(range) if range.first == BlockIndex(1) => range.last.safe_add(BlockIndex(1)),
+            _ => BlockIndex(1),
+        }
+    }
+
+    /// Checks if the given block can be skipped.
     #[must_use]
     pub fn has_block(&self, block_index: BlockIndex) -> bool {
-        let chunk_index: usize = u64::from(block_index.0)
-            .checked_div(self.chunk_blocks)
-            .expect("chunk_blocks is 0")
-            .try_into()
-            .expect("chunk_index overflow");
-        let maybe_chunk_present: Option<&bool> = self.matches.get(chunk_index);
-        maybe_chunk_present.is_some_and(|present| *present)
+        let position = self
+            .ranges
+            .partition_point(|range| range.last < block_index);
+        self.ranges
+            .get(position)
+            .is_some_and(|range| range.contains(block_index))
     }
 }
 
 /// Compact bitset to store which blocks we already received
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct ReceivedMap {
     pub inner: Vec<u8>,
 }
@@ -277,11 +577,149 @@ impl ReceivedMap {
 
     #[must_use]
     pub fn got_block(&self, blocknr: BlockIndex) -> bool {
-        self.inner[(blocknr.0 / 8) as usize] & (1 << (blocknr.0 % 8)) != 0
+        self.inner
+            .get((blocknr.0 / 8) as usize)
+            .is_some_and(|byte| byte & (1 << (blocknr.0 % 8)) != 0)
     }
 
+    /// Marks the given block as received. The map grows as necessary, for transfers whose number
+    /// of blocks is not known up front.
+    ///
+    /// # Panics
+    /// Panics on arithmetic overflow.
     pub fn set(&mut self, this_block: BlockIndex) {
-        let fresh1 = &mut self.inner[(this_block.0 / 8) as usize];
-        *fresh1 |= 1 << (this_block.0 % 8);
+        let index = (this_block.0 / 8) as usize;
+        if index >= self.inner.len() {
+            self.inner.resize(
+                index
+                    .checked_add(1)
+                    .expect("`received` bitfield size overflow"),
+                0,
+            );
+        }
+        self.inner[index] |= 1 << (this_block.0 % 8);
+    }
+
+    /// Returns the ranges of blocks from 1 to `block_count` (inclusive) that have been received, in
+    /// ascending order.
+    ///
+    /// # Panics
+    /// Panics on arithmetic overflow.
+    #[must_use]
+    pub fn ranges(&self, block_count: BlockIndex) -> Vec<BlockRange> {
+        let mut ranges = vec![];
+        let mut current: Option<BlockRange> = None;
+        let mut block = 1_u32;
+
+        while block <= block_count.0 {
+            // Skip over whole bytes of the bitset at once, if possible
+            let whole_byte = block.is_multiple_of(8)
+                && block_count.0.saturating_sub(block) >= 7
+                && matches!(self.inner.get((block / 8) as usize), Some(0 | 0xff));
+            let step = if whole_byte { 8 } else { 1 };
+            let last = BlockIndex(
+                block
+                    .checked_add(step)
+                    .and_then(|end| end.checked_sub(1))
+                    .expect("block overflow"),
+            );
+
+            if self.got_block(BlockIndex(block)) {
+                match &mut current {
+                    Some(range) => range.last = last,
+                    None => {
+                        current = Some(BlockRange {
+                            first: BlockIndex(block),
+                            last,
+                        });
+                    }
+                }
+            } else if let Some(range) = current.take() {
+                ranges.push(range);
+            }
+
+            block = block.checked_add(step).expect("block overflow");
+        }
+        ranges.extend(current);
+
+        ranges
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockIndex, BlockRange, SkipChunks};
+
+    fn range(first: u32, last: u32) -> BlockRange {
+        BlockRange {
+            first: BlockIndex(first),
+            last: BlockIndex(last),
+        }
+    }
+
+    #[test]
+    fn skip_chunks_merge_overlapping_ranges() {
+        let skip_chunks = SkipChunks::from_ranges(vec![
+            range(20, 30),
+            range(1, 5),
+            range(4, 10),
+            range(11, 12),
+            range(25, 28),
+            range(40, 39),
+        ]);
+
+        assert_eq!(skip_chunks.ranges(), [range(1, 12), range(20, 30)]);
+        assert_eq!(skip_chunks.count_blocks(), 23);
+        assert_eq!(skip_chunks.first_missing(), BlockIndex(13));
+        assert!(skip_chunks.has_block(BlockIndex(12)));
+        assert!(!skip_chunks.has_block(BlockIndex(13)));
+        assert!(!skip_chunks.has_block(BlockIndex(39)));
+    }
+
+    #[test]
+    fn skip_chunks_invalidate_blocks() {
+        let mut skip_chunks = SkipChunks::from_ranges(vec![range(1, 10), range(20, 30)]);
+
+        // overlapping the end of one range and the start of the next
+        skip_chunks.invalidate_blocks(BlockIndex(8), BlockIndex(21));
+        assert_eq!(skip_chunks.ranges(), [range(1, 7), range(22, 30)]);
+
+        // within a range, splitting it
+        skip_chunks.invalidate_blocks(BlockIndex(3), BlockIndex(4));
+        assert_eq!(
+            skip_chunks.ranges(),
+            [range(1, 2), range(5, 7), range(22, 30)]
+        );
+
+        // between ranges
+        skip_chunks.invalidate_blocks(BlockIndex(12), BlockIndex(15));
+        assert_eq!(
+            skip_chunks.ranges(),
+            [range(1, 2), range(5, 7), range(22, 30)]
+        );
+
+        // covering a whole range
+        skip_chunks.invalidate_blocks(BlockIndex(5), BlockIndex(7));
+        assert_eq!(skip_chunks.ranges(), [range(1, 2), range(22, 30)]);
+        assert_eq!(skip_chunks.count_blocks(), 11);
+    }
+
+    #[test]
+    fn skip_chunks_at_end_of_file() {
+        let block_count = BlockIndex(30);
+        let mut skip_chunks = SkipChunks::from_ranges(vec![range(1, 30)]);
+
+        // the final block, and beyond the end of the file
+        skip_chunks.invalidate_blocks(block_count, BlockIndex(u32::MAX));
+        assert_eq!(skip_chunks.ranges(), [range(1, 29)]);
+        assert!(!skip_chunks.has_block(block_count));
+
+        // a final chunk that is shorter than the others
+        let last_chunk = BlockRange::of_chunk(3, 8, block_count);
+        assert_eq!(last_chunk, Some(range(25, 30)));
+        assert_eq!(BlockRange::of_chunk(4, 8, block_count), None);
+        skip_chunks.invalidate_blocks(BlockIndex(25), BlockIndex(30));
+        assert_eq!(skip_chunks.ranges(), [range(1, 24)]);
+        assert_eq!(skip_chunks.first_missing(), BlockIndex(25));
     }
 }
@@ -0,0 +1,51 @@
+require 'open3'
+require 'digest/md5'
+require 'fileutils'
+
+NAMIDA_PATH = ["../target/release/namida"]
+
+puts "namida put, namida server"
+puts "-------------------------"
+puts
+
+FileUtils.rm_rf("uploads")
+
+start = Time.now
+sin, sout, swait = Open3.popen2e({ "RUST_BACKTRACE" => "1" }, *NAMIDA_PATH, "serve", "--verbose", "--index", "never", "--upload-dir", "uploads", "--secret", "psk.txt")
+sleep 0.2
+cin, cout, cwait = Open3.popen2e({ "RUST_BACKTRACE" => "1" }, *NAMIDA_PATH, "put", "--secret", "psk.txt", "--server", "127.0.0.1", "source/fish.jpg")
+
+sleep 1.0
+
+Process.kill("KILL", cwait.pid) rescue puts "failed to kill client"
+Process.kill("KILL", swait.pid) rescue puts "failed to kill server"
+finish = Time.now
+
+puts "client output:"
+puts cout.read
+puts
+
+puts "server output:"
+puts sout.read
+puts
+
+sin.close
+sout.close
+cin.close
+cout.close
+
+if File.exist?("uploads/fish.jpg")
+  content = File.read("uploads/fish.jpg")
+  digest = Digest::MD5.hexdigest(content)
+  if digest == "17f6d0c96590ad1c933314c0cbdb0aa0"
+    puts "ok"
+  else
+    puts "not ok, read #{content.length} bytes, md5: #{digest}"
+  end
+else
+  puts "not ok, no file created"
+end
+
+FileUtils.rm_rf("uploads")
+
+puts "time taken: #{finish - start}"